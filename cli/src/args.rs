@@ -21,16 +21,23 @@
 
 #![allow(clippy::needless_update)] // Caused by the From derivation macro
 
+use std::path::PathBuf;
+use std::time::Duration;
+
 use bp_util::{Config, DescriptorOpts};
-use bpstd::{Wpkh, XpubDerivable};
+use bpstd::{Network, Wpkh, XpubDerivable};
 use rgb_rt::{
-    electrum, esplora_blocking, AnyResolver, AnyResolverError, RgbDescr, Runtime, RuntimeError,
-    TapretKey,
+    bitcoind_rpc, electrum, esplora_blocking, offline, AnyResolver, AnyResolverError,
+    CachingResolver, LockMode, MultiResolver, ResolveTip, RgbDescr, Runtime, RuntimeError,
+    StockLock, TapretKey, DEFAULT_CACHE_DEPTH,
 };
 use rgbstd::persistence::Stock;
+use rgbstd::Layer1;
 
 use crate::Command;
 
+pub const WITNESS_CACHE_FILE: &str = "witness.cache";
+
 #[derive(Args, Clone, PartialEq, Eq, Debug)]
 #[group()]
 pub struct DescrRgbOpts {
@@ -57,6 +64,89 @@ impl DescriptorOpts for DescrRgbOpts {
     }
 }
 
+#[derive(Args, Clone, PartialEq, Eq, Debug, Default)]
+pub struct BitcoindOpts {
+    /// Bitcoin Core JSON-RPC server to use as a resolver instead of
+    /// electrum/esplora. Requires bitcoind running with `txindex=1`.
+    #[arg(long, global = true, value_name = "URL", env = "BITCOIND_RPC")]
+    pub bitcoind_rpc: Option<String>,
+
+    /// Path to the bitcoind cookie file used for RPC authentication.
+    #[arg(
+        long,
+        global = true,
+        value_name = "FILE",
+        conflicts_with_all = ["bitcoind_user", "bitcoind_password"]
+    )]
+    pub bitcoind_cookie: Option<PathBuf>,
+
+    /// User name for bitcoind RPC authentication.
+    #[arg(long, global = true, requires = "bitcoind_password")]
+    pub bitcoind_user: Option<String>,
+
+    /// Password for bitcoind RPC authentication.
+    #[arg(long, global = true, env = "BITCOIND_PASSWORD", requires = "bitcoind_user")]
+    pub bitcoind_password: Option<String>,
+}
+
+impl BitcoindOpts {
+    pub fn auth(&self) -> bitcoind_rpc::Auth {
+        match (&self.bitcoind_cookie, &self.bitcoind_user, &self.bitcoind_password) {
+            (Some(cookie), _, _) => bitcoind_rpc::Auth::CookieFile(cookie.clone()),
+            (None, Some(user), Some(password)) => {
+                bitcoind_rpc::Auth::UserPass(user.clone(), password.clone())
+            }
+            _ => bitcoind_rpc::Auth::None,
+        }
+    }
+}
+
+#[derive(Args, Clone, PartialEq, Eq, Debug)]
+pub struct CacheOpts {
+    /// Number of confirmations after which witness transaction mining
+    /// information is cached in the stock directory and reused across runs.
+    #[arg(long, global = true, default_value_t = DEFAULT_CACHE_DEPTH)]
+    pub cache_depth: u32,
+
+    /// Do not use persistent witness cache.
+    #[arg(long, global = true)]
+    pub no_cache: bool,
+}
+
+#[derive(Args, Clone, PartialEq, Eq, Debug)]
+pub struct FallbackOpts {
+    /// Additional electrum server to use if the main resolver fails, or to
+    /// confirm its data when `--quorum` is used. May be given several times.
+    #[arg(long, global = true, value_name = "URL")]
+    pub fallback_electrum: Vec<String>,
+
+    /// Additional esplora server to use if the main resolver fails, or to
+    /// confirm its data when `--quorum` is used. May be given several times.
+    #[arg(long, global = true, value_name = "URL")]
+    pub fallback_esplora: Vec<String>,
+
+    /// Esplora server indexing liquid, used to resolve witness transactions
+    /// of the contracts operating on liquid. May be given several times.
+    #[arg(long, global = true, value_name = "URL")]
+    pub liquid_esplora: Vec<String>,
+
+    /// Number of resolvers which must agree on witness transactions and their
+    /// mining status. Witnesses on bitcoin and liquid are resolved only by
+    /// the resolvers for the same layer 1, thus the quorum applies to each of
+    /// them separately.
+    #[arg(long, global = true, default_value_t = 1)]
+    pub quorum: usize,
+}
+
+impl FallbackOpts {
+    pub fn is_some(&self) -> bool {
+        !self.fallback_electrum.is_empty() ||
+            !self.fallback_esplora.is_empty() ||
+            !self.liquid_esplora.is_empty() ||
+            self.quorum > 1
+    }
+}
+
 /// Command-line arguments
 #[derive(Parser)]
 #[derive(Wrapper, WrapperMut, Clone, Eq, PartialEq, Debug, From)]
@@ -65,7 +155,34 @@ impl DescriptorOpts for DescrRgbOpts {
 #[command(author, version, about)]
 pub struct RgbArgs {
     #[clap(flatten)]
+    #[wrap]
     pub inner: bp_util::Args<Command, DescrRgbOpts>,
+
+    #[clap(flatten)]
+    pub bitcoind: BitcoindOpts,
+
+    /// Directory with witness transactions and their SPV proofs to use for
+    /// validation instead of network resolvers.
+    #[arg(long, global = true, value_name = "DIR")]
+    pub offline: Option<PathBuf>,
+
+    /// Verify merkle proofs and block headers of witness transactions
+    /// locally instead of trusting electrum or esplora server. Can't be used
+    /// with `--bitcoind-rpc` or `--offline`; liquid witnesses are not
+    /// verified, since liquid blocks are not secured by proof of work.
+    #[arg(long, global = true)]
+    pub spv: bool,
+
+    #[clap(flatten)]
+    pub fallback: FallbackOpts,
+
+    #[clap(flatten)]
+    pub cache: CacheOpts,
+
+    /// Time to wait, in seconds, for the stock to be unlocked by another
+    /// process. If not given, fails immediately when the stock is locked.
+    #[arg(long, global = true, value_name = "SECS")]
+    pub lock_timeout: Option<u64>,
 }
 
 impl Default for RgbArgs {
@@ -73,33 +190,136 @@ impl Default for RgbArgs {
 }
 
 impl RgbArgs {
+    pub fn lock_timeout(&self) -> Option<Duration> { self.lock_timeout.map(Duration::from_secs) }
+
+    pub fn lock_stock(&self, mode: LockMode) -> Result<StockLock, RuntimeError> {
+        StockLock::acquire(self.general.base_dir(), mode, self.lock_timeout())
+    }
+
     pub fn rgb_stock(&self) -> Result<Stock, RuntimeError> {
         eprint!("Loading stock ... ");
-        let runtime = Runtime::<RgbDescr>::load_walletless(&self.general.base_dir())?;
+        let runtime =
+            Runtime::<RgbDescr>::load_walletless(&self.general.base_dir(), self.lock_timeout())?;
         eprintln!("success");
 
         Ok(runtime)
     }
 
+    /// Loads runtime locking the stock for reading if the command doesn't
+    /// modify it, or for writing otherwise.
     pub fn rgb_runtime(&self, config: &Config) -> Result<Runtime, RuntimeError> {
         let bprt = self.inner.bp_runtime::<RgbDescr>(config)?;
+        let mode = self.command.lock_mode().unwrap_or(LockMode::Exclusive);
         eprint!("Loading stock ... ");
-        let runtime = Runtime::<RgbDescr>::load_attach(self.general.base_dir(), bprt)?;
+        let runtime = Runtime::<RgbDescr>::load_attach(
+            self.general.base_dir(),
+            bprt,
+            mode,
+            self.lock_timeout(),
+        )?;
         eprintln!("success");
 
         Ok(runtime)
     }
 
+    fn spv_network(&self) -> Option<Network> { self.spv.then_some(self.general.network) }
+
     #[allow(clippy::result_large_err)]
-    pub fn resolver(&self) -> Result<AnyResolver, AnyResolverError> {
-        if self.resolver.electrum != bp_util::DEFAULT_ELECTRUM {
+    pub fn resolver(&self) -> Result<CachingResolver<AnyResolver>, RuntimeError> {
+        if self.spv && (self.offline.is_some() || self.bitcoind.bitcoind_rpc.is_some()) {
+            return Err("--spv can be used only with electrum and esplora resolvers"
+                .to_owned()
+                .into());
+        }
+        let resolver = if self.fallback.is_some() {
+            if self.offline.is_some() {
+                return Err(RuntimeError::OfflineFallback);
+            }
+            self.multi_resolver()?
+        } else {
+            self.any_resolver()?
+        };
+        if self.cache.no_cache {
+            return Ok(CachingResolver::new(resolver, self.cache.cache_depth));
+        }
+        let path = self.general.base_dir().join(WITNESS_CACHE_FILE);
+        Ok(CachingResolver::load(resolver, path, self.cache.cache_depth)?)
+    }
+
+    #[allow(clippy::result_large_err)]
+    fn multi_resolver(&self) -> Result<AnyResolver, RuntimeError> {
+        let mut first_err = None;
+        let mut resolvers = vec![];
+        let electrum = self.fallback.fallback_electrum.iter().map(|url| {
+            electrum::Resolver::new(url)
+                .map(|c| c.with_spv(self.spv_network()))
+                .map(|c| AnyResolver::Electrum(Box::new(c)))
+                .map_err(AnyResolverError::Electrum)
+        });
+        let esplora = self.fallback.fallback_esplora.iter().map(|url| {
+            esplora_blocking::Resolver::new(url)
+                .map(|c| c.with_spv(self.spv_network()))
+                .map(|c| AnyResolver::Esplora(Box::new(c)))
+                .map_err(AnyResolverError::Esplora)
+        });
+        let liquid = self.fallback.liquid_esplora.iter().map(|url| {
+            esplora_blocking::Resolver::with_layer1(url, Layer1::Liquid)
+                .map(|c| AnyResolver::Esplora(Box::new(c)))
+                .map_err(AnyResolverError::Esplora)
+        });
+        let fallbacks = electrum.chain(esplora).chain(liquid);
+        for resolver in [self.any_resolver()].into_iter().chain(fallbacks) {
+            match resolver {
+                Ok(resolver) => resolvers.push(resolver),
+                Err(err) => {
+                    eprintln!("Warning: resolver is not available: {err}");
+                    first_err.get_or_insert(err);
+                }
+            }
+        }
+        if resolvers.is_empty() {
+            return Err(first_err.expect("at least one resolver is always present").into());
+        }
+        let mut layers = vec![Layer1::Bitcoin];
+        if !self.fallback.liquid_esplora.is_empty() {
+            layers.push(Layer1::Liquid);
+        }
+        for layer1 in layers {
+            let available = resolvers
+                .iter()
+                .filter(|resolver| resolver.supports(layer1))
+                .count();
+            if self.fallback.quorum > available {
+                return Err(RuntimeError::QuorumUnreachable {
+                    quorum: self.fallback.quorum,
+                    available,
+                    layer1,
+                });
+            }
+        }
+        Ok(AnyResolver::Multi(Box::new(MultiResolver::new(resolvers, self.fallback.quorum))))
+    }
+
+    #[allow(clippy::result_large_err)]
+    fn any_resolver(&self) -> Result<AnyResolver, AnyResolverError> {
+        if let Some(dir) = &self.offline {
+            match offline::Resolver::load(dir) {
+                Ok(c) => Ok(AnyResolver::Offline(Box::new(c))),
+                Err(e) => Err(AnyResolverError::Offline(e)),
+            }
+        } else if let Some(url) = &self.bitcoind.bitcoind_rpc {
+            match bitcoind_rpc::Resolver::new(url, self.bitcoind.auth()) {
+                Ok(c) => Ok(AnyResolver::BitcoindRpc(Box::new(c))),
+                Err(e) => Err(AnyResolverError::BitcoindRpc(e)),
+            }
+        } else if self.resolver.electrum != bp_util::DEFAULT_ELECTRUM {
             match electrum::Resolver::new(&self.resolver.electrum) {
-                Ok(c) => Ok(AnyResolver::Electrum(Box::new(c))),
+                Ok(c) => Ok(AnyResolver::Electrum(Box::new(c.with_spv(self.spv_network())))),
                 Err(e) => Err(AnyResolverError::Electrum(e)),
             }
         } else {
             match esplora_blocking::Resolver::new(&self.resolver.esplora) {
-                Ok(c) => Ok(AnyResolver::Esplora(Box::new(c))),
+                Ok(c) => Ok(AnyResolver::Esplora(Box::new(c.with_spv(self.spv_network())))),
                 Err(e) => Err(AnyResolverError::Esplora(e)),
             }
         }
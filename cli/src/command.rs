@@ -21,35 +21,39 @@
 
 use std::fs;
 use std::fs::File;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::slice;
 use std::str::FromStr;
 
-use amplify::confinement::{SmallOrdMap, TinyOrdMap, TinyOrdSet, U16 as MAX16};
+use amplify::confinement::{SmallOrdMap, TinyOrdMap, TinyOrdSet};
 use baid58::ToBaid58;
 use bp_util::{BpCommand, Config, Exec};
-use bpstd::Sats;
+use bpstd::{Sats, SeqNo, Txid};
 use psbt::{Psbt, PsbtVer};
-use rgb_rt::{DescriptorRgb, RgbKeychain, RuntimeError, TransferParams};
+use rgb_rt::{
+    DescriptorRgb, FeeRate, HistoryFilter, IssueError, IssueRequest, LockMode, RgbDescr,
+    RgbKeychain, Runtime, RuntimeError, StateSelection, TransferParams,
+};
 use rgbstd::containers::{
-    BuilderSeal, ContainerVer, ContentId, ContentSigs, Contract, FileContent, Terminal, Transfer,
-    UniversalFile,
+    ContainerVer, ContentId, ContentSigs, Contract, FileContent, Terminal, Transfer, UniversalFile,
 };
-use rgbstd::contract::{ContractId, GenesisSeal, GraphSeal, StateType};
-use rgbstd::interface::{AmountChange, ContractSuppl, FilterExclude, IfaceId};
+use rgbstd::contract::{ContractId, GraphSeal};
+use rgbstd::interface::{AmountChange, ContractSuppl, FilterExclude};
 use rgbstd::invoice::{Beneficiary, RgbInvoice, RgbInvoiceBuilder, XChainNet};
-use rgbstd::persistence::fs::StoreFs;
-use rgbstd::persistence::{SchemaIfaces, StashReadProvider};
+use rgbstd::persistence::StashReadProvider;
 use rgbstd::schema::SchemaId;
 use rgbstd::validation::Validity;
 use rgbstd::vm::RgbIsa;
-use rgbstd::{BundleId, OutputSeal, XChain, XOutputSeal};
+use rgbstd::{BundleId, XChain, XOutputSeal};
 use seals::txout::CloseMethod;
 use serde_crate::{Deserialize, Serialize};
 use strict_types::encoding::{FieldName, TypeName};
-use strict_types::StrictVal;
 
 use crate::RgbArgs;
 
+/// Sequence number signalling replaceability of a transaction (BIP-125).
+const RBF_SEQ_NO: u32 = 0xFFFF_FFFD;
+
 #[derive(Subcommand, Clone, PartialEq, Eq, Debug, Display)]
 #[display(lowercase)]
 #[allow(clippy::large_enum_variant)]
@@ -132,6 +136,20 @@ pub enum Command {
         iface: String,
     },
 
+    /// Print history of operations with all types of the contract state
+    /// affecting the wallet, ordered by their mining. If no contract is
+    /// given, prints all wallet transactions together with the contract
+    /// operations they have carried
+    #[display("history")]
+    History {
+        /// Contract identifier
+        #[clap(requires = "iface")]
+        contract_id: Option<ContractId>,
+
+        /// Interface to interpret the state data
+        iface: Option<String>,
+    },
+
     /// Display all known UTXOs belonging to this wallet
     Utxos,
 
@@ -141,7 +159,18 @@ pub enum Command {
         /// Schema name to use for the contract
         schema: SchemaId, //String,
 
-        /// File containing contract genesis description in YAML format
+        /// Format of the contract description file: `yaml`, `json` or
+        /// `toml`. If not given, detected from the file extension, defaulting
+        /// to YAML
+        #[clap(long)]
+        format: Option<ContractFormat>,
+
+        /// File containing contract genesis description
+        ///
+        /// Each assignment must provide `seal` and, depending on the type of
+        /// its state, `amount` for fungible state, `data` for structured
+        /// state or `file` with a path to the attached file; rights need no
+        /// other fields.
         contract: PathBuf,
     },
 
@@ -179,6 +208,21 @@ pub enum Command {
         #[clap(long, default_value = "2000")]
         sats: Sats,
 
+        /// Strategy for selecting RGB state to spend: `largest-first`,
+        /// `smallest-first`, `exact-match` or `oldest-first`
+        #[clap(long, default_value = "largest-first")]
+        selection: StateSelection,
+
+        /// Fee rate for bitcoin transaction, in satoshis per virtual byte. If
+        /// given, the fee is used as a minimal fee
+        #[clap(long)]
+        fee_rate: Option<FeeRate>,
+
+        /// Signal replaceability of the bitcoin transaction, such that its fee
+        /// can be bumped later with `bump-fee` command
+        #[clap(long)]
+        rbf: bool,
+
         /// Invoice data
         invoice: RgbInvoice,
 
@@ -219,6 +263,21 @@ pub enum Command {
         #[clap(long, default_value = "2000")]
         sats: Sats,
 
+        /// Strategy for selecting RGB state to spend: `largest-first`,
+        /// `smallest-first`, `exact-match` or `oldest-first`
+        #[clap(long, default_value = "largest-first")]
+        selection: StateSelection,
+
+        /// Fee rate for bitcoin transaction, in satoshis per virtual byte. If
+        /// given, the fee is used as a minimal fee
+        #[clap(long)]
+        fee_rate: Option<FeeRate>,
+
+        /// Signal replaceability of the bitcoin transaction, such that its fee
+        /// can be bumped later with `bump-fee` command
+        #[clap(long)]
+        rbf: bool,
+
         /// Invoice data
         invoice: RgbInvoice,
 
@@ -233,6 +292,29 @@ pub enum Command {
         psbt: Option<PathBuf>,
     },
 
+    /// Bump fee of a not yet mined RGB transfer using replace-by-fee
+    #[display("bump-fee")]
+    BumpFee {
+        /// Encode PSBT as V2
+        #[clap(short = '2')]
+        v2: bool,
+
+        /// Witness transaction id of the transfer
+        witness_txid: Txid,
+
+        /// New fee rate, in satoshis per virtual byte
+        fee_rate: FeeRate,
+
+        /// File for regenerated transfer consignment. If the transfer pays
+        /// several invoices, the consignments for the invoices following the
+        /// first one are saved with the invoice number added to the file name
+        consignment: PathBuf,
+
+        /// Name of PSBT file to save replacement transaction. If not given,
+        /// prints PSBT to STDOUT
+        new_psbt: Option<PathBuf>,
+    },
+
     /// Inspects any RGB data file
     #[display("inspect")]
     Inspect {
@@ -270,6 +352,11 @@ pub enum Command {
         root_dir: String,
     },
 
+    /// Migrate the stock from the file system layout to the SQLite database
+    #[cfg(feature = "sqlite")]
+    #[display("migrate-sqlite")]
+    MigrateSqlite,
+
     /// Validate transfer consignment
     #[display("validate")]
     Validate {
@@ -296,11 +383,85 @@ pub enum DebugCommand {
     Taprets,
 }
 
+/// Format of the file with contract genesis description.
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug, Default, Display)]
+#[display(lowercase)]
+pub enum ContractFormat {
+    #[default]
+    Yaml,
+    Json,
+    Toml,
+}
+
+impl FromStr for ContractFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "yaml" | "yml" => Ok(ContractFormat::Yaml),
+            "json" => Ok(ContractFormat::Json),
+            "toml" => Ok(ContractFormat::Toml),
+            _ => Err(format!("unknown contract description format '{s}'")),
+        }
+    }
+}
+
+impl ContractFormat {
+    /// Detects format from the file extension.
+    pub fn from_path(path: &Path) -> Option<Self> { path.extension()?.to_str()?.parse().ok() }
+
+    /// Parses contract description, reporting errors in the same way for all
+    /// formats.
+    pub fn parse(self, data: &str) -> Result<IssueRequest, IssueError> {
+        match self {
+            ContractFormat::Yaml => serde_yaml::from_str(data).map_err(|err| err.to_string()),
+            ContractFormat::Json => serde_json::from_str(data).map_err(|err| err.to_string()),
+            ContractFormat::Toml => toml::from_str(data).map_err(|err| err.to_string()),
+        }
+        .map_err(|details| IssueError::InvalidDescription {
+            format: self.to_string(),
+            details,
+        })
+    }
+}
+
+impl Command {
+    /// Lock on the stock required to execute the command, if any.
+    pub fn lock_mode(&self) -> Option<LockMode> {
+        match self {
+            Command::General(_) |
+            Command::Armor { .. } |
+            Command::Inspect { .. } |
+            Command::Reconstruct { .. } => None,
+            Command::Debug(_) |
+            Command::Schemata |
+            Command::Interfaces |
+            Command::Contracts |
+            Command::Export { .. } |
+            Command::State { .. } |
+            Command::HistoryFungible { .. } |
+            Command::History { .. } |
+            Command::Dump { .. } |
+            Command::Validate { .. } => Some(LockMode::Shared),
+            _ => Some(LockMode::Exclusive),
+        }
+    }
+}
+
 impl Exec for RgbArgs {
     type Error = RuntimeError;
     const CONF_FILE_NAME: &'static str = "rgb.toml";
 
     fn exec(self, config: Config, _name: &'static str) -> Result<(), RuntimeError> {
+        // Commands modifying the stock hold the lock until the stock is saved at
+        // the end of the command. Reading commands rely on the shared lock taken
+        // while the stock is loaded, since loading may need to create the stock,
+        // which can't be done under a shared lock.
+        let _lock = match self.command.lock_mode() {
+            Some(LockMode::Exclusive) => Some(self.lock_stock(LockMode::Exclusive)?),
+            Some(LockMode::Shared) | None => None,
+        };
+
         if let Some(stock) = match &self.command {
             Command::General(cmd) => {
                 self.inner.translate(cmd).exec(config, "rgb")?;
@@ -377,6 +538,77 @@ impl Exec for RgbArgs {
                 }
                 None
             }
+            Command::History {
+                contract_id: None, ..
+            } => {
+                let runtime = self.rgb_runtime(&config)?;
+                println!("Witness Id\tOperation\tAmount\tFee");
+                for tx in runtime.wallet_history()? {
+                    let row = &tx.row;
+                    println!("{}\t{}\t{}\t{}", row.txid, row.operation, row.amount, row.fee);
+                    for op in tx.ops {
+                        println!(
+                            "\t{} {}\t{}\t{} {}",
+                            op.contract_id,
+                            op.iface,
+                            op.entry.assignment,
+                            op.entry.direction,
+                            op.entry.state
+                        );
+                    }
+                }
+                None
+            }
+            Command::History {
+                contract_id: Some(contract_id),
+                iface,
+            } => {
+                let runtime = self.rgb_runtime(&config)?;
+                let iface = iface
+                    .clone()
+                    .ok_or_else(|| s!("interface name is required to interpret contract state"))?;
+                let iface: TypeName = tn!(iface);
+                let history = runtime.history(*contract_id, iface, &HistoryFilter::default())?;
+                println!(
+                    "Height\tTime\tOperation\tAssignment\tState\tDirection\tFee\tCounterparty\t\
+                     Witness Id"
+                );
+                for entry in history {
+                    let cparty = match entry.counterparties.first() {
+                        None => s!("none"),
+                        Some(seal) if entry.counterparties.len() > 1 => {
+                            format!("{seal} (+{})", entry.counterparties.len() - 1)
+                        }
+                        Some(seal) => seal.to_string(),
+                    };
+                    println!(
+                        "{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}",
+                        entry
+                            .height()
+                            .map(|h| h.to_string())
+                            .unwrap_or_else(|| s!("~")),
+                        entry
+                            .timestamp()
+                            .map(|t| t.to_string())
+                            .unwrap_or_else(|| s!("~")),
+                        entry
+                            .operation
+                            .as_ref()
+                            .map(FieldName::to_string)
+                            .unwrap_or_else(|| s!("~")),
+                        entry.assignment,
+                        entry.state,
+                        entry.direction,
+                        entry
+                            .fee
+                            .map(|fee| fee.to_string())
+                            .unwrap_or_else(|| s!("~")),
+                        cparty,
+                        entry.witness_id
+                    );
+                }
+                None
+            }
 
             Command::Import { armored, file } => {
                 let mut stock = self.rgb_stock()?;
@@ -425,6 +657,7 @@ impl Exec for RgbArgs {
                             .validate(&mut resolver, self.general.network.is_testnet())
                             .map_err(|(status, _)| status.to_string())?;
                         stock.import_contract(contract, &mut resolver)?;
+                        resolver.store()?;
                         eprintln!("Contract {id} is imported");
                     }
                     UniversalFile::Transfer(_) => {
@@ -508,135 +741,20 @@ impl Exec for RgbArgs {
             }
             Command::Issue {
                 schema: schema_id,
+                format,
                 contract,
             } => {
                 let mut stock = self.rgb_stock()?;
 
-                let file = fs::File::open(contract)?;
-
-                let code = serde_yaml::from_reader::<_, serde_yaml::Value>(file)?;
-
-                let code = code
-                    .as_mapping()
-                    .expect("invalid YAML root-level structure");
-
-                let iface_name = code
-                    .get("interface")
-                    .expect("contract must specify interface under which it is constructed")
-                    .as_str()
-                    .expect("interface name must be a string");
-                let SchemaIfaces {
-                    ref schema,
-                    ref iimpls,
-                } = stock.schema(*schema_id)?;
-                let iface_name = tn!(iface_name.to_owned());
-                let iface = stock
-                    .iface(iface_name.clone())
-                    .or_else(|_| {
-                        let id = IfaceId::from_str(iface_name.as_str())?;
-                        stock.iface(id).map_err(RuntimeError::from)
-                    })?
-                    .clone();
-                let iface_id = iface.iface_id();
-                let iface_impl = iimpls.get(&iface_id).ok_or_else(|| {
-                    RuntimeError::Custom(format!(
-                        "no known interface implementation for {iface_name}"
-                    ))
-                })?;
-
-                let mut builder = stock.contract_builder(*schema_id, iface_id)?;
-                let types = builder.type_system().clone();
-
-                if let Some(globals) = code.get("globals") {
-                    for (name, val) in globals
-                        .as_mapping()
-                        .expect("invalid YAML: globals must be an mapping")
-                    {
-                        let name = name
-                            .as_str()
-                            .expect("invalid YAML: global name must be a string");
-                        let state_type = iface_impl
-                            .global_state
-                            .iter()
-                            .find(|info| info.name.as_str() == name)
-                            .unwrap_or_else(|| panic!("unknown type name '{name}'"))
-                            .id;
-                        let sem_id = schema
-                            .global_types
-                            .get(&state_type)
-                            .expect("invalid schema implementation")
-                            .sem_id;
-                        let val = StrictVal::from(val.clone());
-                        let typed_val = types
-                            .typify(val, sem_id)
-                            .expect("global type doesn't match type definition");
-
-                        let serialized = types
-                            .strict_serialize_type::<MAX16>(&typed_val)
-                            .expect("internal error");
-                        // Workaround for borrow checker:
-                        let field_name =
-                            FieldName::try_from(name.to_owned()).expect("invalid type name");
-                        builder = builder
-                            .add_global_state(field_name, serialized)
-                            .expect("invalid global state data");
-                    }
-                }
-
-                if let Some(assignments) = code.get("assignments") {
-                    for (name, val) in assignments
-                        .as_mapping()
-                        .expect("invalid YAML: assignments must be an mapping")
-                    {
-                        let name = name
-                            .as_str()
-                            .expect("invalid YAML: assignments name must be a string");
-                        let state_type = iface_impl
-                            .assignments
-                            .iter()
-                            .find(|info| info.name.as_str() == name)
-                            .expect("unknown type name")
-                            .id;
-                        let state_schema = schema
-                            .owned_types
-                            .get(&state_type)
-                            .expect("invalid schema implementation");
-
-                        let assign = val.as_mapping().expect("an assignment must be a mapping");
-                        let seal = assign
-                            .get("seal")
-                            .expect("assignment doesn't provide seal information")
-                            .as_str()
-                            .expect("seal must be a string");
-                        let seal = OutputSeal::from_str(seal).expect("invalid seal definition");
-                        let seal = GenesisSeal::new_random(seal.method, seal.txid, seal.vout);
-
-                        // Workaround for borrow checker:
-                        let field_name =
-                            FieldName::try_from(name.to_owned()).expect("invalid type name");
-                        match state_schema.state_type() {
-                            StateType::Void => todo!(),
-                            StateType::Fungible => {
-                                let amount = assign
-                                    .get("amount")
-                                    .expect("owned state must be a fungible amount")
-                                    .as_u64()
-                                    .expect("fungible state must be an integer");
-                                let seal = BuilderSeal::Revealed(XChain::Bitcoin(seal));
-                                builder = builder
-                                    .add_fungible_state(field_name, seal, amount)
-                                    .expect("invalid global state data");
-                            }
-                            StateType::Structured => todo!(),
-                            StateType::Attachment => todo!(),
-                        }
-                    }
-                }
-
-                let contract = builder.issue_contract()?;
+                let format = format
+                    .or_else(|| ContractFormat::from_path(contract))
+                    .unwrap_or_default();
+                let request = format.parse(&fs::read_to_string(contract)?)?;
+                let contract = request.issue(&stock, *schema_id)?;
                 let id = contract.contract_id();
                 let mut resolver = self.resolver()?;
                 stock.import_contract(contract, &mut resolver)?;
+                resolver.store()?;
                 eprintln!(
                     "A new contract {id} is issued and added to the stash.\nUse `export` command \
                      to export the contract."
@@ -650,7 +768,8 @@ impl Exec for RgbArgs {
                 value,
             } => {
                 let mut runtime = self.rgb_runtime(&config)?;
-                let iface = TypeName::try_from(iface.to_owned()).expect("invalid interface name");
+                let iface = TypeName::try_from(iface.to_owned())
+                    .map_err(|_| RuntimeError::InvalidIfaceName(iface.to_owned()))?;
 
                 let outpoint = runtime
                     .wallet()
@@ -670,7 +789,7 @@ impl Exec for RgbArgs {
                             .wallet()
                             .addresses(RgbKeychain::Rgb)
                             .next()
-                            .expect("no addresses left")
+                            .ok_or(RuntimeError::NoAddresses)?
                             .addr;
                         Beneficiary::WitnessVout(addr.payload)
                     }
@@ -690,7 +809,8 @@ impl Exec for RgbArgs {
                     .set_amount_raw(*value)
                     .finish();
                 println!("{invoice}");
-                Some(runtime.into_stock())
+                runtime.store()?;
+                None
             }
             Command::Prepare {
                 v2,
@@ -698,11 +818,18 @@ impl Exec for RgbArgs {
                 invoice,
                 fee,
                 sats,
+                selection,
+                fee_rate,
+                rbf,
                 psbt: psbt_file,
             } => {
                 let mut runtime = self.rgb_runtime(&config)?;
-                // TODO: Support lock time and RBFs
-                let params = TransferParams::with(*fee, *sats);
+                // TODO: Support lock time
+                let mut params = TransferParams::with(*fee, *sats).with_selector(*selection);
+                params.fee_rate = *fee_rate;
+                if *rbf {
+                    params.tx.seq_no = SeqNo::from_consensus_u32(RBF_SEQ_NO);
+                }
 
                 let (psbt, _) = runtime
                     .construct_psbt(invoice, *method, params)
@@ -719,7 +846,8 @@ impl Exec for RgbArgs {
                         PsbtVer::V2 => println!("{psbt:#}"),
                     },
                 }
-                Some(runtime.into_stock())
+                runtime.store()?;
+                None
             }
             Command::Consign {
                 invoice,
@@ -732,10 +860,12 @@ impl Exec for RgbArgs {
                 let transfer = runtime
                     .transfer(invoice, &mut psbt)
                     .map_err(|err| err.to_string())?;
+                runtime.save_transfer(slice::from_ref(invoice), &psbt)?;
                 let mut psbt_file = File::create(psbt_name)?;
                 psbt.encode(psbt.version, &mut psbt_file)?;
                 transfer.save_file(out_file)?;
-                Some(runtime.into_stock())
+                runtime.store()?;
+                None
             }
             Command::Transfer {
                 v2,
@@ -743,16 +873,24 @@ impl Exec for RgbArgs {
                 invoice,
                 fee,
                 sats,
+                selection,
+                fee_rate,
+                rbf,
                 psbt: psbt_file,
                 consignment: out_file,
             } => {
                 let mut runtime = self.rgb_runtime(&config)?;
-                // TODO: Support lock time and RBFs
-                let params = TransferParams::with(*fee, *sats);
+                // TODO: Support lock time
+                let mut params = TransferParams::with(*fee, *sats).with_selector(*selection);
+                params.fee_rate = *fee_rate;
+                if *rbf {
+                    params.tx.seq_no = SeqNo::from_consensus_u32(RBF_SEQ_NO);
+                }
 
                 let (psbt, _, transfer) = runtime
                     .pay(invoice, *method, params)
                     .map_err(|err| err.to_string())?;
+                runtime.save_transfer(slice::from_ref(invoice), &psbt)?;
 
                 transfer.save_file(out_file)?;
 
@@ -767,7 +905,44 @@ impl Exec for RgbArgs {
                         PsbtVer::V2 => println!("{psbt:#}"),
                     },
                 }
-                Some(runtime.into_stock())
+                runtime.store()?;
+                None
+            }
+            Command::BumpFee {
+                v2,
+                witness_txid,
+                fee_rate,
+                consignment: out_file,
+                new_psbt: psbt_file,
+            } => {
+                let mut runtime = self.rgb_runtime(&config)?;
+                let (psbt, transfers) = runtime.bump_fee(*witness_txid, *fee_rate)?;
+
+                for (no, transfer) in transfers.into_iter().enumerate() {
+                    let mut path = out_file.clone();
+                    if no > 0 {
+                        let stem = out_file.file_stem().unwrap_or_default().to_string_lossy();
+                        path.set_file_name(match out_file.extension() {
+                            Some(ext) => format!("{stem}.{no}.{}", ext.to_string_lossy()),
+                            None => format!("{stem}.{no}"),
+                        });
+                    }
+                    transfer.save_file(path)?;
+                }
+
+                let ver = if *v2 { PsbtVer::V2 } else { PsbtVer::V0 };
+                match psbt_file {
+                    Some(file_name) => {
+                        let mut psbt_file = File::create(file_name)?;
+                        psbt.encode(ver, &mut psbt_file)?;
+                    }
+                    None => match ver {
+                        PsbtVer::V0 => println!("{psbt}"),
+                        PsbtVer::V2 => println!("{psbt:#}"),
+                    },
+                }
+                runtime.store()?;
+                None
             }
             Command::Inspect { file, dir, path } => {
                 #[derive(Clone, Debug)]
@@ -786,7 +961,7 @@ impl Exec for RgbArgs {
                     UniversalFile::Contract(contract) if *dir => Some(contract),
                     UniversalFile::Transfer(transfer) if *dir => Some(transfer.into_contract()),
                     content => {
-                        let s = serde_yaml::to_string(&content).expect("unable to present as YAML");
+                        let s = serde_yaml::to_string(&content)?;
                         match path {
                             None => println!("{s}"),
                             Some(path) => fs::write(path, s)?,
@@ -967,6 +1142,22 @@ impl Exec for RgbArgs {
                 eprintln!("Dump is successfully generated and saved to '{root_dir}'");
                 None
             }
+            #[cfg(feature = "sqlite")]
+            Command::MigrateSqlite => {
+                let base_dir = self.general.base_dir();
+                if rgb_rt::SqliteStore::exists(&base_dir) {
+                    return Err(s!("stock is already migrated to the SQLite database").into());
+                }
+                eprint!("Migrating stock to the SQLite database ... ");
+                let mut store = rgb_rt::SqliteStore::open(&base_dir)?;
+                if let Err(err) = store.migrate_fs(&base_dir) {
+                    fs::remove_file(store.path()).ok();
+                    return Err(err.into());
+                }
+                eprintln!("success");
+                eprintln!("Stock is now kept in '{}'", store.path().display());
+                None
+            }
             Command::Validate { file } => {
                 let mut resolver = self.resolver()?;
                 let consignment = Transfer::load_file(file)?;
@@ -976,6 +1167,7 @@ impl Exec for RgbArgs {
                         Ok(consignment) => consignment.into_validation_status(),
                         Err((status, _)) => status,
                     };
+                resolver.store()?;
                 if status.validity() == Validity::Valid {
                     eprintln!("The provided consignment is valid")
                 } else {
@@ -993,13 +1185,16 @@ impl Exec for RgbArgs {
                     .validate(&mut resolver, self.general.network.is_testnet())
                     .map_err(|(status, _)| status)?;
                 stock.accept_transfer(valid, &mut resolver)?;
+                resolver.store()?;
                 eprintln!("Transfer accepted into the stash");
                 Some(stock)
             }
         } {
-            stock
-                .store(self.general.base_dir())
-                .expect("unable to save stock");
+            Runtime::<RgbDescr>::store_walletless(
+                &stock,
+                &self.general.base_dir(),
+                self.lock_timeout(),
+            )?;
         }
 
         println!();
@@ -1007,3 +1202,32 @@ impl Exec for RgbArgs {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // Besides the random seal blinding and the issue time, genesis is fully
+    // defined by the contract description; thus equal descriptions result in
+    // the same genesis.
+    #[test]
+    fn example_formats() {
+        let yaml = ContractFormat::Yaml
+            .parse(include_str!("../../examples/rgb20-demo.yaml"))
+            .unwrap();
+        let json = ContractFormat::Json
+            .parse(include_str!("../../examples/rgb20-demo.json"))
+            .unwrap();
+        let toml = ContractFormat::Toml
+            .parse(include_str!("../../examples/rgb20-demo.toml"))
+            .unwrap();
+        assert_eq!(yaml, json);
+        assert_eq!(yaml.assignments["assetOwner"][0].amount, Some(100_000_000));
+
+        // TOML has no null value, so the example omits empty `media`
+        let mut yaml = yaml;
+        let data = yaml.globals.get_mut("data").unwrap().as_object_mut().unwrap();
+        assert_eq!(data.remove("media"), Some(serde_json::Value::Null));
+        assert_eq!(yaml, toml);
+    }
+}
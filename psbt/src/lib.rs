@@ -25,18 +25,21 @@ extern crate amplify;
 mod rgb;
 
 use bp::dbc::opret::OpretProof;
-use bp::dbc::tapret::TapretProof;
+use bp::dbc::tapret::{TapretPathProof, TapretProof};
+use bp::dbc::{Anchor, Method};
+use commit_verify::{mpc, CommitId};
 pub use psbt::*;
 pub use rgb::*;
 use rgbstd::containers::{AnchorSet, Batch, CloseMethodSet, Fascia};
-use rgbstd::{XChain, XWitnessId};
+use rgbstd::{ContractId, XChain, XWitnessId};
 
 pub use self::rgb::{
-    ProprietaryKeyRgb, RgbExt, RgbInExt, RgbOutExt, RgbPsbtError, PSBT_GLOBAL_RGB_TRANSITION,
-    PSBT_IN_RGB_CONSUMED_BY, PSBT_OUT_RGB_VELOCITY_HINT, PSBT_RGB_PREFIX,
+    ProprietaryKeyRgb, RgbExt, RgbInExt, RgbOutExt, RgbPsbtError, PSBT_GLOBAL_RGB_LAYER1,
+    PSBT_GLOBAL_RGB_TRANSITION, PSBT_IN_RGB_CONSUMED_BY, PSBT_OUT_RGB_CHANGE,
+    PSBT_OUT_RGB_MPC_PROOF, PSBT_OUT_RGB_TAPRET_PROOF, PSBT_OUT_RGB_VELOCITY_HINT, PSBT_RGB_PREFIX,
 };
 
-#[derive(Clone, Eq, PartialEq, Debug, Display, Error)]
+#[derive(Clone, Eq, PartialEq, Debug, Display, Error, From)]
 #[display(doc_comments)]
 pub enum EmbedError {
     /// provided transaction batch references inputs which are absent from the
@@ -46,6 +49,10 @@ pub enum EmbedError {
     /// the provided PSBT is invalid since it doublespends on some of its
     /// inputs.
     PsbtRepeatedInputs,
+
+    #[from]
+    #[display(inner)]
+    Rgb(RgbPsbtError),
 }
 
 #[derive(Clone, Eq, PartialEq, Debug, Display, Error, From)]
@@ -58,9 +65,47 @@ pub enum CommitError {
     Dbc(DbcPsbtError),
 }
 
-#[derive(Clone, Eq, PartialEq, Debug, Display, Error)]
+#[derive(Clone, Eq, PartialEq, Debug, Display, Error, From)]
 #[display(doc_comments)]
-pub enum ExtractError {}
+pub enum ExtractError {
+    /// PSBT doesn't specify an output which hosts {0} commitment.
+    NoHostOutput(Method),
+
+    /// {0} commitment is not yet present in the PSBT; the PSBT must be
+    /// committed with `rgb_commit` before the data can be extracted.
+    NotCommitted(Method),
+
+    /// {0} commitment present in the PSBT doesn't match the state transitions
+    /// and multi-protocol commitment messages it contains.
+    CommitmentMismatch(Method),
+
+    /// multi-protocol commitment message for contract {0} doesn't match the
+    /// transition bundle contained in the PSBT.
+    MessageMismatch(ContractId),
+
+    /// tapret host output doesn't specify taproot internal key.
+    NoInternalKey,
+
+    /// tapret host output has a script tree, but the PSBT doesn't contain
+    /// tapret path proof for the commitment.
+    NoTapretProof,
+
+    /// {0} host output doesn't contain multi-protocol commitment proof, which
+    /// is saved by `rgb_commit`.
+    NoMpcProof(Method),
+
+    #[from]
+    #[display(inner)]
+    MpcPsbt(MpcPsbtError),
+
+    #[from]
+    #[display(inner)]
+    TapretKey(TapretKeyError),
+
+    #[from]
+    #[display(inner)]
+    Rgb(RgbPsbtError),
+}
 
 // TODO: Batch must be homomorphic by the outpoint type (chain)
 
@@ -73,11 +118,16 @@ pub trait RgbPsbt {
 
 impl RgbPsbt for Psbt {
     fn rgb_embed(&mut self, batch: Batch) -> Result<(), EmbedError> {
+        let layer1 = self.rgb_layer1()?;
         for info in batch {
             let contract_id = info.transition.contract_id;
             let mut inputs = info.inputs.into_inner();
             for input in self.inputs_mut() {
-                inputs.remove(&XChain::Bitcoin(input.prevout().outpoint()));
+                // PSBT may contain inputs spent by other transitions, including other
+                // transitions of the same contract when several payments are batched
+                if !inputs.remove(&XChain::with(layer1, input.prevout().outpoint())) {
+                    continue;
+                }
                 input
                     .set_rgb_consumer(contract_id, info.id)
                     .map_err(|_| EmbedError::PsbtRepeatedInputs)?;
@@ -94,6 +144,7 @@ impl RgbPsbt for Psbt {
     fn rgb_commit(&mut self) -> Result<Fascia, CommitError> {
         // Convert RGB data to MPCs? Or should we do it at the moment we add them... No,
         // since we may require more DBC methods with each additional state transition
+        let layer1 = self.rgb_layer1()?;
         let bundles = self.rgb_bundles_to_mpc()?;
         // DBC commitment for the required methods
         let methods = bundles
@@ -104,10 +155,25 @@ impl RgbPsbt for Psbt {
             .ok_or(RgbPsbtError::NoContracts)?;
         let (mut tapret_anchor, mut opret_anchor) = (None, None);
         if methods.has_tapret_first() {
-            tapret_anchor = Some(self.dbc_commit::<TapretProof>()?);
+            let anchor = self.dbc_commit::<TapretProof>()?;
+            // Commitment parameters can't be reliably re-created from the rest of
+            // the PSBT data, so we keep the proofs for `rgb_extract`
+            let host = self
+                .outputs_mut()
+                .find(|output| output.is_tapret_host())
+                .ok_or(RgbPsbtError::NoHostOutput(Method::TapretFirst))?;
+            host.set_rgb_mpc_proof(&anchor.mpc_proof)?;
+            host.set_rgb_tapret_proof(&anchor.dbc_proof.path_proof)?;
+            tapret_anchor = Some(anchor);
         }
         if methods.has_opret_first() {
-            opret_anchor = Some(self.dbc_commit::<OpretProof>()?);
+            let anchor = self.dbc_commit::<OpretProof>()?;
+            let host = self
+                .outputs_mut()
+                .find(|output| output.is_opret_host())
+                .ok_or(RgbPsbtError::NoHostOutput(Method::OpretFirst))?;
+            host.set_rgb_mpc_proof(&anchor.mpc_proof)?;
+            opret_anchor = Some(anchor);
         }
         let anchor = match (tapret_anchor, opret_anchor) {
             (None, None) => return Err(RgbPsbtError::NoContracts.into()),
@@ -116,13 +182,217 @@ impl RgbPsbt for Psbt {
             (Some(tapret), Some(opret)) => AnchorSet::Double { tapret, opret },
         };
         Ok(Fascia {
-            witness_id: XWitnessId::Bitcoin(self.txid()),
+            witness_id: XWitnessId::with(layer1, self.txid()),
             anchor,
             bundles,
         })
     }
 
     fn rgb_extract(&self) -> Result<Fascia, ExtractError> {
-        todo!("implement RGB PSBT fascia extraction for multi-party protocols")
+        // Unlike `rgb_commit` we do not modify PSBT, and instead re-create the
+        // anchors from the proofs saved by the party which did the commitment
+        let layer1 = self.rgb_layer1()?;
+        let bundles = self.rgb_bundles()?;
+        let methods = bundles
+            .values()
+            .flat_map(|b| b.iter())
+            .map(|b| CloseMethodSet::from(b.close_method))
+            .reduce(|methods, method| methods | method)
+            .ok_or(RgbPsbtError::NoContracts)?;
+
+        for (contract_id, bundle) in bundles
+            .iter()
+            .flat_map(|(id, b)| b.iter().map(move |b| (id, b)))
+        {
+            let output = self.rgb_host_output(bundle.close_method)?;
+            let protocol_id = mpc::ProtocolId::from(*contract_id);
+            let message = mpc::Message::from(bundle.bundle_id());
+            if output.mpc_message(protocol_id) != Some(message) {
+                return Err(ExtractError::MessageMismatch(*contract_id));
+            }
+        }
+
+        let (mut tapret_anchor, mut opret_anchor) = (None, None);
+        if methods.has_tapret_first() {
+            tapret_anchor = Some(self.rgb_tapret_anchor()?);
+        }
+        if methods.has_opret_first() {
+            opret_anchor = Some(self.rgb_opret_anchor()?);
+        }
+        let anchor = match (tapret_anchor, opret_anchor) {
+            (None, None) => return Err(RgbPsbtError::NoContracts.into()),
+            (Some(tapret), None) => AnchorSet::Tapret(tapret),
+            (None, Some(opret)) => AnchorSet::Opret(opret),
+            (Some(tapret), Some(opret)) => AnchorSet::Double { tapret, opret },
+        };
+        let bundles = rgb::confine_bundles(bundles)?;
+        Ok(Fascia {
+            witness_id: XWitnessId::with(layer1, self.txid()),
+            anchor,
+            bundles,
+        })
+    }
+}
+
+trait RgbExtractExt {
+    fn rgb_host_output(&self, method: Method) -> Result<&psbt::Output, ExtractError>;
+    fn rgb_mpc_proof(
+        &self,
+        method: Method,
+        commitment: mpc::Commitment,
+    ) -> Result<mpc::MerkleBlock, ExtractError>;
+    fn rgb_tapret_anchor(&self) -> Result<Anchor<mpc::MerkleBlock, TapretProof>, ExtractError>;
+    fn rgb_opret_anchor(&self) -> Result<Anchor<mpc::MerkleBlock, OpretProof>, ExtractError>;
+}
+
+impl RgbExtractExt for Psbt {
+    fn rgb_host_output(&self, method: Method) -> Result<&psbt::Output, ExtractError> {
+        self.outputs()
+            .find(|output| match method {
+                Method::TapretFirst => output.is_tapret_host(),
+                Method::OpretFirst => output.is_opret_host(),
+            })
+            .ok_or(ExtractError::NoHostOutput(method))
+    }
+
+    /// Returns MPC merkle block for the `commitment` present in the host
+    /// output, checking that it commits to all messages of the output.
+    fn rgb_mpc_proof(
+        &self,
+        method: Method,
+        commitment: mpc::Commitment,
+    ) -> Result<mpc::MerkleBlock, ExtractError> {
+        let output = self.rgb_host_output(method)?;
+        let messages = output.mpc_messages()?;
+        // The tree can't be deterministically re-created from the messages and
+        // entropy alone, so we require the proof saved by `rgb_commit`
+        let mpc_proof = output
+            .rgb_mpc_proof()?
+            .ok_or(ExtractError::NoMpcProof(method))?;
+
+        if mpc_proof.commit_id() != commitment {
+            return Err(ExtractError::CommitmentMismatch(method));
+        }
+        for (protocol_id, message) in messages.iter() {
+            let committed = mpc_proof
+                .to_merkle_proof(*protocol_id)
+                .ok()
+                .and_then(|proof| proof.convolve(*protocol_id, *message).ok());
+            if committed != Some(commitment) {
+                return Err(ExtractError::CommitmentMismatch(method));
+            }
+        }
+        Ok(mpc_proof)
+    }
+
+    fn rgb_tapret_anchor(&self) -> Result<Anchor<mpc::MerkleBlock, TapretProof>, ExtractError> {
+        let output = self
+            .dbc_output::<TapretProof>()
+            .ok_or(ExtractError::NoHostOutput(Method::TapretFirst))?;
+        let tapret_commitment = output
+            .tapret_commitment()
+            .map_err(|_| ExtractError::NotCommitted(Method::TapretFirst))?;
+        let mpc_proof = self.rgb_mpc_proof(Method::TapretFirst, tapret_commitment.mpc)?;
+        let internal_pk = output.tap_internal_key.ok_or(ExtractError::NoInternalKey)?;
+        let path_proof = match output.rgb_tapret_proof()? {
+            Some(path_proof) if path_proof.nonce == tapret_commitment.nonce => path_proof,
+            Some(_) => return Err(ExtractError::CommitmentMismatch(Method::TapretFirst)),
+            // Without a script tree the commitment is the only leaf of the tree
+            None if output.tap_tree.is_none() => TapretPathProof::root(tapret_commitment.nonce),
+            None => return Err(ExtractError::NoTapretProof),
+        };
+        let dbc_proof = TapretProof {
+            path_proof,
+            internal_pk,
+        };
+        Ok(Anchor::new(mpc_proof, dbc_proof))
+    }
+
+    fn rgb_opret_anchor(&self) -> Result<Anchor<mpc::MerkleBlock, OpretProof>, ExtractError> {
+        let output = self
+            .dbc_output::<OpretProof>()
+            .ok_or(ExtractError::NoHostOutput(Method::OpretFirst))?;
+        let script: &[u8] = output.script.as_ref();
+        let commitment = match script {
+            // OP_RETURN followed by a 32-byte push of the commitment
+            [0x6a, 0x20, data @ ..] if data.len() == 32 => {
+                mpc::Commitment::copy_from_slice(data).expect("fixed length")
+            }
+            _ => return Err(ExtractError::NotCommitted(Method::OpretFirst)),
+        };
+        let mpc_proof = self.rgb_mpc_proof(Method::OpretFirst, commitment)?;
+        Ok(Anchor::new(mpc_proof, OpretProof::default()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use amplify::hex::FromHex;
+    use bp::seals::txout::CloseMethod;
+    use bp::{ConsensusDecode, ScriptPubkey, Tx};
+    use rgbstd::{Layer1, Operation, Transition};
+    use strict_encoding::StrictDumb;
+
+    use super::*;
+
+    // Unsigned transaction with a single input and an empty OP_RETURN output
+    const TX: &str = "02000000011111111111111111111111111111111111111111111111111111111111111111\
+                      0000000000ffffffff010000000000000000016a00000000";
+
+    fn opret_psbt() -> Psbt {
+        let tx = Tx::consensus_deserialize(Vec::<u8>::from_hex(TX).unwrap()).unwrap();
+        let mut psbt = Psbt::from_tx(UnsignedTx::with_sigs_removed(tx));
+        let output = psbt.outputs_mut().next().unwrap();
+        output.script = ScriptPubkey::op_return(&[]);
+        output.set_opret_host().unwrap();
+        psbt.complete_construction();
+
+        let transition = Transition::strict_dumb();
+        let (contract_id, opid) = (transition.contract_id, transition.id());
+        psbt.inputs_mut()
+            .next()
+            .unwrap()
+            .set_rgb_consumer(contract_id, opid)
+            .unwrap();
+        psbt.push_rgb_transition(transition, CloseMethod::OpretFirst)
+            .unwrap();
+        psbt
+    }
+
+    #[test]
+    fn commit_extract_roundtrip() {
+        let mut psbt = opret_psbt();
+        assert_eq!(psbt.rgb_extract(), Err(ExtractError::NotCommitted(Method::OpretFirst)));
+
+        let fascia = psbt.rgb_commit().unwrap();
+        assert_eq!(psbt.rgb_extract().unwrap(), fascia);
+    }
+
+    #[test]
+    fn extract_without_saved_proof() {
+        let mut psbt = opret_psbt();
+        psbt.rgb_commit().unwrap();
+        for output in psbt.outputs_mut() {
+            output
+                .proprietary
+                .retain(|key, _| key.subtype != PSBT_OUT_RGB_MPC_PROOF);
+        }
+        assert_eq!(psbt.rgb_extract(), Err(ExtractError::NoMpcProof(Method::OpretFirst)));
+    }
+
+    #[test]
+    fn liquid_witness() {
+        let mut psbt = opret_psbt();
+        assert_eq!(psbt.rgb_layer1(), Ok(Layer1::Bitcoin));
+        psbt.set_rgb_layer1(Layer1::Liquid);
+        assert_eq!(psbt.rgb_layer1(), Ok(Layer1::Liquid));
+        // Setting layer 1 once again replaces the previous value
+        psbt.set_rgb_layer1(Layer1::Bitcoin);
+        assert_eq!(psbt.rgb_layer1(), Ok(Layer1::Bitcoin));
+        psbt.set_rgb_layer1(Layer1::Liquid);
+
+        let fascia = psbt.rgb_commit().unwrap();
+        assert_eq!(fascia.witness_id, XWitnessId::Liquid(psbt.txid()));
+        assert_eq!(psbt.rgb_extract().unwrap(), fascia);
     }
 }
@@ -20,9 +20,11 @@
 // limitations under the License.
 
 use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::io;
 
 use amplify::confinement::{Confined, SmallOrdMap, U24};
 use amplify::{confinement, FromSliceError};
+use bp::dbc::tapret::TapretPathProof;
 use bp::dbc::Method;
 use bp::seals::txout::CloseMethod;
 use commit_verify::mpc;
@@ -30,8 +32,11 @@ use psbt::{KeyAlreadyPresent, KeyMap, MpcPsbtError, PropKey, Psbt};
 use rgbstd::accessors::{MergeReveal, MergeRevealError};
 use rgbstd::containers::BundleDichotomy;
 use rgbstd::interface::VelocityHint;
-use rgbstd::{ContractId, InputMap, OpId, Operation, Transition, TransitionBundle, Vin};
-use strict_encoding::{DeserializeError, StrictDeserialize, StrictSerialize};
+use rgbstd::{ContractId, InputMap, Layer1, OpId, Operation, Transition, TransitionBundle, Vin};
+use strict_encoding::{
+    DecodeError, DeserializeError, StrictDecode, StrictDeserialize, StrictEncode, StrictSerialize,
+    StrictType, TypedRead, TypedWrite,
+};
 
 // TODO: Instead of storing whole RGB contract in PSBT create a shortened
 //       contract version which skips all info not important for hardware
@@ -48,12 +53,24 @@ pub const PSBT_GLOBAL_RGB_TRANSITION: u64 = 0x01;
 /// Proprietary key subtype for storing information on which closed methods
 /// should be used for each of RGB state transitions.
 pub const PSBT_GLOBAL_RGB_CLOSE_METHODS: u64 = 0x02;
+/// Proprietary key subtype for storing layer 1 on which the witness transaction
+/// is going to be published. If absent, the witness is on bitcoin.
+pub const PSBT_GLOBAL_RGB_LAYER1: u64 = 0x03;
 /// Proprietary key subtype for storing RGB state transition operation id which
 /// consumes this input.
 pub const PSBT_IN_RGB_CONSUMED_BY: u64 = 0x01;
 /// Proprietary key subtype for storing hint for the velocity of the state
 /// which can be assigned to the provided output.
 pub const PSBT_OUT_RGB_VELOCITY_HINT: u64 = 0x01;
+/// Proprietary key subtype for storing multi-protocol commitment merkle block
+/// created when the output was used as a host for the commitment.
+pub const PSBT_OUT_RGB_MPC_PROOF: u64 = 0x02;
+/// Proprietary key subtype for storing tapret path proof created when the
+/// output was used as a host for the tapret commitment.
+pub const PSBT_OUT_RGB_TAPRET_PROOF: u64 = 0x03;
+/// Proprietary key subtype marking the output as a change of the wallet which
+/// constructed the transfer, which can be used to pay additional fees.
+pub const PSBT_OUT_RGB_CHANGE: u64 = 0x04;
 
 /// Extension trait for static functions returning RGB-related proprietary keys.
 pub trait ProprietaryKeyRgb {
@@ -73,6 +90,14 @@ pub trait ProprietaryKeyRgb {
             data: opid.to_vec().into(),
         }
     }
+    /// Constructs [`PSBT_GLOBAL_RGB_LAYER1`] proprietary key.
+    fn rgb_layer1() -> PropKey {
+        PropKey {
+            identifier: PSBT_RGB_PREFIX.to_owned(),
+            subtype: PSBT_GLOBAL_RGB_LAYER1,
+            data: none!(),
+        }
+    }
 
     /// Constructs [`PSBT_IN_RGB_CONSUMED_BY`] proprietary key.
     fn rgb_in_consumed_by(contract_id: ContractId) -> PropKey {
@@ -91,6 +116,33 @@ pub trait ProprietaryKeyRgb {
             data: none!(),
         }
     }
+
+    /// Constructs [`PSBT_OUT_RGB_MPC_PROOF`] proprietary key.
+    fn rgb_out_mpc_proof() -> PropKey {
+        PropKey {
+            identifier: PSBT_RGB_PREFIX.to_owned(),
+            subtype: PSBT_OUT_RGB_MPC_PROOF,
+            data: none!(),
+        }
+    }
+
+    /// Constructs [`PSBT_OUT_RGB_TAPRET_PROOF`] proprietary key.
+    fn rgb_out_tapret_proof() -> PropKey {
+        PropKey {
+            identifier: PSBT_RGB_PREFIX.to_owned(),
+            subtype: PSBT_OUT_RGB_TAPRET_PROOF,
+            data: none!(),
+        }
+    }
+
+    /// Constructs [`PSBT_OUT_RGB_CHANGE`] proprietary key.
+    fn rgb_out_change() -> PropKey {
+        PropKey {
+            identifier: PSBT_RGB_PREFIX.to_owned(),
+            subtype: PSBT_OUT_RGB_CHANGE,
+            data: none!(),
+        }
+    }
 }
 
 impl ProprietaryKeyRgb for PropKey {}
@@ -123,6 +175,9 @@ pub enum RgbPsbtError {
     /// invalid close method data for opid {0}
     InvalidCloseMethod(OpId),
 
+    /// invalid layer 1 data.
+    InvalidLayer1,
+
     /// PSBT doesn't specify an output which can host {0} commitment.
     NoHostOutput(Method),
 
@@ -140,6 +195,12 @@ pub enum RgbPsbtError {
     #[from]
     InvalidTransition(DeserializeError),
 
+    /// the size of the commitment proof exceeds 16 MB.
+    ProofTooBig,
+
+    /// commitment proof data in PSBT are invalid. Details: {0}
+    InvalidProof(DeserializeError),
+
     #[from]
     #[display(inner)]
     Mpc(MpcPsbtError),
@@ -166,6 +227,15 @@ pub trait RgbExt {
         method: CloseMethod,
     ) -> Result<bool, RgbPsbtError>;
 
+    /// Returns layer 1 on which the witness transaction is going to be
+    /// published.
+    fn rgb_layer1(&self) -> Result<Layer1, RgbPsbtError>;
+
+    /// Sets layer 1 on which the witness transaction is going to be published,
+    /// which defines the chain of the seals spent and defined by the
+    /// transitions. Must be set before the transitions are embedded.
+    fn set_rgb_layer1(&mut self, layer1: Layer1);
+
     fn rgb_bundles(&self) -> Result<BTreeMap<ContractId, BundleDichotomy>, RgbPsbtError> {
         let mut map = BTreeMap::new();
         for contract_id in self.rgb_contract_ids()? {
@@ -301,6 +371,23 @@ impl RgbExt for Psbt {
         Ok(prev_transition.is_none())
     }
 
+    fn rgb_layer1(&self) -> Result<Layer1, RgbPsbtError> {
+        match self.proprietary(&PropKey::rgb_layer1()).map(|data| &data[..]) {
+            None | Some([0]) => Ok(Layer1::Bitcoin),
+            Some([1]) => Ok(Layer1::Liquid),
+            Some(_) => Err(RgbPsbtError::InvalidLayer1),
+        }
+    }
+
+    fn set_rgb_layer1(&mut self, layer1: Layer1) {
+        let data = match layer1 {
+            Layer1::Bitcoin => 0u8,
+            Layer1::Liquid => 1u8,
+        };
+        self.proprietary
+            .insert(PropKey::rgb_layer1(), vec![data].into());
+    }
+
     fn rgb_bundles_to_mpc(
         &mut self,
     ) -> Result<Confined<BTreeMap<ContractId, BundleDichotomy>, 1, U24>, RgbPsbtError> {
@@ -329,9 +416,7 @@ impl RgbExt for Psbt {
             }
         }
 
-        let map = Confined::try_from(bundles).map_err(|_| RgbPsbtError::NoContracts)?;
-
-        Ok(map)
+        confine_bundles(bundles)
     }
 }
 
@@ -410,6 +495,33 @@ pub trait RgbOutExt {
     /// `false`, if a velocity hint was already present in the input and
     /// `true` otherwise.
     fn set_rgb_velocity_hint(&mut self, hint: VelocityHint) -> bool;
+
+    /// Returns multi-protocol commitment merkle block which was committed to
+    /// by the output, if the output was used as a commitment host.
+    fn rgb_mpc_proof(&self) -> Result<Option<mpc::MerkleBlock>, RgbPsbtError>;
+
+    /// Saves multi-protocol commitment merkle block committed to by the output,
+    /// replacing the previous one.
+    fn set_rgb_mpc_proof(&mut self, proof: &mpc::MerkleBlock) -> Result<(), RgbPsbtError>;
+
+    /// Returns tapret path proof which was used for the tapret commitment in
+    /// the output, if the output was used as a tapret host.
+    fn rgb_tapret_proof(&self) -> Result<Option<TapretPathProof>, RgbPsbtError>;
+
+    /// Saves tapret path proof used for the tapret commitment in the output,
+    /// replacing the previous one.
+    fn set_rgb_tapret_proof(&mut self, proof: &TapretPathProof) -> Result<(), RgbPsbtError>;
+
+    /// Checks whether the output was marked as a change of the wallet which
+    /// constructed the transfer.
+    fn is_rgb_change(&self) -> bool;
+
+    /// Marks the output as a change of the wallet constructing the transfer.
+    ///
+    /// # Returns
+    ///
+    /// `false`, if the output was already marked and `true` otherwise.
+    fn set_rgb_change(&mut self) -> bool;
 }
 
 impl RgbOutExt for psbt::Output {
@@ -428,4 +540,89 @@ impl RgbOutExt for psbt::Output {
             .ok();
         Some(hint) == prev
     }
+    fn rgb_mpc_proof(&self) -> Result<Option<mpc::MerkleBlock>, RgbPsbtError> {
+        self.proprietary
+            .get(&PropKey::rgb_out_mpc_proof())
+            .map(|data| prop_value_decode(data))
+            .transpose()
+    }
+
+    fn set_rgb_mpc_proof(&mut self, proof: &mpc::MerkleBlock) -> Result<(), RgbPsbtError> {
+        let data = prop_value_encode(proof)?;
+        // The proof is replaced if the output gets committed once again
+        self.proprietary.insert(PropKey::rgb_out_mpc_proof(), data.into());
+        Ok(())
+    }
+
+    fn rgb_tapret_proof(&self) -> Result<Option<TapretPathProof>, RgbPsbtError> {
+        self.proprietary
+            .get(&PropKey::rgb_out_tapret_proof())
+            .map(|data| prop_value_decode(data))
+            .transpose()
+    }
+
+    fn set_rgb_tapret_proof(&mut self, proof: &TapretPathProof) -> Result<(), RgbPsbtError> {
+        let data = prop_value_encode(proof)?;
+        self.proprietary.insert(PropKey::rgb_out_tapret_proof(), data.into());
+        Ok(())
+    }
+
+    fn is_rgb_change(&self) -> bool { self.proprietary.contains_key(&PropKey::rgb_out_change()) }
+
+    fn set_rgb_change(&mut self) -> bool {
+        let prev = self.is_rgb_change();
+        self.push_proprietary(PropKey::rgb_out_change(), vec![]).ok();
+        !prev
+    }
+}
+
+/// Converts bundles into the form used by [`Fascia`], which requires at least
+/// one and no more than 2^24 contracts.
+///
+/// [`Fascia`]: rgbstd::containers::Fascia
+pub(crate) fn confine_bundles(
+    bundles: BTreeMap<ContractId, BundleDichotomy>,
+) -> Result<Confined<BTreeMap<ContractId, BundleDichotomy>, 1, U24>, RgbPsbtError> {
+    Confined::try_from(bundles).map_err(|err| match err {
+        confinement::Error::Undersize { .. } => RgbPsbtError::NoContracts,
+        _ => RgbPsbtError::TooManyContracts,
+    })
+}
+
+/// Wrapper strict-serializing values stored under RGB proprietary keys, which
+/// are strict types but not standalone serializable data.
+struct PropValue<T>(T);
+
+impl<T: StrictType> StrictType for PropValue<T> {
+    const STRICT_LIB_NAME: &'static str = T::STRICT_LIB_NAME;
+}
+
+impl<T: StrictEncode> StrictEncode for PropValue<T> {
+    fn strict_encode<W: TypedWrite>(&self, writer: W) -> io::Result<W> {
+        self.0.strict_encode(writer)
+    }
+}
+
+impl<T: StrictDecode> StrictDecode for PropValue<T> {
+    fn strict_decode(reader: &mut impl TypedRead) -> Result<Self, DecodeError> {
+        T::strict_decode(reader).map(PropValue)
+    }
+}
+
+impl<T: StrictEncode> StrictSerialize for PropValue<T> {}
+impl<T: StrictDecode> StrictDeserialize for PropValue<T> {}
+
+fn prop_value_encode<T: StrictEncode + Clone>(value: &T) -> Result<Vec<u8>, RgbPsbtError> {
+    PropValue(value.clone())
+        .to_strict_serialized::<U24>()
+        .map(Confined::into_inner)
+        .map_err(|_| RgbPsbtError::ProofTooBig)
+}
+
+fn prop_value_decode<T: StrictDecode>(data: &[u8]) -> Result<T, RgbPsbtError> {
+    let data = Confined::try_from_iter(data.iter().copied())
+        .map_err(|_| RgbPsbtError::ProofTooBig)?;
+    PropValue::<T>::from_strict_serialized::<U24>(data)
+        .map(|value| value.0)
+        .map_err(RgbPsbtError::InvalidProof)
 }
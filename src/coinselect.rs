@@ -0,0 +1,305 @@
+// RGB wallet library for smart contracts on Bitcoin & Lightning network
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+// Written in 2019-2023 by
+//     Dr Maxim Orlovsky <orlovsky@lnp-bp.org>
+//
+// Copyright (C) 2019-2023 LNP/BP Standards Association. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::collections::BTreeSet;
+use std::str::FromStr;
+
+use rgbstd::invoice::Amount;
+use rgbstd::XOutputSeal;
+
+/// Maximal number of branches explored by [`StateSelection::ExactMatch`]
+/// before it falls back to the largest-first selection.
+pub const BNB_MAX_TRIES: usize = 100_000;
+
+/// Fungible state allocated to a single seal, which can be used as an input
+/// for a state transition.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub struct StateCandidate {
+    pub seal: XOutputSeal,
+    /// Sum of all fungible allocations under the seal.
+    pub amount: Amount,
+    /// Height of the block mining the witness transaction which created the
+    /// allocation. Genesis allocations have zero height; allocations with
+    /// unknown or unmined witness have no height.
+    pub height: Option<u32>,
+}
+
+/// Algorithm selecting seals with fungible state to be spent in a state
+/// transition.
+pub trait StateSelector {
+    /// Selects seals from `candidates` which allocate at least `target` amount
+    /// of state, or returns `None` if the candidates are not sufficient.
+    fn select_state(
+        &self,
+        candidates: Vec<StateCandidate>,
+        target: Amount,
+    ) -> Option<BTreeSet<XOutputSeal>>;
+}
+
+/// Built-in state selection strategies. Candidates which are equal by the
+/// strategy criteria are taken in the order of their seals.
+#[derive(Copy, Clone, Eq, PartialEq, Hash, Debug, Default, Display)]
+pub enum StateSelection {
+    /// Spend largest allocations first, minimizing the number of the inputs.
+    #[default]
+    #[display("largest-first")]
+    LargestFirst,
+
+    /// Spend smallest allocations first, consolidating dust allocations.
+    #[display("smallest-first")]
+    SmallestFirst,
+
+    /// Look for a set of allocations summing exactly to the required amount,
+    /// such that no change allocation is created. Falls back to the
+    /// largest-first strategy if there is no such set.
+    #[display("exact-match")]
+    ExactMatch,
+
+    /// Spend allocations with the oldest witness first, such that unconfirmed
+    /// allocations are spent last.
+    #[display("oldest-first")]
+    OldestFirst,
+}
+
+impl FromStr for StateSelection {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "largest-first" => Ok(StateSelection::LargestFirst),
+            "smallest-first" => Ok(StateSelection::SmallestFirst),
+            "exact-match" => Ok(StateSelection::ExactMatch),
+            "oldest-first" => Ok(StateSelection::OldestFirst),
+            _ => Err(format!("unknown state selection strategy '{s}'")),
+        }
+    }
+}
+
+impl StateSelector for StateSelection {
+    fn select_state(
+        &self,
+        mut candidates: Vec<StateCandidate>,
+        target: Amount,
+    ) -> Option<BTreeSet<XOutputSeal>> {
+        match self {
+            StateSelection::LargestFirst => {
+                sort_descending(&mut candidates);
+                accumulate(candidates, target)
+            }
+            StateSelection::SmallestFirst => {
+                candidates.sort_by_key(|c| (c.amount, c.seal));
+                accumulate(candidates, target)
+            }
+            StateSelection::ExactMatch => {
+                sort_descending(&mut candidates);
+                branch_and_bound(&candidates, target).or_else(|| accumulate(candidates, target))
+            }
+            StateSelection::OldestFirst => {
+                candidates.sort_by_key(|c| (c.height.is_none(), c.height, c.seal));
+                accumulate(candidates, target)
+            }
+        }
+    }
+}
+
+fn sort_descending(candidates: &mut [StateCandidate]) {
+    candidates.sort_by(|a, b| b.amount.cmp(&a.amount).then(a.seal.cmp(&b.seal)));
+}
+
+fn accumulate(
+    candidates: impl IntoIterator<Item = StateCandidate>,
+    target: Amount,
+) -> Option<BTreeSet<XOutputSeal>> {
+    let mut sum = 0u64;
+    let mut selected = bset![];
+    for candidate in candidates {
+        if sum >= target.value() {
+            break;
+        }
+        sum = sum.saturating_add(candidate.amount.value());
+        selected.insert(candidate.seal);
+    }
+    if sum < target.value() {
+        return None;
+    }
+    Some(selected)
+}
+
+/// Depth-first search for a subset of candidates (which must be sorted in
+/// descending order) summing exactly to the target.
+fn branch_and_bound(
+    candidates: &[StateCandidate],
+    target: Amount,
+) -> Option<BTreeSet<XOutputSeal>> {
+    fn search(
+        candidates: &[StateCandidate],
+        remaining: &[u64],
+        target: u64,
+        selected: &mut Vec<usize>,
+        pos: usize,
+        tries: &mut usize,
+    ) -> bool {
+        if target == 0 {
+            return true;
+        }
+        if pos >= candidates.len() || remaining[pos] < target || *tries >= BNB_MAX_TRIES {
+            return false;
+        }
+        *tries += 1;
+        let value = candidates[pos].amount.value();
+        if value <= target {
+            selected.push(pos);
+            if search(candidates, remaining, target - value, selected, pos + 1, tries) {
+                return true;
+            }
+            selected.pop();
+        }
+        search(candidates, remaining, target, selected, pos + 1, tries)
+    }
+
+    // Sum of all candidate amounts starting from the given position, used to
+    // prune branches which can't reach the target
+    let mut remaining = vec![0u64; candidates.len() + 1];
+    for (pos, candidate) in candidates.iter().enumerate().rev() {
+        remaining[pos] = remaining[pos + 1].saturating_add(candidate.amount.value());
+    }
+
+    let mut selected = vec![];
+    let mut tries = 0usize;
+    if !search(candidates, &remaining, target.value(), &mut selected, 0, &mut tries) {
+        return None;
+    }
+    Some(selected.into_iter().map(|pos| candidates[pos].seal).collect())
+}
+
+#[cfg(test)]
+mod test {
+    use rgbstd::{OutputSeal, XChain};
+
+    use super::*;
+
+    const TXID: &str = "01d46e52c4bdb51931a0eae83e958c78bdef9cac2057b36d55370410edafdd42";
+
+    fn seal(vout: u32) -> XOutputSeal {
+        XChain::Bitcoin(OutputSeal::from_str(&format!("opret1st:{TXID}:{vout}")).unwrap())
+    }
+
+    // Candidates are identified by their vouts
+    fn candidates(list: &[(u32, u64, Option<u32>)]) -> Vec<StateCandidate> {
+        list.iter()
+            .map(|(vout, amount, height)| StateCandidate {
+                seal: seal(*vout),
+                amount: Amount::from(*amount),
+                height: *height,
+            })
+            .collect()
+    }
+
+    fn select(
+        selection: StateSelection,
+        list: &[(u32, u64, Option<u32>)],
+        target: u64,
+    ) -> Option<BTreeSet<XOutputSeal>> {
+        selection.select_state(candidates(list), Amount::from(target))
+    }
+
+    fn seals(vouts: &[u32]) -> Option<BTreeSet<XOutputSeal>> {
+        Some(vouts.iter().copied().map(seal).collect())
+    }
+
+    const LIST: [(u32, u64, Option<u32>); 4] =
+        [(0, 10, Some(5)), (1, 50, None), (2, 30, Some(1)), (3, 20, Some(0))];
+
+    #[test]
+    fn largest_first() {
+        assert_eq!(select(StateSelection::LargestFirst, &LIST, 40), seals(&[1]));
+        assert_eq!(select(StateSelection::LargestFirst, &LIST, 70), seals(&[1, 2]));
+        assert_eq!(select(StateSelection::LargestFirst, &LIST, 110), seals(&[0, 1, 2, 3]));
+        assert_eq!(select(StateSelection::LargestFirst, &LIST, 111), None);
+    }
+
+    #[test]
+    fn smallest_first() {
+        assert_eq!(select(StateSelection::SmallestFirst, &LIST, 25), seals(&[0, 3]));
+        assert_eq!(select(StateSelection::SmallestFirst, &LIST, 60), seals(&[0, 2, 3]));
+        assert_eq!(select(StateSelection::SmallestFirst, &LIST, 111), None);
+    }
+
+    #[test]
+    fn exact_match() {
+        // Largest-first would select 50 + 30
+        assert_eq!(select(StateSelection::ExactMatch, &LIST, 70), seals(&[1, 3]));
+        assert_eq!(select(StateSelection::ExactMatch, &LIST, 40), seals(&[0, 2]));
+        assert_eq!(select(StateSelection::ExactMatch, &LIST, 110), seals(&[0, 1, 2, 3]));
+    }
+
+    #[test]
+    fn exact_match_fallback() {
+        // No subset sums to 45, so the largest allocations are taken
+        assert_eq!(select(StateSelection::ExactMatch, &LIST, 45), seals(&[1]));
+        assert_eq!(select(StateSelection::ExactMatch, &LIST, 75), seals(&[1, 2]));
+        assert_eq!(select(StateSelection::ExactMatch, &LIST, 111), None);
+    }
+
+    #[test]
+    fn exact_match_tries_limit() {
+        // Odd target can't be reached with even amounts, while the search
+        // space is too large to be explored in full
+        let list = (0..60).map(|vout| (vout, 2, None)).collect::<Vec<_>>();
+        let selected = select(StateSelection::ExactMatch, &list, 41).unwrap();
+        assert_eq!(selected, (0..21).map(seal).collect());
+    }
+
+    #[test]
+    fn oldest_first() {
+        assert_eq!(select(StateSelection::OldestFirst, &LIST, 20), seals(&[3]));
+        assert_eq!(select(StateSelection::OldestFirst, &LIST, 40), seals(&[2, 3]));
+        // Allocations with unknown height are spent last
+        assert_eq!(select(StateSelection::OldestFirst, &LIST, 61), seals(&[0, 1, 2, 3]));
+        assert_eq!(select(StateSelection::OldestFirst, &LIST, 111), None);
+    }
+
+    #[test]
+    fn ties() {
+        let list = [(3, 10, Some(1)), (1, 10, Some(1)), (2, 10, Some(1))];
+        for selection in [
+            StateSelection::LargestFirst,
+            StateSelection::SmallestFirst,
+            StateSelection::ExactMatch,
+            StateSelection::OldestFirst,
+        ] {
+            assert_eq!(select(selection, &list, 10), seals(&[1]), "{selection}");
+            assert_eq!(select(selection, &list, 20), seals(&[1, 2]), "{selection}");
+        }
+    }
+
+    #[test]
+    fn names() {
+        for selection in [
+            StateSelection::LargestFirst,
+            StateSelection::SmallestFirst,
+            StateSelection::ExactMatch,
+            StateSelection::OldestFirst,
+        ] {
+            assert_eq!(StateSelection::from_str(&selection.to_string()), Ok(selection));
+        }
+    }
+}
@@ -40,13 +40,32 @@ use indexmap::IndexMap;
 #[display("terminal derivation {0} already has a taptweak assigned")]
 pub struct TapTweakAlreadyAssigned(pub Terminal);
 
+#[derive(Clone, Eq, PartialEq, Debug, Display, Error, From)]
+#[display(doc_comments)]
+pub enum TapretTweakError {
+    #[from]
+    #[display(inner)]
+    AlreadyAssigned(TapTweakAlreadyAssigned),
+
+    /// {descriptor} descriptor is not taproot-based and can't hold tapret tweak
+    /// for terminal derivation {terminal}.
+    NonTaproot {
+        descriptor: &'static str,
+        terminal: Terminal,
+    },
+}
+
+#[derive(Clone, Eq, PartialEq, Debug, Display, Error)]
+#[display("descriptor {0} is not supported by RGB wallets, which can use only wpkh and tr ones")]
+pub struct UnsupportedDescriptor(pub String);
+
 pub trait DescriptorRgb<K = XpubDerivable, V = ()>: Descriptor<K, V> {
     fn seal_close_method(&self) -> CloseMethod;
     fn add_tapret_tweak(
         &mut self,
         terminal: Terminal,
         tweak: TapretCommitment,
-    ) -> Result<(), TapTweakAlreadyAssigned>;
+    ) -> Result<(), TapretTweakError>;
 }
 
 #[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Hash, Debug, Display)]
@@ -223,9 +242,9 @@ impl<K: DeriveXOnly> DescriptorRgb<K> for TapretKey<K> {
         &mut self,
         terminal: Terminal,
         tweak: TapretCommitment,
-    ) -> Result<(), TapTweakAlreadyAssigned> {
+    ) -> Result<(), TapretTweakError> {
         if self.tweaks.contains_key(&terminal) {
-            return Err(TapTweakAlreadyAssigned(terminal));
+            return Err(TapTweakAlreadyAssigned(terminal).into());
         }
         self.tweaks.insert(terminal, tweak);
         Ok(())
@@ -343,20 +362,25 @@ where Self: Derive<DerivedScript>
         &mut self,
         terminal: Terminal,
         tweak: TapretCommitment,
-    ) -> Result<(), TapTweakAlreadyAssigned> {
+    ) -> Result<(), TapretTweakError> {
         match self {
-            RgbDescr::Wpkh(_) => panic!("adding tapret tweak to non-taproot descriptor"),
+            RgbDescr::Wpkh(_) => Err(TapretTweakError::NonTaproot {
+                descriptor: "wpkh",
+                terminal,
+            }),
             RgbDescr::TapretKey(d) => d.add_tapret_tweak(terminal, tweak),
         }
     }
 }
 
-impl From<StdDescr> for RgbDescr {
-    fn from(descr: StdDescr) -> Self {
+impl TryFrom<StdDescr> for RgbDescr {
+    type Error = UnsupportedDescriptor;
+
+    fn try_from(descr: StdDescr) -> Result<Self, Self::Error> {
         match descr {
-            StdDescr::Wpkh(wpkh) => RgbDescr::Wpkh(wpkh),
-            StdDescr::TrKey(tr) => RgbDescr::TapretKey(tr.into()),
-            _ => todo!(),
+            StdDescr::Wpkh(wpkh) => Ok(RgbDescr::Wpkh(wpkh)),
+            StdDescr::TrKey(tr) => Ok(RgbDescr::TapretKey(tr.into())),
+            descr => Err(UnsupportedDescriptor(descr.to_string())),
         }
     }
 }
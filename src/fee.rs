@@ -0,0 +1,218 @@
+// RGB wallet library for smart contracts on Bitcoin & Lightning network
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+// Written in 2019-2023 by
+//     Dr Maxim Orlovsky <orlovsky@lnp-bp.org>
+//
+// Copyright (C) 2019-2023 LNP/BP Standards Association. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::fmt::{self, Display, Formatter};
+use std::str::FromStr;
+
+use bp::Sats;
+use psbt::Psbt;
+
+/// Witness size of a taproot key-path spend with the default sighash type.
+const TR_KEY_SPEND_WITNESS: u64 = 1 + 1 + 64;
+/// Witness size of a P2WPKH spend; also used as a conservative estimate for
+/// all other input types.
+const WPKH_WITNESS: u64 = 1 + 1 + 72 + 1 + 33;
+/// Size of the data which are added to the OP_RETURN host output script once
+/// the commitment is made (`OP_PUSHBYTES_32` and 32-byte commitment).
+const OPRET_COMMITMENT: u64 = 1 + 32;
+
+/// Fee rate, measured in satoshis per 1000 virtual bytes.
+///
+/// Displayed and parsed as a (possibly fractional) number of satoshis per
+/// virtual byte.
+#[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Hash, Debug, Default)]
+pub struct FeeRate(u64);
+
+impl FeeRate {
+    pub const fn from_sat_per_kvb(sat_per_kvb: u64) -> Self { FeeRate(sat_per_kvb) }
+
+    pub const fn from_sat_per_vb(sat_per_vb: u64) -> Self { FeeRate(sat_per_vb * 1000) }
+
+    pub const fn sat_per_kvb(self) -> u64 { self.0 }
+
+    /// Computes fee for a transaction of a given virtual size, rounding up.
+    pub fn fee_for(self, vsize: u64) -> Sats {
+        Sats::from_sats(self.0.saturating_mul(vsize).saturating_add(999) / 1000)
+    }
+}
+
+impl Display for FeeRate {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0 / 1000)?;
+        if self.0 % 1000 != 0 {
+            let frac = format!("{:03}", self.0 % 1000);
+            write!(f, ".{}", frac.trim_end_matches('0'))?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for FeeRate {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let rate = f64::from_str(s).map_err(|_| format!("invalid fee rate '{s}'"))?;
+        if !rate.is_finite() || rate < 0.0 {
+            return Err(format!("invalid fee rate '{s}'"));
+        }
+        Ok(FeeRate((rate * 1000.0).round() as u64))
+    }
+}
+
+/// Estimates virtual size of the witness transaction defined by a PSBT once
+/// it will be committed and signed.
+///
+/// The estimation accounts for the OP_RETURN host output being extended with
+/// the commitment, unless the PSBT is already committed. Tapret commitment
+/// doesn't change the size of the host output, and taproot inputs spending
+/// outputs with tapret tweaks are still key-path spent, so they do not require
+/// special handling.
+pub fn estimate_vsize(psbt: &Psbt) -> u64 {
+    fn var_int_len(n: u64) -> u64 {
+        match n {
+            0..=0xFC => 1,
+            0xFD..=0xFFFF => 3,
+            0x10000..=0xFFFF_FFFF => 5,
+            _ => 9,
+        }
+    }
+
+    let inputs = psbt.inputs().count() as u64;
+    let outputs = psbt.outputs().count() as u64;
+
+    // version, lock time and input and output counts
+    let mut base = 4 + 4 + var_int_len(inputs) + var_int_len(outputs);
+    // prevout, empty script sig and sequence number
+    base += inputs * (32 + 4 + 1 + 4);
+    for output in psbt.outputs() {
+        let mut script_len = output.script.len() as u64;
+        if output.is_opret_host() {
+            // Host output of the committed PSBT already contains the commitment
+            script_len = script_len.max(1 + OPRET_COMMITMENT);
+        }
+        base += 8 + var_int_len(script_len) + script_len;
+    }
+
+    // segwit marker and flag
+    let mut witness = 2;
+    for input in psbt.inputs() {
+        witness += if input.prev_txout().script_pubkey.is_p2tr() {
+            TR_KEY_SPEND_WITNESS
+        } else {
+            WPKH_WITNESS
+        };
+    }
+
+    (base * 4 + witness + 3) / 4
+}
+
+#[cfg(test)]
+mod test {
+    use amplify::hex::FromHex;
+    use bp::{ConsensusDecode, ScriptPubkey, Tx, TxOut};
+    use psbt::UnsignedTx;
+
+    use super::*;
+
+    // Unsigned transaction with a single input and an empty OP_RETURN output
+    const TX: &str = "02000000011111111111111111111111111111111111111111111111111111111111111111\
+                      0000000000ffffffff010000000000000000016a00000000";
+    const P2WPKH_PREVOUT: &str = "a0860100000000001600143333333333333333333333333333333333333333";
+    const P2TR_PREVOUT: &str = "a086010000000000225120444444444444444444444444444444444444444444\
+                                4444444444444444444444";
+
+    fn psbt(prevout: &str) -> Psbt {
+        let tx = Tx::consensus_deserialize(Vec::<u8>::from_hex(TX).unwrap()).unwrap();
+        let prevout = TxOut::consensus_deserialize(Vec::<u8>::from_hex(prevout).unwrap()).unwrap();
+        let mut psbt = Psbt::from_tx(UnsignedTx::with_sigs_removed(tx));
+        psbt.inputs_mut().next().unwrap().witness_utxo = Some(prevout);
+        psbt
+    }
+
+    #[test]
+    fn units() {
+        assert_eq!(FeeRate::from_sat_per_vb(2).sat_per_kvb(), 2000);
+        assert_eq!(FeeRate::from_sat_per_kvb(1500).sat_per_kvb(), 1500);
+        assert_eq!(FeeRate::from_sat_per_vb(0), FeeRate::default());
+    }
+
+    #[test]
+    fn display() {
+        assert_eq!(FeeRate::from_sat_per_vb(2).to_string(), "2");
+        assert_eq!(FeeRate::from_sat_per_kvb(1500).to_string(), "1.5");
+        assert_eq!(FeeRate::from_sat_per_kvb(12250).to_string(), "12.25");
+        assert_eq!(FeeRate::from_sat_per_kvb(1).to_string(), "0.001");
+        assert_eq!(FeeRate::default().to_string(), "0");
+    }
+
+    #[test]
+    fn parse() {
+        assert_eq!(FeeRate::from_str("2"), Ok(FeeRate::from_sat_per_vb(2)));
+        assert_eq!(FeeRate::from_str("1.5"), Ok(FeeRate::from_sat_per_kvb(1500)));
+        assert_eq!(FeeRate::from_str("0.001"), Ok(FeeRate::from_sat_per_kvb(1)));
+        assert_eq!(FeeRate::from_str("0.0004"), Ok(FeeRate::default()));
+        for s in ["", "x", "-1", "NaN", "inf"] {
+            assert!(FeeRate::from_str(s).is_err(), "{s}");
+        }
+        for rate in [0, 1, 999, 1000, 1500, 12250] {
+            let rate = FeeRate::from_sat_per_kvb(rate);
+            assert_eq!(FeeRate::from_str(&rate.to_string()), Ok(rate));
+        }
+    }
+
+    #[test]
+    fn fee() {
+        assert_eq!(FeeRate::from_sat_per_vb(1).fee_for(141), Sats::from_sats(141u64));
+        // Fractional fee is rounded up
+        assert_eq!(FeeRate::from_sat_per_kvb(1500).fee_for(141), Sats::from_sats(212u64));
+        assert_eq!(FeeRate::from_sat_per_kvb(1).fee_for(1), Sats::from_sats(1u64));
+        assert_eq!(FeeRate::default().fee_for(141), Sats::ZERO);
+        assert_eq!(
+            FeeRate::from_sat_per_kvb(u64::MAX).fee_for(2),
+            Sats::from_sats(u64::MAX / 1000)
+        );
+    }
+
+    #[test]
+    fn vsize_p2wpkh() {
+        // (61 * 4 + 2 + 108) / 4, rounded up
+        assert_eq!(estimate_vsize(&psbt(P2WPKH_PREVOUT)), 89);
+    }
+
+    #[test]
+    fn vsize_p2tr() {
+        // (61 * 4 + 2 + 66) / 4
+        assert_eq!(estimate_vsize(&psbt(P2TR_PREVOUT)), 78);
+    }
+
+    #[test]
+    fn vsize_opret_host() {
+        // OP_RETURN output gets extended with 33 bytes of the commitment
+        let mut psbt = psbt(P2WPKH_PREVOUT);
+        let output = psbt.outputs_mut().next().unwrap();
+        output.set_opret_host().unwrap();
+        assert_eq!(estimate_vsize(&psbt), 122);
+
+        // Commitment is not counted twice
+        psbt.outputs_mut().next().unwrap().script = ScriptPubkey::op_return(&[0u8; 32]);
+        assert_eq!(estimate_vsize(&psbt), 122);
+    }
+}
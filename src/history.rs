@@ -0,0 +1,542 @@
+// RGB wallet library for smart contracts on Bitcoin & Lightning network
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+// Written in 2019-2023 by
+//     Dr Maxim Orlovsky <orlovsky@lnp-bp.org>
+//
+// Copyright (C) 2019-2023 LNP/BP Standards Association. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::{self, Display, Formatter};
+
+use bp::{Outpoint, Sats, Txid};
+use bpwallet::{Party, TxRow, TxStatus};
+use rgbstd::interface::{AmountChange, FilterExclude, IfaceRef, OutpointFilter, OwnedIface};
+use rgbstd::persistence::{
+    IndexProvider, StashProvider, StashReadProvider, StateProvider, StockError,
+};
+use rgbstd::{
+    AssignmentWitness, ContractId, DataState, Layer1, WitnessOrd, WitnessPos, XChain, XOutpoint,
+    XOutputSeal, XWitnessId,
+};
+use strict_types::encoding::{FieldName, TypeName};
+
+use crate::{DescriptorRgb, Runtime, RuntimeError};
+
+/// Kind of the owned state, as defined by the contract interface.
+#[derive(Copy, Clone, Eq, PartialEq, Hash, Debug, Display)]
+#[display(lowercase)]
+pub enum StateKind {
+    Fungible,
+    Data,
+    Void,
+}
+
+/// Direction in which the state was moved by an operation, from the wallet
+/// perspective.
+#[derive(Copy, Clone, Eq, PartialEq, Hash, Debug, Display)]
+#[display(lowercase)]
+pub enum OpDirection {
+    /// The state was received by the wallet from other parties.
+    Received,
+    /// The state was sent by the wallet to other parties.
+    Sent,
+    /// The state was moved between the wallet outputs by a transaction
+    /// spending the wallet outputs, like a change of a payment.
+    Internal,
+}
+
+/// State moved by an operation.
+#[derive(Clone, Eq, PartialEq, Debug)]
+pub enum HistoryState {
+    Fungible(AmountChange),
+    Data(Vec<DataState>),
+    /// Number of the moved rights.
+    Void(usize),
+}
+
+impl Display for HistoryState {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            HistoryState::Fungible(change) => Display::fmt(change, f),
+            HistoryState::Data(states) => write!(f, "{} data", states.len()),
+            HistoryState::Void(count) => write!(f, "{count} rights"),
+        }
+    }
+}
+
+/// Filter applied to the contract operation history.
+#[derive(Clone, Eq, PartialEq, Debug, Default)]
+pub struct HistoryFilter {
+    /// Include only operations on these assignment types; all if empty.
+    pub assignments: BTreeSet<FieldName>,
+    /// Include only state of this kind; all if `None`.
+    pub kind: Option<StateKind>,
+    /// Include only operations moving state in this direction; all if `None`.
+    pub direction: Option<OpDirection>,
+}
+
+/// Single operation from the contract history affecting the wallet.
+#[derive(Clone, Eq, PartialEq, Debug)]
+pub struct HistoryEntry {
+    pub witness_id: XWitnessId,
+    /// Mining status of the witness transaction, or `None` if the transaction
+    /// is not known to the wallet.
+    pub witness_ord: Option<WitnessOrd>,
+    /// Fee paid by the witness transaction, if it is known to the wallet.
+    pub fee: Option<Sats>,
+    /// Name of the interface operation for which the assignment is the
+    /// default one, if any.
+    pub operation: Option<FieldName>,
+    pub assignment: FieldName,
+    pub state: HistoryState,
+    pub direction: OpDirection,
+    /// Seals of the other party: payers for the received state and
+    /// beneficiaries for the sent state, when they are known.
+    pub counterparties: BTreeSet<XOutputSeal>,
+}
+
+/// Contract operation carried by a wallet transaction.
+#[derive(Clone, Eq, PartialEq, Debug)]
+pub struct RgbTxOp {
+    pub contract_id: ContractId,
+    /// Name of the interface used to interpret the contract state.
+    pub iface: TypeName,
+    pub entry: HistoryEntry,
+}
+
+/// Wallet transaction annotated with the contract operations it has
+/// carried.
+#[derive(Clone, Debug)]
+pub struct RgbTxRow {
+    pub row: TxRow,
+    pub ops: Vec<RgbTxOp>,
+}
+
+impl HistoryEntry {
+    fn mining_pos(&self) -> Option<WitnessPos> {
+        match self.witness_ord {
+            Some(WitnessOrd::OnChain(pos)) => Some(pos),
+            _ => None,
+        }
+    }
+
+    /// Height of the block mining the witness transaction.
+    pub fn height(&self) -> Option<u32> { self.mining_pos().map(|pos| u32::from(pos.height())) }
+
+    /// Timestamp of the block mining the witness transaction.
+    pub fn timestamp(&self) -> Option<i64> { self.mining_pos().map(|pos| pos.timestamp()) }
+}
+
+impl HistoryFilter {
+    fn includes(&self, assignment: &FieldName, kind: StateKind) -> bool {
+        (self.assignments.is_empty() || self.assignments.contains(assignment)) &&
+            self.kind.map(|k| k == kind).unwrap_or(true)
+    }
+
+    fn includes_direction(&self, direction: OpDirection) -> bool {
+        self.direction.map(|d| d == direction).unwrap_or(true)
+    }
+}
+
+/// Filter including all outputs ever owned by the wallet, both spent and
+/// unspent. Unlike the runtime filter, which includes only unspent outputs, it
+/// keeps in the history the state which was received by the wallet and spent
+/// afterwards.
+#[derive(Clone, Eq, PartialEq, Debug)]
+struct WalletOutputs(BTreeSet<XOutpoint>);
+
+impl WalletOutputs {
+    fn with(layer1: Layer1, outpoints: impl IntoIterator<Item = Outpoint>) -> Self {
+        Self(
+            outpoints
+                .into_iter()
+                .map(|outpoint| XChain::with(layer1, outpoint))
+                .collect(),
+        )
+    }
+}
+
+impl OutpointFilter for WalletOutputs {
+    fn include_outpoint(&self, output: impl Into<XOutpoint>) -> bool {
+        self.0.contains(&output.into())
+    }
+}
+
+/// Groups allocations by their witness, skipping genesis allocations.
+fn group_by_witness<S>(
+    allocations: impl Iterator<Item = (AssignmentWitness, XOutputSeal, S)>,
+) -> BTreeMap<XWitnessId, (Vec<S>, BTreeSet<XOutputSeal>)> {
+    let mut groups = BTreeMap::<_, (Vec<S>, BTreeSet<_>)>::new();
+    for (witness, seal, state) in allocations {
+        let AssignmentWitness::Present(witness_id) = witness else {
+            continue;
+        };
+        let (states, seals) = groups.entry(witness_id).or_default();
+        states.push(state);
+        seals.insert(seal);
+    }
+    groups
+}
+
+impl<D: DescriptorRgb<K>, K, S: StashProvider, H: StateProvider, P: IndexProvider>
+    Runtime<D, K, S, H, P>
+{
+    /// Returns history of the contract operations affecting the wallet for
+    /// all types of owned state defined by the interface, ordered by the
+    /// mining status of their witness transactions.
+    ///
+    /// The history is not stored separately: it is derived on each call from
+    /// the stock and wallet data. It covers all outputs ever owned by the
+    /// wallet, thus the state received and spent afterwards is reported both
+    /// as received and sent. Since data and rights are not fungible,
+    /// their allocations are not netted: the ones assigned to the wallet by a
+    /// transaction spending the wallet outputs are reported as
+    /// [`OpDirection::Internal`], and the ones assigned to other parties by
+    /// such transaction as [`OpDirection::Sent`].
+    pub fn history(
+        &self,
+        contract_id: ContractId,
+        iface: impl Into<IfaceRef>,
+        filter: &HistoryFilter,
+    ) -> Result<Vec<HistoryEntry>, RuntimeError> {
+        let iref = iface.into();
+        let iface = self.stock().iface(iref.clone())?.clone();
+        let contract = self.stock().contract_iface(contract_id, iref)?;
+        let outputs = self.wallet_outputs();
+
+        let mut entries = vec![];
+        for (name, assign) in &iface.assignments {
+            let kind = match assign.owned_state {
+                OwnedIface::Amount => StateKind::Fungible,
+                OwnedIface::Data(_) | OwnedIface::AnyData => StateKind::Data,
+                OwnedIface::Rights => StateKind::Void,
+                OwnedIface::Any | OwnedIface::AnyAttach => continue,
+            };
+            if !filter.includes(name, kind) {
+                continue;
+            }
+            let operation = iface
+                .transitions
+                .iter()
+                .find(|(_, t)| t.default_assignment.as_ref() == Some(name))
+                .map(|(op, _)| op.clone());
+            let mut push = |witness_id, state, direction, counterparties| {
+                if !filter.includes_direction(direction) {
+                    return;
+                }
+                let (witness_ord, fee) = self.witness_info(witness_id);
+                entries.push(HistoryEntry {
+                    witness_id,
+                    witness_ord,
+                    fee,
+                    operation: operation.clone(),
+                    assignment: name.clone(),
+                    state,
+                    direction,
+                    counterparties,
+                });
+            };
+
+            match kind {
+                StateKind::Fungible => {
+                    let ops = contract
+                        .fungible_ops::<AmountChange>(name.clone(), &outputs, self)
+                        .map_err(RuntimeError::from)?;
+                    for (witness_id, op) in ops {
+                        let (direction, counterparties) = match op.state_change {
+                            AmountChange::Dec(_) => (OpDirection::Sent, op.beneficiaries),
+                            AmountChange::Inc(_) => (OpDirection::Received, op.payers),
+                            AmountChange::Zero => (OpDirection::Internal, none!()),
+                        };
+                        let state = HistoryState::Fungible(op.state_change);
+                        push(witness_id, state, direction, counterparties);
+                    }
+                }
+                StateKind::Data => {
+                    let received = contract
+                        .data(name.clone(), &outputs)
+                        .map_err(RuntimeError::from)?
+                        .map(|a| (a.witness, a.seal, a.state));
+                    for (witness_id, (states, _)) in group_by_witness(received) {
+                        let state = HistoryState::Data(states);
+                        push(witness_id, state, self.receive_direction(witness_id), none!());
+                    }
+                    let sent = contract
+                        .data(name.clone(), &FilterExclude(&outputs))
+                        .map_err(RuntimeError::from)?
+                        .filter(|a| self.spends_wallet(a.witness))
+                        .map(|a| (a.witness, a.seal, a.state));
+                    for (witness_id, (states, seals)) in group_by_witness(sent) {
+                        let state = HistoryState::Data(states);
+                        push(witness_id, state, OpDirection::Sent, seals);
+                    }
+                }
+                StateKind::Void => {
+                    let received = contract
+                        .rights(name.clone(), &outputs)
+                        .map_err(RuntimeError::from)?
+                        .map(|a| (a.witness, a.seal, ()));
+                    for (witness_id, (states, _)) in group_by_witness(received) {
+                        let state = HistoryState::Void(states.len());
+                        push(witness_id, state, self.receive_direction(witness_id), none!());
+                    }
+                    let sent = contract
+                        .rights(name.clone(), &FilterExclude(&outputs))
+                        .map_err(RuntimeError::from)?
+                        .filter(|a| self.spends_wallet(a.witness))
+                        .map(|a| (a.witness, a.seal, ()));
+                    for (witness_id, (states, seals)) in group_by_witness(sent) {
+                        let state = HistoryState::Void(states.len());
+                        push(witness_id, state, OpDirection::Sent, seals);
+                    }
+                }
+            }
+        }
+
+        // Operations with witnesses unknown to the wallet go last
+        entries.sort_by_key(|entry| {
+            (entry.witness_ord.is_none(), entry.witness_ord, entry.witness_id)
+        });
+        Ok(entries)
+    }
+
+    /// Returns history of the wallet transactions, where each transaction is
+    /// annotated with the operations on all known contracts it has carried.
+    ///
+    /// Contracts are interpreted using all interfaces implemented by their
+    /// schema; an operation on the same assignment reported by several
+    /// interfaces is listed only once. Fails if the history of any of the
+    /// contracts can't be constructed.
+    pub fn wallet_history(&self) -> Result<Vec<RgbTxRow>, RuntimeError> {
+        let mut ops = BTreeMap::<Txid, Vec<RgbTxOp>>::new();
+        for contract_id in self.stock().contract_ids()? {
+            let schema_id = self
+                .stock()
+                .as_stash_provider()
+                .genesis(contract_id)
+                .map_err(StockError::<S, H, P>::from)?
+                .schema_id;
+            let iface_ids = self
+                .stock()
+                .schema(schema_id)?
+                .iimpls
+                .keys()
+                .copied()
+                .collect::<Vec<_>>();
+            let mut seen = BTreeSet::new();
+            for iface_id in iface_ids {
+                let iface_name = self.stock().iface(iface_id)?.name.clone();
+                let entries = self.history(contract_id, iface_id, &HistoryFilter::default())?;
+                for entry in entries {
+                    let Some(txid) = self.wallet_txid(entry.witness_id) else {
+                        continue;
+                    };
+                    if !seen.insert((entry.witness_id, entry.assignment.clone(), entry.direction)) {
+                        continue;
+                    }
+                    ops.entry(txid).or_default().push(RgbTxOp {
+                        contract_id,
+                        iface: iface_name.clone(),
+                        entry,
+                    });
+                }
+            }
+        }
+
+        Ok(self
+            .wallet()
+            .history()
+            .map(|row| RgbTxRow {
+                ops: ops.remove(&row.txid).unwrap_or_default(),
+                row,
+            })
+            .collect())
+    }
+
+    /// Returns id of the witness transaction if it is on the layer 1 the
+    /// wallet operates on.
+    fn wallet_txid(&self, witness_id: XWitnessId) -> Option<Txid> {
+        match witness_id {
+            XWitnessId::Bitcoin(txid) | XWitnessId::Liquid(txid)
+                if witness_id.layer1() == self.layer1() =>
+            {
+                Some(txid)
+            }
+            _ => None,
+        }
+    }
+
+    /// Returns all outputs ever owned by the wallet, including the spent ones.
+    fn wallet_outputs(&self) -> WalletOutputs {
+        let outpoints = self
+            .wallet()
+            .transactions()
+            .values()
+            .flat_map(|tx| &tx.outputs)
+            .filter(|output| matches!(output.beneficiary, Party::Wallet(_)))
+            .map(|output| output.outpoint);
+        WalletOutputs::with(self.layer1(), outpoints)
+    }
+
+    /// Checks whether the witness transaction spends any of the wallet
+    /// outputs, i.e. it was constructed by the wallet.
+    fn spends_wallet(&self, witness: AssignmentWitness) -> bool {
+        let AssignmentWitness::Present(witness_id) = witness else {
+            return false;
+        };
+        self.wallet_txid(witness_id)
+            .and_then(|txid| self.wallet().transactions().get(&txid))
+            .map(|tx| {
+                tx.inputs
+                    .iter()
+                    .any(|input| matches!(input.payer, Party::Wallet(_)))
+            })
+            .unwrap_or_default()
+    }
+
+    /// Direction of the state assigned to the wallet by the witness
+    /// transaction.
+    fn receive_direction(&self, witness_id: XWitnessId) -> OpDirection {
+        if self.spends_wallet(AssignmentWitness::Present(witness_id)) {
+            OpDirection::Internal
+        } else {
+            OpDirection::Received
+        }
+    }
+
+    /// Returns mining status and fee of the witness transaction, if the
+    /// transaction is known to the wallet.
+    fn witness_info(&self, witness_id: XWitnessId) -> (Option<WitnessOrd>, Option<Sats>) {
+        let Some(tx) = self
+            .wallet_txid(witness_id)
+            .and_then(|txid| self.wallet().transactions().get(&txid))
+        else {
+            return (None, None);
+        };
+        let ord = match tx.status {
+            TxStatus::Mined(info) => WitnessPos::new(info.height.get(), info.time as i64)
+                .map(WitnessOrd::OnChain)
+                .unwrap_or(WitnessOrd::OffChain),
+            _ => WitnessOrd::OffChain,
+        };
+        (Some(ord), Some(tx.fee))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::str::FromStr;
+
+    use bp::seals::txout::{CloseMethod, ExplicitSeal};
+
+    use super::*;
+
+    fn txid(no: u8) -> Txid { Txid::from_str(&format!("{no:064x}")).unwrap() }
+
+    fn outpoint(no: u8) -> Outpoint { Outpoint::new(txid(no), 0u32) }
+
+    fn seal(no: u8) -> XOutputSeal {
+        XChain::Bitcoin(ExplicitSeal::new(CloseMethod::TapretFirst, outpoint(no)))
+    }
+
+    fn witness(no: u8) -> AssignmentWitness {
+        AssignmentWitness::Present(XWitnessId::Bitcoin(txid(no)))
+    }
+
+    #[test]
+    fn grouping() {
+        let allocations = [
+            (AssignmentWitness::Absent, seal(1), 1),
+            (witness(2), seal(2), 2),
+            (witness(3), seal(3), 3),
+            (witness(2), seal(4), 4),
+            (witness(2), seal(2), 5),
+        ];
+        let groups = group_by_witness(allocations.into_iter());
+        assert_eq!(groups.len(), 2);
+        assert_eq!(
+            groups[&XWitnessId::Bitcoin(txid(2))],
+            (vec![2, 4, 5], bset![seal(2), seal(4)])
+        );
+        assert_eq!(groups[&XWitnessId::Bitcoin(txid(3))], (vec![3], bset![seal(3)]));
+    }
+
+    #[test]
+    fn wallet_outputs() {
+        let outputs = WalletOutputs::with(Layer1::Bitcoin, [outpoint(1), outpoint(2)]);
+        assert!(outputs.include_outpoint(XChain::Bitcoin(outpoint(1))));
+        assert!(outputs.include_outpoint(XChain::Bitcoin(outpoint(2))));
+        assert!(!outputs.include_outpoint(XChain::Bitcoin(outpoint(3))));
+        // the same outpoint on another layer 1
+        assert!(!outputs.include_outpoint(XChain::Liquid(outpoint(1))));
+
+        let outputs = WalletOutputs::with(Layer1::Liquid, [outpoint(1)]);
+        assert!(outputs.include_outpoint(XChain::Liquid(outpoint(1))));
+        assert!(!outputs.include_outpoint(XChain::Bitcoin(outpoint(1))));
+    }
+
+    #[test]
+    fn filter() {
+        let owner = FieldName::from("assetOwner");
+        let issued = FieldName::from("inflationAllowance");
+
+        let all = HistoryFilter::default();
+        assert!(all.includes(&owner, StateKind::Fungible));
+        assert!(all.includes(&issued, StateKind::Data));
+        assert!(all.includes_direction(OpDirection::Internal));
+
+        let filter = HistoryFilter {
+            assignments: bset![owner.clone()],
+            kind: Some(StateKind::Fungible),
+            direction: Some(OpDirection::Sent),
+        };
+        assert!(filter.includes(&owner, StateKind::Fungible));
+        assert!(!filter.includes(&owner, StateKind::Data));
+        assert!(!filter.includes(&issued, StateKind::Fungible));
+        assert!(filter.includes_direction(OpDirection::Sent));
+        assert!(!filter.includes_direction(OpDirection::Received));
+    }
+
+    #[test]
+    fn entry_mining() {
+        let mut entry = HistoryEntry {
+            witness_id: XWitnessId::Bitcoin(txid(1)),
+            witness_ord: None,
+            fee: None,
+            operation: None,
+            assignment: FieldName::from("assetOwner"),
+            state: HistoryState::Void(1),
+            direction: OpDirection::Received,
+            counterparties: none!(),
+        };
+        assert_eq!(entry.height(), None);
+        assert_eq!(entry.timestamp(), None);
+
+        entry.witness_ord = Some(WitnessOrd::OffChain);
+        assert_eq!(entry.height(), None);
+
+        entry.witness_ord = WitnessPos::new(840_000, 1_713_571_767).map(WitnessOrd::OnChain);
+        assert_eq!(entry.height(), Some(840_000));
+        assert_eq!(entry.timestamp(), Some(1_713_571_767));
+    }
+
+    #[test]
+    fn state_display() {
+        assert_eq!(HistoryState::Void(3).to_string(), "3 rights");
+        assert_eq!(HistoryState::Data(vec![]).to_string(), "0 data");
+    }
+}
@@ -0,0 +1,580 @@
+// RGB wallet library for smart contracts on Bitcoin & Lightning network
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+// Written in 2019-2024 by
+//     Dr Maxim Orlovsky <orlovsky@lnp-bp.org>
+//
+// Copyright (C) 2019-2024 LNP/BP Standards Association. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use amplify::confinement::{SmallBlob, U16 as MAX16};
+use rgbstd::containers::{BuilderSeal, ValidContract};
+use rgbstd::contract::{AttachId, AttachState, GenesisSeal};
+use rgbstd::interface::{BuilderError, IfaceId};
+use rgbstd::persistence::{
+    IndexProvider, SchemaIfaces, StashProvider, StateProvider, Stock, StockError,
+};
+use rgbstd::schema::{SchemaId, StateSchema};
+use rgbstd::validation::{self, ResolveWitness, WitnessResolverError};
+use rgbstd::{OutputSeal, XChain, XWitnessId, XWitnessTx};
+use serde::de::Error as _;
+use serde::{Deserialize, Deserializer};
+use sha2::{Digest, Sha256};
+use strict_types::encoding::{FieldName, TypeName};
+use strict_types::{StrictVal, TypeSystem};
+
+use crate::{DescriptorRgb, Runtime};
+
+/// Errors happening during contract issuance from a contract description.
+#[derive(Debug, Display, Error, From)]
+#[display(doc_comments)]
+pub enum IssueError {
+    /// invalid {format} contract description: {details}
+    InvalidDescription { format: String, details: String },
+
+    /// interface '{0}' is not known to the stock.
+    UnknownIface(String),
+
+    /// schema has no known implementation for the interface {0}.
+    NoIfaceImpl(TypeName),
+
+    /// '{0}' is not a valid state name.
+    InvalidFieldName(String),
+
+    /// interface doesn't define global state '{0}'.
+    UnknownGlobal(String),
+
+    /// interface doesn't define assignment '{0}'.
+    UnknownAssignment(String),
+
+    /// schema implementation doesn't define type for the state '{0}'.
+    InvalidSchemaImpl(String),
+
+    /// value of global state '{name}' doesn't match its type definition:
+    /// {details}
+    InvalidGlobal { name: String, details: String },
+
+    /// seal '{seal}' of assignment '{name}' is invalid.
+    InvalidSeal { name: String, seal: String },
+
+    /// fungible assignment '{0}' must provide amount as an integer.
+    NoAmount(String),
+
+    /// structured assignment '{0}' must provide data.
+    NoData(String),
+
+    /// data of assignment '{name}' don't match their type definition:
+    /// {details}
+    InvalidData { name: String, details: String },
+
+    /// attachment assignment '{0}' must provide path to the attached file.
+    NoFile(String),
+
+    /// unable to read file '{path}' attached to assignment '{name}': {details}
+    AttachmentFile {
+        name: String,
+        path: String,
+        details: String,
+    },
+
+    #[from]
+    #[display(inner)]
+    Builder(BuilderError),
+
+    /// the issued contract doesn't fit requirements imposed by the used
+    /// schema.
+    ///
+    /// {0}
+    InvalidContract(validation::Status),
+
+    /// stock error: {0}
+    Stock(Box<dyn Error + Send + Sync>),
+}
+
+impl<S: StashProvider, H: StateProvider, P: IndexProvider, E: Error> From<StockError<S, H, P, E>>
+    for IssueError
+where StockError<S, H, P, E>: Error + Send + Sync + 'static
+{
+    fn from(err: StockError<S, H, P, E>) -> Self { Self::Stock(Box::new(err)) }
+}
+
+/// Description of a contract to issue, which may be given in any
+/// self-describing serde format, like YAML, JSON or TOML (see
+/// `examples/rgb20-demo.*`).
+#[derive(Clone, Eq, PartialEq, Debug, Serialize, Deserialize)]
+#[serde(crate = "serde_crate", rename_all = "camelCase")]
+pub struct IssueRequest {
+    /// Name or id of the interface under which the contract is constructed.
+    pub interface: String,
+    /// Global state values, by the interface global state names. May also be
+    /// given under the `global` key or as a list of maps.
+    #[serde(default, alias = "global", deserialize_with = "merged_maps")]
+    pub globals: BTreeMap<String, serde_json::Value>,
+    /// Owned state allocations, by the interface assignment names. Each name
+    /// may have a single allocation or a list of them. May also be given under
+    /// the `assignment` key or as a list of maps, in which the same name may
+    /// repeat.
+    #[serde(default, alias = "assignment", deserialize_with = "merged_allocations")]
+    pub assignments: BTreeMap<String, Vec<AssignmentRequest>>,
+}
+
+/// Owned state allocation in [`IssueRequest`]. Besides the seal, it must
+/// provide `amount` for fungible state, `data` for structured state or `file`
+/// for attachments; rights need no other fields. Fungible allocation may also
+/// be given as a map of the seal to the amount.
+#[derive(Clone, Eq, PartialEq, Debug, Serialize, Deserialize)]
+#[serde(crate = "serde_crate", rename_all = "camelCase", try_from = "AssignmentRepr")]
+pub struct AssignmentRequest {
+    pub seal: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub amount: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub data: Option<serde_json::Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub file: Option<PathBuf>,
+}
+
+#[derive(Deserialize)]
+#[serde(crate = "serde_crate", untagged)]
+enum AssignmentRepr {
+    Fields {
+        seal: String,
+        #[serde(default)]
+        amount: Option<u64>,
+        #[serde(default)]
+        data: Option<serde_json::Value>,
+        #[serde(default)]
+        file: Option<PathBuf>,
+    },
+    Allocation(BTreeMap<String, u64>),
+}
+
+impl TryFrom<AssignmentRepr> for AssignmentRequest {
+    type Error = String;
+
+    fn try_from(repr: AssignmentRepr) -> Result<Self, Self::Error> {
+        match repr {
+            AssignmentRepr::Fields {
+                seal,
+                amount,
+                data,
+                file,
+            } => Ok(AssignmentRequest {
+                seal,
+                amount,
+                data,
+                file,
+            }),
+            AssignmentRepr::Allocation(allocation) => {
+                let mut iter = allocation.into_iter();
+                match (iter.next(), iter.next()) {
+                    (Some((seal, amount)), None) => Ok(AssignmentRequest {
+                        seal,
+                        amount: Some(amount),
+                        data: None,
+                        file: None,
+                    }),
+                    _ => Err(s!("allocation must map a single seal to its amount")),
+                }
+            }
+        }
+    }
+}
+
+/// Map which may also be given as a list of maps.
+#[derive(Deserialize)]
+#[serde(crate = "serde_crate", untagged)]
+enum Maps<V> {
+    Map(BTreeMap<String, V>),
+    List(Vec<BTreeMap<String, V>>),
+}
+
+impl<V> Maps<V> {
+    fn into_entries(self) -> Vec<(String, V)> {
+        match self {
+            Maps::Map(map) => map.into_iter().collect(),
+            Maps::List(list) => list.into_iter().flatten().collect(),
+        }
+    }
+}
+
+/// Single allocation or a list of them.
+#[derive(Deserialize)]
+#[serde(crate = "serde_crate", untagged)]
+enum Allocations {
+    List(Vec<AssignmentRequest>),
+    Single(AssignmentRequest),
+}
+
+/// Deserializes map which may also be given as a list of maps, which are
+/// merged together.
+fn merged_maps<'de, D: Deserializer<'de>, V: Deserialize<'de>>(
+    deserializer: D,
+) -> Result<BTreeMap<String, V>, D::Error> {
+    let mut merged = BTreeMap::new();
+    for (name, val) in Maps::deserialize(deserializer)?.into_entries() {
+        if merged.contains_key(&name) {
+            return Err(D::Error::custom(format!("duplicate entry '{name}'")));
+        }
+        merged.insert(name, val);
+    }
+    Ok(merged)
+}
+
+/// Deserializes allocations by the assignment names like [`merged_maps`], but
+/// collects allocations under repeated names instead of rejecting them.
+fn merged_allocations<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<BTreeMap<String, Vec<AssignmentRequest>>, D::Error> {
+    let mut merged = BTreeMap::<String, Vec<AssignmentRequest>>::new();
+    for (name, allocations) in Maps::<Allocations>::deserialize(deserializer)?.into_entries() {
+        let entry = merged.entry(name).or_default();
+        match allocations {
+            Allocations::List(list) => entry.extend(list),
+            Allocations::Single(assign) => entry.push(assign),
+        }
+    }
+    Ok(merged)
+}
+
+impl IssueRequest {
+    /// Constructs genesis for the contract using schema and interface known
+    /// to the stock, returning the issued contract once it passes validation.
+    /// The contract is not added to the stock.
+    pub fn issue<S: StashProvider, H: StateProvider, P: IndexProvider>(
+        &self,
+        stock: &Stock<S, H, P>,
+        schema_id: SchemaId,
+    ) -> Result<ValidContract, IssueError> {
+        let SchemaIfaces {
+            ref schema,
+            ref iimpls,
+        } = stock.schema(schema_id)?;
+        let iface = TypeName::try_from(self.interface.clone())
+            .ok()
+            .and_then(|name| stock.iface(name).ok())
+            .or_else(|| {
+                let id = IfaceId::from_str(&self.interface).ok()?;
+                stock.iface(id).ok()
+            })
+            .ok_or_else(|| IssueError::UnknownIface(self.interface.clone()))?;
+        let iface_name = iface.name.clone();
+        let iface_id = iface.iface_id();
+        let iface_impl = iimpls
+            .get(&iface_id)
+            .ok_or_else(|| IssueError::NoIfaceImpl(iface_name))?;
+
+        let mut builder = stock.contract_builder(schema_id, iface_id)?;
+        let types = builder.type_system().clone();
+
+        for (name, val) in &self.globals {
+            let state_type = iface_impl
+                .global_state
+                .iter()
+                .find(|info| info.name.as_str() == name)
+                .ok_or_else(|| IssueError::UnknownGlobal(name.clone()))?
+                .id;
+            let sem_id = schema
+                .global_types
+                .get(&state_type)
+                .ok_or_else(|| IssueError::InvalidSchemaImpl(name.clone()))?
+                .sem_id;
+            let typed_val = strict_val(val)
+                .and_then(|val| types.typify(val, sem_id).map_err(|err| err.to_string()))
+                .map_err(|details| IssueError::InvalidGlobal {
+                    name: name.clone(),
+                    details,
+                })?;
+            let serialized = types
+                .strict_serialize_type::<MAX16>(&typed_val)
+                .map_err(|err| IssueError::InvalidGlobal {
+                    name: name.clone(),
+                    details: err.to_string(),
+                })?;
+            builder = builder.add_global_state(field_name(name)?, serialized)?;
+        }
+
+        for (name, allocations) in &self.assignments {
+            let state_type = iface_impl
+                .assignments
+                .iter()
+                .find(|info| info.name.as_str() == name)
+                .ok_or_else(|| IssueError::UnknownAssignment(name.clone()))?
+                .id;
+            let state_schema = schema
+                .owned_types
+                .get(&state_type)
+                .ok_or_else(|| IssueError::InvalidSchemaImpl(name.clone()))?;
+            let field_name = field_name(name)?;
+
+            for assign in allocations {
+                let seal =
+                    OutputSeal::from_str(&assign.seal).map_err(|_| IssueError::InvalidSeal {
+                        name: name.clone(),
+                        seal: assign.seal.clone(),
+                    })?;
+                let seal = GenesisSeal::new_random(seal.method, seal.txid, seal.vout);
+                let seal = BuilderSeal::Revealed(XChain::Bitcoin(seal));
+
+                let field_name = field_name.clone();
+                builder = match assign.state(name, state_schema, &types)? {
+                    AssignedState::Rights => builder.add_rights(field_name, seal)?,
+                    AssignedState::Fungible(amount) => {
+                        builder.add_fungible_state(field_name, seal, amount)?
+                    }
+                    AssignedState::Data(data) => builder.add_data(field_name, seal, data)?,
+                    AssignedState::Attachment(attachment) => {
+                        builder.add_attachment(field_name, seal, attachment)?
+                    }
+                };
+            }
+        }
+
+        let contract = builder.issue_contract()?;
+        let testnet = contract.genesis.testnet;
+        contract
+            .validate(&mut GenesisResolver, testnet)
+            .map_err(|(status, _)| IssueError::InvalidContract(status))
+    }
+}
+
+/// Owned state of an assignment, checked against its schema.
+#[derive(Clone, PartialEq, Eq, Debug)]
+enum AssignedState {
+    Rights,
+    Fungible(u64),
+    Data(SmallBlob),
+    Attachment(AttachState),
+}
+
+impl AssignmentRequest {
+    fn state(
+        &self,
+        name: &str,
+        state_schema: &StateSchema,
+        types: &TypeSystem,
+    ) -> Result<AssignedState, IssueError> {
+        Ok(match state_schema {
+            StateSchema::Declarative => AssignedState::Rights,
+            StateSchema::Fungible(_) => {
+                let amount = self
+                    .amount
+                    .ok_or_else(|| IssueError::NoAmount(name.to_owned()))?;
+                AssignedState::Fungible(amount)
+            }
+            StateSchema::Structured(sem_id) => {
+                let data = self
+                    .data
+                    .as_ref()
+                    .ok_or_else(|| IssueError::NoData(name.to_owned()))?;
+                let serialized = strict_val(data)
+                    .and_then(|val| types.typify(val, *sem_id).map_err(|err| err.to_string()))
+                    .and_then(|typed_val| {
+                        types
+                            .strict_serialize_type::<MAX16>(&typed_val)
+                            .map_err(|err| err.to_string())
+                    })
+                    .map_err(|details| IssueError::InvalidData {
+                        name: name.to_owned(),
+                        details,
+                    })?;
+                AssignedState::Data(serialized)
+            }
+            StateSchema::Attachment(media_type) => {
+                let path = self
+                    .file
+                    .as_ref()
+                    .ok_or_else(|| IssueError::NoFile(name.to_owned()))?;
+                let data = std::fs::read(path).map_err(|err| IssueError::AttachmentFile {
+                    name: name.to_owned(),
+                    path: path.display().to_string(),
+                    details: err.to_string(),
+                })?;
+                AssignedState::Attachment(AttachState {
+                    id: attach_id(&data),
+                    media_type: media_type.clone(),
+                })
+            }
+        })
+    }
+}
+
+// Strict values are constructed from the YAML data model, which is a superset
+// of the JSON one; thus the conversion is lossless and doesn't apply any of
+// the YAML-specific parsing rules.
+fn strict_val(val: &serde_json::Value) -> Result<StrictVal, String> {
+    serde_yaml::to_value(val)
+        .map(StrictVal::from)
+        .map_err(|err| err.to_string())
+}
+
+/// Attachments are identified by the SHA256 hash of their content, in the
+/// same way as RGB21 `Attachment::digest`, under which they are provided in
+/// consignments.
+fn attach_id(data: &[u8]) -> AttachId {
+    let digest: [u8; 32] = Sha256::digest(data).into();
+    AttachId::from(digest)
+}
+
+/// Resolver used in validation of just issued contracts, which contain only
+/// genesis and thus don't reference any witness transactions.
+struct GenesisResolver;
+
+impl ResolveWitness for GenesisResolver {
+    fn resolve_pub_witness(
+        &self,
+        witness_id: XWitnessId,
+    ) -> Result<XWitnessTx, WitnessResolverError> {
+        Err(WitnessResolverError::Unknown(witness_id))
+    }
+}
+
+impl<D: DescriptorRgb<K>, K, S: StashProvider, H: StateProvider, P: IndexProvider>
+    Runtime<D, K, S, H, P>
+{
+    /// Issues new contract using schema and interface known to the runtime
+    /// stock. The contract is not added to the stock; this must be done by
+    /// importing it.
+    pub fn issue(
+        &self,
+        schema_id: SchemaId,
+        request: &IssueRequest,
+    ) -> Result<ValidContract, IssueError> {
+        request.issue(self.stock(), schema_id)
+    }
+}
+
+fn field_name(name: &str) -> Result<FieldName, IssueError> {
+    FieldName::try_from(name.to_owned()).map_err(|_| IssueError::InvalidFieldName(name.to_owned()))
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+
+    use rgbstd::schema::{FungibleType, MediaType};
+    use rgbstd::stl::{rgb_contract_stl, StandardTypes};
+
+    use super::*;
+    use crate::test_helpers::TempDir;
+
+    const SEAL: &str =
+        "seal: tapret1st:01d46e52c4bdb51931a0eae83e958c78bdef9cac2057b36d55370410edafdd42:0";
+
+    fn assignment(fields: &str) -> AssignmentRequest {
+        serde_yaml::from_str(&format!("{SEAL}\n{fields}")).unwrap()
+    }
+
+    fn types() -> TypeSystem { StandardTypes::with(rgb_contract_stl()).type_system() }
+
+    #[test]
+    fn repeated_assignments() {
+        let seal = SEAL.trim_start_matches("seal: ");
+        let listed = format!(
+            "interface: RGB20\nassignments:\n  owner:\n    - {SEAL}\n      amount: 1\n    - \
+             {seal}: 2\n"
+        );
+        let repeated = format!(
+            "interface: RGB20\nassignment:\n  - owner:\n      {SEAL}\n      amount: 1\n  - \
+             owner:\n      {seal}: 2\n"
+        );
+        let listed: IssueRequest = serde_yaml::from_str(&listed).unwrap();
+        let repeated: IssueRequest = serde_yaml::from_str(&repeated).unwrap();
+        assert_eq!(listed, repeated);
+        assert_eq!(listed.assignments["owner"], vec![
+            assignment("amount: 1"),
+            assignment("amount: 2")
+        ]);
+    }
+
+    #[test]
+    fn rights() {
+        let state = assignment("")
+            .state("right", &StateSchema::Declarative, &types())
+            .unwrap();
+        assert_eq!(state, AssignedState::Rights);
+    }
+
+    #[test]
+    fn fungible() {
+        let schema = StateSchema::Fungible(FungibleType::Unsigned64Bit);
+        let state = assignment("amount: 100")
+            .state("owner", &schema, &types())
+            .unwrap();
+        assert_eq!(state, AssignedState::Fungible(100));
+        assert!(matches!(
+            assignment("").state("owner", &schema, &types()),
+            Err(IssueError::NoAmount(name)) if name == "owner"
+        ));
+    }
+
+    #[test]
+    fn data() {
+        let std_types = StandardTypes::with(rgb_contract_stl());
+        let schema = StateSchema::Structured(std_types.get("RGBContract.Amount"));
+        let types = std_types.type_system();
+
+        let AssignedState::Data(data) = assignment("data: 100")
+            .state("data", &schema, &types)
+            .unwrap()
+        else {
+            panic!("structured state expected")
+        };
+        assert_eq!(data.as_slice(), &100u64.to_le_bytes());
+        assert!(matches!(
+            assignment("").state("data", &schema, &types),
+            Err(IssueError::NoData(name)) if name == "data"
+        ));
+        assert!(matches!(
+            assignment("data: text").state("data", &schema, &types),
+            Err(IssueError::InvalidData { name, .. }) if name == "data"
+        ));
+    }
+
+    #[test]
+    fn attachment() {
+        let schema = StateSchema::Attachment(MediaType::Any);
+        let dir = TempDir::new("issue-attachment");
+        let path = dir.join("attachment");
+        fs::write(&path, b"attached data").unwrap();
+
+        let state = assignment(&format!("file: {}", path.display()))
+            .state("file", &schema, &types())
+            .unwrap();
+        let digest: [u8; 32] = Sha256::digest(b"attached data").into();
+        assert_eq!(
+            state,
+            AssignedState::Attachment(AttachState {
+                id: AttachId::from(digest),
+                media_type: MediaType::Any,
+            })
+        );
+        assert!(matches!(
+            assignment("").state("file", &schema, &types()),
+            Err(IssueError::NoFile(name)) if name == "file"
+        ));
+
+        fs::remove_file(&path).unwrap();
+        assert!(matches!(
+            assignment(&format!("file: {}", path.display())).state("file", &schema, &types()),
+            Err(IssueError::AttachmentFile { name, .. }) if name == "file"
+        ));
+    }
+}
@@ -0,0 +1,238 @@
+// RGB wallet library for smart contracts on Bitcoin & Lightning network
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+// Written in 2019-2024 by
+//     Dr Maxim Orlovsky <orlovsky@lnp-bp.org>
+//
+// Copyright (C) 2019-2024 LNP/BP Standards Association. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Crash-safe saving of the stock files.
+//!
+//! Saving is done in two phases. First, the stock is written into a pending
+//! directory next to the existing stock files; an interruption at this phase
+//! leaves the existing stock untouched, and the pending data are discarded on
+//! the next load. Second, the journal file is atomically created, which marks
+//! the pending data as committed, and the pending files are moved over the
+//! existing ones. If this phase gets interrupted, the move is completed
+//! (rolled forward) on the next load.
+//!
+//! The journal covers only the stock files. The wallet is saved by
+//! [`crate::Runtime::store`] after the journal is committed, and its files are
+//! written in place by the bitcoin wallet: an interruption while they are
+//! written may leave them partially updated. Of the wallet data, only tapret
+//! tweaks are derived from the stock, and thus restored when the wallet gets
+//! attached to it.
+
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::Path;
+
+use rgbstd::persistence::fs::StoreFs;
+
+use crate::RuntimeError;
+
+/// Name of the journal file marking committed, but not yet applied, stock.
+pub const STORE_JOURNAL: &str = "store.journal";
+const STORE_JOURNAL_TMP: &str = "store.journal.tmp";
+const PENDING_DIR: &str = "stock.pending";
+
+/// Writes stock into the pending directory, without touching the existing
+/// stock files.
+pub(crate) fn prepare(stock: &impl StoreFs, stock_path: &Path) -> Result<(), RuntimeError> {
+    let pending = stock_path.join(PENDING_DIR);
+    if pending.exists() {
+        fs::remove_dir_all(&pending)?;
+    }
+    fs::create_dir_all(&pending)?;
+    stock.store(&pending)?;
+    for entry in fs::read_dir(&pending)? {
+        File::open(entry?.path())?.sync_all()?;
+    }
+    sync_dir(&pending);
+    Ok(())
+}
+
+/// Atomically creates the journal, after which the pending stock is
+/// considered saved.
+pub(crate) fn commit(stock_path: &Path) -> Result<(), RuntimeError> {
+    let tmp = stock_path.join(STORE_JOURNAL_TMP);
+    let mut file = File::create(&tmp)?;
+    writeln!(file, "{PENDING_DIR}")?;
+    file.sync_all()?;
+    fs::rename(&tmp, stock_path.join(STORE_JOURNAL))?;
+    sync_dir(stock_path);
+    Ok(())
+}
+
+/// Moves committed pending stock files over the existing ones and removes
+/// the journal.
+pub(crate) fn apply(stock_path: &Path) -> Result<(), RuntimeError> {
+    let pending = stock_path.join(PENDING_DIR);
+    // The pending directory is removed only after all of its files were
+    // moved, thus its absence means that the interruption happened right
+    // before the journal removal
+    if pending.exists() {
+        for entry in fs::read_dir(&pending)? {
+            let entry = entry?;
+            fs::rename(entry.path(), stock_path.join(entry.file_name()))?;
+        }
+        sync_dir(stock_path);
+        fs::remove_dir(&pending)?;
+    }
+    fs::remove_file(stock_path.join(STORE_JOURNAL))?;
+    sync_dir(stock_path);
+    Ok(())
+}
+
+/// Checks whether saving of the stock was interrupted, requiring
+/// [`recover`] to be run before the stock is loaded.
+pub(crate) fn is_pending(stock_path: &Path) -> bool {
+    stock_path.join(STORE_JOURNAL).exists() ||
+        stock_path.join(STORE_JOURNAL_TMP).exists() ||
+        stock_path.join(PENDING_DIR).exists()
+}
+
+/// Completes saving interrupted after the commit, or discards the data of
+/// saving interrupted before it.
+pub(crate) fn recover(stock_path: &Path) -> Result<(), RuntimeError> {
+    if stock_path.join(STORE_JOURNAL).is_file() {
+        #[cfg(feature = "log")]
+        eprint!("completing interrupted stock saving ... ");
+        return apply(stock_path);
+    }
+    let pending = stock_path.join(PENDING_DIR);
+    if pending.exists() {
+        #[cfg(feature = "log")]
+        eprint!("discarding incomplete stock saving ... ");
+        fs::remove_dir_all(pending)?;
+    }
+    match fs::remove_file(stock_path.join(STORE_JOURNAL_TMP)) {
+        Err(err) if err.kind() != io::ErrorKind::NotFound => Err(err.into()),
+        _ => Ok(()),
+    }
+}
+
+/// Saves stock in a crash-safe way.
+pub(crate) fn store(stock: &impl StoreFs, stock_path: &Path) -> Result<(), RuntimeError> {
+    prepare(stock, stock_path)?;
+    commit(stock_path)?;
+    apply(stock_path)
+}
+
+// Directories can't be opened for syncing on some platforms (like Windows),
+// where renames are durable on their own; thus the errors are ignored.
+fn sync_dir(path: &Path) { let _ = File::open(path).and_then(|dir| dir.sync_all()); }
+
+#[cfg(test)]
+mod test {
+    use std::str::FromStr;
+
+    use bp::seals::txout::CloseMethod;
+    use bp::{Txid, Vout};
+    use rgbstd::contract::GraphSeal;
+    use rgbstd::persistence::fs::LoadFs;
+    use rgbstd::persistence::Stock;
+    use rgbstd::XChain;
+
+    use super::*;
+    use crate::test_helpers::TempDir;
+
+    fn stock_dir(name: &str) -> TempDir {
+        let dir = TempDir::new(&format!("journal-{name}"));
+        let stock: Stock = Stock::default();
+        store(&stock, &dir).unwrap();
+        dir
+    }
+
+    fn updated_stock() -> Stock {
+        let mut stock = Stock::default();
+        let txid =
+            Txid::from_str("01d46e52c4bdb51931a0eae83e958c78bdef9cac2057b36d55370410edafdd42")
+                .unwrap();
+        let seal = GraphSeal::new_random(CloseMethod::TapretFirst, txid, Vout::from_u32(0));
+        stock.store_secret_seal(XChain::Bitcoin(seal)).unwrap();
+        stock
+    }
+
+    fn seals(dir: &Path) -> usize {
+        let stock: Stock = Stock::load(dir).unwrap();
+        stock.as_stash_provider().debug_secret_seals().len()
+    }
+
+    #[test]
+    fn crash_before_commit() {
+        let dir = stock_dir("prepare");
+        prepare(&updated_stock(), &dir).unwrap();
+        assert!(is_pending(&dir));
+        recover(&dir).unwrap();
+        assert!(!is_pending(&dir));
+        assert_eq!(seals(&dir), 0);
+    }
+
+    #[test]
+    fn crash_after_commit() {
+        let dir = stock_dir("commit");
+        prepare(&updated_stock(), &dir).unwrap();
+        commit(&dir).unwrap();
+        recover(&dir).unwrap();
+        assert!(!dir.join(STORE_JOURNAL).exists());
+        assert_eq!(seals(&dir), 1);
+    }
+
+    #[test]
+    fn crash_during_apply() {
+        let dir = stock_dir("apply");
+        prepare(&updated_stock(), &dir).unwrap();
+        commit(&dir).unwrap();
+        // Only some of the files were moved when the process was interrupted
+        let entry = fs::read_dir(dir.join(PENDING_DIR))
+            .unwrap()
+            .next()
+            .unwrap()
+            .unwrap();
+        fs::rename(entry.path(), dir.join(entry.file_name())).unwrap();
+        recover(&dir).unwrap();
+        assert!(!dir.join(PENDING_DIR).exists());
+        assert_eq!(seals(&dir), 1);
+    }
+
+    #[test]
+    fn crash_after_pending_removed() {
+        let dir = stock_dir("removed");
+        prepare(&updated_stock(), &dir).unwrap();
+        commit(&dir).unwrap();
+        // All the files were moved, but the journal wasn't removed
+        for entry in fs::read_dir(dir.join(PENDING_DIR)).unwrap() {
+            let entry = entry.unwrap();
+            fs::rename(entry.path(), dir.join(entry.file_name())).unwrap();
+        }
+        fs::remove_dir(dir.join(PENDING_DIR)).unwrap();
+        assert!(is_pending(&dir));
+        recover(&dir).unwrap();
+        assert!(!is_pending(&dir));
+        assert_eq!(seals(&dir), 1);
+    }
+
+    #[test]
+    fn crash_during_journal_write() {
+        let dir = stock_dir("journal");
+        prepare(&updated_stock(), &dir).unwrap();
+        fs::write(dir.join(STORE_JOURNAL_TMP), "").unwrap();
+        recover(&dir).unwrap();
+        assert!(!dir.join(STORE_JOURNAL_TMP).exists());
+        assert_eq!(seals(&dir), 0);
+    }
+}
@@ -31,10 +31,39 @@ extern crate serde_with;
 mod runtime;
 mod descriptor;
 mod pay;
+mod coinselect;
+mod fee;
+mod history;
+#[cfg(feature = "serde")]
+mod issue;
+#[cfg(feature = "serde")]
+mod journal;
+mod lock;
 mod resolvers;
+#[cfg(feature = "sqlite")]
+mod sqlite;
+#[cfg(test)]
+mod test_helpers;
 
-pub use descriptor::{DescriptorRgb, RgbDescr, RgbKeychain, TapTweakAlreadyAssigned, TapretKey};
-pub use pay::{CompletionError, CompositionError, PayError, TransferParams};
-#[cfg(any(feature = "electrum", feature = "esplora_blocking"))]
+pub use coinselect::{StateCandidate, StateSelection, StateSelector, BNB_MAX_TRIES};
+pub use descriptor::{
+    DescriptorRgb, RgbDescr, RgbKeychain, TapTweakAlreadyAssigned, TapretKey, TapretTweakError,
+    UnsupportedDescriptor,
+};
+pub use fee::{estimate_vsize, FeeRate};
+pub use history::{
+    HistoryEntry, HistoryFilter, HistoryState, OpDirection, RgbTxOp, RgbTxRow, StateKind,
+};
+#[cfg(feature = "serde")]
+pub use issue::{AssignmentRequest, IssueError, IssueRequest};
+#[cfg(feature = "serde")]
+pub use journal::STORE_JOURNAL;
+pub use lock::{LockMode, StockLock, LOCK_FILE};
+pub use pay::{
+    BumpError, Clock, CompletionError, CompositionError, PayError, SystemClock, TransferParams,
+    DUST_LIMIT, FEE_RATE_MAX_ATTEMPTS, TRANSFERS_DIR,
+};
 pub use resolvers::*;
-pub use runtime::{ContractOutpointsFilter, Runtime, RuntimeError};
+pub use runtime::{ContractOutpointsFilter, PersistedStock, Runtime, RuntimeError};
+#[cfg(feature = "sqlite")]
+pub use sqlite::{SqliteError, SqliteProvider, SqliteStock, SqliteStore, SqliteTable, SQLITE_FILE};
@@ -0,0 +1,248 @@
+// RGB wallet library for smart contracts on Bitcoin & Lightning network
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+// Written in 2019-2024 by
+//     Dr Maxim Orlovsky <orlovsky@lnp-bp.org>
+//
+// Copyright (C) 2019-2024 LNP/BP Standards Association. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Advisory locking of the stock directory.
+//!
+//! Locks are taken on a dedicated file inside the stock directory and protect
+//! against concurrent access by other processes. Within a single process the
+//! locks are reentrant: while the process holds an exclusive lock, any further
+//! lock on the same stock succeeds immediately, and so do shared locks while
+//! it holds a shared one.
+
+use std::collections::BTreeMap;
+use std::fs::{self, File, OpenOptions};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use fs2::FileExt;
+
+use crate::RuntimeError;
+
+/// Name of the lock file created inside the stock directory.
+pub const LOCK_FILE: &str = "stock.lock";
+
+const LOCK_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+#[derive(Copy, Clone, Eq, PartialEq, Hash, Debug, Display)]
+#[display(lowercase)]
+pub enum LockMode {
+    /// Lock allowing other processes to read, but not to write the stock.
+    Shared,
+    /// Lock preventing other processes from accessing the stock.
+    Exclusive,
+}
+
+struct HeldLock {
+    mode: LockMode,
+    count: usize,
+    // The lock is released when the file gets closed
+    _file: File,
+}
+
+static HELD_LOCKS: Mutex<BTreeMap<PathBuf, HeldLock>> = Mutex::new(BTreeMap::new());
+
+/// Advisory lock on the stock directory, which is released on drop.
+///
+/// The lock is owned by the process rather than by the handle: locking the
+/// stock already locked by the same process succeeds immediately, returning a
+/// handle with the mode of the held lock, and the lock is released only when
+/// the last of the handles is dropped. Thus the lock doesn't exclude the code
+/// of the same process holding other handles on the stock.
+#[derive(Debug)]
+pub struct StockLock {
+    path: PathBuf,
+    mode: LockMode,
+}
+
+impl StockLock {
+    /// Locks stock located in `stock_path` directory. If the stock is locked
+    /// by some other process, waits for the lock to be released for up to the
+    /// `timeout`, or fails immediately if no timeout is given.
+    pub fn acquire(
+        stock_path: impl AsRef<Path>,
+        mode: LockMode,
+        timeout: Option<Duration>,
+    ) -> Result<Self, RuntimeError> {
+        let stock_path = stock_path.as_ref();
+        fs::create_dir_all(stock_path)?;
+        let path = stock_path.canonicalize()?;
+
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .open(path.join(LOCK_FILE))?;
+        let start = Instant::now();
+        loop {
+            // The registry must not be held while waiting, since this would block
+            // all other threads of the process taking or releasing their locks
+            {
+                let mut held = HELD_LOCKS.lock().expect("poisoned lock registry");
+                if let Some(lock) = held.get_mut(&path) {
+                    if lock.mode == LockMode::Shared && mode == LockMode::Exclusive {
+                        return Err(RuntimeError::LockUpgrade(path.display().to_string()));
+                    }
+                    lock.count += 1;
+                    return Ok(Self {
+                        path,
+                        mode: lock.mode,
+                    });
+                }
+
+                let res = match mode {
+                    LockMode::Shared => file.try_lock_shared(),
+                    LockMode::Exclusive => file.try_lock_exclusive(),
+                };
+                match res {
+                    Ok(()) => {
+                        held.insert(path.clone(), HeldLock {
+                            mode,
+                            count: 1,
+                            _file: file,
+                        });
+                        return Ok(Self { path, mode });
+                    }
+                    Err(err) if err.kind() == fs2::lock_contended_error().kind() => {}
+                    Err(err) => return Err(err.into()),
+                }
+            }
+            if timeout.map_or(true, |timeout| start.elapsed() >= timeout) {
+                return Err(RuntimeError::Locked(path.display().to_string()));
+            }
+            thread::sleep(LOCK_POLL_INTERVAL);
+        }
+    }
+
+    /// Mode of the lock held by the process, which may be stronger than the
+    /// requested one for reentrant locks.
+    pub fn mode(&self) -> LockMode { self.mode }
+}
+
+impl Drop for StockLock {
+    fn drop(&mut self) {
+        let Ok(mut held) = HELD_LOCKS.lock() else {
+            return;
+        };
+        if let Some(lock) = held.get_mut(&self.path) {
+            lock.count -= 1;
+            if lock.count == 0 {
+                held.remove(&self.path);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_helpers::TempDir;
+
+    fn stock_dir(name: &str) -> TempDir { TempDir::new(&format!("lock-{name}")) }
+
+    // Locks held through a separate file handle behave as locks of another
+    // process
+    fn foreign_lock(dir: &Path) -> File {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .open(dir.join(LOCK_FILE))
+            .unwrap();
+        file.try_lock_exclusive().unwrap();
+        file
+    }
+
+    #[test]
+    fn reentrancy() {
+        let dir = stock_dir("reentrancy");
+        {
+            let lock = StockLock::acquire(&dir, LockMode::Exclusive, None).unwrap();
+            let shared = StockLock::acquire(&dir, LockMode::Shared, None).unwrap();
+            let exclusive = StockLock::acquire(&dir, LockMode::Exclusive, None).unwrap();
+            assert_eq!(lock.mode(), LockMode::Exclusive);
+            assert_eq!(shared.mode(), LockMode::Exclusive);
+            assert_eq!(exclusive.mode(), LockMode::Exclusive);
+            drop(lock);
+            drop(shared);
+            // The lock is still held by the last guard
+            let file = File::open(dir.join(LOCK_FILE)).unwrap();
+            assert!(file.try_lock_shared().is_err());
+        }
+        {
+            let _first = StockLock::acquire(&dir, LockMode::Shared, None).unwrap();
+            let second = StockLock::acquire(&dir, LockMode::Shared, None).unwrap();
+            assert_eq!(second.mode(), LockMode::Shared);
+        }
+        // All guards are dropped, so the lock is released
+        let file = File::open(dir.join(LOCK_FILE)).unwrap();
+        file.try_lock_exclusive().unwrap();
+    }
+
+    #[test]
+    fn upgrade() {
+        let dir = stock_dir("upgrade");
+        let shared = StockLock::acquire(&dir, LockMode::Shared, None).unwrap();
+        assert!(matches!(
+            StockLock::acquire(&dir, LockMode::Exclusive, Some(Duration::from_secs(1))),
+            Err(RuntimeError::LockUpgrade(_))
+        ));
+        drop(shared);
+        StockLock::acquire(&dir, LockMode::Exclusive, None).unwrap();
+    }
+
+    #[test]
+    fn contention() {
+        let dir = stock_dir("contention");
+        let other = stock_dir("contention-other");
+        let foreign = foreign_lock(&dir);
+
+        assert!(matches!(
+            StockLock::acquire(&dir, LockMode::Shared, None),
+            Err(RuntimeError::Locked(_))
+        ));
+        let start = Instant::now();
+        assert!(matches!(
+            StockLock::acquire(&dir, LockMode::Exclusive, Some(Duration::from_millis(300))),
+            Err(RuntimeError::Locked(_))
+        ));
+        assert!(start.elapsed() >= Duration::from_millis(300));
+
+        // Waiting for a lock doesn't block other threads of the process
+        let waiting = {
+            let dir = dir.to_path_buf();
+            thread::spawn(move || {
+                StockLock::acquire(&dir, LockMode::Exclusive, Some(Duration::from_secs(10)))
+                    .map(|lock| lock.mode())
+                    .ok()
+            })
+        };
+        thread::sleep(Duration::from_millis(200));
+        let start = Instant::now();
+        drop(StockLock::acquire(&other, LockMode::Exclusive, None).unwrap());
+        assert!(start.elapsed() < Duration::from_secs(1));
+
+        // The waiting thread gets the lock once it is released
+        drop(foreign);
+        assert_eq!(waiting.join().unwrap(), Some(LockMode::Exclusive));
+    }
+}
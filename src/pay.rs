@@ -20,26 +20,47 @@
 // limitations under the License.
 
 use std::collections::{BTreeMap, BTreeSet};
+use std::error::Error;
+use std::fs::{self, File};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::{fmt, io, slice};
 
 use bp::dbc::tapret::TapretProof;
 use bp::seals::txout::{CloseMethod, ExplicitSeal};
-use bp::{Outpoint, Sats, ScriptPubkey, Vout};
-use bpstd::Address;
-use bpwallet::{Beneficiary as BpBeneficiary, ConstructionError, PsbtMeta, TxParams};
-use psbt::{CommitError, EmbedError, Psbt, RgbPsbt, TapretKeyError};
+use bp::{Outpoint, Sats, ScriptPubkey, Txid, Vout};
+use bpstd::{Address, Network};
+use bpwallet::{Beneficiary as BpBeneficiary, ConstructionError, PsbtMeta, TxParams, TxStatus};
+use psbt::{
+    CommitError, EmbedError, ExtractError, Psbt, PsbtVer, RgbExt, RgbOutExt, RgbPsbt,
+    TapretKeyError,
+};
 use rgbstd::containers::Transfer;
 use rgbstd::interface::ContractError;
-use rgbstd::invoice::{Amount, Beneficiary, InvoiceState, RgbInvoice};
+use rgbstd::invoice::{Amount, Beneficiary, ChainNet, InvoiceState, NonFungible, RgbInvoice};
 use rgbstd::persistence::{
-    ComposeError, ConsignError, ContractIfaceError, FasciaError, StockError, StockErrorAll,
-    StockErrorMem,
+    IndexProvider, StashProvider, StashReadProvider, StateProvider, StockError,
 };
-use rgbstd::XChain;
+use rgbstd::{AssignmentWitness, DataState, Layer1, XChain, XOutputSeal, XWitnessId};
 
 use crate::{
-    ContractOutpointsFilter, DescriptorRgb, RgbKeychain, Runtime, TapTweakAlreadyAssigned,
+    estimate_vsize, ContractOutpointsFilter, DescriptorRgb, FeeRate, RgbKeychain, Runtime,
+    RuntimeError, StateCandidate, StateSelection, StateSelector, TapTweakAlreadyAssigned,
+    TapretTweakError,
 };
 
+/// Maximal number of attempts to construct a PSBT matching the requested fee
+/// rate.
+pub const FEE_RATE_MAX_ATTEMPTS: usize = 4;
+
+/// Minimal amount of the change output which remains after the fee bumping.
+pub const DUST_LIMIT: Sats = Sats(546);
+
+/// Name of the directory inside the stock directory, where PSBTs and invoices
+/// of the transfers saved with [`Runtime::save_transfer`] are kept, such that
+/// their witness transactions can be replaced with [`Runtime::bump_fee`].
+pub const TRANSFERS_DIR: &str = "transfers";
+
 #[derive(Debug, Display, Error, From)]
 #[display(inner)]
 pub enum PayError {
@@ -53,6 +74,9 @@ pub enum PayError {
 #[derive(Debug, Display, Error, From)]
 #[display(doc_comments)]
 pub enum CompositionError {
+    /// no invoices to pay were provided.
+    NoInvoices,
+
     /// unspecified contract.
     NoContract,
 
@@ -74,13 +98,32 @@ pub enum CompositionError {
     /// the invoice has expired.
     InvoiceExpired,
 
+    /// the invoice is issued for {invoice} network, while the wallet operates
+    /// on {wallet}.
+    NetworkMismatch { invoice: ChainNet, wallet: Network },
+
+    /// the wallet is unable to spend state on {0}, since it operates on a
+    /// different layer 1.
+    UnsupportedLayer1(Layer1),
+
+    /// unable to construct transaction paying the fee rate of {0} sat/vB.
+    FeeRateUnreachable(FeeRate),
+
     /// one of the RGB assignments spent require presence of tapret output -
     /// even this is not a taproot wallet. Unable to create a valid PSBT, manual
     /// work is needed.
     TapretRequired,
 
-    /// non-fungible state is not yet supported by the invoices.
-    Unsupported,
+    /// the constructed transaction pays several invoices to the same address
+    /// with a single output, while each of them requires a separate one.
+    MergedBeneficiaries,
+
+    /// the wallet doesn't own state matching the non-fungible allocation
+    /// requested by the invoice.
+    NoMatchingState,
+
+    /// the invoice requests {0}, which is not yet supported for payments.
+    Unsupported(&'static str),
 
     #[from]
     #[display(inner)]
@@ -94,12 +137,15 @@ pub enum CompositionError {
     #[display(inner)]
     Embed(EmbedError),
 
-    #[from]
-    #[from(StockError)]
-    #[from(StockErrorMem<ComposeError>)]
-    #[from(StockErrorMem<ContractIfaceError>)]
-    #[display(inner)]
-    Stock(StockErrorAll),
+    /// stock error: {0}
+    Stock(Box<dyn Error + Send + Sync>),
+}
+
+impl<S: StashProvider, H: StateProvider, P: IndexProvider, E: Error> From<StockError<S, H, P, E>>
+    for CompositionError
+where StockError<S, H, P, E>: Error + Send + Sync + 'static
+{
+    fn from(err: StockError<S, H, P, E>) -> Self { Self::Stock(Box::new(err)) }
 }
 
 #[derive(Debug, Display, Error, From)]
@@ -115,8 +161,9 @@ pub enum CompletionError {
     InconclusiveDerivation,
 
     #[from]
+    #[from(TapTweakAlreadyAssigned)]
     #[display(inner)]
-    MultipleTweaks(TapTweakAlreadyAssigned),
+    TapretTweak(TapretTweakError),
 
     #[from]
     #[display(inner)]
@@ -126,16 +173,89 @@ pub enum CompletionError {
     #[display(inner)]
     Commit(CommitError),
 
-    #[from(StockErrorMem<ConsignError>)]
-    #[from(StockErrorMem<FasciaError>)]
+    /// unable to save transfer data: {0}
+    #[from]
+    Io(io::Error),
+
+    /// stock error: {0}
+    Stock(Box<dyn Error + Send + Sync>),
+}
+
+impl<S: StashProvider, H: StateProvider, P: IndexProvider, E: Error> From<StockError<S, H, P, E>>
+    for CompletionError
+where StockError<S, H, P, E>: Error + Send + Sync + 'static
+{
+    fn from(err: StockError<S, H, P, E>) -> Self { Self::Stock(Box::new(err)) }
+}
+
+/// Source of the current time used to check invoice expiration.
+pub trait Clock {
+    /// Returns current time as a UNIX timestamp (in seconds).
+    fn now(&self) -> i64;
+}
+
+/// Clock using the system time.
+#[derive(Copy, Clone, Eq, PartialEq, Hash, Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> i64 { chrono::Utc::now().timestamp() }
+}
+
+#[derive(Debug, Display, Error, From)]
+#[display(doc_comments)]
+pub enum BumpError {
+    /// transfer with witness transaction {0} is not known to the wallet.
+    UnknownTransfer(Txid),
+
+    /// saved data of the transfer with witness transaction {0} are invalid.
+    InvalidTransfer(Txid),
+
+    /// witness transaction {0} is already mined and can't be replaced.
+    AlreadyMined(Txid),
+
+    /// witness transaction {0} doesn't signal replaceability (BIP-125).
+    NotReplaceable(Txid),
+
+    /// the PSBT doesn't provide information about the previous outputs, so the
+    /// fee paid by the original transaction is unknown.
+    UnknownFee,
+
+    /// the PSBT doesn't contain a change output which can pay the additional
+    /// fee.
+    NoChangeOutput,
+
+    /// the change output lacks {0} to pay the new fee without dropping below the
+    /// dust limit.
+    InsufficientChange(Sats),
+
+    #[from]
     #[display(inner)]
-    Stock(StockErrorAll),
+    Extract(ExtractError),
+
+    #[from]
+    #[display(inner)]
+    Completion(CompletionError),
+
+    #[from]
+    #[display(inner)]
+    Io(io::Error),
+
+    #[from]
+    #[display(inner)]
+    PsbtDecode(psbt::DecodeError),
 }
 
-#[derive(Clone, PartialEq, Debug)]
+#[derive(Clone)]
 pub struct TransferParams {
     pub tx: TxParams,
     pub min_amount: Sats,
+    /// Algorithm selecting fungible state to spend; defaults to
+    /// [`StateSelection::LargestFirst`].
+    pub selection: Arc<dyn StateSelector + Send + Sync>,
+    /// Fee rate the witness transaction must pay. If present, the fee
+    /// specified in [`TxParams`] is used as the minimal fee.
+    pub fee_rate: Option<FeeRate>,
 }
 
 impl TransferParams {
@@ -143,11 +263,40 @@ impl TransferParams {
         TransferParams {
             tx: TxParams::with(fee),
             min_amount,
+            selection: Arc::new(StateSelection::default()),
+            fee_rate: None,
         }
     }
+
+    pub fn with_fee_rate(fee_rate: FeeRate, min_amount: Sats) -> Self {
+        TransferParams {
+            tx: TxParams::with(Sats::ZERO),
+            min_amount,
+            selection: Arc::new(StateSelection::default()),
+            fee_rate: Some(fee_rate),
+        }
+    }
+
+    /// Replaces the algorithm used to select fungible state to spend.
+    pub fn with_selector(mut self, selector: impl StateSelector + Send + Sync + 'static) -> Self {
+        self.selection = Arc::new(selector);
+        self
+    }
 }
 
-impl Runtime {
+impl fmt::Debug for TransferParams {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TransferParams")
+            .field("tx", &self.tx)
+            .field("min_amount", &self.min_amount)
+            .field("fee_rate", &self.fee_rate)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<D: DescriptorRgb<K>, K, S: StashProvider, H: StateProvider, P: IndexProvider>
+    Runtime<D, K, S, H, P>
+{
     #[allow(clippy::result_large_err)]
     pub fn pay(
         &mut self,
@@ -161,97 +310,119 @@ impl Runtime {
         Ok((psbt, meta, transfer))
     }
 
+    /// Pays multiple invoices, which may be issued by different beneficiaries
+    /// and for different contracts, with a single witness transaction.
+    ///
+    /// Returns transfer consignments in the same order as the invoices.
+    #[allow(clippy::result_large_err)]
+    pub fn pay_batch(
+        &mut self,
+        invoices: &[RgbInvoice],
+        method: CloseMethod,
+        params: TransferParams,
+    ) -> Result<(Psbt, PsbtMeta, Vec<Transfer>), PayError> {
+        let (mut psbt, meta) = self.construct_psbt_batch(invoices, method, params)?;
+        // ... here we pass PSBT around signers, if necessary
+        let transfers = self.transfer_batch(invoices, &mut psbt)?;
+        Ok((psbt, meta, transfers))
+    }
+
+    /// Checks that the invoice can be paid by this wallet: it is issued for
+    /// the same layer 1 and network the wallet operates on and hasn't expired
+    /// yet.
+    #[allow(clippy::result_large_err)]
+    pub fn check_invoice(&self, invoice: &RgbInvoice) -> Result<(), CompositionError> {
+        let wallet = self.network();
+        let chain_net = invoice.beneficiary.chain_network();
+        let expected = match (self.layer1(), wallet) {
+            (Layer1::Liquid, Network::Mainnet) => ChainNet::LiquidMainnet,
+            (Layer1::Liquid, _) => ChainNet::LiquidTestnet,
+            (_, Network::Mainnet) => ChainNet::BitcoinMainnet,
+            (_, Network::Testnet3) => ChainNet::BitcoinTestnet,
+            (_, Network::Signet) => ChainNet::BitcoinSignet,
+            (_, Network::Regtest) => ChainNet::BitcoinRegtest,
+        };
+        if chain_net != expected {
+            return Err(CompositionError::NetworkMismatch {
+                invoice: chain_net,
+                wallet,
+            });
+        }
+        if matches!(invoice.expiry, Some(expiry) if expiry <= self.clock().now()) {
+            return Err(CompositionError::InvoiceExpired);
+        }
+        Ok(())
+    }
+
     #[allow(clippy::result_large_err)]
     pub fn construct_psbt(
         &mut self,
         invoice: &RgbInvoice,
         method: CloseMethod,
-        mut params: TransferParams,
+        params: TransferParams,
     ) -> Result<(Psbt, PsbtMeta), CompositionError> {
-        let contract_id = invoice.contract.ok_or(CompositionError::NoContract)?;
+        self.construct_psbt_batch(slice::from_ref(invoice), method, params)
+    }
 
-        let iface_name = invoice.iface.clone().ok_or(CompositionError::NoIface)?;
-        let iface = self.stock().iface(iface_name.clone())?;
-        let contract = self.contract_iface(contract_id, iface_name)?;
-        let operation = invoice
-            .operation
-            .as_ref()
-            .or(iface.default_operation.as_ref())
-            .ok_or(CompositionError::NoOperation)?;
+    #[allow(clippy::result_large_err)]
+    pub fn construct_psbt_batch(
+        &mut self,
+        invoices: &[RgbInvoice],
+        method: CloseMethod,
+        params: TransferParams,
+    ) -> Result<(Psbt, PsbtMeta), CompositionError> {
+        let Some(fee_rate) = params.fee_rate else {
+            return self.construct_psbt_with_fee(invoices, method, params);
+        };
+        fit_fee_rate(fee_rate, params.tx.fee, |fee| {
+            let mut params = params.clone();
+            params.tx.fee = fee;
+            self.construct_psbt_with_fee(invoices, method, params)
+        })?
+        .ok_or(CompositionError::FeeRateUnreachable(fee_rate))
+    }
 
-        let assignment_name = invoice
-            .assignment
-            .as_ref()
-            .or_else(|| {
-                iface
-                    .transitions
-                    .get(operation)
-                    .and_then(|t| t.default_assignment.as_ref())
-            })
-            .cloned()
-            .ok_or(CompositionError::NoAssignment)?;
+    #[allow(clippy::result_large_err)]
+    fn construct_psbt_with_fee(
+        &mut self,
+        invoices: &[RgbInvoice],
+        method: CloseMethod,
+        mut params: TransferParams,
+    ) -> Result<(Psbt, PsbtMeta), CompositionError> {
+        if invoices.is_empty() {
+            return Err(CompositionError::NoInvoices);
+        }
 
-        let prev_outputs = match invoice.owned_state {
-            InvoiceState::Amount(amount) => {
-                let filter = ContractOutpointsFilter {
-                    contract_id,
-                    filter: self,
-                };
-                let state: BTreeMap<_, Vec<Amount>> = contract
-                    .fungible(assignment_name, &filter)?
-                    .fold(bmap![], |mut set, a| {
-                        set.entry(a.seal).or_default().push(a.state);
-                        set
-                    });
-                let mut state: Vec<_> = state
-                    .into_iter()
-                    .map(|(seal, vals)| (vals.iter().copied().sum::<Amount>(), seal, vals))
-                    .collect();
-                state.sort_by_key(|(sum, _, _)| *sum);
-                let mut sum = Amount::ZERO;
-                state
-                    .iter()
-                    .rev()
-                    .take_while(|(val, _, _)| {
-                        if sum >= amount {
-                            false
-                        } else {
-                            sum += *val;
-                            true
-                        }
-                    })
-                    .map(|(_, seal, _)| *seal)
-                    .collect::<BTreeSet<_>>()
-            }
-            _ => return Err(CompositionError::Unsupported),
-        };
-        let beneficiaries = match invoice.beneficiary.into_inner() {
-            Beneficiary::BlindedSeal(_) => vec![],
-            Beneficiary::WitnessVout(payload) => {
-                vec![BpBeneficiary::new(
+        // Seals selected for one of the invoices can't be re-used by the others
+        let mut spent = BTreeSet::new();
+        let mut selections = Vec::with_capacity(invoices.len());
+        let mut beneficiaries = vec![];
+        for invoice in invoices {
+            self.check_invoice(invoice)?;
+            let prev_outputs = self.select_state(invoice, &spent, params.selection.as_ref())?;
+            spent.extend(prev_outputs.iter().copied());
+            selections.push(prev_outputs);
+            if let Beneficiary::WitnessVout(payload) = invoice.beneficiary.into_inner() {
+                beneficiaries.push(BpBeneficiary::new(
                     Address::new(payload, invoice.address_network()),
                     params.min_amount,
-                )]
+                ));
             }
-        };
-        let prev_outpoints = prev_outputs
-            .iter()
-            // TODO: Support liquid
-            .map(|o| o.as_reduced_unsafe())
-            .map(|o| Outpoint::new(o.txid, o.vout));
+        }
+
+        let layer1 = self.layer1();
+        let prev_outpoints = witness_prevouts(layer1, &spent)?;
         params.tx.change_keychain = RgbKeychain::for_method(method).into();
         let (mut psbt, mut meta) =
             self.wallet_mut()
                 .construct_psbt(prev_outpoints, &beneficiaries, params.tx)?;
 
-        let beneficiary_script =
-            if let Beneficiary::WitnessVout(addr) = invoice.beneficiary.into_inner() {
-                Some(addr.script_pubkey())
-            } else {
-                None
-            };
+        let beneficiary_scripts = beneficiaries
+            .iter()
+            .map(|beneficiary| beneficiary.address.script_pubkey())
+            .collect::<BTreeSet<_>>();
         psbt.outputs_mut()
-            .find(|o| o.script.is_p2tr() && Some(&o.script) != beneficiary_script.as_ref())
+            .find(|o| o.script.is_p2tr() && !beneficiary_scripts.contains(&o.script))
             .map(|o| o.set_tapret_host().expect("just created"));
         // TODO: Add descriptor id to the tapret host data
 
@@ -262,48 +433,159 @@ impl Runtime {
         psbt.sort_outputs_by(|output| !output.is_tapret_host())
             .expect("PSBT must be modifiable at this stage");
         if let Some(change_script) = change_script {
-            for output in psbt.outputs() {
+            for output in psbt.outputs_mut() {
                 if output.script == change_script {
                     meta.change_vout = Some(output.vout());
+                    // Used to pay additional fee when the transfer gets replaced
+                    output.set_rgb_change();
                     break;
                 }
             }
         }
 
-        let beneficiary_vout = match invoice.beneficiary.into_inner() {
-            Beneficiary::WitnessVout(addr) => {
-                let s = addr.script_pubkey();
-                let vout = psbt
-                    .outputs()
-                    .find(|output| output.script == s)
-                    .map(psbt::Output::vout)
-                    .expect("PSBT without beneficiary address");
-                debug_assert_ne!(Some(vout), meta.change_vout);
-                Some(vout)
-            }
-            Beneficiary::BlindedSeal(_) => None,
-        };
-        let batch = self
-            .compose(invoice, prev_outputs, method, beneficiary_vout, |_, _, _| meta.change_vout)?;
+        let beneficiary_vouts =
+            beneficiary_vouts(invoices, &psbt).ok_or(CompositionError::MergedBeneficiaries)?;
+        let mut batches = Vec::with_capacity(invoices.len());
+        for ((invoice, prev_outputs), beneficiary_vout) in
+            invoices.iter().zip(selections).zip(beneficiary_vouts)
+        {
+            debug_assert!(beneficiary_vout.is_none() || beneficiary_vout != meta.change_vout);
+            let batch = self.compose(invoice, prev_outputs, method, beneficiary_vout, |_, _, _| {
+                meta.change_vout
+            })?;
+            batches.push(batch);
+        }
 
-        let methods = batch.close_method_set();
+        let methods = batches
+            .iter()
+            .map(|batch| batch.close_method_set())
+            .reduce(|methods, method| methods | method)
+            .expect("at least one invoice is always present");
         if methods.has_opret_first() {
             let output = psbt.construct_output_expect(ScriptPubkey::op_return(&[]), Sats::ZERO);
             output.set_opret_host().expect("just created");
         }
 
         psbt.complete_construction();
-        psbt.rgb_embed(batch)?;
+        psbt.set_rgb_layer1(layer1);
+        for batch in batches {
+            psbt.rgb_embed(batch)?;
+        }
         Ok((psbt, meta))
     }
 
+    /// Selects seals holding the state required to pay the invoice, skipping
+    /// the seals from the `exclude` set.
+    #[allow(clippy::result_large_err)]
+    fn select_state(
+        &self,
+        invoice: &RgbInvoice,
+        exclude: &BTreeSet<XOutputSeal>,
+        selection: &dyn StateSelector,
+    ) -> Result<BTreeSet<XOutputSeal>, CompositionError> {
+        let contract_id = invoice.contract.ok_or(CompositionError::NoContract)?;
+
+        let iface_name = invoice.iface.clone().ok_or(CompositionError::NoIface)?;
+        let iface = self.stock().iface(iface_name.clone())?;
+        let contract = self.contract_iface(contract_id, iface_name)?;
+        let operation = invoice
+            .operation
+            .as_ref()
+            .or(iface.default_operation.as_ref())
+            .ok_or(CompositionError::NoOperation)?;
+
+        let assignment_name = invoice
+            .assignment
+            .as_ref()
+            .or_else(|| {
+                iface
+                    .transitions
+                    .get(operation)
+                    .and_then(|t| t.default_assignment.as_ref())
+            })
+            .cloned()
+            .ok_or(CompositionError::NoAssignment)?;
+
+        let filter = ContractOutpointsFilter {
+            contract_id,
+            filter: self,
+        };
+        match invoice.owned_state {
+            InvoiceState::Amount(amount) => {
+                let state: BTreeMap<_, Vec<_>> = contract
+                    .fungible(assignment_name, &filter)?
+                    .filter(|a| !exclude.contains(&a.seal))
+                    .fold(bmap![], |mut set, a| {
+                        set.entry(a.seal).or_default().push((a.state, a.witness));
+                        set
+                    });
+                let candidates = state
+                    .into_iter()
+                    .map(|(seal, vals)| StateCandidate {
+                        seal,
+                        amount: vals.iter().map(|(amount, _)| *amount).sum::<Amount>(),
+                        height: vals
+                            .first()
+                            .and_then(|(_, witness)| self.witness_height(*witness)),
+                    })
+                    .collect();
+                selection
+                    .select_state(candidates, amount)
+                    .ok_or(CompositionError::InsufficientState)
+            }
+            InvoiceState::Data(NonFungible::RGB21(allocation)) => {
+                let data = DataState::from(allocation);
+                // Non-fungible allocations can't be split or merged, so we need exactly
+                // the seal which holds the requested allocation
+                let seal = contract
+                    .data(assignment_name, &filter)?
+                    .find(|a| a.state == data && !exclude.contains(&a.seal))
+                    .map(|a| a.seal)
+                    .ok_or(CompositionError::NoMatchingState)?;
+                Ok(bset![seal])
+            }
+            InvoiceState::Void => Err(CompositionError::Unsupported("rights (void state)")),
+            InvoiceState::Attach(_) => Err(CompositionError::Unsupported("attachment state")),
+        }
+    }
+
+    fn witness_height(&self, witness: AssignmentWitness) -> Option<u32> {
+        match witness {
+            AssignmentWitness::Absent => Some(0),
+            AssignmentWitness::Present(
+                id @ (XWitnessId::Bitcoin(txid) | XWitnessId::Liquid(txid)),
+            ) if id.layer1() == self.layer1() => {
+                match self.wallet().transactions().get(&txid)?.status {
+                    TxStatus::Mined(info) => Some(info.height.get()),
+                    _ => None,
+                }
+            }
+            AssignmentWitness::Present(_) => None,
+        }
+    }
+
     #[allow(clippy::result_large_err)]
     pub fn transfer(
         &mut self,
         invoice: &RgbInvoice,
         psbt: &mut Psbt,
     ) -> Result<Transfer, CompletionError> {
-        let contract_id = invoice.contract.ok_or(CompletionError::NoContract)?;
+        let mut transfers = self.transfer_batch(slice::from_ref(invoice), psbt)?;
+        Ok(transfers.remove(0))
+    }
+
+    /// Commits to the state transitions paying the invoices, which were
+    /// embedded into the PSBT, and produces transfer consignment for each of
+    /// the invoices (in the same order as the invoices).
+    #[allow(clippy::result_large_err)]
+    pub fn transfer_batch(
+        &mut self,
+        invoices: &[RgbInvoice],
+        psbt: &mut Psbt,
+    ) -> Result<Vec<Transfer>, CompletionError> {
+        if invoices.iter().any(|invoice| invoice.contract.is_none()) {
+            return Err(CompletionError::NoContract);
+        }
 
         let fascia = psbt.rgb_commit()?;
         if fascia.anchor.has_tapret() {
@@ -318,28 +600,693 @@ impl Runtime {
                 .add_tapret_tweak(terminal, tapret_commitment)?;
         }
 
+        self.stock_mut().consume_fascia(fascia)?;
+        self.consign_batch(invoices, psbt)
+    }
+
+    /// Re-creates witness transaction of a not yet mined transfer, paying a
+    /// higher fee rate, using replace-by-fee.
+    ///
+    /// The transfer is looked up by its witness transaction id among the
+    /// transfers saved with [`Runtime::save_transfer`]. The original
+    /// transaction must signal replaceability and must not be mined yet. The
+    /// new transaction spends the same inputs and commits to the same state
+    /// transitions: the additional fee is paid from the change output recorded
+    /// during the construction of the transfer, so the transitions (which refer
+    /// to the witness outputs by their number) remain valid.
+    ///
+    /// Returns unsigned PSBT for the replacement transaction and new transfer
+    /// consignments for each of the invoices paid by the original
+    /// transaction. The replacement transfer is saved in place of the
+    /// original one, such that it can be replaced again.
+    #[allow(clippy::result_large_err)]
+    pub fn bump_fee(
+        &mut self,
+        witness_txid: Txid,
+        fee_rate: FeeRate,
+    ) -> Result<(Psbt, Vec<Transfer>), BumpError> {
+        if matches!(
+            self.wallet().transactions().get(&witness_txid).map(|tx| &tx.status),
+            Some(TxStatus::Mined(_))
+        ) {
+            return Err(BumpError::AlreadyMined(witness_txid));
+        }
+        let (invoices, mut psbt) = self.load_transfer(witness_txid)?;
+        replace_witness(&mut psbt, fee_rate)?;
+
+        // The bundles get anchored to both transactions, which is supported by the
+        // stock index: after one of the transactions gets mined the other one
+        // can't be mined anymore and is dropped by the wallet, and the runtime
+        // filters include only the outputs and witnesses known to the wallet.
+        let fascia = psbt.rgb_extract()?;
+        self.stock_mut()
+            .consume_fascia(fascia)
+            .map_err(CompletionError::from)?;
+        self.save_transfer(&invoices, &psbt)?;
+        self.remove_transfer(witness_txid)?;
+        let transfers = self.consign_batch(&invoices, &psbt)?;
+        Ok((psbt, transfers))
+    }
+
+    /// Saves invoices and PSBT of the transfer, which are required to replace
+    /// its witness transaction with [`Runtime::bump_fee`] and to restore its
+    /// tapret tweak with [`Runtime::restore_tapret_tweaks`]. Transfers are not
+    /// saved unless this method is called.
+    pub fn save_transfer(&self, invoices: &[RgbInvoice], psbt: &Psbt) -> Result<(), io::Error> {
+        let dir = self.stock_path().join(TRANSFERS_DIR);
+        fs::create_dir_all(&dir)?;
+        let txid = psbt.txid();
+        let mut file = File::create(dir.join(format!("{txid}.psbt")))?;
+        psbt.encode(PsbtVer::V2, &mut file)?;
+        let invoices = invoices
+            .iter()
+            .map(RgbInvoice::to_string)
+            .collect::<Vec<_>>()
+            .join("\n");
+        fs::write(dir.join(format!("{txid}.invoices")), invoices)
+    }
+
+    fn load_transfer(&self, txid: Txid) -> Result<(Vec<RgbInvoice>, Psbt), BumpError> {
+        let dir = self.stock_path().join(TRANSFERS_DIR);
+        let psbt_path = dir.join(format!("{txid}.psbt"));
+        if !psbt_path.is_file() {
+            return Err(BumpError::UnknownTransfer(txid));
+        }
+        let psbt = Psbt::decode(&mut File::open(psbt_path)?)?;
+        let invoices = fs::read_to_string(dir.join(format!("{txid}.invoices")))?
+            .lines()
+            .map(RgbInvoice::from_str)
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|_| BumpError::InvalidTransfer(txid))?;
+        if psbt.txid() != txid || invoices.is_empty() {
+            return Err(BumpError::InvalidTransfer(txid));
+        }
+        Ok((invoices, psbt))
+    }
+
+    fn remove_transfer(&self, txid: Txid) -> Result<(), io::Error> {
+        let dir = self.stock_path().join(TRANSFERS_DIR);
+        fs::remove_file(dir.join(format!("{txid}.psbt")))?;
+        fs::remove_file(dir.join(format!("{txid}.invoices")))
+    }
+
+    /// Restores tapret tweaks of the transfers saved with
+    /// [`Runtime::save_transfer`], which are missing from the wallet if the
+    /// process was interrupted after the stock with the transfer was saved,
+    /// but before the wallet was (see [`Runtime::store`]). Only tweaks of the
+    /// witness transactions known to the stash are restored.
+    ///
+    /// Returns number of the restored tweaks.
+    pub fn restore_tapret_tweaks(&mut self) -> Result<usize, RuntimeError> {
+        let dir = self.stock_path().join(TRANSFERS_DIR);
+        if !dir.is_dir() {
+            return Ok(0);
+        }
+        let taprets = self
+            .stock()
+            .as_stash_provider()
+            .taprets()
+            .map_err(StockError::<S, H, P>::from)?
+            .collect::<Vec<_>>();
+
+        let mut restored = 0usize;
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            if path.extension().map_or(true, |ext| ext != "psbt") {
+                continue;
+            }
+            let psbt = Psbt::decode(&mut File::open(path)?)?;
+            let Some(output) = psbt.dbc_output::<TapretProof>() else {
+                continue;
+            };
+            let Some(terminal) = output.terminal_derivation() else {
+                continue;
+            };
+            let commitment = output.tapret_commitment()?;
+            let witness_id = XWitnessId::with(self.layer1(), psbt.txid());
+            if !taprets.contains(&(witness_id, commitment)) {
+                continue;
+            }
+            match self.wallet_mut().add_tapret_tweak(terminal, commitment) {
+                Ok(()) => restored += 1,
+                Err(TapretTweakError::AlreadyAssigned(_)) => {}
+                Err(err) => return Err(err.into()),
+            }
+        }
+        Ok(restored)
+    }
+
+    #[allow(clippy::result_large_err)]
+    fn consign_batch(
+        &self,
+        invoices: &[RgbInvoice],
+        psbt: &Psbt,
+    ) -> Result<Vec<Transfer>, CompletionError> {
         let witness_txid = psbt.txid();
-        let (beneficiary1, beneficiary2) = match invoice.beneficiary.into_inner() {
-            Beneficiary::WitnessVout(addr) => {
-                let s = addr.script_pubkey();
-                let vout = psbt
-                    .outputs()
-                    .position(|output| output.script == s)
-                    .ok_or(CompletionError::NoBeneficiaryOutput)?;
-                let vout = Vout::from_u32(vout as u32);
-                let method = self.wallet().seal_close_method();
-                let seal =
-                    XChain::Bitcoin(ExplicitSeal::new(method, Outpoint::new(witness_txid, vout)));
-                (vec![], vec![seal])
+        let layer1 = psbt.rgb_layer1().map_err(CommitError::from)?;
+        let beneficiary_vouts =
+            beneficiary_vouts(invoices, psbt).ok_or(CompletionError::NoBeneficiaryOutput)?;
+        let mut transfers = Vec::with_capacity(invoices.len());
+        for (invoice, beneficiary_vout) in invoices.iter().zip(beneficiary_vouts) {
+            let contract_id = invoice.contract.ok_or(CompletionError::NoContract)?;
+            let (beneficiary1, beneficiary2) = match invoice.beneficiary.into_inner() {
+                Beneficiary::WitnessVout(_) => {
+                    let vout = beneficiary_vout.expect("witness vout beneficiary always has vout");
+                    let method = self.wallet().seal_close_method();
+                    let seal = XChain::with(
+                        layer1,
+                        ExplicitSeal::new(method, Outpoint::new(witness_txid, vout)),
+                    );
+                    (vec![], vec![seal])
+                }
+                Beneficiary::BlindedSeal(seal) => (vec![XChain::with(layer1, seal)], vec![]),
+            };
+            let transfer = self
+                .stock()
+                .transfer(contract_id, beneficiary2, beneficiary1)?;
+            transfers.push(transfer);
+        }
+
+        Ok(transfers)
+    }
+}
+
+/// Returns outpoints of the seals spent by the witness transaction, which is
+/// constructed on the wallet layer 1 and thus can spend only seals defined on
+/// the same layer 1.
+#[allow(clippy::result_large_err)]
+fn witness_prevouts(
+    layer1: Layer1,
+    seals: &BTreeSet<XOutputSeal>,
+) -> Result<Vec<Outpoint>, CompositionError> {
+    seals
+        .iter()
+        .map(|seal| match seal {
+            XChain::Bitcoin(inner) | XChain::Liquid(inner) if seal.layer1() == layer1 => {
+                Ok(Outpoint::new(inner.txid, inner.vout))
             }
-            Beneficiary::BlindedSeal(seal) => (vec![XChain::Bitcoin(seal)], vec![]),
+            other => Err(CompositionError::UnsupportedLayer1(other.layer1())),
+        })
+        .collect()
+}
+
+/// Finds PSBT outputs paying to the witness vout beneficiaries of the invoices
+/// (returning `None` for the invoices with blinded seals). Invoices paying to
+/// the same address are matched to distinct outputs, in the order of the
+/// invoices. Returns `None` if some of the invoices can't be matched.
+fn beneficiary_vouts(invoices: &[RgbInvoice], psbt: &Psbt) -> Option<Vec<Option<Vout>>> {
+    let mut used = BTreeSet::new();
+    let mut vouts = Vec::with_capacity(invoices.len());
+    for invoice in invoices {
+        let Beneficiary::WitnessVout(addr) = invoice.beneficiary.into_inner() else {
+            vouts.push(None);
+            continue;
         };
+        let s = addr.script_pubkey();
+        let vout = psbt
+            .outputs()
+            .find(|output| output.script == s && !used.contains(&output.vout()))
+            .map(psbt::Output::vout)?;
+        used.insert(vout);
+        vouts.push(Some(vout));
+    }
+    Some(vouts)
+}
 
-        self.stock_mut().consume_fascia(fascia)?;
-        let transfer = self
-            .stock()
-            .transfer(contract_id, beneficiary2, beneficiary1)?;
+/// Takes the fee `delta` from the change amount, unless the remaining change
+/// would be below [`DUST_LIMIT`].
+fn reduce_change(change: Sats, delta: Sats) -> Option<Sats> {
+    change
+        .checked_sub(delta)
+        .filter(|amount| *amount >= DUST_LIMIT)
+}
+
+/// Modifies PSBT of a not mined witness transaction, which must be already
+/// committed, such that it replaces the original transaction paying the given
+/// fee rate. The additional fee is paid from the RGB change output.
+fn replace_witness(psbt: &mut Psbt, fee_rate: FeeRate) -> Result<(), BumpError> {
+    let rbf = psbt.inputs().any(|input| {
+        input
+            .sequence_number
+            .map_or(false, |seq_no| seq_no.to_consensus_u32() < 0xFFFF_FFFE)
+    });
+    if !rbf {
+        return Err(BumpError::NotReplaceable(psbt.txid()));
+    }
+
+    let prev_fee = psbt.fee().ok_or(BumpError::UnknownFee)?;
+    let vsize = estimate_vsize(psbt);
+    // BIP-125 requires replacement to pay for its own relay in addition to the
+    // fee of the replaced transaction
+    let fee = fee_rate
+        .fee_for(vsize)
+        .max(prev_fee + FeeRate::from_sat_per_vb(1).fee_for(vsize));
+    let delta = fee - prev_fee;
+
+    let change = psbt
+        .outputs_mut()
+        .find(|output| output.is_rgb_change())
+        .ok_or(BumpError::NoChangeOutput)?;
+    let amount = change.amount;
+    change.amount = reduce_change(amount, delta)
+        .ok_or_else(|| BumpError::InsufficientChange(delta + DUST_LIMIT - amount))?;
+
+    // Signatures for the original transaction are invalid for the replacement
+    for input in psbt.inputs_mut() {
+        input.partial_sigs.clear();
+        input.tap_key_sig = None;
+        input.final_script_sig = None;
+        input.final_witness = None;
+    }
+    Ok(())
+}
+
+/// Constructs PSBT paying at least the given fee rate with the `construct`
+/// closure, which creates a PSBT paying the provided absolute fee.
+///
+/// The final size of the transaction is known only after the coin selection
+/// and addition of the commitment host outputs. Each construction may derive
+/// a new change address, so the missing fee is taken from the change output of
+/// the constructed transaction (which doesn't alter its size), and the
+/// transaction is re-constructed only if there is not enough change. Returns
+/// `None` if the fee rate is not reached after [`FEE_RATE_MAX_ATTEMPTS`].
+fn fit_fee_rate<E>(
+    fee_rate: FeeRate,
+    mut fee: Sats,
+    mut construct: impl FnMut(Sats) -> Result<(Psbt, PsbtMeta), E>,
+) -> Result<Option<(Psbt, PsbtMeta)>, E> {
+    for _ in 0..FEE_RATE_MAX_ATTEMPTS {
+        let (mut psbt, meta) = construct(fee)?;
+        let required = fee_rate.fee_for(estimate_vsize(&psbt));
+        if required <= fee {
+            return Ok(Some((psbt, meta)));
+        }
+        let change = meta
+            .change_vout
+            .and_then(|vout| psbt.outputs_mut().nth(vout.to_usize()));
+        if let Some(change) = change {
+            if let Some(amount) = reduce_change(change.amount, required - fee) {
+                change.amount = amount;
+                return Ok(Some((psbt, meta)));
+            }
+        }
+        fee = required;
+    }
+    Ok(None)
+}
+
+#[cfg(test)]
+mod test {
+    use std::convert::Infallible;
+
+    use amplify::hex::FromHex;
+    use bp::{ConsensusDecode, SeqNo, Tx, TxOut};
+    use psbt::{RgbInExt, UnsignedTx};
+    use rgbstd::containers::UniversalFile;
+    use rgbstd::interface::{AmountChange, OutpointFilter, WitnessFilter};
+    use rgbstd::invoice::{RgbInvoiceBuilder, XChainNet};
+    use rgbstd::persistence::{MemIndex, MemStash, MemState, Stock};
+    use rgbstd::{ContractId, Operation, Transition, XOutpoint};
+    use strict_types::encoding::{FieldName, StrictDumb, TypeName};
+
+    use super::*;
+    use crate::offline;
+    use crate::test_helpers::TempDir;
+
+    // Unsigned transaction spending a single output with a single P2WPKH output
+    const TX: &str = "02000000011111111111111111111111111111111111111111111111111111111111111111\
+                      0000000000fdffffff0100000000000000001600142222222222222222222222222222222222\
+                      22222200000000";
+    // P2WPKH output spent by the transaction
+    const PREVOUT: &str = "a0860100000000001600143333333333333333333333333333333333333333";
+
+    // Virtual size of the transaction: (82 * 4 + 110) / 4, rounded up
+    const VSIZE: u64 = 110;
+    const FEE_RATE: FeeRate = FeeRate::from_sat_per_vb(10);
+
+    // Unsigned replaceable transaction with a P2WPKH change output and an
+    // OP_RETURN output
+    const RBF_TX: &str = "02000000011111111111111111111111111111111111111111111111111111111111111111\
+                          0000000000fdffffff02000000000000000016001422222222222222222222222222222222\
+                          222222220000000000000000016a00000000";
+
+    // Constructs PSBT with the output being a change of the given amount
+    fn psbt(change: u64) -> (Psbt, PsbtMeta) {
+        let tx = Tx::consensus_deserialize(Vec::<u8>::from_hex(TX).unwrap()).unwrap();
+        let prevout = TxOut::consensus_deserialize(Vec::<u8>::from_hex(PREVOUT).unwrap()).unwrap();
+        let mut psbt = Psbt::from_tx(UnsignedTx::with_sigs_removed(tx));
+        psbt.inputs_mut().next().unwrap().witness_utxo = Some(prevout);
+        psbt.outputs_mut().next().unwrap().amount = Sats::from_sats(change);
+        let meta = PsbtMeta {
+            change_vout: Some(Vout::from_u32(0)),
+            change_terminal: None,
+        };
+        (psbt, meta)
+    }
+
+    // Committed transfer paying 1000 sats of fee with 99000 sats of change
+    fn committed_psbt() -> Psbt {
+        let tx = Tx::consensus_deserialize(Vec::<u8>::from_hex(RBF_TX).unwrap()).unwrap();
+        let prevout = TxOut::consensus_deserialize(Vec::<u8>::from_hex(PREVOUT).unwrap()).unwrap();
+        let mut psbt = Psbt::from_tx(UnsignedTx::with_sigs_removed(tx));
+        psbt.inputs_mut().next().unwrap().witness_utxo = Some(prevout);
+        let mut outputs = psbt.outputs_mut();
+        let change = outputs.next().unwrap();
+        change.amount = Sats::from_sats(99_000u64);
+        change.set_rgb_change();
+        outputs.next().unwrap().set_opret_host().unwrap();
+        psbt.complete_construction();
+
+        let transition = Transition::strict_dumb();
+        let (contract_id, opid) = (transition.contract_id, transition.id());
+        psbt.inputs_mut()
+            .next()
+            .unwrap()
+            .set_rgb_consumer(contract_id, opid)
+            .unwrap();
+        psbt.push_rgb_transition(transition, CloseMethod::OpretFirst)
+            .unwrap();
+        psbt.rgb_commit().unwrap();
+        psbt
+    }
+
+    fn change(psbt: &Psbt) -> Sats { psbt.outputs().next().unwrap().amount }
+
+    // Amount paid by the transfer of the demo asset, which has 100000000 units
+    // allocated by its genesis
+    const AMOUNT: u64 = 1000;
+    // Unsigned replaceable transaction spending the genesis seal of the demo
+    // contract, with beneficiary, change and OP_RETURN outputs
+    const TRANSFER_TX: &str = "0200000001b18bede3a8756d57dea5aabc0fb590512ff2e42ec2671112ee25eb7134\
+                               1113070100000000fdffffff03000000000000000016001422222222222222222222\
+                               22222222222222222222000000000000000016001444444444444444444444444444\
+                               444444444444440000000000000000016a00000000";
+    // Address of the beneficiary output of the transfer transaction
+    const BENEFICIARY: &str = "tb1qyg3zyg3zyg3zyg3zyg3zyg3zyg3zyg3zjuhu9x";
+
+    type MemStock = Stock<MemStash, MemState, MemIndex>;
+
+    // Filter including all outputs
+    struct AllOutputs;
+
+    impl OutpointFilter for AllOutputs {
+        fn include_outpoint(&self, _: impl Into<XOutpoint>) -> bool { true }
+    }
+
+    // Outputs and transactions known to the wallet, which drive the runtime
+    // filters: the witness of a replaced transfer gets archived by dropping
+    // the replaced transaction from the wallet.
+    struct WalletView {
+        outpoints: BTreeSet<XOutpoint>,
+        witnesses: BTreeSet<XWitnessId>,
+    }
+
+    impl WalletView {
+        fn with(outpoints: impl IntoIterator<Item = Outpoint>, witness_txid: Txid) -> Self {
+            WalletView {
+                outpoints: outpoints.into_iter().map(XChain::Bitcoin).collect(),
+                witnesses: bset![XWitnessId::Bitcoin(witness_txid)],
+            }
+        }
+    }
+
+    impl OutpointFilter for WalletView {
+        fn include_outpoint(&self, output: impl Into<XOutpoint>) -> bool {
+            self.outpoints.contains(&output.into())
+        }
+    }
+
+    impl WitnessFilter for WalletView {
+        fn include_witness(&self, witness: impl Into<AssignmentWitness>) -> bool {
+            match witness.into() {
+                AssignmentWitness::Present(witness_id) => self.witnesses.contains(&witness_id),
+                AssignmentWitness::Absent => false,
+            }
+        }
+    }
+
+    // Stock with the demo contract, together with its id and genesis seal
+    fn demo_stock() -> (MemStock, ContractId, XOutputSeal) {
+        let dir = TempDir::new("pay-demo-stock");
+        let mut resolver = offline::Resolver::load(&dir).unwrap();
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/examples/rgb20-demo.rgb");
+        let UniversalFile::Contract(contract) = UniversalFile::load_file(path).unwrap() else {
+            panic!("contract consignment expected")
+        };
+        let contract_id = contract.contract_id();
+        let testnet = contract.genesis.testnet;
+        let contract = contract
+            .validate(&mut resolver, testnet)
+            .map_err(|(status, _)| status)
+            .unwrap();
+        let mut stock = MemStock::default();
+        stock.import_contract(contract, &mut resolver).unwrap();
+
+        let seals = stock
+            .contract_iface(contract_id, TypeName::from("RGB20"))
+            .unwrap()
+            .fungible(FieldName::from("assetOwner"), &AllOutputs)
+            .unwrap()
+            .map(|a| a.seal)
+            .collect::<Vec<_>>();
+        assert_eq!(seals.len(), 1);
+        (stock, contract_id, seals[0])
+    }
+
+    fn balance(stock: &MemStock, contract_id: ContractId, view: &WalletView) -> Amount {
+        stock
+            .contract_iface(contract_id, TypeName::from("RGB20"))
+            .unwrap()
+            .fungible(FieldName::from("assetOwner"), view)
+            .unwrap()
+            .map(|a| a.state)
+            .sum()
+    }
+
+    fn history(
+        stock: &MemStock,
+        contract_id: ContractId,
+        view: &WalletView,
+    ) -> BTreeMap<XWitnessId, AmountChange> {
+        stock
+            .contract_iface(contract_id, TypeName::from("RGB20"))
+            .unwrap()
+            .fungible_ops::<AmountChange>(FieldName::from("assetOwner"), view, view)
+            .unwrap()
+            .into_iter()
+            .map(|(witness_id, op)| (witness_id, op.state_change))
+            .collect()
+    }
+
+    #[test]
+    fn vsize() {
+        let (psbt, _) = psbt(10_000);
+        assert_eq!(estimate_vsize(&psbt), VSIZE);
+        assert_eq!(FEE_RATE.fee_for(VSIZE), Sats::from_sats(1100u64));
+    }
+
+    #[test]
+    fn change_reduction() {
+        let delta = Sats::from_sats(1000u64);
+        assert_eq!(reduce_change(Sats::from_sats(5000u64), delta), Some(Sats::from_sats(4000u64)));
+        assert_eq!(reduce_change(Sats::from_sats(1546u64), delta), Some(DUST_LIMIT));
+        assert_eq!(reduce_change(Sats::from_sats(1545u64), delta), None);
+        assert_eq!(reduce_change(Sats::from_sats(999u64), delta), None);
+    }
+
+    #[test]
+    fn fee_sufficient() {
+        let mut fees = vec![];
+        let (psbt, _) = fit_fee_rate(FEE_RATE, Sats::from_sats(1100u64), |fee| {
+            fees.push(fee);
+            Ok::<_, Infallible>(psbt(5000))
+        })
+        .unwrap()
+        .unwrap();
+        assert_eq!(fees, vec![Sats::from_sats(1100u64)]);
+        assert_eq!(change(&psbt), Sats::from_sats(5000u64));
+    }
+
+    #[test]
+    fn fee_paid_from_change() {
+        let mut fees = vec![];
+        let (psbt, _) = fit_fee_rate(FEE_RATE, Sats::from_sats(100u64), |fee| {
+            fees.push(fee);
+            Ok::<_, Infallible>(psbt(5000))
+        })
+        .unwrap()
+        .unwrap();
+        assert_eq!(fees, vec![Sats::from_sats(100u64)]);
+        assert_eq!(change(&psbt), Sats::from_sats(4000u64));
+    }
+
+    #[test]
+    fn change_below_dust() {
+        // The change can't pay the missing 1000 sats without dropping below the
+        // dust limit, so the transaction is re-constructed with the full fee
+        let mut fees = vec![];
+        let (psbt, _) = fit_fee_rate(FEE_RATE, Sats::from_sats(100u64), |fee| {
+            fees.push(fee);
+            Ok::<_, Infallible>(psbt(1500 - fee.0))
+        })
+        .unwrap()
+        .unwrap();
+        assert_eq!(fees, vec![Sats::from_sats(100u64), Sats::from_sats(1100u64)]);
+        assert_eq!(change(&psbt), Sats::from_sats(400u64));
+    }
+
+    #[test]
+    fn fee_rate_unreachable() {
+        // Each construction adds data to the transaction without a change
+        let mut attempts = 0usize;
+        let res = fit_fee_rate(FEE_RATE, Sats::ZERO, |_| {
+            attempts += 1;
+            let (mut psbt, _) = psbt(0);
+            psbt.outputs_mut().next().unwrap().script =
+                ScriptPubkey::op_return(&vec![0u8; attempts * 10]);
+            Ok::<_, Infallible>((psbt, PsbtMeta {
+                change_vout: None,
+                change_terminal: None,
+            }))
+        })
+        .unwrap();
+        assert!(res.is_none());
+        assert_eq!(attempts, FEE_RATE_MAX_ATTEMPTS);
+    }
+
+    #[test]
+    fn liquid_prevouts() {
+        let txid =
+            Txid::from_str("1111111111111111111111111111111111111111111111111111111111111111")
+                .unwrap();
+        let seal =
+            |vout: u32| ExplicitSeal::new(CloseMethod::TapretFirst, Outpoint::new(txid, vout));
+        let seals = bset![XChain::Liquid(seal(0)), XChain::Liquid(seal(1))];
+        assert_eq!(witness_prevouts(Layer1::Liquid, &seals).unwrap(), vec![
+            Outpoint::new(txid, 0u32),
+            Outpoint::new(txid, 1u32)
+        ]);
+
+        // Seals on other layer 1 can't be spent by the witness transaction
+        let seals = bset![XChain::Bitcoin(seal(0)), XChain::Liquid(seal(1))];
+        assert!(matches!(
+            witness_prevouts(Layer1::Liquid, &seals),
+            Err(CompositionError::UnsupportedLayer1(Layer1::Bitcoin))
+        ));
+        assert!(matches!(
+            witness_prevouts(Layer1::Bitcoin, &seals),
+            Err(CompositionError::UnsupportedLayer1(Layer1::Liquid))
+        ));
+    }
+
+    #[test]
+    fn bump_recommit() {
+        let mut psbt = committed_psbt();
+        let fascia = psbt.rgb_extract().unwrap();
+
+        // Committed transaction has vsize of (125 * 4 + 110) / 4, rounded up
+        assert_eq!(estimate_vsize(&psbt), 153);
+        replace_witness(&mut psbt, FEE_RATE).unwrap();
+        assert_eq!(psbt.fee(), Some(Sats::from_sats(1530u64)));
+        assert_eq!(change(&psbt), Sats::from_sats(98_470u64));
+
+        let replacement = psbt.rgb_extract().unwrap();
+        assert_ne!(replacement.witness_id, fascia.witness_id);
+        assert_eq!(replacement.bundles, fascia.bundles);
+    }
+
+    #[test]
+    fn bump_min_relay_fee() {
+        // Replacement must pay at least 1 sat/vB in addition to the replaced fee
+        let mut psbt = committed_psbt();
+        replace_witness(&mut psbt, FeeRate::from_sat_per_vb(1)).unwrap();
+        assert_eq!(psbt.fee(), Some(Sats::from_sats(1153u64)));
+    }
+
+    #[test]
+    fn bump_insufficient_change() {
+        // 153000 sats of fee require 152000 sats more, while the change can
+        // provide only 99000 - 546 of them
+        let mut psbt = committed_psbt();
+        assert_eq!(
+            replace_witness(&mut psbt, FeeRate::from_sat_per_vb(1000)).unwrap_err().to_string(),
+            BumpError::InsufficientChange(Sats::from_sats(53_546u64)).to_string()
+        );
+    }
+
+    #[test]
+    fn bump_not_replaceable() {
+        let mut psbt = committed_psbt();
+        psbt.inputs_mut().next().unwrap().sequence_number =
+            Some(SeqNo::from_consensus_u32(0xFFFF_FFFF));
+        let txid = psbt.txid();
+        assert_eq!(
+            replace_witness(&mut psbt, FEE_RATE).unwrap_err().to_string(),
+            BumpError::NotReplaceable(txid).to_string()
+        );
+    }
+
+    #[test]
+    fn bump_archived_witness() {
+        let (mut stock, contract_id, genesis_seal) = demo_stock();
+        let XChain::Bitcoin(seal) = genesis_seal else {
+            panic!("bitcoin genesis seal expected")
+        };
+        let genesis = Outpoint::new(seal.txid, seal.vout);
+
+        let tx = Tx::consensus_deserialize(Vec::<u8>::from_hex(TRANSFER_TX).unwrap()).unwrap();
+        let prevout = TxOut::consensus_deserialize(Vec::<u8>::from_hex(PREVOUT).unwrap()).unwrap();
+        let mut psbt = Psbt::from_tx(UnsignedTx::with_sigs_removed(tx));
+        psbt.inputs_mut().next().unwrap().witness_utxo = Some(prevout);
+        let mut outputs = psbt.outputs_mut();
+        outputs.next().unwrap().amount = Sats::from_sats(1000u64);
+        let change = outputs.next().unwrap();
+        change.amount = Sats::from_sats(98_000u64);
+        change.set_rgb_change();
+        outputs.next().unwrap().set_opret_host().unwrap();
+        psbt.complete_construction();
+
+        let beneficiary = Beneficiary::WitnessVout(Address::from_str(BENEFICIARY).unwrap().payload);
+        let invoice = RgbInvoiceBuilder::new(XChainNet::bitcoin(Network::Testnet3, beneficiary))
+            .set_contract(contract_id)
+            .set_interface(TypeName::from("RGB20"))
+            .set_amount_raw(AMOUNT)
+            .finish();
+        let batch = stock
+            .compose(
+                &invoice,
+                bset![genesis_seal],
+                CloseMethod::OpretFirst,
+                Some(Vout::from_u32(0)),
+                |_, _, _| Some(Vout::from_u32(1)),
+            )
+            .unwrap();
+        psbt.rgb_embed(batch).unwrap();
+        let fascia = psbt.rgb_commit().unwrap();
+        let original = psbt.txid();
+        stock.consume_fascia(fascia).unwrap();
+
+        let change = Amount::from(100_000_000 - AMOUNT);
+        let wallet = WalletView::with([Outpoint::new(original, 1u32)], original);
+        assert_eq!(balance(&stock, contract_id, &wallet), change);
+
+        // The same bundles get anchored to the replacement
+        replace_witness(&mut psbt, FEE_RATE).unwrap();
+        let replacement = psbt.txid();
+        stock.consume_fascia(psbt.rgb_extract().unwrap()).unwrap();
+        assert_ne!(replacement, original);
+
+        // The original transaction is dropped from the mempool and the wallet:
+        // its outputs and witness are not known to the wallet anymore
+        let wallet = WalletView::with([Outpoint::new(replacement, 1u32)], replacement);
+        assert_eq!(balance(&stock, contract_id, &wallet), change);
 
-        Ok(transfer)
+        let wallet = WalletView::with([genesis, Outpoint::new(replacement, 1u32)], replacement);
+        let history = history(&stock, contract_id, &wallet);
+        assert_eq!(history.len(), 1);
+        assert!(matches!(
+            &history[&XWitnessId::Bitcoin(replacement)],
+            AmountChange::Dec(amount) if *amount == Amount::from(AMOUNT)
+        ));
     }
 }
@@ -22,12 +22,17 @@
 use rgbstd::containers::Consignment;
 use rgbstd::resolvers::ResolveHeight;
 use rgbstd::validation::{ResolveWitness, WitnessResolverError};
-use rgbstd::{WitnessAnchor, XWitnessId, XWitnessTx};
+use rgbstd::{Layer1, WitnessAnchor, XWitnessId, XWitnessTx};
 
+#[cfg(feature = "bitcoind_rpc")]
+use crate::bitcoind_rpc;
 #[cfg(feature = "electrum")]
 use crate::electrum;
+#[cfg(feature = "esplora_async")]
+use crate::esplora_async;
 #[cfg(feature = "esplora_blocking")]
 use crate::esplora_blocking;
+use crate::{multi, offline, ResolveTip, ResolveWitnessTx, ResolverFailure};
 
 /// Type that contains any of the [`Resolver`] types defined by the library
 #[derive(From)]
@@ -41,6 +46,20 @@ pub enum AnyResolver {
     #[from]
     /// Esplora resolver
     Esplora(Box<esplora_blocking::Resolver>),
+    #[cfg(feature = "esplora_async")]
+    #[from]
+    /// Async esplora resolver
+    EsploraAsync(Box<esplora_async::Resolver>),
+    #[cfg(feature = "bitcoind_rpc")]
+    #[from]
+    /// Bitcoin Core JSON-RPC resolver
+    BitcoindRpc(Box<bitcoind_rpc::Resolver>),
+    #[from]
+    /// Resolver using witness data stored on disk
+    Offline(Box<offline::Resolver>),
+    #[from]
+    /// Several resolvers used for fallback or quorum
+    Multi(Box<multi::MultiResolver>),
 }
 
 #[allow(clippy::large_enum_variant)]
@@ -53,6 +72,14 @@ pub enum AnyResolverError {
     #[cfg(feature = "esplora_blocking")]
     #[display(inner)]
     Esplora(esplora::Error),
+    #[cfg(feature = "esplora_async")]
+    #[display(inner)]
+    EsploraAsync(esplora::Error),
+    #[cfg(feature = "bitcoind_rpc")]
+    #[display(inner)]
+    BitcoindRpc(bitcoind_rpc::ResolverError),
+    #[display(inner)]
+    Offline(offline::ResolverError),
 }
 
 #[allow(clippy::large_enum_variant)]
@@ -67,6 +94,37 @@ pub enum AnyAnchorResolverError {
     #[from]
     #[display(inner)]
     Esplora(esplora_blocking::AnchorResolverError),
+    #[cfg(feature = "esplora_async")]
+    #[from]
+    #[display(inner)]
+    EsploraAsync(esplora_async::AnchorResolverError),
+    #[cfg(feature = "bitcoind_rpc")]
+    #[from]
+    #[display(inner)]
+    BitcoindRpc(bitcoind_rpc::AnchorResolverError),
+    #[from]
+    #[display(inner)]
+    Offline(offline::AnchorResolverError),
+    #[from]
+    #[display(inner)]
+    Multi(multi::AnchorResolverError),
+}
+
+impl ResolverFailure for AnyAnchorResolverError {
+    fn is_transport(&self) -> bool {
+        match self {
+            #[cfg(feature = "electrum")]
+            AnyAnchorResolverError::Electrum(err) => err.is_transport(),
+            #[cfg(feature = "esplora_blocking")]
+            AnyAnchorResolverError::Esplora(err) => err.is_transport(),
+            #[cfg(feature = "esplora_async")]
+            AnyAnchorResolverError::EsploraAsync(err) => err.is_transport(),
+            #[cfg(feature = "bitcoind_rpc")]
+            AnyAnchorResolverError::BitcoindRpc(err) => err.is_transport(),
+            AnyAnchorResolverError::Offline(err) => err.is_transport(),
+            AnyAnchorResolverError::Multi(err) => err.is_transport(),
+        }
+    }
 }
 
 impl AnyResolver {
@@ -76,8 +134,31 @@ impl AnyResolver {
             AnyResolver::Electrum(inner) => inner.add_witnesses(consignment),
             #[cfg(feature = "esplora_blocking")]
             AnyResolver::Esplora(inner) => inner.add_witnesses(consignment),
+            #[cfg(feature = "esplora_async")]
+            AnyResolver::EsploraAsync(inner) => inner.add_witnesses(consignment),
+            #[cfg(feature = "bitcoind_rpc")]
+            AnyResolver::BitcoindRpc(inner) => inner.add_witnesses(consignment),
+            AnyResolver::Offline(inner) => inner.add_witnesses(consignment),
+            AnyResolver::Multi(inner) => inner.add_witnesses(consignment),
         }
     }
+
+    /// Retrieves witness data for the consignment in advance, which is required
+    /// for the async resolvers before the validation. For blocking resolvers
+    /// does nothing.
+    #[cfg(feature = "esplora_async")]
+    pub async fn prefetch<const TYPE: bool>(
+        &mut self,
+        consignment: &Consignment<TYPE>,
+    ) -> Result<(), AnyAnchorResolverError> {
+        match self {
+            AnyResolver::EsploraAsync(inner) => inner.prefetch(consignment).await?,
+            AnyResolver::Multi(inner) => inner.prefetch(consignment).await?,
+            #[allow(unreachable_patterns)]
+            _ => {}
+        }
+        Ok(())
+    }
 }
 
 impl ResolveHeight for AnyResolver {
@@ -89,20 +170,102 @@ impl ResolveHeight for AnyResolver {
             AnyResolver::Electrum(inner) => inner.resolve_height(witness_id).map_err(|e| e.into()),
             #[cfg(feature = "esplora_blocking")]
             AnyResolver::Esplora(inner) => inner.resolve_height(witness_id).map_err(|e| e.into()),
+            #[cfg(feature = "esplora_async")]
+            AnyResolver::EsploraAsync(inner) => {
+                inner.resolve_height(witness_id).map_err(|e| e.into())
+            }
+            #[cfg(feature = "bitcoind_rpc")]
+            AnyResolver::BitcoindRpc(inner) => {
+                inner.resolve_height(witness_id).map_err(|e| e.into())
+            }
+            AnyResolver::Offline(inner) => inner.resolve_height(witness_id).map_err(|e| e.into()),
+            AnyResolver::Multi(inner) => inner.resolve_height(witness_id).map_err(|e| e.into()),
         }
     }
 }
 
-impl ResolveWitness for AnyResolver {
-    fn resolve_pub_witness(
+impl ResolveTip for AnyResolver {
+    fn resolve_tip(&mut self, layer1: Layer1) -> Result<u32, Self::Error> {
+        match self {
+            #[cfg(feature = "electrum")]
+            AnyResolver::Electrum(inner) => inner.resolve_tip(layer1).map_err(|e| e.into()),
+            #[cfg(feature = "esplora_blocking")]
+            AnyResolver::Esplora(inner) => inner.resolve_tip(layer1).map_err(|e| e.into()),
+            #[cfg(feature = "esplora_async")]
+            AnyResolver::EsploraAsync(inner) => inner.resolve_tip(layer1).map_err(|e| e.into()),
+            #[cfg(feature = "bitcoind_rpc")]
+            AnyResolver::BitcoindRpc(inner) => inner.resolve_tip(layer1).map_err(|e| e.into()),
+            AnyResolver::Offline(inner) => inner.resolve_tip(layer1).map_err(|e| e.into()),
+            AnyResolver::Multi(inner) => inner.resolve_tip(layer1).map_err(|e| e.into()),
+        }
+    }
+
+    fn is_indexer(&self) -> bool {
+        match self {
+            #[cfg(feature = "electrum")]
+            AnyResolver::Electrum(inner) => inner.is_indexer(),
+            #[cfg(feature = "esplora_blocking")]
+            AnyResolver::Esplora(inner) => inner.is_indexer(),
+            #[cfg(feature = "esplora_async")]
+            AnyResolver::EsploraAsync(inner) => inner.is_indexer(),
+            #[cfg(feature = "bitcoind_rpc")]
+            AnyResolver::BitcoindRpc(inner) => inner.is_indexer(),
+            AnyResolver::Offline(inner) => inner.is_indexer(),
+            AnyResolver::Multi(inner) => inner.is_indexer(),
+        }
+    }
+
+    fn supports(&self, layer1: Layer1) -> bool {
+        match self {
+            #[cfg(feature = "electrum")]
+            AnyResolver::Electrum(inner) => inner.supports(layer1),
+            #[cfg(feature = "esplora_blocking")]
+            AnyResolver::Esplora(inner) => inner.supports(layer1),
+            #[cfg(feature = "esplora_async")]
+            AnyResolver::EsploraAsync(inner) => inner.supports(layer1),
+            #[cfg(feature = "bitcoind_rpc")]
+            AnyResolver::BitcoindRpc(inner) => inner.supports(layer1),
+            AnyResolver::Offline(inner) => inner.supports(layer1),
+            AnyResolver::Multi(inner) => inner.supports(layer1),
+        }
+    }
+}
+
+impl ResolveWitnessTx for AnyResolver {
+    fn resolve_witness_tx(
         &self,
         witness_id: XWitnessId,
-    ) -> Result<XWitnessTx, WitnessResolverError> {
+    ) -> Result<Option<XWitnessTx>, Self::Error> {
         match self {
             #[cfg(feature = "electrum")]
-            AnyResolver::Electrum(inner) => inner.resolve_pub_witness(witness_id),
+            AnyResolver::Electrum(inner) => {
+                inner.resolve_witness_tx(witness_id).map_err(|e| e.into())
+            }
             #[cfg(feature = "esplora_blocking")]
-            AnyResolver::Esplora(inner) => inner.resolve_pub_witness(witness_id),
+            AnyResolver::Esplora(inner) => {
+                inner.resolve_witness_tx(witness_id).map_err(|e| e.into())
+            }
+            #[cfg(feature = "esplora_async")]
+            AnyResolver::EsploraAsync(inner) => {
+                inner.resolve_witness_tx(witness_id).map_err(|e| e.into())
+            }
+            #[cfg(feature = "bitcoind_rpc")]
+            AnyResolver::BitcoindRpc(inner) => {
+                inner.resolve_witness_tx(witness_id).map_err(|e| e.into())
+            }
+            AnyResolver::Offline(inner) => {
+                inner.resolve_witness_tx(witness_id).map_err(|e| e.into())
+            }
+            AnyResolver::Multi(inner) => inner.resolve_witness_tx(witness_id).map_err(|e| e.into()),
         }
     }
 }
+
+impl ResolveWitness for AnyResolver {
+    fn resolve_pub_witness(
+        &self,
+        witness_id: XWitnessId,
+    ) -> Result<XWitnessTx, WitnessResolverError> {
+        multi::resolve_pub_witness(self, witness_id)
+    }
+}
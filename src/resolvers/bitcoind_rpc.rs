@@ -0,0 +1,482 @@
+// RGB smart contracts for Bitcoin & Lightning
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+// Written in 2019-2023 by
+//     Dr Maxim Orlovsky <orlovsky@lnp-bp.org>
+//
+// Copyright (C) 2019-2023 LNP/BP Standards Association. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::{fs, io};
+
+use amplify::hex::FromHex;
+use amplify::IoError;
+use base64::Engine;
+use bp::ConsensusDecode;
+use bpstd::{Tx, Txid};
+use rgbstd::containers::Consignment;
+use rgbstd::resolvers::ResolveHeight;
+use rgbstd::validation::{ResolveWitness, WitnessResolverError};
+use rgbstd::{Layer1, WitnessAnchor, WitnessOrd, WitnessPos, XChain, XWitnessId, XWitnessTx};
+use serde_json::{json, Value};
+
+use crate::{multi, ResolveTip, ResolveWitnessTx, ResolverFailure};
+
+/// Bitcoin Core RPC error code returned when the transaction is not known.
+const RPC_INVALID_ADDRESS_OR_KEY: i64 = -5;
+
+const HTTP_UNAUTHORIZED: i32 = 401;
+
+/// Authentication method for Bitcoin Core JSON-RPC.
+#[derive(Clone, Eq, PartialEq, Hash, Debug, Default)]
+pub enum Auth {
+    /// No authentication.
+    #[default]
+    None,
+
+    /// Authentication with user name and password.
+    UserPass(String, String),
+
+    /// Authentication using cookie file created by bitcoind.
+    CookieFile(PathBuf),
+}
+
+/// Resolver using Bitcoin Core JSON-RPC.
+///
+/// Requires bitcoind to run with `txindex=1`, since otherwise it is unable to
+/// provide information about transactions not belonging to its own wallet.
+pub struct Resolver {
+    url: String,
+    auth: Auth,
+    // Cookie is re-created on each bitcoind restart, so the header may change
+    auth_header: Mutex<Option<String>>,
+    terminal_txes: HashMap<Txid, Tx>,
+}
+
+#[derive(Debug, Display, Error, From)]
+#[display(doc_comments)]
+pub enum ResolverError {
+    /// unable to read bitcoind cookie file. Details: {0}
+    #[from]
+    #[from(io::Error)]
+    Cookie(IoError),
+
+    /// bitcoind cookie file has invalid format.
+    InvalidCookie,
+
+    /// unable to connect to bitcoind RPC. Details: {0}
+    #[from]
+    Connection(minreq::Error),
+
+    /// bitcoind RPC responded with HTTP status {0}.
+    Http(i32),
+
+    /// bitcoind RPC error {code}: {message}
+    Rpc { code: i64, message: String },
+
+    /// invalid bitcoind RPC response: {0}
+    InvalidResponse(Value),
+}
+
+#[allow(clippy::large_enum_variant)]
+#[derive(Debug, Display, Error, From)]
+#[display(doc_comments)]
+pub enum AnchorResolverError {
+    #[from]
+    #[display(inner)]
+    Error(ResolverError),
+
+    /// bitcoind returned transaction {actual} instead of {expected}.
+    TxidMismatch { expected: Txid, actual: Txid },
+
+    /// unsupported layer 1 {0}
+    UnsupportedLayer1(Layer1),
+}
+
+impl ResolverFailure for AnchorResolverError {
+    fn is_transport(&self) -> bool {
+        matches!(
+            self,
+            AnchorResolverError::Error(
+                ResolverError::Cookie(_) |
+                    ResolverError::InvalidCookie |
+                    ResolverError::Connection(_) |
+                    ResolverError::Http(_)
+            )
+        )
+    }
+}
+
+impl Resolver {
+    #[allow(clippy::result_large_err)]
+    pub fn new(url: &str, auth: Auth) -> Result<Self, ResolverError> {
+        let auth_header = auth_header(&auth)?;
+        Ok(Self {
+            url: url.to_owned(),
+            auth,
+            auth_header: Mutex::new(auth_header),
+            terminal_txes: none!(),
+        })
+    }
+
+    pub fn add_witnesses<const TYPE: bool>(&mut self, consignment: &Consignment<TYPE>) {
+        self.terminal_txes.extend(
+            consignment
+                .bundles
+                .iter()
+                .filter_map(|bw| bw.pub_witness.maybe_map_ref(|w| w.tx.clone()))
+                .filter_map(|tx| match tx {
+                    XChain::Bitcoin(tx) => Some(tx),
+                    XChain::Liquid(_) | XChain::Other(_) => None,
+                })
+                .map(|tx| (tx.txid(), tx)),
+        );
+    }
+
+    #[allow(clippy::result_large_err)]
+    fn call(&self, method: &str, params: Value) -> Result<Value, ResolverError> {
+        let body = json!({
+            "jsonrpc": "1.0",
+            "id": "rgb",
+            "method": method,
+            "params": params,
+        })
+        .to_string();
+        let send = |auth_header: &Option<String>| {
+            let mut request = minreq::post(&self.url)
+                .with_header("Content-Type", "application/json")
+                .with_body(body.clone());
+            if let Some(auth) = auth_header {
+                request = request.with_header("Authorization", auth);
+            }
+            request.send()
+        };
+        let mut header = self.auth_header.lock().expect("poisoned auth header");
+        let mut response = send(&header)?;
+        if response.status_code == HTTP_UNAUTHORIZED && matches!(self.auth, Auth::CookieFile(_)) {
+            // bitcoind was restarted and has created a new cookie
+            *header = auth_header(&self.auth)?;
+            response = send(&header)?;
+        }
+        drop(header);
+        // bitcoind returns RPC errors with HTTP 404 and 500 statuses, but still
+        // provides JSON body with the error details
+        let reply: Value = match response.json() {
+            Ok(reply) => reply,
+            Err(_) if response.status_code != 200 => {
+                return Err(ResolverError::Http(response.status_code));
+            }
+            Err(err) => return Err(err.into()),
+        };
+        match reply.get("error") {
+            None | Some(Value::Null) => {}
+            Some(error) => {
+                return Err(ResolverError::Rpc {
+                    code: error.get("code").and_then(Value::as_i64).unwrap_or_default(),
+                    message: error
+                        .get("message")
+                        .and_then(Value::as_str)
+                        .unwrap_or_default()
+                        .to_owned(),
+                });
+            }
+        }
+        reply
+            .get("result")
+            .cloned()
+            .ok_or(ResolverError::InvalidResponse(reply))
+    }
+
+    /// Returns verbose transaction information, or `None` if the transaction
+    /// is not known to bitcoind.
+    #[allow(clippy::result_large_err)]
+    fn raw_transaction(&self, txid: Txid) -> Result<Option<Value>, ResolverError> {
+        match self.call("getrawtransaction", json!([txid.to_string(), true])) {
+            Ok(tx) => Ok(Some(tx)),
+            Err(ResolverError::Rpc { code, .. }) if code == RPC_INVALID_ADDRESS_OR_KEY => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+}
+
+impl ResolveHeight for Resolver {
+    type Error = AnchorResolverError;
+
+    fn resolve_height(&mut self, witness_id: XWitnessId) -> Result<WitnessAnchor, Self::Error> {
+        let XWitnessId::Bitcoin(txid) = witness_id else {
+            return Err(AnchorResolverError::UnsupportedLayer1(witness_id.layer1()));
+        };
+
+        if self.terminal_txes.contains_key(&txid) {
+            return Ok(WitnessAnchor {
+                witness_ord: WitnessOrd::OffChain,
+                witness_id,
+            });
+        }
+
+        let witness_ord = match self
+            .raw_transaction(txid)?
+            .as_ref()
+            .and_then(|tx| tx.get("blockhash"))
+            .and_then(Value::as_str)
+        {
+            Some(block_hash) => {
+                let header = self.call("getblockheader", json!([block_hash, true]))?;
+                // Blocks which are not part of the active chain (i.e. stale forks)
+                // have -1 confirmations
+                let confirmations = header
+                    .get("confirmations")
+                    .and_then(Value::as_i64)
+                    .ok_or_else(|| ResolverError::InvalidResponse(header.clone()))?;
+                if confirmations < 1 {
+                    return Ok(WitnessAnchor {
+                        witness_ord: WitnessOrd::OffChain,
+                        witness_id,
+                    });
+                }
+                let pos = header
+                    .get("height")
+                    .and_then(Value::as_u64)
+                    .and_then(|height| u32::try_from(height).ok())
+                    .zip(header.get("time").and_then(Value::as_i64))
+                    .and_then(|(height, time)| WitnessPos::new(height, time))
+                    .ok_or(ResolverError::InvalidResponse(header))?;
+                WitnessOrd::OnChain(pos)
+            }
+            None => WitnessOrd::OffChain,
+        };
+
+        Ok(WitnessAnchor {
+            witness_ord,
+            witness_id,
+        })
+    }
+}
+
+impl ResolveTip for Resolver {
+    fn resolve_tip(&mut self, layer1: Layer1) -> Result<u32, Self::Error> {
+        if layer1 != Layer1::Bitcoin {
+            return Err(AnchorResolverError::UnsupportedLayer1(layer1));
+        }
+        let count = self.call("getblockcount", json!([]))?;
+        let height = count
+            .as_u64()
+            .and_then(|height| u32::try_from(height).ok())
+            .ok_or(ResolverError::InvalidResponse(count))?;
+        Ok(height)
+    }
+}
+
+impl ResolveWitnessTx for Resolver {
+    fn resolve_witness_tx(
+        &self,
+        witness_id: XWitnessId,
+    ) -> Result<Option<XWitnessTx>, Self::Error> {
+        let XWitnessId::Bitcoin(txid) = witness_id else {
+            return Err(AnchorResolverError::UnsupportedLayer1(witness_id.layer1()));
+        };
+
+        if let Some(tx) = self.terminal_txes.get(&txid) {
+            return Ok(Some(XWitnessTx::Bitcoin(tx.clone())));
+        }
+
+        let Some(info) = self.raw_transaction(txid)? else {
+            return Ok(None);
+        };
+        let tx = info
+            .get("hex")
+            .and_then(Value::as_str)
+            .and_then(|hex| Vec::<u8>::from_hex(hex).ok())
+            .and_then(|raw_tx| Tx::consensus_deserialize(raw_tx).ok())
+            .ok_or(ResolverError::InvalidResponse(info))?;
+        if tx.txid() != txid {
+            return Err(AnchorResolverError::TxidMismatch {
+                expected: txid,
+                actual: tx.txid(),
+            });
+        }
+        Ok(Some(XWitnessTx::Bitcoin(tx)))
+    }
+}
+
+impl ResolveWitness for Resolver {
+    fn resolve_pub_witness(
+        &self,
+        witness_id: XWitnessId,
+    ) -> Result<XWitnessTx, WitnessResolverError> {
+        multi::resolve_pub_witness(self, witness_id)
+    }
+}
+
+#[allow(clippy::result_large_err)]
+fn auth_header(auth: &Auth) -> Result<Option<String>, ResolverError> {
+    let credentials = match auth {
+        Auth::None => None,
+        Auth::UserPass(user, password) => Some(format!("{user}:{password}")),
+        Auth::CookieFile(path) => Some(read_cookie(path)?),
+    };
+    Ok(credentials.map(|credentials| {
+        format!("Basic {}", base64::engine::general_purpose::STANDARD.encode(credentials))
+    }))
+}
+
+#[allow(clippy::result_large_err)]
+fn read_cookie(path: &Path) -> Result<String, ResolverError> {
+    let cookie = fs::read_to_string(path)?;
+    let cookie = cookie.trim();
+    if !cookie.contains(':') {
+        return Err(ResolverError::InvalidCookie);
+    }
+    Ok(cookie.to_owned())
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+    use std::thread;
+
+    use super::*;
+    use crate::test_helpers::TempDir;
+
+    // Genesis block coinbase transaction
+    const TX_HEX: &str = "010000000100000000000000000000000000000000000000000000000000000000000000\
+                          00ffffffff4d04ffff001d0104455468652054696d65732030332f4a616e2f3230303920\
+                          4368616e63656c6c6f72206f6e206272696e6b206f66207365636f6e64206261696c6f75\
+                          7420666f722062616e6b73ffffffff0100f2052a01000000434104678afdb0fe55482719\
+                          67f1a67130b7105cd6a828e03909a67962e0ea1f61deb649f6bc3f4cef38c4f35504e51e\
+                          c112de5c384df7ba0b8d578a4c702b6bf11d5fac00000000";
+    const TXID: &str = "4a5e1e4baab89f3a32518a88c31bc87f618f76673e2cc77ab2127b7afdeda33b";
+    const BLOCK_HASH: &str = "000000000019d6689c085ae165831e934ff763ae46a2a6c172b3f1b60a8ce26f";
+
+    /// Runs HTTP server answering JSON-RPC requests with the results provided
+    /// by `reply`, and returns its URL. Requests with authorization header
+    /// different from `auth` are rejected with HTTP 401.
+    fn mock_rpc(
+        auth: Option<String>,
+        reply: impl Fn(&str, &Value) -> Value + Send + 'static,
+    ) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let (mut len, mut authorized) = (0usize, auth.is_none());
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    let line = line.trim_end();
+                    if line.is_empty() {
+                        break;
+                    }
+                    let (name, value) = line.split_once(':').unwrap_or((line, ""));
+                    match name.to_lowercase().as_str() {
+                        "content-length" => len = value.trim().parse().unwrap(),
+                        "authorization" => authorized = Some(value.trim()) == auth.as_deref(),
+                        _ => {}
+                    }
+                }
+                let mut body = vec![0u8; len];
+                reader.read_exact(&mut body).unwrap();
+                let (status, body) = if authorized {
+                    let request: Value = serde_json::from_slice(&body).unwrap();
+                    let method = request["method"].as_str().unwrap();
+                    let result = reply(method, &request["params"]);
+                    ("200 OK", json!({ "result": result, "error": null, "id": "rgb" }).to_string())
+                } else {
+                    ("401 Unauthorized", String::new())
+                };
+                write!(
+                    stream,
+                    "HTTP/1.1 {status}\r\nContent-Type: application/json\r\nContent-Length: \
+                     {}\r\nConnection: close\r\n\r\n{body}",
+                    body.len()
+                )
+                .unwrap();
+            }
+        });
+        url
+    }
+
+    fn reply(confirmations: i64) -> impl Fn(&str, &Value) -> Value + Send + 'static {
+        move |method: &str, _: &Value| match method {
+            "getrawtransaction" => json!({ "hex": TX_HEX, "blockhash": BLOCK_HASH }),
+            "getblockheader" => json!({
+                "height": 0,
+                "time": 1231006505,
+                "confirmations": confirmations,
+            }),
+            "getblockcount" => json!(840000),
+            _ => Value::Null,
+        }
+    }
+
+    fn witness_id() -> XWitnessId { XWitnessId::Bitcoin(TXID.parse().unwrap()) }
+
+    #[test]
+    fn height_on_active_chain() {
+        let url = mock_rpc(None, reply(840001));
+        let mut resolver = Resolver::new(&url, Auth::None).unwrap();
+        let anchor = resolver.resolve_height(witness_id()).unwrap();
+        let pos = WitnessPos::new(0, 1231006505).unwrap();
+        assert_eq!(anchor.witness_ord, WitnessOrd::OnChain(pos));
+        assert_eq!(resolver.resolve_tip(Layer1::Bitcoin).unwrap(), 840000);
+    }
+
+    #[test]
+    fn height_on_stale_fork() {
+        let url = mock_rpc(None, reply(-1));
+        let mut resolver = Resolver::new(&url, Auth::None).unwrap();
+        let anchor = resolver.resolve_height(witness_id()).unwrap();
+        assert_eq!(anchor.witness_ord, WitnessOrd::OffChain);
+    }
+
+    #[test]
+    fn witness_txid_check() {
+        let url = mock_rpc(None, reply(1));
+        let resolver = Resolver::new(&url, Auth::None).unwrap();
+        let tx = Tx::consensus_deserialize(Vec::<u8>::from_hex(TX_HEX).unwrap()).unwrap();
+        let witness = resolver.resolve_pub_witness(witness_id()).unwrap();
+        assert_eq!(witness, XWitnessTx::Bitcoin(tx));
+
+        let other = XWitnessId::Bitcoin(
+            "0e3e2357e806b6cdb1f70b54c3a3a17b6714ee1f0e68bebb44a74b1efd512098"
+                .parse()
+                .unwrap(),
+        );
+        assert!(resolver.resolve_pub_witness(other).is_err());
+        let err = resolver.resolve_witness_tx(other).unwrap_err();
+        assert!(matches!(err, AnchorResolverError::TxidMismatch { .. }));
+        assert!(!err.is_transport());
+    }
+
+    #[test]
+    fn cookie_reread() {
+        let dir = TempDir::new("bitcoind-rpc");
+        let cookie = dir.join(".cookie");
+        fs::write(&cookie, "__cookie__:old").unwrap();
+        let auth = auth_header(&Auth::UserPass(s!("__cookie__"), s!("new"))).unwrap();
+        let url = mock_rpc(auth, reply(1));
+
+        let mut resolver = Resolver::new(&url, Auth::CookieFile(cookie.clone())).unwrap();
+        // bitcoind restart
+        fs::write(&cookie, "__cookie__:new").unwrap();
+        assert_eq!(resolver.resolve_tip(Layer1::Bitcoin).unwrap(), 840000);
+    }
+}
@@ -0,0 +1,506 @@
+// RGB smart contracts for Bitcoin & Lightning
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+// Written in 2024 by
+//     Zoe Faltibà <zoefaltiba@gmail.com>
+//
+// Copyright (C) 2024 LNP/BP Standards Association. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::cell::RefCell;
+use std::collections::hash_map::RandomState;
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::fs::File;
+use std::hash::{BuildHasher, Hasher};
+use std::io::Write as _;
+use std::ops::{Deref, DerefMut};
+use std::path::{Path, PathBuf};
+use std::{fs, io, process};
+
+use amplify::hex::{FromHex, ToHex};
+use amplify::IoError;
+use bp::{ConsensusDecode, ConsensusEncode};
+use bpstd::Tx;
+use rgbstd::resolvers::ResolveHeight;
+use rgbstd::validation::{ResolveWitness, WitnessResolverError};
+use rgbstd::{Layer1, WitnessAnchor, WitnessOrd, WitnessPos, XChain, XWitnessId, XWitnessTx};
+
+/// Default number of confirmations after which witness anchors are cached.
+pub const DEFAULT_CACHE_DEPTH: u32 = 6;
+
+/// Resolvers which are able to report current height of the blockchain tip.
+pub trait ResolveTip: ResolveHeight {
+    /// Returns height of the tip of the `layer1` blockchain, failing if the
+    /// resolver doesn't [support](ResolveTip::supports) it.
+    fn resolve_tip(&mut self, layer1: Layer1) -> Result<u32, Self::Error>;
+
+    /// Whether the resolver is able to resolve witnesses on the given
+    /// `layer1`. Most of the resolvers support only bitcoin.
+    fn supports(&self, layer1: Layer1) -> bool { layer1 == Layer1::Bitcoin }
+
+    /// Whether the resolver takes the data from blockchain indexers. Data from
+    /// other sources (like user-provided proofs) are specific to a single run,
+    /// and thus are not persisted by [`CachingResolver`].
+    fn is_indexer(&self) -> bool { true }
+}
+
+#[derive(Debug, Display, Error, From)]
+#[display(doc_comments)]
+pub enum CacheError {
+    /// unable to access witness cache. Details: {0}
+    #[from]
+    #[from(io::Error)]
+    Io(IoError),
+}
+
+/// Resolver wrapper caching witness transactions and anchors, which can be
+/// persisted to a file and reused across runs.
+///
+/// Anchors are cached only when the witness has at least `min_depth`
+/// confirmations reported by a blockchain indexer (see
+/// [`ResolveTip::is_indexer`]), such that the cache doesn't retain data which
+/// may be changed by a re-org or which come from unverified sources. During a
+/// run all resolved transactions are kept in memory, but only the ones with a
+/// cached anchor are persisted: this excludes terminal transactions provided by
+/// the consignments, which are not mined yet.
+pub struct CachingResolver<R: ResolveTip + ResolveWitness> {
+    inner: R,
+    path: Option<PathBuf>,
+    min_depth: u32,
+    tips: BTreeMap<Layer1, u32>,
+    txes: RefCell<BTreeMap<XWitnessId, XWitnessTx>>,
+    anchors: BTreeMap<XWitnessId, WitnessPos>,
+}
+
+impl<R: ResolveTip + ResolveWitness> Deref for CachingResolver<R> {
+    type Target = R;
+
+    fn deref(&self) -> &Self::Target { &self.inner }
+}
+
+impl<R: ResolveTip + ResolveWitness> DerefMut for CachingResolver<R> {
+    fn deref_mut(&mut self) -> &mut Self::Target { &mut self.inner }
+}
+
+impl<R: ResolveTip + ResolveWitness> CachingResolver<R> {
+    /// Constructs resolver with an in-memory cache, which is not persisted.
+    pub fn new(inner: R, min_depth: u32) -> Self {
+        Self {
+            inner,
+            path: None,
+            min_depth,
+            tips: none!(),
+            txes: none!(),
+            anchors: none!(),
+        }
+    }
+
+    /// Constructs resolver using cache persisted in the file at `path`. If the
+    /// file doesn't exist, it is created on [`CachingResolver::store`].
+    ///
+    /// The cache is never authoritative, thus a file which can't be parsed is
+    /// treated as an empty cache, and gets overwritten on the next
+    /// [`CachingResolver::store`].
+    pub fn load(inner: R, path: impl AsRef<Path>, min_depth: u32) -> Result<Self, CacheError> {
+        let path = path.as_ref();
+        let mut resolver = Self::new(inner, min_depth);
+        resolver.path = Some(path.to_owned());
+        let data = match fs::read(path) {
+            Ok(data) => data,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(resolver),
+            Err(err) => return Err(err.into()),
+        };
+        let parsed = String::from_utf8(data)
+            .map_err(|_| 1)
+            .and_then(|data| parse_cache(&data));
+        match parsed {
+            Ok((txes, anchors)) => {
+                resolver.txes = RefCell::new(txes);
+                resolver.anchors = anchors;
+            }
+            Err(_line) => {
+                #[cfg(feature = "log")]
+                log::warn!(
+                    "witness cache '{}' is corrupted at line {_line} and will be discarded",
+                    path.display()
+                );
+            }
+        }
+        Ok(resolver)
+    }
+
+    /// Saves cache to the file it was loaded from. Does nothing for in-memory
+    /// caches.
+    pub fn store(&self) -> Result<(), CacheError> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        let mut data = String::new();
+        for (id, tx) in self.txes.borrow().iter() {
+            if !self.anchors.contains_key(id) {
+                continue;
+            }
+            let tx = tx.as_reduced_unsafe().consensus_serialize();
+            writeln!(data, "tx {id} {}", tx.to_hex()).expect("writing to string");
+        }
+        for (id, pos) in &self.anchors {
+            writeln!(data, "anchor {id} {} {}", pos.height(), pos.timestamp())
+                .expect("writing to string");
+        }
+        // The cache is replaced atomically, such that an interrupted write
+        // doesn't corrupt it. Several processes may store the cache at the same
+        // time, thus each write goes through its own temporary file.
+        let mut tmp = path.clone().into_os_string();
+        let suffix = RandomState::new().build_hasher().finish();
+        tmp.push(format!(".{}-{suffix:016x}.tmp", process::id()));
+        let tmp = PathBuf::from(tmp);
+        let res = File::create(&tmp)
+            .and_then(|mut file| {
+                file.write_all(data.as_bytes())?;
+                file.sync_all()
+            })
+            .and_then(|_| fs::rename(&tmp, path));
+        if res.is_err() {
+            let _ = fs::remove_file(&tmp);
+        }
+        res.map_err(CacheError::from)
+    }
+
+    pub fn into_inner(self) -> R { self.inner }
+}
+
+/// Parses cache file data into transactions and anchors, returning number of
+/// the first invalid line on failure.
+#[allow(clippy::type_complexity)]
+fn parse_cache(
+    data: &str,
+) -> Result<(BTreeMap<XWitnessId, XWitnessTx>, BTreeMap<XWitnessId, WitnessPos>), usize> {
+    let mut txes = BTreeMap::new();
+    let mut anchors = BTreeMap::new();
+    for (no, line) in data.lines().enumerate() {
+        let mut cols = line.split_whitespace();
+        let (Some(kind), Some(id)) = (cols.next(), cols.next()) else {
+            continue;
+        };
+        let id = id.parse::<XWitnessId>().map_err(|_| no + 1)?;
+        match (kind, cols.next(), cols.next()) {
+            ("tx", Some(hex), None) => {
+                // Liquid transactions are stored converted into bitcoin ones,
+                // which have different ids (see `elements` module)
+                let tx = Vec::<u8>::from_hex(hex)
+                    .ok()
+                    .and_then(|data| Tx::consensus_deserialize(data).ok())
+                    .filter(|tx| {
+                        id.layer1() != Layer1::Bitcoin || &tx.txid() == id.as_reduced_unsafe()
+                    })
+                    .ok_or(no + 1)?;
+                let tx = match id {
+                    XChain::Bitcoin(_) => XChain::Bitcoin(tx),
+                    XChain::Liquid(_) => XChain::Liquid(tx),
+                    _ => return Err(no + 1),
+                };
+                txes.insert(id, tx);
+            }
+            ("anchor", Some(height), Some(timestamp)) => {
+                let pos = height
+                    .parse()
+                    .ok()
+                    .zip(timestamp.parse().ok())
+                    .and_then(|(height, timestamp)| WitnessPos::new(height, timestamp))
+                    .ok_or(no + 1)?;
+                anchors.insert(id, pos);
+            }
+            _ => return Err(no + 1),
+        }
+    }
+    Ok((txes, anchors))
+}
+
+impl<R: ResolveTip + ResolveWitness> ResolveHeight for CachingResolver<R> {
+    type Error = R::Error;
+
+    fn resolve_height(&mut self, witness_id: XWitnessId) -> Result<WitnessAnchor, Self::Error> {
+        if let Some(pos) = self.anchors.get(&witness_id) {
+            return Ok(WitnessAnchor {
+                witness_ord: WitnessOrd::OnChain(*pos),
+                witness_id,
+            });
+        }
+
+        let anchor = self.inner.resolve_height(witness_id)?;
+        if !self.inner.is_indexer() {
+            return Ok(anchor);
+        }
+        if let WitnessOrd::OnChain(pos) = anchor.witness_ord {
+            let layer1 = witness_id.layer1();
+            let tip = match self.tips.get(&layer1) {
+                Some(tip) => *tip,
+                None => {
+                    let tip = self.inner.resolve_tip(layer1)?;
+                    self.tips.insert(layer1, tip);
+                    tip
+                }
+            };
+            let height = u32::from(pos.height());
+            if tip.saturating_sub(height) + 1 >= self.min_depth {
+                self.anchors.insert(witness_id, pos);
+            }
+        }
+        Ok(anchor)
+    }
+}
+
+impl<R: ResolveTip + ResolveWitness> ResolveWitness for CachingResolver<R> {
+    fn resolve_pub_witness(
+        &self,
+        witness_id: XWitnessId,
+    ) -> Result<XWitnessTx, WitnessResolverError> {
+        if let Some(tx) = self.txes.borrow().get(&witness_id) {
+            return Ok(tx.clone());
+        }
+
+        let tx = self.inner.resolve_pub_witness(witness_id)?;
+        if matches!(tx, XChain::Bitcoin(_) | XChain::Liquid(_)) {
+            self.txes.borrow_mut().insert(witness_id, tx.clone());
+        }
+        Ok(tx)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::cell::Cell;
+
+    use super::*;
+    use crate::test_helpers::TempDir;
+
+    const TIP: u32 = 1000;
+
+    #[derive(Debug, Display, Error)]
+    #[display("witness is not known to the mock resolver")]
+    struct UnknownWitness;
+
+    #[derive(Default)]
+    struct MockResolver {
+        txes: BTreeMap<XWitnessId, XWitnessTx>,
+        heights: BTreeMap<XWitnessId, u32>,
+        indexer: bool,
+        tx_requests: Cell<usize>,
+        height_requests: usize,
+    }
+
+    impl MockResolver {
+        // Resolver knowing witnesses 1 (mined deep enough to be cached), 2
+        // (mined too recently) and 3 (not mined)
+        fn with_witnesses() -> Self {
+            let mut resolver = MockResolver {
+                indexer: true,
+                ..Default::default()
+            };
+            for no in 1..=3 {
+                let tx = tx(no);
+                resolver.txes.insert(witness_id(no), XChain::Bitcoin(tx));
+            }
+            resolver.heights.insert(witness_id(1), 900);
+            resolver.heights.insert(witness_id(2), TIP - 2);
+            resolver
+        }
+    }
+
+    impl ResolveHeight for MockResolver {
+        type Error = UnknownWitness;
+
+        fn resolve_height(&mut self, witness_id: XWitnessId) -> Result<WitnessAnchor, Self::Error> {
+            self.height_requests += 1;
+            if !self.txes.contains_key(&witness_id) {
+                return Err(UnknownWitness);
+            }
+            let witness_ord = match self.heights.get(&witness_id) {
+                Some(height) => {
+                    WitnessOrd::OnChain(WitnessPos::new(*height, 1_700_000_000).unwrap())
+                }
+                None => WitnessOrd::OffChain,
+            };
+            Ok(WitnessAnchor {
+                witness_ord,
+                witness_id,
+            })
+        }
+    }
+
+    impl ResolveTip for MockResolver {
+        fn resolve_tip(&mut self, _: Layer1) -> Result<u32, Self::Error> { Ok(TIP) }
+
+        fn is_indexer(&self) -> bool { self.indexer }
+    }
+
+    impl ResolveWitness for MockResolver {
+        fn resolve_pub_witness(
+            &self,
+            witness_id: XWitnessId,
+        ) -> Result<XWitnessTx, WitnessResolverError> {
+            self.tx_requests.set(self.tx_requests.get() + 1);
+            self.txes
+                .get(&witness_id)
+                .cloned()
+                .ok_or(WitnessResolverError::Unknown(witness_id))
+        }
+    }
+
+    // Unsigned transaction with a single input and an empty OP_RETURN output,
+    // which spends an output of a transaction with id made of `no` bytes
+    fn tx(no: u8) -> Tx {
+        let hex = format!(
+            "0200000001{}0000000000ffffffff010000000000000000016a00000000",
+            format!("{no:02x}").repeat(32)
+        );
+        Tx::consensus_deserialize(Vec::<u8>::from_hex(&hex).unwrap()).unwrap()
+    }
+
+    fn witness_id(no: u8) -> XWitnessId { XWitnessId::Bitcoin(tx(no).txid()) }
+
+    fn resolve_all(resolver: &mut CachingResolver<MockResolver>) {
+        for no in 1..=3 {
+            resolver.resolve_pub_witness(witness_id(no)).unwrap();
+            resolver.resolve_height(witness_id(no)).unwrap();
+        }
+    }
+
+    #[test]
+    fn cache_hit_miss() {
+        let mut resolver = CachingResolver::new(MockResolver::with_witnesses(), 6);
+        resolve_all(&mut resolver);
+        assert_eq!(resolver.tx_requests.get(), 3);
+        assert_eq!(resolver.height_requests, 3);
+
+        // Transactions are kept for the whole run, while only anchors with
+        // enough confirmations are cached
+        resolve_all(&mut resolver);
+        assert_eq!(resolver.tx_requests.get(), 3);
+        assert_eq!(resolver.height_requests, 5);
+        assert_eq!(
+            resolver.resolve_height(witness_id(1)).unwrap().witness_ord,
+            WitnessOrd::OnChain(WitnessPos::new(900, 1_700_000_000).unwrap())
+        );
+
+        // Unknown witnesses are not cached
+        assert!(resolver.resolve_pub_witness(witness_id(4)).is_err());
+        assert!(resolver.resolve_pub_witness(witness_id(4)).is_err());
+        assert_eq!(resolver.tx_requests.get(), 5);
+    }
+
+    #[test]
+    fn non_indexer() {
+        let mut inner = MockResolver::with_witnesses();
+        inner.indexer = false;
+        let mut resolver = CachingResolver::new(inner, 6);
+        resolve_all(&mut resolver);
+        resolve_all(&mut resolver);
+        assert_eq!(resolver.height_requests, 6);
+    }
+
+    #[test]
+    fn persistence() {
+        let dir = TempDir::new("witness-cache");
+        let path = dir.join("witnesses");
+
+        let mut resolver = CachingResolver::load(MockResolver::with_witnesses(), &path, 6).unwrap();
+        resolve_all(&mut resolver);
+        resolver.store().unwrap();
+        assert!(path.is_file());
+
+        // Only the anchored witness is persisted
+        let mut resolver = CachingResolver::load(MockResolver::default(), &path, 6).unwrap();
+        assert_eq!(resolver.resolve_pub_witness(witness_id(1)).unwrap(), XChain::Bitcoin(tx(1)));
+        assert_eq!(
+            resolver.resolve_height(witness_id(1)).unwrap().witness_ord,
+            WitnessOrd::OnChain(WitnessPos::new(900, 1_700_000_000).unwrap())
+        );
+        assert!(resolver.resolve_pub_witness(witness_id(2)).is_err());
+        assert!(resolver.resolve_height(witness_id(2)).is_err());
+        assert!(resolver.resolve_pub_witness(witness_id(3)).is_err());
+        assert_eq!(resolver.tx_requests.get(), 2);
+        assert_eq!(resolver.height_requests, 1);
+
+        // In-memory cache is never persisted
+        CachingResolver::new(MockResolver::default(), 6).store().unwrap();
+        assert_eq!(fs::read_dir(&*dir).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn atomic_store() {
+        let dir = TempDir::new("witness-cache-tmp");
+        let path = dir.join("witnesses");
+        let tmp = dir.join("witnesses.tmp");
+        let files = || {
+            fs::read_dir(&*dir)
+                .unwrap()
+                .map(|entry| entry.unwrap().path())
+                .collect::<Vec<_>>()
+        };
+
+        // Leftover of an interrupted write doesn't affect the cache
+        fs::write(&tmp, "garbage").unwrap();
+        let mut resolver = CachingResolver::load(MockResolver::with_witnesses(), &path, 6).unwrap();
+        resolve_all(&mut resolver);
+        resolver.store().unwrap();
+        fs::remove_file(&tmp).unwrap();
+        assert_eq!(files(), vec![path.clone()]);
+
+        // Existing cache is replaced, and writes of several resolvers don't
+        // interfere
+        fs::write(&path, format!("anchor {} 10 1700000000\n", witness_id(2))).unwrap();
+        let first = CachingResolver::load(MockResolver::default(), &path, 6).unwrap();
+        let second = CachingResolver::load(MockResolver::default(), &path, 6).unwrap();
+        first.store().unwrap();
+        second.store().unwrap();
+        assert_eq!(files(), vec![path.clone()]);
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            format!("anchor {} 10 1700000000\n", witness_id(2))
+        );
+    }
+
+    #[test]
+    fn corrupted() {
+        let dir = TempDir::new("witness-cache-corrupted");
+        let path = dir.join("witnesses");
+        let anchor = format!("anchor {} 900 1700000000", witness_id(1));
+        let cases = [
+            format!("{anchor}\nanchor {} 900", witness_id(2)),
+            format!("{anchor}\nanchor unknown 900 1700000000"),
+            format!("{anchor}\ntx {} 00", witness_id(1)),
+            // Transaction doesn't match the witness id
+            format!("{anchor}\ntx {} {}", witness_id(1), tx(2).consensus_serialize().to_hex()),
+        ];
+        for data in cases {
+            assert_eq!(parse_cache(&data).unwrap_err(), 2, "{data}");
+
+            // Corrupted cache is discarded as a whole and overwritten
+            fs::write(&path, &data).unwrap();
+            let mut resolver =
+                CachingResolver::load(MockResolver::with_witnesses(), &path, 6).unwrap();
+            resolver.resolve_height(witness_id(1)).unwrap();
+            assert_eq!(resolver.height_requests, 1, "{data}");
+            resolver.store().unwrap();
+            assert_eq!(fs::read_to_string(&path).unwrap(), format!("{anchor}\n"));
+        }
+
+        // File which is not a text
+        fs::write(&path, [0xFFu8, 0xFE]).unwrap();
+        assert!(CachingResolver::load(MockResolver::default(), &path, 6).is_ok());
+    }
+}
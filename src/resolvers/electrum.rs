@@ -22,16 +22,20 @@
 use std::collections::HashMap;
 
 use bp::ConsensusDecode;
-use bpstd::{Tx, Txid};
-use electrum::{Client, ElectrumApi, Error, Param};
+use bpstd::{Network, ScriptPubkey, Tx, Txid};
+use electrum::{Client, ElectrumApi, Error, GetMerkleRes};
 use rgbstd::containers::Consignment;
 use rgbstd::resolvers::ResolveHeight;
 use rgbstd::validation::{ResolveWitness, WitnessResolverError};
 use rgbstd::{Layer1, WitnessAnchor, WitnessOrd, WitnessPos, XChain, XWitnessId, XWitnessTx};
 
+use crate::spv::{self, BlockHeader, MerkleBranch, SpvError, SpvParams, SPV_CHAIN_DEPTH};
+use crate::{multi, ResolveTip, ResolveWitnessTx, ResolverFailure};
+
 pub struct Resolver {
     electrum_client: Client,
     terminal_txes: HashMap<Txid, Tx>,
+    spv: Option<SpvParams>,
 }
 
 #[allow(clippy::large_enum_variant)]
@@ -45,10 +49,25 @@ pub enum AnchorResolverError {
     /// impossible conversion
     ImpossibleConversion,
 
+    /// transaction {0} returned by the server can't be deserialized or
+    /// doesn't match its id.
+    InvalidTx(Txid),
+
+    /// unable to determine mining height of transaction {0}.
+    HeightNotFound(Txid),
+
+    /// SPV verification has failed: {0}
+    #[from]
+    Spv(SpvError),
+
     /// unsupported layer 1 {0}
     UnsupportedLayer1(Layer1),
 }
 
+impl ResolverFailure for AnchorResolverError {
+    fn is_transport(&self) -> bool { matches!(self, AnchorResolverError::Error(_)) }
+}
+
 impl Resolver {
     #[allow(clippy::result_large_err)]
     pub fn new(url: &str) -> Result<Self, Error> {
@@ -56,9 +75,82 @@ impl Resolver {
         Ok(Self {
             electrum_client,
             terminal_txes: none!(),
+            spv: None,
         })
     }
 
+    /// Enables SPV mode for the given network, in which the resolver doesn't
+    /// trust the server regarding the transaction mining status, and verifies
+    /// merkle proofs, block headers and the block height committed by the
+    /// coinbase transaction locally against the network parameters (see
+    /// [`SpvParams`]). Transactions reported by the server as not mined are
+    /// treated as such, while a proof failing the verification is returned as
+    /// [`AnchorResolverError::Spv`] error.
+    pub fn with_spv(mut self, network: Option<Network>) -> Self {
+        self.spv = network.map(SpvParams::with);
+        self
+    }
+
+    fn spv_pos(
+        &self,
+        txid: Txid,
+        merkle: &GetMerkleRes,
+        params: &SpvParams,
+    ) -> Result<WitnessPos, AnchorResolverError> {
+        let height = u32::try_from(merkle.block_height)
+            .map_err(|_| AnchorResolverError::ImpossibleConversion)?;
+        let pos =
+            u32::try_from(merkle.pos).map_err(|_| AnchorResolverError::ImpossibleConversion)?;
+        let branch = MerkleBranch::from_display_bytes(pos, merkle.merkle.iter().copied())?;
+        let coinbase = self
+            .electrum_client
+            .txid_from_pos_with_merkle(merkle.block_height, 0)?;
+        let coinbase_branch =
+            MerkleBranch::from_display_bytes(0, coinbase.merkle.iter().copied())?;
+        let coinbase_tx = self.electrum_client.transaction_get_raw(&coinbase.tx_hash)?;
+        let headers = self
+            .electrum_client
+            .block_headers(merkle.block_height, SPV_CHAIN_DEPTH as usize)?
+            .raw_headers
+            .chunks(80)
+            .map(BlockHeader::from_slice)
+            .collect::<Result<Vec<_>, _>>()?;
+        spv::verify_witness(
+            txid,
+            &branch,
+            &coinbase_tx,
+            &coinbase_branch,
+            &headers,
+            height,
+            params,
+        )?;
+        let time = headers[0].time() as i64;
+        WitnessPos::new(height, time).ok_or(AnchorResolverError::Spv(SpvError::InvalidHeader))
+    }
+
+    /// Returns script which history contains the transaction together with
+    /// its mining height. Electrum servers index transactions by the scripts
+    /// they touch, except unspendable (`OP_RETURN`) outputs; thus the first
+    /// spendable output is used, or the output spent by the first input if
+    /// there are no spendable outputs.
+    fn history_script(&self, txid: Txid, tx: &Tx) -> Result<ScriptPubkey, AnchorResolverError> {
+        if let Some(output) = tx.outputs.iter().find(|out| !out.script_pubkey.is_op_return()) {
+            return Ok(output.script_pubkey.clone());
+        }
+        let prevout = tx
+            .inputs
+            .first()
+            .ok_or(AnchorResolverError::InvalidTx(txid))?
+            .prev_output;
+        let raw_prev_tx = self.electrum_client.transaction_get_raw(&prevout.txid)?;
+        let prev_tx = deserialize_tx(prevout.txid, raw_prev_tx)?;
+        let output = prev_tx
+            .outputs
+            .get(prevout.vout.into_u32() as usize)
+            .ok_or(AnchorResolverError::InvalidTx(prevout.txid))?;
+        Ok(output.script_pubkey.clone())
+    }
+
     pub fn add_witnesses<const TYPE: bool>(&mut self, consignment: &Consignment<TYPE>) {
         self.terminal_txes.extend(
             consignment
@@ -74,6 +166,15 @@ impl Resolver {
     }
 }
 
+/// Deserializes transaction returned by the server, checking that it has the
+/// requested id.
+fn deserialize_tx(txid: Txid, raw_tx: Vec<u8>) -> Result<Tx, AnchorResolverError> {
+    match Tx::consensus_deserialize(raw_tx) {
+        Ok(tx) if tx.txid() == txid => Ok(tx),
+        _ => Err(AnchorResolverError::InvalidTx(txid)),
+    }
+}
+
 impl ResolveHeight for Resolver {
     type Error = AnchorResolverError;
 
@@ -89,104 +190,102 @@ impl ResolveHeight for Resolver {
             });
         }
 
-        fn get_block_height(electrum_client: &Client) -> Result<u64, AnchorResolverError> {
-            electrum_client
-                .block_headers_subscribe()?
-                .height
-                .try_into()
-                .map_err(|_| AnchorResolverError::ImpossibleConversion)
-        }
-
-        let last_block_height_min = get_block_height(&self.electrum_client)?;
-        let witness_ord = match self
-            .electrum_client
-            .raw_call("blockchain.transaction.get", vec![
-                Param::String(txid.to_string()),
-                Param::Bool(true),
-            ]) {
-            Ok(tx_details) => {
-                if let Some(confirmations) = tx_details.get("confirmations") {
-                    let confirmations = confirmations
-                        .as_u64()
-                        .ok_or(Error::InvalidResponse(tx_details.clone()))?;
-                    let last_block_height_max = get_block_height(&self.electrum_client)?;
-                    let skew = confirmations - 1;
-                    let mut tx_height: u32 = 0;
-                    for height in (last_block_height_min - skew)..=(last_block_height_max - skew) {
-                        if let Ok(get_merkle_res) = self.electrum_client.transaction_get_merkle(
-                            &txid,
-                            height
-                                .try_into()
-                                .map_err(|_| AnchorResolverError::ImpossibleConversion)?,
-                        ) {
-                            tx_height = get_merkle_res
-                                .block_height
-                                .try_into()
-                                .map_err(|_| AnchorResolverError::ImpossibleConversion)?;
-                            break;
-                        } else {
-                            continue;
-                        }
-                    }
-                    let block_time = tx_details
-                        .get("blocktime")
-                        .ok_or(Error::InvalidResponse(tx_details.clone()))?
-                        .as_i64()
-                        .ok_or(Error::InvalidResponse(tx_details.clone()))?;
-                    WitnessOrd::OnChain(
-                        WitnessPos::new(tx_height, block_time)
-                            .ok_or(Error::InvalidResponse(tx_details.clone()))?,
-                    )
-                } else {
-                    WitnessOrd::OffChain
-                }
-            }
+        let raw_tx = match self.electrum_client.transaction_get_raw(&txid) {
+            Ok(raw_tx) => raw_tx,
             Err(e)
                 if e.to_string()
                     .contains("No such mempool or blockchain transaction") =>
             {
-                WitnessOrd::OffChain
+                return Ok(WitnessAnchor {
+                    witness_ord: WitnessOrd::OffChain,
+                    witness_id,
+                });
             }
             Err(e) => return Err(e.into()),
         };
+        let tx = deserialize_tx(txid, raw_tx)?;
+        let script = self.history_script(txid, &tx)?;
+        let height = self
+            .electrum_client
+            .script_get_history(&script)?
+            .into_iter()
+            .find(|item| item.tx_hash == txid)
+            .ok_or(AnchorResolverError::HeightNotFound(txid))?
+            .height;
+        // Zero and negative heights are used for the mempool transactions
+        if height <= 0 {
+            return Ok(WitnessAnchor {
+                witness_ord: WitnessOrd::OffChain,
+                witness_id,
+            });
+        }
+        let height = height as usize;
+
+        let pos = if let Some(params) = self.spv {
+            let merkle = self.electrum_client.transaction_get_merkle(&txid, height)?;
+            if merkle.block_height != height {
+                return Err(AnchorResolverError::HeightNotFound(txid));
+            }
+            self.spv_pos(txid, &merkle, &params)?
+        } else {
+            let header = self.electrum_client.block_header_raw(height)?;
+            let time = BlockHeader::from_slice(&header)?.time() as i64;
+            let height =
+                u32::try_from(height).map_err(|_| AnchorResolverError::ImpossibleConversion)?;
+            WitnessPos::new(height, time).ok_or(AnchorResolverError::HeightNotFound(txid))?
+        };
 
         Ok(WitnessAnchor {
-            witness_ord,
+            witness_ord: WitnessOrd::OnChain(pos),
             witness_id,
         })
     }
 }
 
-impl ResolveWitness for Resolver {
-    fn resolve_pub_witness(
+impl ResolveTip for Resolver {
+    fn resolve_tip(&mut self, layer1: Layer1) -> Result<u32, Self::Error> {
+        if layer1 != Layer1::Bitcoin {
+            return Err(AnchorResolverError::UnsupportedLayer1(layer1));
+        }
+        self.electrum_client
+            .block_headers_subscribe()?
+            .height
+            .try_into()
+            .map_err(|_| AnchorResolverError::ImpossibleConversion)
+    }
+}
+
+impl ResolveWitnessTx for Resolver {
+    fn resolve_witness_tx(
         &self,
         witness_id: XWitnessId,
-    ) -> Result<XWitnessTx, WitnessResolverError> {
+    ) -> Result<Option<XWitnessTx>, Self::Error> {
         let XWitnessId::Bitcoin(txid) = witness_id else {
-            return Err(WitnessResolverError::Other(
-                witness_id,
-                AnchorResolverError::UnsupportedLayer1(witness_id.layer1()).to_string(),
-            ));
+            return Err(AnchorResolverError::UnsupportedLayer1(witness_id.layer1()));
         };
 
         if let Some(tx) = self.terminal_txes.get(&txid) {
-            return Ok(XWitnessTx::Bitcoin(tx.clone()));
+            return Ok(Some(XWitnessTx::Bitcoin(tx.clone())));
         }
 
         match self.electrum_client.transaction_get_raw(&txid) {
-            Ok(raw_tx) => {
-                let tx = Tx::consensus_deserialize(raw_tx).map_err(|_| {
-                    WitnessResolverError::Other(witness_id, s!("cannot deserialize raw TX"))
-                })?;
-                Ok(XWitnessTx::Bitcoin(tx))
-            }
+            Ok(raw_tx) => Ok(Some(XWitnessTx::Bitcoin(deserialize_tx(txid, raw_tx)?))),
             Err(e)
                 if e.to_string()
                     .contains("No such mempool or blockchain transaction") =>
             {
-                Err(WitnessResolverError::Unknown(witness_id))
+                Ok(None)
             }
-            Err(e) => Err(WitnessResolverError::Other(witness_id, e.to_string())),
+            Err(e) => Err(e.into()),
         }
     }
 }
+
+impl ResolveWitness for Resolver {
+    fn resolve_pub_witness(
+        &self,
+        witness_id: XWitnessId,
+    ) -> Result<XWitnessTx, WitnessResolverError> {
+        multi::resolve_pub_witness(self, witness_id)
+    }
+}
@@ -0,0 +1,317 @@
+// RGB smart contracts for Bitcoin & Lightning
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+// Written in 2024 by
+//     Zoe Faltibà <zoefaltiba@gmail.com>
+//
+// Copyright (C) 2024 LNP/BP Standards Association. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Parsing of the Elements (liquid) transactions.
+//!
+//! Liquid witness transactions are represented by the bitcoin [`Tx`] type,
+//! which keeps everything RGB validation relies on: the spent outpoints and
+//! the output scripts hosting the commitments. Liquid-specific data (asset
+//! issuances, confidential assets and nonces, and all witness data) are
+//! dropped, and confidential output values are represented as zero.
+//!
+//! Since the conversion is lossy, the transaction id of the converted
+//! transaction differs from the liquid one; thus the liquid transaction id is
+//! verified against the original data during parsing.
+
+use std::str::FromStr;
+
+use bp::ConsensusDecode;
+use bpstd::{Tx, Txid};
+
+use crate::spv::{sha256d, Hash};
+
+/// Input index value used by coinbase inputs, which don't have flags.
+const COINBASE_VOUT: u32 = 0xFFFF_FFFF;
+/// Input index flag signalling presence of an asset issuance.
+const VOUT_ISSUANCE_FLAG: u32 = 1 << 31;
+/// Input index flag signalling a peg-in input.
+const VOUT_PEGIN_FLAG: u32 = 1 << 30;
+/// Transaction flag signalling presence of the witness data.
+const TX_WITNESS_FLAG: u8 = 0x01;
+
+#[derive(Clone, Eq, PartialEq, Debug, Display, Error)]
+#[display(doc_comments)]
+pub enum ElementsError {
+    /// liquid transaction data end unexpectedly.
+    UnexpectedEnd,
+
+    /// liquid transaction has an invalid {0} prefix {1:#04x}.
+    InvalidPrefix(&'static str, u8),
+
+    /// liquid transaction contains unknown flags {0:#04x}.
+    UnknownFlags(u8),
+
+    /// liquid transaction has no inputs.
+    NoInputs,
+
+    /// liquid transaction data contain {0} extra bytes.
+    TrailingData(usize),
+
+    /// liquid transaction data have id {actual}, while {expected} was
+    /// requested.
+    TxidMismatch { expected: Txid, actual: Txid },
+
+    /// unable to represent liquid transaction as a bitcoin one.
+    Conversion,
+}
+
+struct Reader<'data> {
+    data: &'data [u8],
+    pos: usize,
+}
+
+impl<'data> Reader<'data> {
+    fn take(&mut self, len: usize) -> Result<&'data [u8], ElementsError> {
+        let end = self.pos.checked_add(len).ok_or(ElementsError::UnexpectedEnd)?;
+        let slice = self.data.get(self.pos..end).ok_or(ElementsError::UnexpectedEnd)?;
+        self.pos = end;
+        Ok(slice)
+    }
+
+    fn u8(&mut self) -> Result<u8, ElementsError> { Ok(self.take(1)?[0]) }
+
+    fn u32(&mut self) -> Result<u32, ElementsError> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().expect("fixed length")))
+    }
+
+    fn compact_size(&mut self) -> Result<u64, ElementsError> {
+        Ok(match self.u8()? {
+            0xFD => u16::from_le_bytes(self.take(2)?.try_into().expect("fixed length")) as u64,
+            0xFE => self.u32()? as u64,
+            0xFF => u64::from_le_bytes(self.take(8)?.try_into().expect("fixed length")),
+            len => len as u64,
+        })
+    }
+
+    fn var_bytes(&mut self) -> Result<&'data [u8], ElementsError> {
+        let len = self.compact_size()?;
+        self.take(usize::try_from(len).map_err(|_| ElementsError::UnexpectedEnd)?)
+    }
+
+    /// Reads confidential value, returning the amount if it is explicit.
+    fn value(&mut self) -> Result<Option<u64>, ElementsError> {
+        match self.u8()? {
+            0x00 => Ok(None),
+            0x01 => Ok(Some(u64::from_be_bytes(self.take(8)?.try_into().expect("fixed length")))),
+            0x08 | 0x09 => self.take(32).map(|_| None),
+            prefix => Err(ElementsError::InvalidPrefix("value", prefix)),
+        }
+    }
+
+    fn asset(&mut self) -> Result<(), ElementsError> {
+        match self.u8()? {
+            0x00 => Ok(()),
+            0x01 | 0x0A | 0x0B => self.take(32).map(|_| ()),
+            prefix => Err(ElementsError::InvalidPrefix("asset", prefix)),
+        }
+    }
+
+    fn nonce(&mut self) -> Result<(), ElementsError> {
+        match self.u8()? {
+            0x00 => Ok(()),
+            0x01..=0x03 => self.take(32).map(|_| ()),
+            prefix => Err(ElementsError::InvalidPrefix("nonce", prefix)),
+        }
+    }
+
+    fn skip_witness_stack(&mut self) -> Result<(), ElementsError> {
+        for _ in 0..self.compact_size()? {
+            self.var_bytes()?;
+        }
+        Ok(())
+    }
+}
+
+fn push_compact_size(buf: &mut Vec<u8>, len: usize) {
+    match len {
+        0..=0xFC => buf.push(len as u8),
+        0xFD..=0xFFFF => {
+            buf.push(0xFD);
+            buf.extend((len as u16).to_le_bytes());
+        }
+        _ => {
+            buf.push(0xFE);
+            buf.extend((len as u32).to_le_bytes());
+        }
+    }
+}
+
+fn push_var_bytes(buf: &mut Vec<u8>, data: &[u8]) {
+    push_compact_size(buf, data.len());
+    buf.extend(data);
+}
+
+fn txid_from_hash(mut hash: Hash) -> Txid {
+    hash.reverse();
+    let hex = hash.iter().map(|byte| format!("{byte:02x}")).collect::<String>();
+    Txid::from_str(&hex).expect("32-byte hash is always a valid txid")
+}
+
+/// Parses consensus-serialized liquid transaction, verifying that it has the
+/// `txid`, and converts it into a bitcoin transaction (see the module docs).
+pub fn parse_tx(txid: Txid, data: &[u8]) -> Result<Tx, ElementsError> {
+    let mut reader = Reader { data, pos: 0 };
+    // Transaction id commits to the data without the witness, with the
+    // witness flag unset
+    let mut txid_data = Vec::with_capacity(data.len());
+    // Bitcoin serialization of the same transaction without witness
+    let mut btc = Vec::with_capacity(data.len());
+
+    let version = reader.take(4)?;
+    txid_data.extend(version);
+    txid_data.push(0);
+    btc.extend(version);
+    let flags = reader.u8()?;
+    if flags & !TX_WITNESS_FLAG != 0 {
+        return Err(ElementsError::UnknownFlags(flags));
+    }
+
+    let body_start = reader.pos;
+    let input_count = reader.compact_size()?;
+    if input_count == 0 {
+        return Err(ElementsError::NoInputs);
+    }
+    let mut inputs = Vec::new();
+    for _ in 0..input_count {
+        let prev_txid = reader.take(32)?;
+        let mut vout = reader.u32()?;
+        let script_sig = reader.var_bytes()?;
+        let sequence = reader.take(4)?;
+        let has_issuance = vout != COINBASE_VOUT && vout & VOUT_ISSUANCE_FLAG != 0;
+        if vout != COINBASE_VOUT {
+            vout &= !(VOUT_ISSUANCE_FLAG | VOUT_PEGIN_FLAG);
+        }
+        if has_issuance {
+            // asset blinding nonce and entropy
+            reader.take(64)?;
+            // amount and inflation keys
+            reader.value()?;
+            reader.value()?;
+        }
+        inputs.push((prev_txid, vout, script_sig, sequence));
+    }
+    push_compact_size(&mut btc, inputs.len());
+    for (prev_txid, vout, script_sig, sequence) in inputs {
+        btc.extend(prev_txid);
+        btc.extend(vout.to_le_bytes());
+        push_var_bytes(&mut btc, script_sig);
+        btc.extend(sequence);
+    }
+
+    let output_count = reader.compact_size()?;
+    let mut outputs = Vec::new();
+    for _ in 0..output_count {
+        reader.asset()?;
+        let value = reader.value()?;
+        reader.nonce()?;
+        let script = reader.var_bytes()?;
+        outputs.push((value.unwrap_or_default(), script));
+    }
+    push_compact_size(&mut btc, outputs.len());
+    for (value, script) in outputs {
+        btc.extend(value.to_le_bytes());
+        push_var_bytes(&mut btc, script);
+    }
+
+    let lock_time = reader.take(4)?;
+    btc.extend(lock_time);
+    txid_data.extend(&data[body_start..reader.pos]);
+
+    if flags & TX_WITNESS_FLAG != 0 {
+        for _ in 0..input_count {
+            // issuance amount and inflation keys range proofs
+            reader.var_bytes()?;
+            reader.var_bytes()?;
+            reader.skip_witness_stack()?;
+            // peg-in witness
+            reader.skip_witness_stack()?;
+        }
+        for _ in 0..output_count {
+            // surjection and range proofs
+            reader.var_bytes()?;
+            reader.var_bytes()?;
+        }
+    }
+    if reader.pos != data.len() {
+        return Err(ElementsError::TrailingData(data.len() - reader.pos));
+    }
+
+    let actual = txid_from_hash(sha256d(&txid_data));
+    if actual != txid {
+        return Err(ElementsError::TxidMismatch {
+            expected: txid,
+            actual,
+        });
+    }
+    Tx::consensus_deserialize(btc).map_err(|_| ElementsError::Conversion)
+}
+
+#[cfg(test)]
+mod test {
+    use amplify::hex::FromHex;
+
+    use super::*;
+
+    // Liquid transaction spending an input with asset issuance, with explicit,
+    // confidential and fee outputs and with witness data
+    const LIQUID_TX: &str =
+        "020000000101000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f0100008000fd\
+         ffffff1111111111111111111111111111111111111111111111111111111111111111222222222222222222\
+         22222222222222222222222222222222222222222222220100000000000003e80003016d6d6d6d6d6d6d6d6d\
+         6d6d6d6d6d6d6d6d6d6d6d6d6d6d6d6d6d6d6d6d6d6d6d01000000000000c35000160014abababababababab\
+         abababababababababababab0a33333333333333333333333333333333333333333333333333333333333333\
+         3308444444444444444444444444444444444444444444444444444444444444444402555555555555555555\
+         5555555555555555555555555555555555555555555555226a20cdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcd\
+         cdcdcdcdcdcdcdcdcdcdcdcdcdcd016d6d6d6d6d6d6d6d6d6d6d6d6d6d6d6d6d6d6d6d6d6d6d6d6d6d6d6d6d\
+         6d6d6d0100000000000000fa00000000000000000203777777028888000000049999999905aaaaaaaaaa0000";
+    const LIQUID_TXID: &str = "bef91a67dbb1ebdf4f06f89f13591f9919091855de83caff5775d0c1a85b19d1";
+    // Bitcoin serialization of the same transaction
+    const BITCOIN_TXID: &str = "7c6eae27aaf9156d5b9bd0105378adce934f3266e036af7765f466b998279ccb";
+
+    fn data() -> Vec<u8> { Vec::<u8>::from_hex(LIQUID_TX).unwrap() }
+
+    #[test]
+    fn convert() {
+        let tx = parse_tx(Txid::from_str(LIQUID_TXID).unwrap(), &data()).unwrap();
+        assert_eq!(tx.txid(), Txid::from_str(BITCOIN_TXID).unwrap());
+        assert_eq!(tx.inputs[0].prev_output.vout.to_usize(), 1);
+        assert!(tx.outputs[1].script_pubkey.is_op_return());
+    }
+
+    #[test]
+    fn txid_mismatch() {
+        let bitcoin_txid = Txid::from_str(BITCOIN_TXID).unwrap();
+        assert_eq!(parse_tx(bitcoin_txid, &data()), Err(ElementsError::TxidMismatch {
+            expected: bitcoin_txid,
+            actual: Txid::from_str(LIQUID_TXID).unwrap(),
+        }));
+    }
+
+    #[test]
+    fn invalid_data() {
+        let txid = Txid::from_str(LIQUID_TXID).unwrap();
+        let mut data = data();
+        data.push(0);
+        assert_eq!(parse_tx(txid, &data), Err(ElementsError::TrailingData(1)));
+        data.truncate(100);
+        assert_eq!(parse_tx(txid, &data), Err(ElementsError::UnexpectedEnd));
+    }
+}
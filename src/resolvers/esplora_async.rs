@@ -0,0 +1,361 @@
+// RGB smart contracts for Bitcoin & Lightning
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+// Written in 2019-2023 by
+//     Dr Maxim Orlovsky <orlovsky@lnp-bp.org>
+//
+// Copyright (C) 2019-2023 LNP/BP Standards Association. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::collections::HashMap;
+
+use async_trait::async_trait;
+use bpstd::{Tx, Txid};
+pub use esplora::Error as ResolverError;
+use rgbstd::containers::Consignment;
+use rgbstd::resolvers::ResolveHeight;
+use rgbstd::validation::{ResolveWitness, WitnessResolverError};
+use rgbstd::{Layer1, WitnessAnchor, WitnessOrd, WitnessPos, XChain, XWitnessId, XWitnessTx};
+
+use crate::{multi, ResolveTip, ResolveWitnessTx, ResolverFailure};
+
+/// Asynchronous version of [`ResolveHeight`] and [`ResolveWitness`] traits.
+#[async_trait]
+pub trait ResolveAsync {
+    type Error: std::error::Error;
+
+    async fn resolve_height_async(
+        &self,
+        witness_id: XWitnessId,
+    ) -> Result<WitnessAnchor, Self::Error>;
+
+    async fn resolve_pub_witness_async(
+        &self,
+        witness_id: XWitnessId,
+    ) -> Result<XWitnessTx, WitnessResolverError>;
+}
+
+/// Esplora resolver using non-blocking client.
+///
+/// Consignment validation is synchronous, thus the resolver implements
+/// [`ResolveHeight`] and [`ResolveWitness`] traits using only the data
+/// which were retrieved beforehand with [`Resolver::prefetch`]. This allows
+/// validating consignments without blocking the async runtime.
+pub struct Resolver {
+    esplora_client: esplora::AsyncClient,
+    terminal_txes: HashMap<Txid, Tx>,
+    /// Witness transactions retrieved by [`Resolver::prefetch`], including
+    /// the ones which are not known to the server.
+    witness_txes: HashMap<Txid, Option<Tx>>,
+    anchors: HashMap<Txid, WitnessAnchor>,
+    tip: Option<u32>,
+}
+
+#[allow(clippy::large_enum_variant)]
+#[derive(Debug, Display, Error, From)]
+#[display(doc_comments)]
+pub enum AnchorResolverError {
+    #[from]
+    #[display(inner)]
+    Error(esplora::Error),
+
+    /// witness {0} was not prefetched by the async resolver.
+    NotPrefetched(XWitnessId),
+
+    /// blockchain tip height was not prefetched by the async resolver.
+    TipNotPrefetched,
+
+    /// unsupported layer 1 {0}
+    UnsupportedLayer1(Layer1),
+}
+
+impl ResolverFailure for AnchorResolverError {
+    fn is_transport(&self) -> bool { !matches!(self, AnchorResolverError::UnsupportedLayer1(_)) }
+}
+
+impl Resolver {
+    #[allow(clippy::result_large_err)]
+    pub fn new(url: &str) -> Result<Self, ResolverError> {
+        let esplora_client = esplora::Builder::new(url).build_async()?;
+        Ok(Self {
+            esplora_client,
+            terminal_txes: none!(),
+            witness_txes: none!(),
+            anchors: none!(),
+            tip: None,
+        })
+    }
+
+    pub fn add_witnesses<const TYPE: bool>(&mut self, consignment: &Consignment<TYPE>) {
+        self.terminal_txes.extend(
+            consignment
+                .bundles
+                .iter()
+                .filter_map(|bw| bw.pub_witness.maybe_map_ref(|w| w.tx.clone()))
+                .filter_map(|tx| match tx {
+                    XChain::Bitcoin(tx) => Some(tx),
+                    XChain::Liquid(_) | XChain::Other(_) => None,
+                })
+                .map(|tx| (tx.txid(), tx)),
+        );
+    }
+
+    /// Retrieves all witness transactions and their mining status used by the
+    /// consignment, such that it can be validated without doing any further
+    /// network requests.
+    pub async fn prefetch<const TYPE: bool>(
+        &mut self,
+        consignment: &Consignment<TYPE>,
+    ) -> Result<(), AnchorResolverError> {
+        self.tip = Some(self.esplora_client.height().await?);
+        for bw in &consignment.bundles {
+            let witness_id = bw.witness_id();
+            let XWitnessId::Bitcoin(txid) = witness_id else {
+                return Err(AnchorResolverError::UnsupportedLayer1(witness_id.layer1()));
+            };
+            if self.terminal_txes.contains_key(&txid) || self.anchors.contains_key(&txid) {
+                continue;
+            }
+            let anchor = self.resolve_height_async(witness_id).await?;
+            self.anchors.insert(txid, anchor);
+            let tx = self.esplora_client.tx(&txid).await?;
+            self.witness_txes.insert(txid, tx);
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl ResolveAsync for Resolver {
+    type Error = AnchorResolverError;
+
+    async fn resolve_height_async(
+        &self,
+        witness_id: XWitnessId,
+    ) -> Result<WitnessAnchor, Self::Error> {
+        let XWitnessId::Bitcoin(txid) = witness_id else {
+            return Err(AnchorResolverError::UnsupportedLayer1(witness_id.layer1()));
+        };
+
+        if self.terminal_txes.contains_key(&txid) {
+            return Ok(WitnessAnchor {
+                witness_ord: WitnessOrd::OffChain,
+                witness_id,
+            });
+        }
+
+        let status = self.esplora_client.tx_status(&txid).await?;
+        let ord = match status
+            .block_height
+            .and_then(|h| status.block_time.map(|t| (h, t)))
+        {
+            Some((h, t)) => WitnessOrd::OnChain(
+                WitnessPos::new(h, t as i64).ok_or(esplora::Error::InvalidServerData)?,
+            ),
+            None => WitnessOrd::OffChain,
+        };
+        Ok(WitnessAnchor {
+            witness_ord: ord,
+            witness_id,
+        })
+    }
+
+    async fn resolve_pub_witness_async(
+        &self,
+        witness_id: XWitnessId,
+    ) -> Result<XWitnessTx, WitnessResolverError> {
+        let XWitnessId::Bitcoin(txid) = witness_id else {
+            return Err(WitnessResolverError::Other(
+                witness_id,
+                AnchorResolverError::UnsupportedLayer1(witness_id.layer1()).to_string(),
+            ));
+        };
+
+        if let Some(tx) = self.terminal_txes.get(&txid) {
+            return Ok(XWitnessTx::Bitcoin(tx.clone()));
+        }
+
+        self.esplora_client
+            .tx(&txid)
+            .await
+            .map_err(|err| WitnessResolverError::Other(witness_id, err.to_string()))?
+            .ok_or(WitnessResolverError::Unknown(witness_id))
+            .map(XChain::Bitcoin)
+    }
+}
+
+impl ResolveHeight for Resolver {
+    type Error = AnchorResolverError;
+
+    fn resolve_height(&mut self, witness_id: XWitnessId) -> Result<WitnessAnchor, Self::Error> {
+        let XWitnessId::Bitcoin(txid) = witness_id else {
+            return Err(AnchorResolverError::UnsupportedLayer1(witness_id.layer1()));
+        };
+
+        if self.terminal_txes.contains_key(&txid) {
+            return Ok(WitnessAnchor {
+                witness_ord: WitnessOrd::OffChain,
+                witness_id,
+            });
+        }
+
+        self.anchors
+            .get(&txid)
+            .copied()
+            .ok_or(AnchorResolverError::NotPrefetched(witness_id))
+    }
+}
+
+impl ResolveTip for Resolver {
+    fn resolve_tip(&mut self, layer1: Layer1) -> Result<u32, Self::Error> {
+        if layer1 != Layer1::Bitcoin {
+            return Err(AnchorResolverError::UnsupportedLayer1(layer1));
+        }
+        self.tip.ok_or(AnchorResolverError::TipNotPrefetched)
+    }
+}
+
+impl ResolveWitnessTx for Resolver {
+    fn resolve_witness_tx(
+        &self,
+        witness_id: XWitnessId,
+    ) -> Result<Option<XWitnessTx>, Self::Error> {
+        let XWitnessId::Bitcoin(txid) = witness_id else {
+            return Err(AnchorResolverError::UnsupportedLayer1(witness_id.layer1()));
+        };
+
+        if let Some(tx) = self.terminal_txes.get(&txid) {
+            return Ok(Some(XWitnessTx::Bitcoin(tx.clone())));
+        }
+        self.witness_txes
+            .get(&txid)
+            .map(|tx| tx.clone().map(XWitnessTx::Bitcoin))
+            .ok_or(AnchorResolverError::NotPrefetched(witness_id))
+    }
+}
+
+impl ResolveWitness for Resolver {
+    fn resolve_pub_witness(
+        &self,
+        witness_id: XWitnessId,
+    ) -> Result<XWitnessTx, WitnessResolverError> {
+        multi::resolve_pub_witness(self, witness_id)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::str::FromStr;
+
+    use amplify::hex::FromHex;
+    use bp::ConsensusDecode;
+
+    use super::*;
+
+    // Unsigned transaction with a single input and an empty OP_RETURN output
+    const TX: &str = "02000000011111111111111111111111111111111111111111111111111111111111111111\
+                      0000000000ffffffff010000000000000000016a00000000";
+    const UNKNOWN_TXID: &str = "2222222222222222222222222222222222222222222222222222222222222222";
+    const MISSING_TXID: &str = "3333333333333333333333333333333333333333333333333333333333333333";
+
+    fn tx() -> Tx { Tx::consensus_deserialize(Vec::<u8>::from_hex(TX).unwrap()).unwrap() }
+
+    // Resolver in the state after prefetching a mined witness and a witness
+    // unknown to the server; no requests are made to the server
+    fn resolver() -> Resolver {
+        let mut resolver = Resolver::new("http://127.0.0.1:1").unwrap();
+        let tx = tx();
+        let txid = tx.txid();
+        let unknown = Txid::from_str(UNKNOWN_TXID).unwrap();
+        resolver.witness_txes.insert(txid, Some(tx));
+        resolver.witness_txes.insert(unknown, None);
+        resolver.anchors.insert(txid, WitnessAnchor {
+            witness_ord: WitnessOrd::OnChain(WitnessPos::new(1000, 1_700_000_000).unwrap()),
+            witness_id: XWitnessId::Bitcoin(txid),
+        });
+        resolver.anchors.insert(unknown, WitnessAnchor {
+            witness_ord: WitnessOrd::OffChain,
+            witness_id: XWitnessId::Bitcoin(unknown),
+        });
+        resolver.tip = Some(1010);
+        resolver
+    }
+
+    #[test]
+    fn prefetched_witness() {
+        let mut resolver = resolver();
+        let witness_id = XWitnessId::Bitcoin(tx().txid());
+        assert_eq!(resolver.resolve_pub_witness(witness_id).unwrap(), XWitnessTx::Bitcoin(tx()));
+        assert!(matches!(
+            resolver.resolve_height(witness_id).unwrap().witness_ord,
+            WitnessOrd::OnChain(pos) if u32::from(pos.height()) == 1000
+        ));
+        assert_eq!(resolver.resolve_tip(Layer1::Bitcoin).unwrap(), 1010);
+    }
+
+    #[test]
+    fn unknown_witness() {
+        let mut resolver = resolver();
+        let witness_id = XWitnessId::Bitcoin(Txid::from_str(UNKNOWN_TXID).unwrap());
+        assert!(matches!(
+            resolver.resolve_pub_witness(witness_id),
+            Err(WitnessResolverError::Unknown(id)) if id == witness_id
+        ));
+        assert_eq!(resolver.resolve_height(witness_id).unwrap().witness_ord, WitnessOrd::OffChain);
+    }
+
+    #[test]
+    fn not_prefetched() {
+        let mut resolver = Resolver::new("http://127.0.0.1:1").unwrap();
+        let witness_id = XWitnessId::Bitcoin(Txid::from_str(MISSING_TXID).unwrap());
+        assert!(matches!(
+            resolver.resolve_pub_witness(witness_id),
+            Err(WitnessResolverError::Other(id, _)) if id == witness_id
+        ));
+        assert!(matches!(
+            resolver.resolve_height(witness_id),
+            Err(AnchorResolverError::NotPrefetched(id)) if id == witness_id
+        ));
+        assert!(matches!(
+            resolver.resolve_tip(Layer1::Bitcoin),
+            Err(AnchorResolverError::TipNotPrefetched)
+        ));
+    }
+
+    #[test]
+    fn terminal_witness() {
+        let mut resolver = Resolver::new("http://127.0.0.1:1").unwrap();
+        let tx = tx();
+        let witness_id = XWitnessId::Bitcoin(tx.txid());
+        resolver.terminal_txes.insert(tx.txid(), tx.clone());
+        assert_eq!(resolver.resolve_pub_witness(witness_id).unwrap(), XWitnessTx::Bitcoin(tx));
+        assert_eq!(resolver.resolve_height(witness_id).unwrap().witness_ord, WitnessOrd::OffChain);
+    }
+
+    #[test]
+    fn unsupported_layer1() {
+        let mut resolver = resolver();
+        let witness_id = XWitnessId::Liquid(tx().txid());
+        assert!(matches!(
+            resolver.resolve_height(witness_id),
+            Err(AnchorResolverError::UnsupportedLayer1(Layer1::Liquid))
+        ));
+        assert!(resolver.resolve_pub_witness(witness_id).is_err());
+        assert!(matches!(
+            resolver.resolve_tip(Layer1::Liquid),
+            Err(AnchorResolverError::UnsupportedLayer1(Layer1::Liquid))
+        ));
+    }
+}
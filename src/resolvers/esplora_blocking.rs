@@ -20,17 +20,30 @@
 // limitations under the License.
 
 use std::collections::HashMap;
+use std::str::FromStr;
 
-use bpstd::{Tx, Txid};
+use bp::ConsensusEncode;
+use bpstd::{Network, Tx, Txid};
 pub use esplora::Error as ResolverError;
 use rgbstd::containers::Consignment;
 use rgbstd::resolvers::ResolveHeight;
 use rgbstd::validation::{ResolveWitness, WitnessResolverError};
 use rgbstd::{Layer1, WitnessAnchor, WitnessOrd, WitnessPos, XChain, XWitnessId, XWitnessTx};
 
+use crate::elements::{self, ElementsError};
+use crate::spv::{self, BlockHeader, MerkleBranch, SpvError, SpvParams, SPV_CHAIN_DEPTH};
+use crate::{multi, ResolveTip, ResolveWitnessTx, ResolverFailure};
+
+const HTTP_NOT_FOUND: i32 = 404;
+
+/// Esplora resolver, which may work either with bitcoin or liquid esplora
+/// servers.
 pub struct Resolver {
     esplora_client: esplora::BlockingClient,
+    url: String,
+    layer1: Layer1,
     terminal_txes: HashMap<Txid, Tx>,
+    spv: Option<SpvParams>,
 }
 
 #[allow(clippy::large_enum_variant)]
@@ -41,40 +54,199 @@ pub enum AnchorResolverError {
     #[display(inner)]
     Error(esplora::Error),
 
+    /// SPV verification has failed: {0}
+    #[from]
+    Spv(SpvError),
+
     /// unsupported layer 1 {0}
     UnsupportedLayer1(Layer1),
+
+    /// unable to retrieve liquid transaction. Details: {0}
+    #[from]
+    Connection(minreq::Error),
+
+    /// esplora server responded with HTTP status {0}.
+    Http(i32),
+
+    #[from]
+    #[display(inner)]
+    Elements(ElementsError),
+}
+
+impl ResolverFailure for AnchorResolverError {
+    fn is_transport(&self) -> bool {
+        matches!(
+            self,
+            AnchorResolverError::Error(_) |
+                AnchorResolverError::Connection(_) |
+                AnchorResolverError::Http(_)
+        )
+    }
 }
 
 impl Resolver {
     #[allow(clippy::result_large_err)]
-    pub fn new(url: &str) -> Result<Self, ResolverError> {
+    pub fn new(url: &str) -> Result<Self, ResolverError> { Self::with_layer1(url, Layer1::Bitcoin) }
+
+    /// Constructs resolver for esplora server indexing the given layer 1.
+    ///
+    /// Liquid transactions are retrieved in their raw form and converted into
+    /// bitcoin transactions (see [`elements`] module).
+    #[allow(clippy::result_large_err)]
+    pub fn with_layer1(url: &str, layer1: Layer1) -> Result<Self, ResolverError> {
         let esplora_client = esplora::Builder::new(url).build_blocking()?;
         Ok(Self {
             esplora_client,
+            url: url.trim_end_matches('/').to_owned(),
+            layer1,
             terminal_txes: none!(),
+            spv: None,
         })
     }
 
+    /// Enables SPV mode for the given network, in which the resolver doesn't
+    /// trust the server regarding the transaction mining status, and verifies
+    /// merkle proofs, block headers and the block height committed by the
+    /// coinbase transaction locally against the network parameters (see
+    /// [`SpvParams`]). Transactions reported by the server as not mined are
+    /// treated as such, while a proof failing the verification is returned as
+    /// [`AnchorResolverError::Spv`] error.
+    ///
+    /// SPV mode is available only for bitcoin, since liquid blocks are not
+    /// secured by proof of work.
+    pub fn with_spv(mut self, network: Option<Network>) -> Self {
+        self.spv = network.map(SpvParams::with);
+        self
+    }
+
+    fn spv_pos(
+        &self,
+        txid: Txid,
+        params: &SpvParams,
+    ) -> Result<Option<WitnessPos>, AnchorResolverError> {
+        if self.layer1 != Layer1::Bitcoin {
+            return Err(AnchorResolverError::UnsupportedLayer1(self.layer1));
+        }
+        let Some(proof) = self.esplora_client.tx_merkle_proof(&txid)? else {
+            return Ok(None);
+        };
+        let pos = u32::try_from(proof.pos).map_err(|_| SpvError::InvalidProof)?;
+        let branch = merkle_branch(pos, &proof.merkle)?;
+
+        // the coinbase transaction is not verified to be the block one here:
+        // this is done by `spv::verify_witness` against the block header
+        let block_hash = self.esplora_client.block_hash(proof.block_height)?;
+        let coinbase_txid = self
+            .raw(&format!("block/{block_hash}/txid/0"))?
+            .and_then(|data| Txid::from_str(String::from_utf8(data).ok()?.trim()).ok())
+            .ok_or(SpvError::InvalidProof)?;
+        let coinbase_proof = self
+            .esplora_client
+            .tx_merkle_proof(&coinbase_txid)?
+            .ok_or(SpvError::InvalidProof)?;
+        let coinbase_branch = merkle_branch(0, &coinbase_proof.merkle)?;
+        let coinbase = self
+            .raw(&format!("tx/{coinbase_txid}/raw"))?
+            .ok_or(SpvError::InvalidProof)?;
+
+        let tip = self.esplora_client.height()?;
+        let last = tip.min(proof.block_height + SPV_CHAIN_DEPTH - 1);
+        let mut headers = Vec::with_capacity(SPV_CHAIN_DEPTH as usize);
+        for height in proof.block_height..=last {
+            let block_hash = self.esplora_client.block_hash(height)?;
+            let header = self.esplora_client.header_by_hash(&block_hash)?;
+            headers.push(BlockHeader::from_slice(&header.consensus_serialize())?);
+        }
+        spv::verify_witness(
+            txid,
+            &branch,
+            &coinbase,
+            &coinbase_branch,
+            &headers,
+            proof.block_height,
+            params,
+        )?;
+
+        let time = headers[0].time() as i64;
+        WitnessPos::new(proof.block_height, time)
+            .map(Some)
+            .ok_or(AnchorResolverError::Spv(SpvError::InvalidHeader))
+    }
+
+    /// Retrieves raw data from the given path of the esplora server API,
+    /// returning `None` if the server doesn't know the requested object.
+    fn raw(&self, path: &str) -> Result<Option<Vec<u8>>, AnchorResolverError> {
+        let response = minreq::get(format!("{}/{path}", self.url)).send()?;
+        match response.status_code {
+            200 => Ok(Some(response.into_bytes())),
+            HTTP_NOT_FOUND => Ok(None),
+            status => Err(AnchorResolverError::Http(status)),
+        }
+    }
+
+    /// Retrieves liquid transaction, which can't be decoded by the esplora
+    /// client, since it uses the Elements transaction format.
+    fn liquid_tx(&self, txid: Txid) -> Result<Option<Tx>, AnchorResolverError> {
+        let Some(data) = self.raw(&format!("tx/{txid}/raw"))? else {
+            return Ok(None);
+        };
+        Ok(Some(elements::parse_tx(txid, &data)?))
+    }
+
+    pub fn layer1(&self) -> Layer1 { self.layer1 }
+
     pub fn add_witnesses<const TYPE: bool>(&mut self, consignment: &Consignment<TYPE>) {
-        self.terminal_txes.extend(
-            consignment
-                .bundles
-                .iter()
-                .filter_map(|bw| bw.pub_witness.maybe_map_ref(|w| w.tx.clone()))
-                .filter_map(|tx| match tx {
-                    XChain::Bitcoin(tx) => Some(tx),
-                    XChain::Liquid(_) | XChain::Other(_) => None,
-                })
-                .map(|tx| (tx.txid(), tx)),
-        );
+        self.add_terminal_txes(consignment.bundles.iter().filter_map(|bw| {
+            let tx = bw.pub_witness.maybe_map_ref(|w| w.tx.clone())?;
+            Some((bw.witness_id(), tx))
+        }));
     }
+
+    /// Adds terminal transactions under their witness ids. Bitcoin
+    /// transactions not matching their ids are ignored; ids of the liquid
+    /// transactions can't be computed from their bitcoin representation (see
+    /// [`elements`] module), thus they are taken as declared.
+    fn add_terminal_txes(&mut self, txes: impl IntoIterator<Item = (XWitnessId, XWitnessTx)>) {
+        for (witness_id, tx) in txes {
+            let Some(txid) = self.witness_txid(witness_id) else {
+                continue;
+            };
+            match (witness_id, tx) {
+                (XChain::Bitcoin(_), XChain::Bitcoin(tx)) if tx.txid() == txid => {
+                    self.terminal_txes.insert(txid, tx);
+                }
+                (XChain::Liquid(_), XChain::Liquid(tx)) => {
+                    self.terminal_txes.insert(txid, tx);
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Returns transaction id if the witness belongs to the layer 1 indexed
+    /// by the server.
+    fn witness_txid(&self, witness_id: XWitnessId) -> Option<Txid> {
+        match witness_id {
+            XChain::Bitcoin(txid) | XChain::Liquid(txid) if witness_id.layer1() == self.layer1 => {
+                Some(txid)
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Converts merkle branch returned by the esplora client, which represents
+/// the hashes as transaction ids.
+fn merkle_branch(pos: u32, merkle: &[Txid]) -> Result<MerkleBranch, SpvError> {
+    let merkle = merkle.iter().map(Txid::to_string).collect::<Vec<_>>();
+    MerkleBranch::from_display_hex(pos, merkle.iter().map(String::as_str))
 }
 
 impl ResolveHeight for Resolver {
     type Error = AnchorResolverError;
 
     fn resolve_height(&mut self, witness_id: XWitnessId) -> Result<WitnessAnchor, Self::Error> {
-        let XWitnessId::Bitcoin(txid) = witness_id else {
+        let Some(txid) = self.witness_txid(witness_id) else {
             return Err(AnchorResolverError::UnsupportedLayer1(witness_id.layer1()));
         };
 
@@ -85,6 +257,17 @@ impl ResolveHeight for Resolver {
             });
         }
 
+        if let Some(params) = self.spv {
+            let witness_ord = match self.spv_pos(txid, &params)? {
+                Some(pos) => WitnessOrd::OnChain(pos),
+                None => WitnessOrd::OffChain,
+            };
+            return Ok(WitnessAnchor {
+                witness_ord,
+                witness_id,
+            });
+        }
+
         let status = self.esplora_client.tx_status(&txid)?;
         let ord = match status
             .block_height
@@ -102,26 +285,95 @@ impl ResolveHeight for Resolver {
     }
 }
 
-impl ResolveWitness for Resolver {
-    fn resolve_pub_witness(
+impl ResolveTip for Resolver {
+    fn resolve_tip(&mut self, layer1: Layer1) -> Result<u32, Self::Error> {
+        if layer1 != self.layer1 {
+            return Err(AnchorResolverError::UnsupportedLayer1(layer1));
+        }
+        Ok(self.esplora_client.height()?)
+    }
+
+    fn supports(&self, layer1: Layer1) -> bool { layer1 == self.layer1 }
+}
+
+impl ResolveWitnessTx for Resolver {
+    fn resolve_witness_tx(
         &self,
         witness_id: XWitnessId,
-    ) -> Result<XWitnessTx, WitnessResolverError> {
-        let XWitnessId::Bitcoin(txid) = witness_id else {
-            return Err(WitnessResolverError::Other(
-                witness_id,
-                AnchorResolverError::UnsupportedLayer1(witness_id.layer1()).to_string(),
-            ));
+    ) -> Result<Option<XWitnessTx>, Self::Error> {
+        let Some(txid) = self.witness_txid(witness_id) else {
+            return Err(AnchorResolverError::UnsupportedLayer1(witness_id.layer1()));
         };
 
         if let Some(tx) = self.terminal_txes.get(&txid) {
-            return Ok(XWitnessTx::Bitcoin(tx.clone()));
+            return Ok(Some(XWitnessTx::with(self.layer1, tx.clone())));
         }
 
-        self.esplora_client
-            .tx(&txid)
-            .map_err(|err| WitnessResolverError::Other(witness_id, err.to_string()))?
-            .ok_or(WitnessResolverError::Unknown(witness_id))
-            .map(XChain::Bitcoin)
+        let tx = match self.layer1 {
+            Layer1::Liquid => self.liquid_tx(txid)?,
+            _ => self.esplora_client.tx(&txid)?,
+        };
+        Ok(tx.map(|tx| XChain::with(self.layer1, tx)))
+    }
+}
+
+impl ResolveWitness for Resolver {
+    fn resolve_pub_witness(
+        &self,
+        witness_id: XWitnessId,
+    ) -> Result<XWitnessTx, WitnessResolverError> {
+        multi::resolve_pub_witness(self, witness_id)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use amplify::hex::FromHex;
+    use bp::ConsensusDecode;
+
+    use super::*;
+
+    // Unsigned transaction with a single input and an empty OP_RETURN output
+    const TX: &str = "02000000011111111111111111111111111111111111111111111111111111111111111111\
+                      0000000000ffffffff010000000000000000016a00000000";
+    // Id of the liquid transaction, which is represented by the bitcoin one
+    const LIQUID_TXID: &str = "bef91a67dbb1ebdf4f06f89f13591f9919091855de83caff5775d0c1a85b19d1";
+
+    #[test]
+    fn liquid_terminal_witness() {
+        // Server is not reachable, thus the witness can be resolved only from
+        // the terminal transactions
+        let mut resolver = Resolver::with_layer1("http://127.0.0.1:1", Layer1::Liquid).unwrap();
+        let tx = Tx::consensus_deserialize(Vec::<u8>::from_hex(TX).unwrap()).unwrap();
+        let witness_id = XWitnessId::Liquid(Txid::from_str(LIQUID_TXID).unwrap());
+        assert_ne!(XWitnessId::Liquid(tx.txid()), witness_id);
+        resolver.add_terminal_txes([
+            (witness_id, XChain::Liquid(tx.clone())),
+            // witnesses on other layers are ignored
+            (XWitnessId::Bitcoin(tx.txid()), XChain::Bitcoin(tx.clone())),
+        ]);
+
+        assert_eq!(resolver.resolve_pub_witness(witness_id).unwrap(), XChain::Liquid(tx));
+        assert_eq!(resolver.resolve_height(witness_id).unwrap().witness_ord, WitnessOrd::OffChain);
+        assert_eq!(resolver.terminal_txes.len(), 1);
+    }
+
+    #[test]
+    fn bitcoin_terminal_witness() {
+        let mut resolver = Resolver::with_layer1("http://127.0.0.1:1", Layer1::Bitcoin).unwrap();
+        let tx = Tx::consensus_deserialize(Vec::<u8>::from_hex(TX).unwrap()).unwrap();
+        let witness_id = XWitnessId::Bitcoin(tx.txid());
+        let forged = Txid::from_str(LIQUID_TXID).unwrap();
+        let forged_id = XWitnessId::Bitcoin(forged);
+        resolver.add_terminal_txes([
+            (witness_id, XChain::Bitcoin(tx.clone())),
+            // transactions not matching their witness ids are ignored
+            (forged_id, XChain::Bitcoin(tx.clone())),
+        ]);
+
+        assert_eq!(resolver.resolve_pub_witness(witness_id).unwrap(), XChain::Bitcoin(tx));
+        assert_eq!(resolver.terminal_txes.len(), 1);
+        assert!(!resolver.terminal_txes.contains_key(&forged));
+        assert!(resolver.resolve_height(forged_id).is_err());
     }
 }
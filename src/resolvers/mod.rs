@@ -19,12 +19,23 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-#[cfg(any(feature = "electrum", feature = "esplora_blocking"))]
 mod any;
+mod caching;
+pub mod elements;
+pub mod multi;
+pub mod offline;
+pub mod spv;
 #[cfg(feature = "esplora_blocking")]
 pub mod esplora_blocking;
+#[cfg(feature = "esplora_async")]
+pub mod esplora_async;
 #[cfg(feature = "electrum")]
 pub mod electrum;
+#[cfg(feature = "bitcoind_rpc")]
+pub mod bitcoind_rpc;
 
-#[cfg(any(feature = "electrum", feature = "esplora_blocking"))]
 pub use any::{AnyResolver, AnyResolverError};
+pub use caching::{CacheError, CachingResolver, ResolveTip, DEFAULT_CACHE_DEPTH};
+pub use multi::{MultiResolver, ResolveWitnessTx, ResolverFailure};
+#[cfg(feature = "esplora_async")]
+pub use esplora_async::ResolveAsync;
@@ -0,0 +1,603 @@
+// RGB smart contracts for Bitcoin & Lightning
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+// Written in 2024 by
+//     Zoe Faltibà <zoefaltiba@gmail.com>
+//
+// Copyright (C) 2024 LNP/BP Standards Association. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::error::Error;
+
+use rgbstd::containers::Consignment;
+use rgbstd::resolvers::ResolveHeight;
+use rgbstd::validation::{ResolveWitness, WitnessResolverError};
+use rgbstd::{Layer1, WitnessAnchor, XChain, XWitnessId, XWitnessTx};
+
+use crate::resolvers::any::AnyAnchorResolverError;
+use crate::{AnyResolver, ResolveTip};
+
+/// Resolver errors distinguishing failures to reach the source of the data
+/// from the answers of the source.
+pub trait ResolverFailure {
+    /// Whether the error is caused by the source of the data being unreachable
+    /// (like a network, I/O or server failure), rather than by the source
+    /// providing invalid data.
+    fn is_transport(&self) -> bool;
+}
+
+/// Resolvers of witness transactions reporting failures with their own error
+/// type, which, unlike [`WitnessResolverError`], allows to tell the failures
+/// apart (see [`ResolverFailure`]).
+pub trait ResolveWitnessTx: ResolveHeight {
+    /// Resolves witness transaction, returning `None` if it is not known to
+    /// the resolver.
+    ///
+    /// Liquid transactions are converted into bitcoin ones, which have
+    /// different ids (see [`crate::elements`]), thus the resolvers must verify
+    /// their ids against the original data.
+    fn resolve_witness_tx(
+        &self,
+        witness_id: XWitnessId,
+    ) -> Result<Option<XWitnessTx>, Self::Error>;
+}
+
+/// Implements [`ResolveWitness::resolve_pub_witness`] for the resolver,
+/// reporting its errors as strings.
+pub(crate) fn resolve_pub_witness<R: ResolveWitnessTx>(
+    resolver: &R,
+    witness_id: XWitnessId,
+) -> Result<XWitnessTx, WitnessResolverError> {
+    resolver
+        .resolve_witness_tx(witness_id)
+        .map_err(|err| WitnessResolverError::Other(witness_id, err.to_string()))?
+        .ok_or(WitnessResolverError::Unknown(witness_id))
+}
+
+/// Resolver combining several indexers.
+///
+/// With quorum of one (or zero) the resolvers are queried in order, and the
+/// next one is used only if the previous has failed to respond (see
+/// [`ResolverFailure::is_transport`]). Answers of a resolver, including the
+/// witness being unknown or the data being invalid, are final. With a larger
+/// quorum the resolvers are queried until the required number of them have
+/// returned the same data; failed resolvers are not counted. This prevents a
+/// single lying or flaky indexer from making the wallet accept an invalid
+/// consignment. Resolvers which are not indexers (like the offline one, which
+/// serves unverified local data) never vote when the quorum is larger than
+/// one.
+///
+/// Each witness is resolved only by the resolvers supporting its layer 1 (see
+/// [`ResolveTip::supports`]), thus the quorum must be reachable by the
+/// resolvers of each of the layers separately.
+pub struct MultiResolver<R = AnyResolver> {
+    resolvers: Vec<R>,
+    quorum: usize,
+}
+
+#[derive(Debug, Display, Error)]
+#[display(doc_comments)]
+pub enum AnchorResolverError<E: Error = AnyAnchorResolverError> {
+    /// no resolvers were provided to the multi-resolver.
+    NoResolvers,
+
+    /// all resolvers have failed; last error: {0}
+    AllFailed(Box<E>),
+
+    /// {0}
+    Failed(Box<E>),
+
+    /// resolver has returned transaction which is not witness {0}.
+    WitnessMismatch(XWitnessId),
+
+    /// only {agreed} resolvers agreed on the data for witness {witness_id},
+    /// while {quorum} are required.
+    QuorumNotReached {
+        witness_id: XWitnessId,
+        agreed: usize,
+        quorum: usize,
+    },
+
+    /// only {agreed} resolvers have reported blockchain tip height, while
+    /// {quorum} are required.
+    TipQuorumNotReached { agreed: usize, quorum: usize },
+}
+
+impl<E: Error + ResolverFailure> ResolverFailure for AnchorResolverError<E> {
+    fn is_transport(&self) -> bool {
+        match self {
+            AnchorResolverError::NoResolvers => true,
+            AnchorResolverError::AllFailed(err) => err.is_transport(),
+            AnchorResolverError::Failed(_) |
+            AnchorResolverError::WitnessMismatch(_) |
+            AnchorResolverError::QuorumNotReached { .. } |
+            AnchorResolverError::TipQuorumNotReached { .. } => false,
+        }
+    }
+}
+
+/// Registers a vote for the value, returning number of votes it has got.
+fn most_agreed<T: Eq>(votes: &mut Vec<(T, usize)>, value: T) -> usize {
+    match votes.iter_mut().find(|(v, _)| *v == value) {
+        Some((_, count)) => {
+            *count += 1;
+            *count
+        }
+        None => {
+            votes.push((value, 1));
+            1
+        }
+    }
+}
+
+impl<R> MultiResolver<R> {
+    pub fn new(resolvers: Vec<R>, quorum: usize) -> Self { Self { resolvers, quorum } }
+
+    pub fn quorum(&self) -> usize { self.quorum }
+
+    pub fn resolvers(&self) -> &[R] { &self.resolvers }
+}
+
+impl MultiResolver {
+    pub fn add_witnesses<const TYPE: bool>(&mut self, consignment: &Consignment<TYPE>) {
+        for resolver in &mut self.resolvers {
+            resolver.add_terminals(consignment);
+        }
+    }
+
+    /// Prefetches witness data for all async resolvers, failing only if none
+    /// of them has succeeded.
+    #[cfg(feature = "esplora_async")]
+    pub async fn prefetch<const TYPE: bool>(
+        &mut self,
+        consignment: &Consignment<TYPE>,
+    ) -> Result<(), AnyAnchorResolverError> {
+        let mut last_err = None;
+        let mut succeeded = false;
+        for resolver in &mut self.resolvers {
+            if let AnyResolver::EsploraAsync(inner) = resolver {
+                match inner.prefetch(consignment).await {
+                    Ok(()) => succeeded = true,
+                    Err(err) => last_err = Some(err.into()),
+                }
+            }
+        }
+        match last_err {
+            Some(err) if !succeeded => Err(err),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl<E: Error> AnchorResolverError<E> {
+    fn all_failed(err: Option<E>) -> Self {
+        match err {
+            Some(err) => AnchorResolverError::AllFailed(Box::new(err)),
+            None => AnchorResolverError::NoResolvers,
+        }
+    }
+}
+
+impl<R: ResolveTip> ResolveHeight for MultiResolver<R>
+where R::Error: ResolverFailure
+{
+    type Error = AnchorResolverError<R::Error>;
+
+    fn resolve_height(&mut self, witness_id: XWitnessId) -> Result<WitnessAnchor, Self::Error> {
+        let quorum = self.quorum.max(1);
+        let mut votes = Vec::<(WitnessAnchor, usize)>::new();
+        let mut agreed = 0;
+        let mut last_err = None;
+        let layer1 = witness_id.layer1();
+        for resolver in &mut self.resolvers {
+            if !resolver.supports(layer1) || (quorum > 1 && !resolver.is_indexer()) {
+                continue;
+            }
+            match resolver.resolve_height(witness_id) {
+                Ok(anchor) => {
+                    let count = most_agreed(&mut votes, anchor);
+                    agreed = agreed.max(count);
+                    if count >= quorum {
+                        return Ok(anchor);
+                    }
+                }
+                Err(err) if quorum == 1 && !err.is_transport() => {
+                    return Err(AnchorResolverError::Failed(Box::new(err)));
+                }
+                Err(err) => last_err = Some(err),
+            }
+        }
+        if votes.is_empty() {
+            return Err(AnchorResolverError::all_failed(last_err));
+        }
+        Err(AnchorResolverError::QuorumNotReached {
+            witness_id,
+            agreed,
+            quorum,
+        })
+    }
+}
+
+impl<R: ResolveTip> ResolveTip for MultiResolver<R>
+where R::Error: ResolverFailure
+{
+    /// Returns the lowest tip height among the resolvers which have
+    /// responded, which is the conservative choice for computing
+    /// confirmation depth.
+    fn resolve_tip(&mut self, layer1: Layer1) -> Result<u32, Self::Error> {
+        let quorum = self.quorum.max(1);
+        let mut tips = Vec::with_capacity(quorum);
+        let mut last_err = None;
+        for resolver in &mut self.resolvers {
+            if !resolver.supports(layer1) {
+                continue;
+            }
+            match resolver.resolve_tip(layer1) {
+                Ok(tip) => tips.push(tip),
+                Err(err) if quorum == 1 && !err.is_transport() => {
+                    return Err(AnchorResolverError::Failed(Box::new(err)));
+                }
+                Err(err) => last_err = Some(err),
+            }
+            if tips.len() >= quorum {
+                break;
+            }
+        }
+        if tips.is_empty() {
+            return Err(AnchorResolverError::all_failed(last_err));
+        }
+        if tips.len() < quorum {
+            return Err(AnchorResolverError::TipQuorumNotReached {
+                agreed: tips.len(),
+                quorum,
+            });
+        }
+        Ok(tips.into_iter().min().expect("non-empty tips"))
+    }
+
+    /// Since it is not known which of the resolvers has provided the data, all
+    /// of them must be indexers.
+    fn is_indexer(&self) -> bool { self.resolvers.iter().all(R::is_indexer) }
+
+    fn supports(&self, layer1: Layer1) -> bool {
+        self.resolvers.iter().any(|resolver| resolver.supports(layer1))
+    }
+}
+
+/// Checks that the transaction returned by a resolver is the witness one. Ids
+/// of liquid transactions can't be computed from their bitcoin representation
+/// and are verified by the resolvers (see [`ResolveWitnessTx`]), thus only
+/// their layer 1 is checked here.
+fn is_witness(witness_id: XWitnessId, tx: &XWitnessTx) -> bool {
+    match (witness_id, tx) {
+        (XChain::Bitcoin(txid), XChain::Bitcoin(tx)) => tx.txid() == txid,
+        (XChain::Liquid(_), XChain::Liquid(_)) => true,
+        _ => false,
+    }
+}
+
+impl<R: ResolveTip + ResolveWitnessTx> ResolveWitnessTx for MultiResolver<R>
+where R::Error: ResolverFailure
+{
+    /// Transactions not matching the witness id are treated as invalid data
+    /// provided by the resolver. With a quorum larger than one, the witness
+    /// being unknown is an answer which has to be agreed on as well.
+    fn resolve_witness_tx(
+        &self,
+        witness_id: XWitnessId,
+    ) -> Result<Option<XWitnessTx>, Self::Error> {
+        let quorum = self.quorum.max(1);
+        let mut votes = Vec::<(Option<XWitnessTx>, usize)>::new();
+        let mut agreed = 0;
+        let mut last_err = None;
+        let mut mismatch = false;
+        let layer1 = witness_id.layer1();
+        for resolver in &self.resolvers {
+            if !resolver.supports(layer1) || (quorum > 1 && !resolver.is_indexer()) {
+                continue;
+            }
+            let tx = match resolver.resolve_witness_tx(witness_id) {
+                Ok(Some(tx)) if !is_witness(witness_id, &tx) => {
+                    if quorum == 1 {
+                        return Err(AnchorResolverError::WitnessMismatch(witness_id));
+                    }
+                    mismatch = true;
+                    continue;
+                }
+                Ok(tx) => tx,
+                Err(err) if quorum == 1 && !err.is_transport() => {
+                    return Err(AnchorResolverError::Failed(Box::new(err)));
+                }
+                Err(err) => {
+                    last_err = Some(err);
+                    continue;
+                }
+            };
+            let count = most_agreed(&mut votes, tx.clone());
+            agreed = agreed.max(count);
+            if count >= quorum {
+                return Ok(tx);
+            }
+        }
+        if votes.is_empty() {
+            if mismatch {
+                return Err(AnchorResolverError::WitnessMismatch(witness_id));
+            }
+            return Err(AnchorResolverError::all_failed(last_err));
+        }
+        Err(AnchorResolverError::QuorumNotReached {
+            witness_id,
+            agreed,
+            quorum,
+        })
+    }
+}
+
+impl<R: ResolveTip + ResolveWitnessTx> ResolveWitness for MultiResolver<R>
+where R::Error: ResolverFailure
+{
+    fn resolve_pub_witness(
+        &self,
+        witness_id: XWitnessId,
+    ) -> Result<XWitnessTx, WitnessResolverError> {
+        resolve_pub_witness(self, witness_id)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::cell::Cell;
+
+    use amplify::hex::FromHex;
+    use bp::ConsensusDecode;
+    use bpstd::Tx;
+    use rgbstd::{WitnessOrd, WitnessPos};
+
+    use super::*;
+
+    #[derive(Copy, Clone, Debug, Display, Error)]
+    #[display(doc_comments)]
+    enum MockError {
+        /// resolver is unreachable.
+        Down,
+
+        /// resolver has provided invalid data.
+        Invalid,
+    }
+
+    impl ResolverFailure for MockError {
+        fn is_transport(&self) -> bool { matches!(self, MockError::Down) }
+    }
+
+    #[derive(Copy, Clone)]
+    enum Mock {
+        Down,
+        Invalid,
+        Unknown,
+        Mined(u32),
+    }
+
+    struct MockResolver {
+        mock: Mock,
+        requests: Cell<usize>,
+    }
+
+    impl MockResolver {
+        fn request(&self) -> Mock {
+            self.requests.set(self.requests.get() + 1);
+            self.mock
+        }
+    }
+
+    impl ResolveHeight for MockResolver {
+        type Error = MockError;
+
+        fn resolve_height(&mut self, witness_id: XWitnessId) -> Result<WitnessAnchor, Self::Error> {
+            let witness_ord = match self.request() {
+                Mock::Down => return Err(MockError::Down),
+                Mock::Invalid => return Err(MockError::Invalid),
+                Mock::Unknown => WitnessOrd::OffChain,
+                Mock::Mined(height) => {
+                    WitnessOrd::OnChain(WitnessPos::new(height, 1_700_000_000).unwrap())
+                }
+            };
+            Ok(WitnessAnchor {
+                witness_ord,
+                witness_id,
+            })
+        }
+    }
+
+    impl ResolveTip for MockResolver {
+        fn resolve_tip(&mut self, _: Layer1) -> Result<u32, Self::Error> {
+            match self.request() {
+                Mock::Down => Err(MockError::Down),
+                Mock::Invalid => Err(MockError::Invalid),
+                Mock::Unknown | Mock::Mined(_) => Ok(1000),
+            }
+        }
+
+        fn supports(&self, _: Layer1) -> bool { true }
+    }
+
+    impl ResolveWitnessTx for MockResolver {
+        fn resolve_witness_tx(
+            &self,
+            witness_id: XWitnessId,
+        ) -> Result<Option<XWitnessTx>, Self::Error> {
+            match (self.request(), witness_id) {
+                (Mock::Down, _) => Err(MockError::Down),
+                // Liquid resolvers verify transaction ids themselves
+                (Mock::Invalid, XChain::Liquid(_)) => Err(MockError::Invalid),
+                (Mock::Invalid, _) => Ok(Some(XChain::Bitcoin(tx(2)))),
+                (Mock::Unknown, _) => Ok(None),
+                (Mock::Mined(_), _) => Ok(Some(XChain::with(witness_id.layer1(), tx(1)))),
+            }
+        }
+    }
+
+    // Unsigned transaction with a single input and an empty OP_RETURN output,
+    // which spends an output of a transaction with id made of `no` bytes
+    fn tx(no: u8) -> Tx {
+        let hex = format!(
+            "0200000001{}0000000000ffffffff010000000000000000016a00000000",
+            format!("{no:02x}").repeat(32)
+        );
+        Tx::consensus_deserialize(Vec::<u8>::from_hex(&hex).unwrap()).unwrap()
+    }
+
+    fn witness_id() -> XWitnessId { XWitnessId::Bitcoin(tx(1).txid()) }
+
+    fn multi(mocks: &[Mock], quorum: usize) -> MultiResolver<MockResolver> {
+        let resolvers = mocks
+            .iter()
+            .map(|mock| MockResolver {
+                mock: *mock,
+                requests: Cell::new(0),
+            })
+            .collect();
+        MultiResolver::new(resolvers, quorum)
+    }
+
+    fn requests(multi: &MultiResolver<MockResolver>) -> Vec<usize> {
+        multi.resolvers().iter().map(|r| r.requests.get()).collect()
+    }
+
+    fn height(anchor: WitnessAnchor) -> Option<u32> {
+        match anchor.witness_ord {
+            WitnessOrd::OnChain(pos) => Some(u32::from(pos.height())),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn primary_down() {
+        let mut resolver = multi(&[Mock::Down, Mock::Mined(900)], 1);
+        assert_eq!(height(resolver.resolve_height(witness_id()).unwrap()), Some(900));
+        assert_eq!(resolver.resolve_pub_witness(witness_id()).unwrap(), XChain::Bitcoin(tx(1)));
+        assert_eq!(resolver.resolve_tip(Layer1::Bitcoin).unwrap(), 1000);
+        assert_eq!(requests(&resolver), vec![3, 3]);
+    }
+
+    #[test]
+    fn primary_answer() {
+        let mut resolver = multi(&[Mock::Mined(900), Mock::Down], 0);
+        assert_eq!(height(resolver.resolve_height(witness_id()).unwrap()), Some(900));
+        assert_eq!(requests(&resolver), vec![1, 0]);
+    }
+
+    #[test]
+    fn no_fallback_on_unknown() {
+        let mut resolver = multi(&[Mock::Unknown, Mock::Mined(900)], 1);
+        assert_eq!(height(resolver.resolve_height(witness_id()).unwrap()), None);
+        assert!(matches!(
+            resolver.resolve_pub_witness(witness_id()),
+            Err(WitnessResolverError::Unknown(_))
+        ));
+        assert_eq!(requests(&resolver), vec![2, 0]);
+    }
+
+    #[test]
+    fn no_fallback_on_invalid() {
+        let mut resolver = multi(&[Mock::Invalid, Mock::Mined(900)], 1);
+        assert!(matches!(
+            resolver.resolve_height(witness_id()),
+            Err(AnchorResolverError::Failed(err)) if matches!(*err, MockError::Invalid)
+        ));
+        assert!(matches!(
+            resolver.resolve_witness_tx(witness_id()),
+            Err(AnchorResolverError::WitnessMismatch(id)) if id == witness_id()
+        ));
+        assert!(matches!(
+            resolver.resolve_pub_witness(witness_id()),
+            Err(WitnessResolverError::Other(..))
+        ));
+        assert!(matches!(
+            resolver.resolve_tip(Layer1::Bitcoin),
+            Err(AnchorResolverError::Failed(_))
+        ));
+        assert_eq!(requests(&resolver), vec![4, 0]);
+    }
+
+    #[test]
+    fn liquid_witness() {
+        let witness_id = XWitnessId::Liquid(tx(3).txid());
+        let resolver = multi(&[Mock::Down, Mock::Mined(900)], 1);
+        assert_eq!(resolver.resolve_pub_witness(witness_id).unwrap(), XChain::Liquid(tx(1)));
+
+        // Liquid transaction failing id verification is reported, not skipped
+        let resolver = multi(&[Mock::Invalid, Mock::Mined(900)], 1);
+        assert!(matches!(
+            resolver.resolve_witness_tx(witness_id),
+            Err(AnchorResolverError::Failed(err)) if matches!(*err, MockError::Invalid)
+        ));
+        assert_eq!(requests(&resolver), vec![1, 0]);
+    }
+
+    #[test]
+    fn all_down() {
+        let mut resolver = multi(&[Mock::Down, Mock::Down], 1);
+        let err = resolver.resolve_height(witness_id()).unwrap_err();
+        assert!(matches!(err, AnchorResolverError::AllFailed(_)));
+        assert!(err.is_transport());
+        assert!(resolver.resolve_pub_witness(witness_id()).is_err());
+        assert_eq!(requests(&resolver), vec![2, 2]);
+
+        let mut resolver = multi(&[], 1);
+        assert!(matches!(
+            resolver.resolve_height(witness_id()),
+            Err(AnchorResolverError::NoResolvers)
+        ));
+    }
+
+    #[test]
+    fn quorum_met() {
+        let mut resolver = multi(&[Mock::Mined(900), Mock::Down, Mock::Mined(900)], 2);
+        assert_eq!(height(resolver.resolve_height(witness_id()).unwrap()), Some(900));
+
+        // Invalid data are not counted
+        let resolver = multi(&[Mock::Mined(900), Mock::Invalid, Mock::Mined(900)], 2);
+        assert_eq!(resolver.resolve_pub_witness(witness_id()).unwrap(), XChain::Bitcoin(tx(1)));
+        assert_eq!(requests(&resolver), vec![1, 1, 1]);
+    }
+
+    #[test]
+    fn quorum_disagreement() {
+        let mut resolver = multi(&[Mock::Mined(900), Mock::Mined(901), Mock::Down], 2);
+        assert!(matches!(
+            resolver.resolve_height(witness_id()),
+            Err(AnchorResolverError::QuorumNotReached {
+                agreed: 1,
+                quorum: 2,
+                ..
+            })
+        ));
+
+        let resolver = multi(&[Mock::Mined(900), Mock::Invalid, Mock::Unknown], 2);
+        assert!(matches!(
+            resolver.resolve_pub_witness(witness_id()),
+            Err(WitnessResolverError::Other(..))
+        ));
+
+        let mut resolver = multi(&[Mock::Mined(900), Mock::Down], 2);
+        assert!(matches!(
+            resolver.resolve_tip(Layer1::Bitcoin),
+            Err(AnchorResolverError::TipQuorumNotReached {
+                agreed: 1,
+                quorum: 2
+            })
+        ));
+    }
+}
@@ -0,0 +1,378 @@
+// RGB smart contracts for Bitcoin & Lightning
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+// Written in 2024 by
+//     Zoe Faltibà <zoefaltiba@gmail.com>
+//
+// Copyright (C) 2024 LNP/BP Standards Association. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Resolver working with witness data stored on disk, allowing validation of
+//! consignments on air-gapped machines.
+//!
+//! The directory must contain files named after the transaction ids:
+//! - `<txid>.tx` with the transaction in hex-encoded consensus serialization
+//!   (as returned by `bitcoin-cli getrawtransaction <txid>`);
+//! - `<txid>.proof`, only for mined transactions, containing SPV proof of the
+//!   transaction mining. The first line of the file is the block height, the
+//!   second is the hex-encoded 80-byte block header, the third is the position
+//!   of the transaction in the block, and the rest of the lines are merkle
+//!   branch hashes (as returned by electrum `blockchain.transaction.get_merkle`
+//!   or esplora `/tx/<txid>/merkle-proof` calls);
+//! - `<txid>.coinbase`, required for each `<txid>.proof`, proving the block
+//!   height. The first line of the file is the hex-encoded coinbase
+//!   transaction of the same block, and the rest of the lines are its merkle
+//!   branch hashes (as returned for the transaction at position 0).
+//!
+//! The proofs are checked to have a valid proof of work and to commit to the
+//! transaction at the depth of the block merkle tree, and the block height is
+//! checked against the one committed in the coinbase transaction according to
+//! BIP-34. However the resolver can't check that the block is a part of the
+//! most-work chain, thus the user must ensure that the headers come from a
+//! trusted source. For the same reason the resolver is not considered to be an
+//! indexer: its data are never cached and are not counted towards the quorum
+//! of the multi-resolver.
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use std::str::FromStr;
+
+use amplify::hex::FromHex;
+use amplify::IoError;
+use bp::ConsensusDecode;
+use bpstd::{Tx, Txid};
+use rgbstd::containers::Consignment;
+use rgbstd::resolvers::ResolveHeight;
+use rgbstd::validation::{ResolveWitness, WitnessResolverError};
+use rgbstd::{Layer1, WitnessAnchor, WitnessOrd, WitnessPos, XChain, XWitnessId, XWitnessTx};
+
+use crate::spv::{BlockHeader, MerkleBranch, SpvError};
+use crate::{multi, ResolveTip, ResolveWitnessTx, ResolverFailure};
+
+pub struct Resolver {
+    terminal_txes: HashMap<Txid, Tx>,
+    witness_txes: HashMap<Txid, Tx>,
+    positions: HashMap<Txid, WitnessPos>,
+}
+
+#[derive(Debug, Display, Error, From)]
+#[display(doc_comments)]
+pub enum ResolverError {
+    /// unable to read offline witness data. Details: {0}
+    #[from]
+    #[from(std::io::Error)]
+    Io(IoError),
+
+    /// file '{0}' doesn't contain a valid transaction.
+    InvalidTx(String),
+
+    /// transaction in file '{0}' doesn't match the id in the file name.
+    TxidMismatch(String),
+
+    /// file '{0}' doesn't contain a valid SPV proof.
+    InvalidProofFile(String),
+
+    /// SPV proof for transaction {0} is invalid: {1}
+    InvalidProof(Txid, SpvError),
+
+    /// SPV proof is provided for transaction {0}, which is absent.
+    UnknownTx(Txid),
+
+    /// SPV proof for transaction {0} lacks coinbase transaction proving the
+    /// block height.
+    NoCoinbase(Txid),
+}
+
+#[derive(Debug, Display, Error)]
+#[display(doc_comments)]
+pub enum AnchorResolverError {
+    /// unsupported layer 1 {0}
+    UnsupportedLayer1(Layer1),
+}
+
+impl ResolverFailure for AnchorResolverError {
+    fn is_transport(&self) -> bool { false }
+}
+
+impl Resolver {
+    /// Loads and verifies all transactions and SPV proofs from the directory.
+    pub fn load(dir: impl AsRef<Path>) -> Result<Self, ResolverError> {
+        let mut witness_txes = HashMap::new();
+        let mut proofs = HashMap::new();
+        let mut coinbases = HashMap::new();
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            let name = path.display().to_string();
+            let (Some(stem), Some(ext)) = (
+                path.file_stem().and_then(|s| s.to_str()),
+                path.extension().and_then(|s| s.to_str()),
+            ) else {
+                continue;
+            };
+            let Ok(txid) = Txid::from_str(stem) else {
+                continue;
+            };
+            match ext {
+                "tx" => {
+                    let tx = Vec::<u8>::from_hex(fs::read_to_string(&path)?.trim())
+                        .ok()
+                        .and_then(|data| Tx::consensus_deserialize(data).ok())
+                        .ok_or_else(|| ResolverError::InvalidTx(name.clone()))?;
+                    if tx.txid() != txid {
+                        return Err(ResolverError::TxidMismatch(name));
+                    }
+                    witness_txes.insert(txid, tx);
+                }
+                "proof" => {
+                    let proof = fs::read_to_string(&path)?;
+                    let proof = parse_proof(&proof)
+                        .ok_or_else(|| ResolverError::InvalidProofFile(name.clone()))?;
+                    proofs.insert(txid, proof);
+                }
+                "coinbase" => {
+                    let coinbase = fs::read_to_string(&path)?;
+                    let coinbase = parse_coinbase(&coinbase)
+                        .ok_or_else(|| ResolverError::InvalidProofFile(name.clone()))?;
+                    coinbases.insert(txid, coinbase);
+                }
+                _ => {}
+            }
+        }
+
+        let mut positions = HashMap::new();
+        for (txid, (height, header, branch)) in proofs {
+            if !witness_txes.contains_key(&txid) {
+                return Err(ResolverError::UnknownTx(txid));
+            }
+            let (coinbase, coinbase_branch) =
+                coinbases.get(&txid).ok_or(ResolverError::NoCoinbase(txid))?;
+            header
+                .verify_position(txid, &branch, height, coinbase, coinbase_branch)
+                .map_err(|err| ResolverError::InvalidProof(txid, err))?;
+            let pos = WitnessPos::new(height, header.time() as i64)
+                .ok_or_else(|| ResolverError::InvalidProof(txid, SpvError::InvalidHeader))?;
+            positions.insert(txid, pos);
+        }
+
+        Ok(Self {
+            terminal_txes: none!(),
+            witness_txes,
+            positions,
+        })
+    }
+
+    pub fn add_witnesses<const TYPE: bool>(&mut self, consignment: &Consignment<TYPE>) {
+        self.terminal_txes.extend(
+            consignment
+                .bundles
+                .iter()
+                .filter_map(|bw| bw.pub_witness.maybe_map_ref(|w| w.tx.clone()))
+                .filter_map(|tx| match tx {
+                    XChain::Bitcoin(tx) => Some(tx),
+                    XChain::Liquid(_) | XChain::Other(_) => None,
+                })
+                .map(|tx| (tx.txid(), tx)),
+        );
+    }
+}
+
+fn parse_proof(s: &str) -> Option<(u32, BlockHeader, MerkleBranch)> {
+    let mut lines = s.lines().map(str::trim).filter(|line| !line.is_empty());
+    let height = lines.next()?.parse().ok()?;
+    let header = BlockHeader::from_hex(lines.next()?).ok()?;
+    let pos = lines.next()?.parse().ok()?;
+    let branch = MerkleBranch::from_display_hex(pos, lines).ok()?;
+    Some((height, header, branch))
+}
+
+fn parse_coinbase(s: &str) -> Option<(Vec<u8>, MerkleBranch)> {
+    let mut lines = s.lines().map(str::trim).filter(|line| !line.is_empty());
+    let coinbase = Vec::<u8>::from_hex(lines.next()?).ok()?;
+    let branch = MerkleBranch::from_display_hex(0, lines).ok()?;
+    Some((coinbase, branch))
+}
+
+impl ResolveHeight for Resolver {
+    type Error = AnchorResolverError;
+
+    fn resolve_height(&mut self, witness_id: XWitnessId) -> Result<WitnessAnchor, Self::Error> {
+        let XWitnessId::Bitcoin(txid) = witness_id else {
+            return Err(AnchorResolverError::UnsupportedLayer1(witness_id.layer1()));
+        };
+
+        let witness_ord = match self.positions.get(&txid) {
+            Some(pos) if !self.terminal_txes.contains_key(&txid) => WitnessOrd::OnChain(*pos),
+            _ => WitnessOrd::OffChain,
+        };
+        Ok(WitnessAnchor {
+            witness_ord,
+            witness_id,
+        })
+    }
+}
+
+impl ResolveTip for Resolver {
+    /// Returns the height of the most recent block with the witness
+    /// transactions, since offline resolver doesn't know the actual tip.
+    fn resolve_tip(&mut self, layer1: Layer1) -> Result<u32, Self::Error> {
+        if layer1 != Layer1::Bitcoin {
+            return Err(AnchorResolverError::UnsupportedLayer1(layer1));
+        }
+        Ok(self
+            .positions
+            .values()
+            .map(|pos| u32::from(pos.height()))
+            .max()
+            .unwrap_or_default())
+    }
+
+    fn is_indexer(&self) -> bool { false }
+}
+
+impl ResolveWitnessTx for Resolver {
+    fn resolve_witness_tx(
+        &self,
+        witness_id: XWitnessId,
+    ) -> Result<Option<XWitnessTx>, Self::Error> {
+        let XWitnessId::Bitcoin(txid) = witness_id else {
+            return Err(AnchorResolverError::UnsupportedLayer1(witness_id.layer1()));
+        };
+
+        Ok(self
+            .terminal_txes
+            .get(&txid)
+            .or_else(|| self.witness_txes.get(&txid))
+            .cloned()
+            .map(XChain::Bitcoin))
+    }
+}
+
+impl ResolveWitness for Resolver {
+    fn resolve_pub_witness(
+        &self,
+        witness_id: XWitnessId,
+    ) -> Result<XWitnessTx, WitnessResolverError> {
+        multi::resolve_pub_witness(self, witness_id)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_helpers::TempDir;
+
+    // Genesis block coinbase transaction
+    const TX_HEX: &str = "010000000100000000000000000000000000000000000000000000000000000000000000\
+                          00ffffffff4d04ffff001d0104455468652054696d65732030332f4a616e2f3230303920\
+                          4368616e63656c6c6f72206f6e206272696e6b206f66207365636f6e64206261696c6f75\
+                          7420666f722062616e6b73ffffffff0100f2052a01000000434104678afdb0fe55482719\
+                          67f1a67130b7105cd6a828e03909a67962e0ea1f61deb649f6bc3f4cef38c4f35504e51e\
+                          c112de5c384df7ba0b8d578a4c702b6bf11d5fac00000000";
+    const TXID: &str = "4a5e1e4baab89f3a32518a88c31bc87f618f76673e2cc77ab2127b7afdeda33b";
+    const HEADER: &str = "0100000000000000000000000000000000000000000000000000000000000000000000\
+                          003ba3edfd7a7b12b27ac72c3e67768f617fc81bc3888a51323a9fb8aa4b1e5e4a29ab5f\
+                          49ffff001d1dac2b7c";
+    // Genesis coinbase predates BIP-34, but its input script starts with a
+    // number push, which is interpreted as the height
+    const COINBASE_HEIGHT: u32 = 0x1d00ffff;
+
+    fn witness_dir(name: &str, files: &[(&str, String)]) -> TempDir {
+        let dir = TempDir::new(&format!("offline-{name}"));
+        for (ext, data) in files {
+            fs::write(dir.join(format!("{TXID}.{ext}")), data).unwrap();
+        }
+        dir
+    }
+
+    fn witness_id() -> XWitnessId { XWitnessId::Bitcoin(Txid::from_str(TXID).unwrap()) }
+
+    fn proof(height: u32) -> String { format!("{height}\n{HEADER}\n0\n") }
+
+    #[test]
+    fn unmined() {
+        let dir = witness_dir("unmined", &[("tx", TX_HEX.to_owned())]);
+        let mut resolver = Resolver::load(&dir).unwrap();
+        let anchor = resolver.resolve_height(witness_id()).unwrap();
+        assert_eq!(anchor.witness_ord, WitnessOrd::OffChain);
+        assert!(resolver.resolve_pub_witness(witness_id()).is_ok());
+    }
+
+    #[test]
+    fn mined() {
+        let dir = witness_dir("mined", &[
+            ("tx", TX_HEX.to_owned()),
+            ("proof", proof(COINBASE_HEIGHT)),
+            ("coinbase", TX_HEX.to_owned()),
+        ]);
+        let mut resolver = Resolver::load(&dir).unwrap();
+        let anchor = resolver.resolve_height(witness_id()).unwrap();
+        let WitnessOrd::OnChain(pos) = anchor.witness_ord else {
+            panic!("witness is not mined");
+        };
+        assert_eq!(u32::from(pos.height()), COINBASE_HEIGHT);
+    }
+
+    #[test]
+    fn height_without_coinbase() {
+        let dir = witness_dir("no-coinbase", &[
+            ("tx", TX_HEX.to_owned()),
+            ("proof", proof(COINBASE_HEIGHT)),
+        ]);
+        assert!(matches!(Resolver::load(&dir), Err(ResolverError::NoCoinbase(_))));
+    }
+
+    #[test]
+    fn wrong_height() {
+        let dir = witness_dir("wrong-height", &[
+            ("tx", TX_HEX.to_owned()),
+            ("proof", proof(800_000)),
+            ("coinbase", TX_HEX.to_owned()),
+        ]);
+        assert!(matches!(
+            Resolver::load(&dir),
+            Err(ResolverError::InvalidProof(_, SpvError::HeightMismatch {
+                claimed: 800_000,
+                committed: COINBASE_HEIGHT
+            }))
+        ));
+    }
+
+    #[test]
+    fn forged_header() {
+        // header with a tampered merkle root
+        let header = HEADER.replace("3ba3edfd", "3ba3edfe");
+        let dir = witness_dir("forged", &[
+            ("tx", TX_HEX.to_owned()),
+            ("proof", format!("{COINBASE_HEIGHT}\n{header}\n0\n")),
+            ("coinbase", TX_HEX.to_owned()),
+        ]);
+        assert!(matches!(Resolver::load(&dir), Err(ResolverError::InvalidProof(..))));
+    }
+
+    #[test]
+    fn branch_depth() {
+        // transaction branch is deeper than the coinbase one
+        let dir = witness_dir("depth", &[
+            ("tx", TX_HEX.to_owned()),
+            ("proof", format!("{}{}\n", proof(COINBASE_HEIGHT), "00".repeat(32))),
+            ("coinbase", TX_HEX.to_owned()),
+        ]);
+        assert!(matches!(
+            Resolver::load(&dir),
+            Err(ResolverError::InvalidProof(_, SpvError::BranchDepth(_)))
+        ));
+    }
+}
@@ -0,0 +1,723 @@
+// RGB smart contracts for Bitcoin & Lightning
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+// Written in 2024 by
+//     Zoe Faltibà <zoefaltiba@gmail.com>
+//
+// Copyright (C) 2024 LNP/BP Standards Association. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Simplified payment verification (SPV) of witness transaction inclusion
+//! into a block.
+//!
+//! All hashes passed as hex strings are expected in the same byte order as
+//! they are displayed by bitcoind, electrum and esplora servers.
+
+use amplify::hex::FromHex;
+use bp::ConsensusDecode;
+use bpstd::{Network, Tx, Txid};
+use sha2::{Digest, Sha256};
+
+pub type Hash = [u8; 32];
+
+/// Number of block headers, starting from the block containing witness
+/// transaction, which are verified in SPV mode.
+pub const SPV_CHAIN_DEPTH: u32 = 6;
+
+#[derive(Clone, Eq, PartialEq, Debug, Display, Error)]
+#[display(doc_comments)]
+pub enum SpvError {
+    /// invalid block header data.
+    InvalidHeader,
+
+    /// invalid merkle proof data.
+    InvalidProof,
+
+    /// block {0} doesn't satisfy its proof-of-work target.
+    InsufficientWork(String),
+
+    /// merkle proof doesn't commit transaction {0} to the block {1}.
+    NotIncluded(Txid, String),
+
+    /// block {0} doesn't follow the previous block in the header chain.
+    BrokenChain(String),
+
+    /// block {0} has proof-of-work target above the one allowed for the
+    /// network.
+    TargetTooHigh(String),
+
+    /// block {0} doesn't match the checkpoint at the same height.
+    CheckpointMismatch(String),
+
+    /// no block headers were provided for the verification.
+    NoHeaders,
+
+    /// transaction {0} is not a coinbase committing to the block height.
+    NotCoinbase(Txid),
+
+    /// coinbase transaction commits to the block height {committed}, while
+    /// the height {claimed} was expected.
+    HeightMismatch { claimed: u32, committed: u32 },
+
+    /// merkle branch of transaction {0} doesn't match the depth of the block
+    /// merkle tree proven by the coinbase transaction.
+    BranchDepth(Txid),
+}
+
+pub fn sha256d(data: &[u8]) -> Hash {
+    let hash = Sha256::digest(Sha256::digest(data));
+    hash.into()
+}
+
+/// Parses hash from the hex string in displayed (reversed) byte order.
+pub fn hash_from_display_hex(s: &str) -> Option<Hash> {
+    let mut hash: Hash = Vec::<u8>::from_hex(s.trim()).ok()?.try_into().ok()?;
+    hash.reverse();
+    Some(hash)
+}
+
+fn hash_to_display_hex(hash: &Hash) -> String {
+    hash.iter().rev().map(|byte| format!("{byte:02x}")).collect()
+}
+
+fn txid_hash(txid: Txid) -> Hash {
+    hash_from_display_hex(&txid.to_string()).expect("txid is always a valid 32-byte hash")
+}
+
+/// Block known to be a part of the most-work chain.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub struct Checkpoint {
+    pub height: u32,
+    /// Block hash in internal byte order.
+    pub hash: Hash,
+    /// Proof-of-work target of the block in the compact form.
+    pub bits: u32,
+}
+
+/// Network parameters against which block headers are verified.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub struct SpvParams {
+    /// Highest proof-of-work target allowed by the network consensus, as a
+    /// big-endian number.
+    pub pow_limit: [u8; 32],
+    /// Block anchoring the amount of work which must be done for the verified
+    /// headers. Their target must not exceed the checkpoint one more than four
+    /// times, leaving room for a difficulty drop since the checkpoint, and
+    /// the header at the checkpoint height must match it.
+    ///
+    /// Without the checkpoint, headers can be forged with the work allowed by
+    /// the `pow_limit`; thus it is present only for the mainnet, since other
+    /// networks allow blocks with the minimal difficulty.
+    pub checkpoint: Option<Checkpoint>,
+}
+
+impl SpvParams {
+    pub fn with(network: Network) -> Self {
+        let mut pow_limit = [0u8; 32];
+        let mut checkpoint = None;
+        match network {
+            Network::Mainnet => {
+                pow_limit[4..].fill(0xFF);
+                checkpoint = Some(Checkpoint {
+                    height: 840_000,
+                    hash: hash_from_display_hex(
+                        "0000000000000000000320283a032748cef8227873ff4872689bf23f1cda83a5",
+                    )
+                    .expect("valid checkpoint hash"),
+                    bits: 0x17034219,
+                });
+            }
+            Network::Testnet3 => pow_limit[4..].fill(0xFF),
+            Network::Signet => pow_limit[2..5].copy_from_slice(&[0x03, 0x77, 0xAE]),
+            Network::Regtest => {
+                pow_limit.fill(0xFF);
+                pow_limit[0] = 0x7F;
+            }
+        }
+        Self {
+            pow_limit,
+            checkpoint,
+        }
+    }
+
+    /// Returns the highest proof-of-work target the verified headers may have,
+    /// as a big-endian number.
+    pub fn max_target(&self) -> [u8; 32] {
+        let Some(target) = self.checkpoint.and_then(|cp| target_from_bits(cp.bits)) else {
+            return self.pow_limit;
+        };
+        if target[0] >= 0x40 {
+            return self.pow_limit;
+        }
+        // multiplying the checkpoint target by four
+        let mut max = [0u8; 32];
+        for (no, byte) in max.iter_mut().enumerate() {
+            *byte = (target[no] << 2) | target.get(no + 1).map_or(0, |next| next >> 6);
+        }
+        max.min(self.pow_limit)
+    }
+}
+
+/// Merkle branch proving inclusion of a transaction into a block, as returned
+/// by electrum and esplora servers.
+#[derive(Clone, Eq, PartialEq, Debug)]
+pub struct MerkleBranch {
+    /// Position of the transaction in the block.
+    pub pos: u32,
+    /// Hashes of the branch from the leaf level up to the root, in internal
+    /// byte order.
+    pub hashes: Vec<Hash>,
+}
+
+impl MerkleBranch {
+    pub fn from_display_hex<'s>(
+        pos: u32,
+        hashes: impl IntoIterator<Item = &'s str>,
+    ) -> Result<Self, SpvError> {
+        let hashes = hashes
+            .into_iter()
+            .map(hash_from_display_hex)
+            .collect::<Option<Vec<_>>>()
+            .ok_or(SpvError::InvalidProof)?;
+        if hashes.len() >= 32 {
+            return Err(SpvError::InvalidProof);
+        }
+        Ok(Self { pos, hashes })
+    }
+
+    /// Constructs merkle branch from hashes in displayed (reversed) byte order,
+    /// as they are returned by electrum client.
+    pub fn from_display_bytes(
+        pos: u32,
+        hashes: impl IntoIterator<Item = Hash>,
+    ) -> Result<Self, SpvError> {
+        let hashes = hashes
+            .into_iter()
+            .map(|mut hash| {
+                hash.reverse();
+                hash
+            })
+            .collect::<Vec<_>>();
+        if hashes.len() >= 32 {
+            return Err(SpvError::InvalidProof);
+        }
+        Ok(Self { pos, hashes })
+    }
+
+    /// Computes merkle root of the block which the transaction with `txid` is
+    /// proven to be included into.
+    pub fn merkle_root(&self, txid: Txid) -> Hash {
+        let mut hash = txid_hash(txid);
+        let mut data = [0u8; 64];
+        for (depth, sibling) in self.hashes.iter().enumerate() {
+            if (self.pos >> depth) & 1 == 0 {
+                data[..32].copy_from_slice(&hash);
+                data[32..].copy_from_slice(sibling);
+            } else {
+                data[..32].copy_from_slice(sibling);
+                data[32..].copy_from_slice(&hash);
+            }
+            hash = sha256d(&data);
+        }
+        hash
+    }
+}
+
+/// Bitcoin block header.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub struct BlockHeader([u8; 80]);
+
+impl BlockHeader {
+    pub fn from_slice(data: &[u8]) -> Result<Self, SpvError> {
+        data.try_into().map(Self).map_err(|_| SpvError::InvalidHeader)
+    }
+
+    pub fn from_hex(s: &str) -> Result<Self, SpvError> {
+        let data = Vec::<u8>::from_hex(s.trim()).map_err(|_| SpvError::InvalidHeader)?;
+        Self::from_slice(&data)
+    }
+
+    pub fn block_hash(&self) -> Hash { sha256d(&self.0) }
+
+    pub fn prev_block_hash(&self) -> Hash {
+        self.0[4..36].try_into().expect("fixed header size")
+    }
+
+    pub fn merkle_root(&self) -> Hash { self.0[36..68].try_into().expect("fixed header size") }
+
+    pub fn time(&self) -> u32 { self.u32_at(68) }
+
+    pub fn bits(&self) -> u32 { self.u32_at(72) }
+
+    fn u32_at(&self, pos: usize) -> u32 {
+        u32::from_le_bytes(self.0[pos..pos + 4].try_into().expect("fixed header size"))
+    }
+
+    /// Returns proof-of-work target encoded in the header `bits` field as a
+    /// big-endian number, or `None` if the encoding is invalid.
+    pub fn target(&self) -> Option<[u8; 32]> { target_from_bits(self.bits()) }
+
+    /// Checks that the block hash satisfies the proof-of-work target.
+    pub fn check_pow(&self) -> Result<(), SpvError> {
+        let hash = self.block_hash();
+        let mut hash_be = hash;
+        hash_be.reverse();
+        match self.target() {
+            Some(target) if hash_be <= target => Ok(()),
+            _ => Err(SpvError::InsufficientWork(hash_to_display_hex(&hash))),
+        }
+    }
+
+    /// Verifies that the header has a valid proof of work and commits to the
+    /// transaction with `txid` via the provided merkle branch.
+    pub fn verify_inclusion(&self, txid: Txid, branch: &MerkleBranch) -> Result<(), SpvError> {
+        self.check_pow()?;
+        if branch.merkle_root(txid) != self.merkle_root() {
+            return Err(SpvError::NotIncluded(txid, hash_to_display_hex(&self.block_hash())));
+        }
+        Ok(())
+    }
+
+    /// Verifies that the header commits to the consensus-serialized `coinbase`
+    /// transaction via the provided merkle branch, and that the coinbase
+    /// commits to the block `height` as required by BIP-34.
+    pub fn verify_height(
+        &self,
+        height: u32,
+        coinbase: &[u8],
+        branch: &MerkleBranch,
+    ) -> Result<(), SpvError> {
+        let txid = Tx::consensus_deserialize(coinbase)
+            .map_err(|_| SpvError::InvalidProof)?
+            .txid();
+        if branch.pos != 0 {
+            return Err(SpvError::NotCoinbase(txid));
+        }
+        self.verify_inclusion(txid, branch)?;
+        let committed = bip34_height(coinbase).ok_or(SpvError::NotCoinbase(txid))?;
+        if committed != height {
+            return Err(SpvError::HeightMismatch {
+                claimed: height,
+                committed,
+            });
+        }
+        Ok(())
+    }
+
+    /// Verifies that the header commits to the transaction with `txid` via the
+    /// provided merkle branch, and that the block is located at the `height`
+    /// proven by the consensus-serialized `coinbase` (see
+    /// [`Self::verify_height`]).
+    ///
+    /// The transaction branch must have the same depth as the coinbase one,
+    /// which is the depth of the block merkle tree. Otherwise a 64-byte
+    /// transaction may be presented as an inner node of the tree, and an inner
+    /// node as a transaction.
+    pub fn verify_position(
+        &self,
+        txid: Txid,
+        branch: &MerkleBranch,
+        height: u32,
+        coinbase: &[u8],
+        coinbase_branch: &MerkleBranch,
+    ) -> Result<(), SpvError> {
+        let depth = branch.hashes.len();
+        if depth != coinbase_branch.hashes.len() ||
+            branch.pos.checked_shr(depth as u32).unwrap_or(0) != 0
+        {
+            return Err(SpvError::BranchDepth(txid));
+        }
+        self.verify_inclusion(txid, branch)?;
+        self.verify_height(height, coinbase, coinbase_branch)
+    }
+}
+
+/// Decodes proof-of-work target from its compact form into a big-endian
+/// number, returning `None` if the encoding is invalid.
+fn target_from_bits(bits: u32) -> Option<[u8; 32]> {
+    let exp = (bits >> 24) as usize;
+    let mantissa = bits & 0x007f_ffff;
+    if bits & 0x0080_0000 != 0 || mantissa == 0 || exp > 32 {
+        return None;
+    }
+    let mut target = [0u8; 32];
+    let mantissa = mantissa.to_be_bytes();
+    for (no, byte) in mantissa[1..].iter().enumerate() {
+        // position of the byte counting from the least significant one
+        let Some(pos) = (exp + 2).checked_sub(no + 3) else {
+            continue;
+        };
+        if pos >= 32 {
+            if *byte != 0 {
+                return None;
+            }
+            continue;
+        }
+        target[31 - pos] = *byte;
+    }
+    Some(target)
+}
+
+/// Extracts block height from the input script of the consensus-serialized
+/// coinbase transaction, where it is put by miners according to BIP-34.
+/// Returns `None` if the transaction is not a coinbase or doesn't start its
+/// input script with the height.
+pub fn bip34_height(coinbase: &[u8]) -> Option<u32> {
+    // skipping version and segwit marker with flag
+    let mut data = coinbase.get(4..)?;
+    if data.starts_with(&[0x00, 0x01]) {
+        data = &data[2..];
+    }
+    // coinbase has a single input spending null outpoint, and its script is
+    // never longer than 100 bytes, thus its length is a single-byte varint
+    let (&1, input) = data.split_first()? else {
+        return None;
+    };
+    if input.get(..32)? != [0u8; 32] || input.get(32..36)? != [0xFFu8; 4] {
+        return None;
+    }
+    let len = *input.get(36)? as usize;
+    let (&op, script) = input.get(37..37 + len)?.split_first()?;
+    match op {
+        0x00 => Some(0),
+        0x51..=0x60 => Some(u32::from(op - 0x50)),
+        0x01..=0x04 => {
+            let num = script.get(..op as usize)?;
+            if num.last()? & 0x80 != 0 {
+                return None;
+            }
+            let mut bytes = [0u8; 4];
+            bytes[..num.len()].copy_from_slice(num);
+            Some(u32::from_le_bytes(bytes))
+        }
+        _ => None,
+    }
+}
+
+/// Verifies that each of the headers, starting from the given `height`, has a
+/// valid proof of work satisfying the network `params` and commits to the
+/// previous one.
+pub fn verify_header_chain(
+    headers: &[BlockHeader],
+    height: u32,
+    params: &SpvParams,
+) -> Result<(), SpvError> {
+    let max_target = params.max_target();
+    for (no, header) in headers.iter().enumerate() {
+        let hash = header.block_hash();
+        header.check_pow()?;
+        if header.target().map_or(true, |target| target > max_target) {
+            return Err(SpvError::TargetTooHigh(hash_to_display_hex(&hash)));
+        }
+        if matches!(params.checkpoint, Some(cp)
+            if cp.height.checked_sub(height) == Some(no as u32) && cp.hash != hash)
+        {
+            return Err(SpvError::CheckpointMismatch(hash_to_display_hex(&hash)));
+        }
+        if no > 0 && header.prev_block_hash() != headers[no - 1].block_hash() {
+            return Err(SpvError::BrokenChain(hash_to_display_hex(&hash)));
+        }
+    }
+    Ok(())
+}
+
+/// Verifies that the headers form a valid chain, and that the transaction is
+/// included into the first of them, which is located at the `height` proven by
+/// the coinbase transaction (see [`BlockHeader::verify_position`]).
+pub fn verify_witness(
+    txid: Txid,
+    branch: &MerkleBranch,
+    coinbase: &[u8],
+    coinbase_branch: &MerkleBranch,
+    headers: &[BlockHeader],
+    height: u32,
+    params: &SpvParams,
+) -> Result<(), SpvError> {
+    let header = headers.first().ok_or(SpvError::NoHeaders)?;
+    verify_header_chain(headers, height, params)?;
+    header.verify_position(txid, branch, height, coinbase, coinbase_branch)
+}
+
+#[cfg(test)]
+mod test {
+    use std::str::FromStr;
+
+    use super::*;
+
+    // Mainnet block 100000
+    const HEADER_100000: &str = "0100000050120119172a610421a6c3011dd330d9df07b63616c2cc1f1cd00200\
+                                 000000006657a9252aacd5c0b2940996ecff952228c3067cc38d4885efb5a4ac\
+                                 4247e9f337221b4d4c86041b0f2b5710";
+    const TXIDS_100000: [&str; 4] = [
+        "8c14f0db3df150123e6f3dbbf30f8b955a8249b62ac1d1ff16284aefa3d06d87",
+        "fff2525b8931402dd09222c50775608f75787bd2b87e56995a7bdd30f79702c4",
+        "6359f0868171b1d194cbee1af2f16ea598ae8fad666d9b012c8ed2b79a236ec4",
+        "e9a66845e05d5abc0ad04ec80f774a7e585c6e8db975962d069a522137b80c1d",
+    ];
+    // Coinbase transaction of the block 100000, which predates BIP-34
+    const COINBASE_100000: &str = "01000000010000000000000000000000000000000000000000000000000000\
+                                   000000000000ffffffff08044c86041b020602ffffffff0100f2052a010000\
+                                   004341041b0e8c2567c12536aa13357b79a073dc4444acb83c4ec7a0e2f99d\
+                                   d7457516c5817242da796924ca4e99947d087fedf9ce467cb9f7c6287078f8\
+                                   01df276fdf84ac00000000";
+    // Hashes of the first and second pairs of the block 100000 transactions
+    const NODE_01: &str = "ccdafb73d8dcd0173d5d5c3c9a0770d0b3953db889dab99ef05b1907518cb815";
+    const NODE_23: &str = "8e30899078ca1813be036a073bbf80b86cdddde1c96e9e9c99e9e3782df4ae49";
+
+    // Mainnet blocks 0, 1 and 2
+    const HEADERS_0_2: [&str; 3] = [
+        "0100000000000000000000000000000000000000000000000000000000000000000000003ba3edfd7a7b12b2\
+         7ac72c3e67768f617fc81bc3888a51323a9fb8aa4b1e5e4a29ab5f49ffff001d1dac2b7c",
+        "010000006fe28c0ab6f1b372c1a6a246ae63f74f931e8365e15a089c68d6190000000000982051fd1e4ba744\
+         bbbe680e1fee14677ba1a3c3540bf7b1cdb606e857233e0e61bc6649ffff001d01e36299",
+        "010000004860eb18bf1b1620e37e9490fc8a427514416fd75159ab86688e9a8300000000d5fdcc541e25de1c\
+         7a5addedf24858b8bb665c9f36ef744ee42c316022c90f9bb0bc6649ffff001d08d2bd61",
+    ];
+    // Chain forged on top of the block 100000 using the regtest difficulty
+    // (bits 0x207fffff), with the first block containing a single
+    // transaction from the block 100000
+    const FORGED: [&str; 2] = [
+        "0000002006e533fd1ada86391f3f6c343204b0d278d4aaec1c0b20aa27ba030000000000876dd0a3ef4a2816\
+         ffd1c12ab649825a958b0ff3bb3d6f3e1250f13ddbf0148c8f241b4dffff7f2000000000",
+        "00000020e98304e54420e7d4abfbef8aa6bc6eeafe84c0e3916f4bf34118e8ef05fb286a876dd0a3ef4a2816\
+         ffd1c12ab649825a958b0ff3bb3d6f3e1250f13ddbf0148ce7261b4dffff7f2003000000",
+    ];
+
+    fn txid(no: usize) -> Txid { Txid::from_str(TXIDS_100000[no]).unwrap() }
+
+    fn headers<const LEN: usize>(hexes: [&str; LEN]) -> Vec<BlockHeader> {
+        hexes.map(|hex| BlockHeader::from_hex(hex).unwrap()).to_vec()
+    }
+
+    #[test]
+    fn header_fields() {
+        let header = BlockHeader::from_hex(HEADER_100000).unwrap();
+        assert_eq!(
+            hash_to_display_hex(&header.block_hash()),
+            "000000000003ba27aa200b1cecaad478d2b00432346c3f1f3986da1afd33e506"
+        );
+        assert_eq!(
+            hash_to_display_hex(&header.merkle_root()),
+            "f3e94742aca4b5ef85488dc37c06c3282295ffec960994b2c0d5ac2a25a95766"
+        );
+        assert_eq!(header.time(), 1293623863);
+        assert_eq!(header.bits(), 0x1b04864c);
+        assert_eq!(BlockHeader::from_hex(&HEADER_100000[2..]), Err(SpvError::InvalidHeader));
+    }
+
+    #[test]
+    fn target() {
+        let header = BlockHeader::from_hex(HEADER_100000).unwrap();
+        let mut target = [0u8; 32];
+        target[5..8].copy_from_slice(&[0x04, 0x86, 0x4c]);
+        assert_eq!(header.target(), Some(target));
+        assert_eq!(header.check_pow(), Ok(()));
+
+        let mut data = header.0;
+        // bits 0x1d00ffff of the genesis block
+        data[72..76].copy_from_slice(&0x1d00ffffu32.to_le_bytes());
+        let mut target = [0u8; 32];
+        target[4..6].copy_from_slice(&[0xff, 0xff]);
+        assert_eq!(BlockHeader(data).target(), Some(target));
+
+        // negative, zero and overflowing targets
+        for bits in [0x1d80ffffu32, 0x1d000000, 0x2301ffff] {
+            data[72..76].copy_from_slice(&bits.to_le_bytes());
+            assert_eq!(BlockHeader(data).target(), None);
+        }
+
+        // changed nonce makes the hash exceed the target
+        let mut data = header.0;
+        data[76] ^= 1;
+        assert!(matches!(BlockHeader(data).check_pow(), Err(SpvError::InsufficientWork(_))));
+    }
+
+    #[test]
+    fn merkle_inclusion() {
+        let header = BlockHeader::from_hex(HEADER_100000).unwrap();
+
+        let branch = MerkleBranch::from_display_hex(0, [TXIDS_100000[1], NODE_23]).unwrap();
+        assert_eq!(header.verify_inclusion(txid(0), &branch), Ok(()));
+        let branch = MerkleBranch::from_display_hex(3, [TXIDS_100000[2], NODE_01]).unwrap();
+        assert_eq!(header.verify_inclusion(txid(3), &branch), Ok(()));
+
+        // wrong position
+        let branch = MerkleBranch::from_display_hex(1, [TXIDS_100000[1], NODE_23]).unwrap();
+        assert!(matches!(
+            header.verify_inclusion(txid(0), &branch),
+            Err(SpvError::NotIncluded(..))
+        ));
+        // wrong transaction
+        let branch = MerkleBranch::from_display_hex(0, [TXIDS_100000[1], NODE_23]).unwrap();
+        assert!(matches!(
+            header.verify_inclusion(txid(2), &branch),
+            Err(SpvError::NotIncluded(..))
+        ));
+        // electrum returns the same hashes as bytes
+        let hashes = [TXIDS_100000[1], NODE_23].map(|hex| {
+            let mut hash = hash_from_display_hex(hex).unwrap();
+            hash.reverse();
+            hash
+        });
+        let branch = MerkleBranch::from_display_bytes(0, hashes).unwrap();
+        assert_eq!(header.verify_inclusion(txid(0), &branch), Ok(()));
+    }
+
+    #[test]
+    fn coinbase_height() {
+        let coinbase = |script: &str| {
+            Vec::<u8>::from_hex(&format!(
+                "0100000001{}ffffffff{:02x}{script}ffffffff01000000000000000000000000",
+                "00".repeat(32),
+                script.len() / 2
+            ))
+            .unwrap()
+        };
+        assert_eq!(bip34_height(&coinbase("03a08601")), Some(100000));
+        assert_eq!(bip34_height(&coinbase("035b7a03")), Some(227931));
+        assert_eq!(bip34_height(&coinbase("03a0860100ff")), Some(100000));
+        assert_eq!(bip34_height(&coinbase("5a")), Some(10));
+        assert_eq!(bip34_height(&coinbase("00")), Some(0));
+        // negative number
+        assert_eq!(bip34_height(&coinbase("0180")), None);
+        // not a number push
+        assert_eq!(bip34_height(&coinbase("4c01ff")), None);
+        // truncated push
+        assert_eq!(bip34_height(&coinbase("03a086")), None);
+
+        // not a coinbase
+        let mut tx = coinbase("03a08601");
+        tx[5] = 1;
+        assert_eq!(bip34_height(&tx), None);
+    }
+
+    #[test]
+    fn max_target() {
+        let mut target = [0u8; 32];
+        target[9..12].copy_from_slice(&[0x0d, 0x08, 0x64]);
+        assert_eq!(SpvParams::with(Network::Mainnet).max_target(), target);
+
+        let mut target = [0u8; 32];
+        target[4..].fill(0xFF);
+        assert_eq!(SpvParams::with(Network::Testnet3).max_target(), target);
+
+        let regtest = SpvParams::with(Network::Regtest);
+        assert_eq!(regtest.max_target(), regtest.pow_limit);
+    }
+
+    #[test]
+    fn mainnet_chain() {
+        let chain = headers(HEADERS_0_2);
+        let mut params = SpvParams::with(Network::Mainnet);
+        // early blocks are below the work of the checkpoint
+        assert!(matches!(
+            verify_header_chain(&chain, 0, &params),
+            Err(SpvError::TargetTooHigh(_))
+        ));
+
+        params.checkpoint = Some(Checkpoint {
+            height: 1,
+            hash: chain[1].block_hash(),
+            bits: chain[1].bits(),
+        });
+        assert_eq!(verify_header_chain(&chain, 0, &params), Ok(()));
+        // headers don't match the checkpoint if taken from another height
+        assert!(matches!(
+            verify_header_chain(&chain, 1, &params),
+            Err(SpvError::CheckpointMismatch(_))
+        ));
+        // checkpoint is beyond the verified headers
+        assert_eq!(verify_header_chain(&chain[..1], 0, &params), Ok(()));
+
+        let mut broken = chain.clone();
+        broken.swap(1, 2);
+        assert!(matches!(
+            verify_header_chain(&broken, 5, &params),
+            Err(SpvError::BrokenChain(_))
+        ));
+        assert_eq!(verify_header_chain(&[], 0, &params), Ok(()));
+        let branch = MerkleBranch { pos: 0, hashes: vec![] };
+        assert_eq!(
+            verify_witness(txid(0), &branch, &[], &branch, &[], 0, &params),
+            Err(SpvError::NoHeaders)
+        );
+    }
+
+    #[test]
+    fn branch_depth() {
+        let header = BlockHeader::from_hex(HEADER_100000).unwrap();
+        let coinbase = Vec::<u8>::from_hex(COINBASE_100000).unwrap();
+        let coinbase_branch =
+            MerkleBranch::from_display_hex(0, [TXIDS_100000[1], NODE_23]).unwrap();
+
+        // the coinbase predates BIP-34, thus the proof gets to the height check
+        let branch = MerkleBranch::from_display_hex(3, [TXIDS_100000[2], NODE_01]).unwrap();
+        assert_eq!(
+            header.verify_position(txid(3), &branch, 100_000, &coinbase, &coinbase_branch),
+            Err(SpvError::HeightMismatch {
+                claimed: 100_000,
+                committed: 0x1b04864c
+            })
+        );
+
+        // inner node is committed to the block, but is not a transaction
+        let node = Txid::from_str(NODE_01).unwrap();
+        let branch = MerkleBranch::from_display_hex(0, [NODE_23]).unwrap();
+        assert_eq!(header.verify_inclusion(node, &branch), Ok(()));
+        assert_eq!(
+            header.verify_position(node, &branch, 100_000, &coinbase, &coinbase_branch),
+            Err(SpvError::BranchDepth(node))
+        );
+
+        // position outside of the tree
+        let branch = MerkleBranch::from_display_hex(7, [TXIDS_100000[2], NODE_01]).unwrap();
+        assert_eq!(
+            header.verify_position(txid(3), &branch, 100_000, &coinbase, &coinbase_branch),
+            Err(SpvError::BranchDepth(txid(3)))
+        );
+    }
+
+    #[test]
+    fn forged_chain() {
+        let chain = headers(FORGED);
+        let branch = MerkleBranch { pos: 0, hashes: vec![] };
+        assert_eq!(
+            chain[0].prev_block_hash(),
+            BlockHeader::from_hex(HEADER_100000).unwrap().block_hash()
+        );
+
+        let coinbase = Vec::<u8>::from_hex(COINBASE_100000).unwrap();
+        let witness = |params: &SpvParams| {
+            verify_witness(txid(0), &branch, &coinbase, &branch, &chain, 100_001, params)
+        };
+
+        // the chain is valid for regtest...
+        let regtest = SpvParams::with(Network::Regtest);
+        assert_eq!(verify_header_chain(&chain, 100_001, &regtest), Ok(()));
+        assert_eq!(chain[0].verify_inclusion(txid(0), &branch), Ok(()));
+        // ...however the reused coinbase doesn't commit to the forged height
+        assert_eq!(
+            witness(&regtest),
+            Err(SpvError::HeightMismatch {
+                claimed: 100_001,
+                committed: 0x1b04864c
+            })
+        );
+        // ...and the chain is not valid for the networks with higher difficulty
+        for network in [Network::Mainnet, Network::Testnet3, Network::Signet] {
+            assert!(matches!(witness(&SpvParams::with(network)), Err(SpvError::TargetTooHigh(_))));
+        }
+    }
+}
@@ -23,9 +23,12 @@
 
 use std::collections::HashMap;
 use std::convert::Infallible;
+use std::error::Error;
 use std::io;
 use std::ops::{Deref, DerefMut};
 use std::path::PathBuf;
+#[cfg(feature = "serde")]
+use std::time::Duration;
 
 use amplify::IoError;
 use bpstd::{Network, XpubDerivable};
@@ -35,12 +38,20 @@ use rgbstd::interface::{
     AmountChange, BuilderError, ContractError, IfaceOp, IfaceRef, OutpointFilter, WitnessFilter,
 };
 use rgbstd::persistence::fs::{LoadFs, StoreFs};
-use rgbstd::persistence::{ContractIfaceError, Stock, StockError, StockErrorAll, StockErrorMem};
+use rgbstd::persistence::{
+    IndexProvider, MemIndex, MemStash, MemState, StashProvider, StateProvider, Stock, StockError,
+};
 use rgbstd::validation::{self};
-use rgbstd::{AssignmentWitness, ContractId, XChain, XOutpoint, XWitnessId};
+use rgbstd::{AssignmentWitness, ContractId, Layer1, XChain, XOutpoint, XWitnessId};
 use strict_types::encoding::{DeserializeError, Ident, SerializeError};
 
-use crate::{DescriptorRgb, RgbDescr};
+#[cfg(feature = "serde")]
+use crate::journal;
+#[cfg(feature = "serde")]
+use crate::LockMode;
+#[cfg(feature = "sqlite")]
+use crate::SqliteStock;
+use crate::{Clock, DescriptorRgb, RgbDescr, StockLock, SystemClock};
 
 #[derive(Debug, Display, Error, From)]
 #[display(inner)]
@@ -61,19 +72,41 @@ pub enum RuntimeError {
     #[from]
     Builder(BuilderError),
 
+    #[cfg(feature = "serde")]
+    #[from]
+    Issue(crate::IssueError),
+
     #[from]
     History(HistoryError),
 
+    #[from]
+    Bump(crate::BumpError),
+
     #[from]
     Contract(ContractError),
 
     #[from]
     PsbtDecode(psbt::DecodeError),
 
+    #[from]
+    TapretKey(psbt::TapretKeyError),
+
+    #[from]
+    #[from(crate::TapTweakAlreadyAssigned)]
+    TapretTweak(crate::TapretTweakError),
+
     /// wallet with id '{0}' is not known to the system.
     #[display(doc_comments)]
     WalletUnknown(Ident),
 
+    /// '{0}' is not a valid interface name.
+    #[display(doc_comments)]
+    InvalidIfaceName(String),
+
+    /// wallet has no more addresses to derive for the RGB keychain.
+    #[display(doc_comments)]
+    NoAddresses,
+
     #[from]
     InvalidConsignment(validation::Status),
 
@@ -90,19 +123,46 @@ pub enum RuntimeError {
 
     #[from]
     #[from(bpwallet::LoadError)]
+    #[from(bpwallet::StoreError)]
     Bp(bpwallet::RuntimeError),
 
     /// resolver error: {0}
-    #[cfg(any(feature = "electrum", feature = "esplora_blocking"))]
     #[from]
     #[display(doc_comments)]
     ResolverError(crate::AnyResolverError),
 
+    /// offline resolver can't be combined with fallback resolvers or quorum.
+    #[display(doc_comments)]
+    OfflineFallback,
+
+    /// quorum of {quorum} resolvers can't be reached with {available} available
+    /// {layer1} resolvers.
+    #[display(doc_comments)]
+    QuorumUnreachable {
+        quorum: usize,
+        available: usize,
+        layer1: Layer1,
+    },
+
     #[from]
-    #[from(StockError)]
-    #[from(StockErrorMem<ContractIfaceError>)]
-    #[display(inner)]
-    Stock(StockErrorAll),
+    WitnessCache(crate::CacheError),
+
+    /// stock at '{0}' is locked by another process.
+    #[display(doc_comments)]
+    Locked(String),
+
+    /// stock at '{0}' is locked for reading and can't be locked for writing
+    /// by the same process.
+    #[display(doc_comments)]
+    LockUpgrade(String),
+
+    #[cfg(feature = "sqlite")]
+    #[from]
+    Sqlite(crate::SqliteError),
+
+    /// stock error: {0}
+    #[display(doc_comments)]
+    Stock(Box<dyn Error + Send + Sync>),
 
     #[cfg(feature = "serde_yaml")]
     #[from]
@@ -116,50 +176,102 @@ impl From<Infallible> for RuntimeError {
     fn from(_: Infallible) -> Self { unreachable!() }
 }
 
+// Stock errors are parameterized by the providers, thus they are boxed to make
+// the runtime errors independent from the providers. The original error can
+// be recovered by downcasting it to `StockError` with the used providers.
+impl<S: StashProvider, H: StateProvider, P: IndexProvider, E: Error> From<StockError<S, H, P, E>>
+    for RuntimeError
+where StockError<S, H, P, E>: Error + Send + Sync + 'static
+{
+    fn from(err: StockError<S, H, P, E>) -> Self { Self::Stock(Box::new(err)) }
+}
+
+/// RGB wallet runtime, combining bitcoin wallet with the RGB stock.
+///
+/// The stock may use any stash, state and index providers. Saving the stock
+/// into the stock directory requires the providers to support the file
+/// system layout (and the SQLite backend, if enabled); otherwise the stock
+/// must be constructed with [`Runtime::with_stock`] and persisted by the
+/// providers themselves.
+///
+/// Runtime loaded from the stock directory locks it until dropped. The lock is
+/// reentrant within the process (see [`StockLock`]), thus it doesn't protect
+/// the stock from other runtimes of the same process, which must coordinate
+/// their access by themselves.
 #[derive(Getters)]
-pub struct Runtime<D: DescriptorRgb<K> = RgbDescr, K = XpubDerivable> {
+pub struct Runtime<
+    D: DescriptorRgb<K> = RgbDescr,
+    K = XpubDerivable,
+    S: StashProvider = MemStash,
+    H: StateProvider = MemState,
+    P: IndexProvider = MemIndex,
+> {
     stock_path: PathBuf,
     #[getter(as_mut)]
-    // TODO: Parametrize by the stock
-    stock: Stock,
+    stock: Stock<S, H, P>,
     bprt: bpwallet::Runtime<D, K /* TODO: Add layer 2 */>,
+    #[getter(skip)]
+    clock: Box<dyn Clock + Send + Sync>,
+    /// Layer 1 on which the wallet operates.
+    #[getter(as_copy)]
+    layer1: Layer1,
+    /// Lock on the stock directory, held until the runtime is dropped.
+    #[getter(skip)]
+    _lock: Option<StockLock>,
 }
 
-impl<D: DescriptorRgb<K>, K> Deref for Runtime<D, K> {
-    type Target = Stock;
+impl<D: DescriptorRgb<K>, K, S: StashProvider, H: StateProvider, P: IndexProvider> Deref
+    for Runtime<D, K, S, H, P>
+{
+    type Target = Stock<S, H, P>;
 
     fn deref(&self) -> &Self::Target { &self.stock }
 }
 
-impl<D: DescriptorRgb<K>, K> DerefMut for Runtime<D, K> {
+impl<D: DescriptorRgb<K>, K, S: StashProvider, H: StateProvider, P: IndexProvider> DerefMut
+    for Runtime<D, K, S, H, P>
+{
     fn deref_mut(&mut self) -> &mut Self::Target { &mut self.stock }
 }
 
-impl<D: DescriptorRgb<K>, K> OutpointFilter for Runtime<D, K> {
+impl<D: DescriptorRgb<K>, K, S: StashProvider, H: StateProvider, P: IndexProvider> OutpointFilter
+    for Runtime<D, K, S, H, P>
+{
     fn include_outpoint(&self, output: impl Into<XOutpoint>) -> bool {
         let output = output.into();
         self.wallet()
             .coins()
-            .any(|utxo| XChain::Bitcoin(utxo.outpoint) == *output)
+            .any(|utxo| XChain::with(self.layer1, utxo.outpoint) == *output)
     }
 }
 
-impl<D: DescriptorRgb<K>, K> WitnessFilter for Runtime<D, K> {
+impl<D: DescriptorRgb<K>, K, S: StashProvider, H: StateProvider, P: IndexProvider> WitnessFilter
+    for Runtime<D, K, S, H, P>
+{
     fn include_witness(&self, witness: impl Into<AssignmentWitness>) -> bool {
         let witness = witness.into();
         self.wallet()
             .transactions()
             .keys()
-            .any(|txid| AssignmentWitness::Present(XWitnessId::Bitcoin(*txid)) == witness)
+            .any(|txid| AssignmentWitness::Present(XWitnessId::with(self.layer1, *txid)) == witness)
     }
 }
 
-pub struct ContractOutpointsFilter<'runtime, D: DescriptorRgb<K>, K> {
+pub struct ContractOutpointsFilter<
+    'runtime,
+    D: DescriptorRgb<K>,
+    K,
+    S: StashProvider = MemStash,
+    H: StateProvider = MemState,
+    P: IndexProvider = MemIndex,
+> {
     pub contract_id: ContractId,
-    pub filter: &'runtime Runtime<D, K>,
+    pub filter: &'runtime Runtime<D, K, S, H, P>,
 }
 
-impl<'runtime, D: DescriptorRgb<K>, K> OutpointFilter for ContractOutpointsFilter<'runtime, D, K> {
+impl<'runtime, D: DescriptorRgb<K>, K, S: StashProvider, H: StateProvider, P: IndexProvider>
+    OutpointFilter for ContractOutpointsFilter<'runtime, D, K, S, H, P>
+{
     fn include_outpoint(&self, output: impl Into<XOutpoint>) -> bool {
         let output = output.into();
         if !self.filter.include_outpoint(output) {
@@ -169,54 +281,229 @@ impl<'runtime, D: DescriptorRgb<K>, K> OutpointFilter for ContractOutpointsFilte
     }
 }
 
+/// Stock which can be saved into and loaded from the stock directory.
+#[cfg(not(feature = "sqlite"))]
+pub trait PersistedStock: LoadFs + StoreFs + Default {}
+#[cfg(not(feature = "sqlite"))]
+impl<T: LoadFs + StoreFs + Default> PersistedStock for T {}
+
+/// Stock which can be saved into and loaded from the stock directory, using
+/// either the file system layout or the SQLite backend.
+#[cfg(feature = "sqlite")]
+pub trait PersistedStock: LoadFs + StoreFs + Default + SqliteStock {}
+#[cfg(feature = "sqlite")]
+impl<T: LoadFs + StoreFs + Default + SqliteStock> PersistedStock for T {}
+
 #[cfg(feature = "serde")]
-impl<D: DescriptorRgb<K>, K> Runtime<D, K>
+impl<D: DescriptorRgb<K>, K, S: StashProvider, H: StateProvider, P: IndexProvider>
+    Runtime<D, K, S, H, P>
 where
+    Stock<S, H, P>: PersistedStock,
     for<'de> D: serde::Serialize + serde::Deserialize<'de>,
     for<'de> bpwallet::WalletDescr<K, D>: serde::Serialize + serde::Deserialize<'de>,
 {
-    pub fn load_walletless(stock_path: &PathBuf) -> Result<Stock, RuntimeError> {
-        use std::io::ErrorKind;
+    /// Loads stock from the `stock_path` directory. If the stock was migrated
+    /// to the SQLite backend, the database takes precedence over the file
+    /// system layout.
+    ///
+    /// The stock is locked for reading while being loaded; if it is locked for
+    /// writing by another process, waits for up to `lock_timeout`. The lock is
+    /// released once the stock is loaded, thus the stock which is going to be
+    /// modified and saved back must be loaded with
+    /// [`Runtime::load_walletless_exclusive`] instead.
+    ///
+    /// If the stock doesn't exist yet or its saving was interrupted, the stock
+    /// gets locked for writing, which fails with [`RuntimeError::LockUpgrade`]
+    /// if the process already holds a shared lock on it.
+    pub fn load_walletless(
+        stock_path: &PathBuf,
+        lock_timeout: Option<Duration>,
+    ) -> Result<Stock<S, H, P>, RuntimeError> {
+        Self::load_walletless_shared(stock_path, lock_timeout).map(|(stock, _)| stock)
+    }
 
-        use strict_types::encoding::DecodeError;
+    /// Loads stock from the `stock_path` directory and returns it together
+    /// with the lock allowing other processes only to read the stock until
+    /// the lock is dropped. If the stock is locked for writing by another
+    /// process, waits for up to `lock_timeout`.
+    ///
+    /// If the stock doesn't exist yet or its saving was interrupted, the stock
+    /// gets created or recovered under the exclusive lock first, which fails
+    /// with [`RuntimeError::LockUpgrade`] if the process already holds a shared
+    /// lock on it.
+    pub fn load_walletless_shared(
+        stock_path: &PathBuf,
+        lock_timeout: Option<Duration>,
+    ) -> Result<(Stock<S, H, P>, StockLock), RuntimeError> {
+        loop {
+            let lock = StockLock::acquire(stock_path, LockMode::Shared, lock_timeout)?;
+            if !journal::is_pending(stock_path) {
+                if let Some(stock) = Self::load_existing(stock_path)? {
+                    return Ok((stock, lock));
+                }
+            }
+            drop(lock);
+            Self::load_walletless_exclusive(stock_path, lock_timeout)?;
+        }
+    }
 
-        Stock::load(stock_path).map_err(RuntimeError::from).or_else(|err| {
-            if matches!(err, RuntimeError::Deserialize(DeserializeError::Decode(DecodeError::Io(ref err))) if err.kind() == ErrorKind::NotFound) {
+    /// Loads stock from the `stock_path` directory, creating it if absent, and
+    /// returns it together with the lock preventing other processes from
+    /// accessing the stock until it is saved with
+    /// [`Runtime::store_walletless`] and the lock is dropped. If the stock is
+    /// locked by another process, waits for up to `lock_timeout`.
+    pub fn load_walletless_exclusive(
+        stock_path: &PathBuf,
+        lock_timeout: Option<Duration>,
+    ) -> Result<(Stock<S, H, P>, StockLock), RuntimeError> {
+        let lock = StockLock::acquire(stock_path, LockMode::Exclusive, lock_timeout)?;
+        journal::recover(stock_path)?;
+        let stock = match Self::load_existing(stock_path)? {
+            Some(stock) => stock,
+            None => {
                 #[cfg(feature = "log")]
                 eprint!("stock file is absent, creating a new one ... ");
                 let stock = Stock::default();
-                std::fs::create_dir_all(stock_path)?;
-                stock.store(stock_path)?;
-                return Ok(stock)
+                Self::store_walletless(&stock, stock_path, lock_timeout)?;
+                stock
             }
-            eprintln!("stock file is damaged");
-            Err(err)
-        })
+        };
+        Ok((stock, lock))
     }
 
+    /// Loads the stock without locking it, returning `None` if the stock
+    /// doesn't exist.
+    fn load_existing(stock_path: &std::path::Path) -> Result<Option<Stock<S, H, P>>, RuntimeError> {
+        use std::io::ErrorKind;
+
+        use strict_types::encoding::DecodeError;
+
+        #[cfg(feature = "sqlite")]
+        if crate::SqliteStore::exists(stock_path) {
+            let store = crate::SqliteStore::open(stock_path)?;
+            return Stock::<S, H, P>::load_sqlite(&store)?
+                .ok_or(crate::SqliteError::Incomplete)
+                .map(Some)
+                .map_err(RuntimeError::from);
+        }
+
+        match Stock::<S, H, P>::load(stock_path).map_err(RuntimeError::from) {
+            Ok(stock) => Ok(Some(stock)),
+            Err(RuntimeError::Deserialize(DeserializeError::Decode(DecodeError::Io(err))))
+                if err.kind() == ErrorKind::NotFound =>
+            {
+                Ok(None)
+            }
+            Err(err) => {
+                eprintln!("stock file is damaged");
+                Err(err)
+            }
+        }
+    }
+
+    /// Saves stock into the `stock_path` directory, using the SQLite backend
+    /// if the stock was migrated to it. Saving is crash-safe: an interrupted
+    /// save leaves either the previous or the new version of the stock.
+    ///
+    /// If the stock is locked by another process, waits for up to
+    /// `lock_timeout`.
+    pub fn store_walletless(
+        stock: &Stock<S, H, P>,
+        stock_path: &PathBuf,
+        lock_timeout: Option<Duration>,
+    ) -> Result<(), RuntimeError> {
+        let _lock = StockLock::acquire(stock_path, LockMode::Exclusive, lock_timeout)?;
+        #[cfg(feature = "sqlite")]
+        if crate::SqliteStore::exists(stock_path) {
+            stock.store_sqlite(&mut crate::SqliteStore::open(stock_path)?)?;
+            return Ok(());
+        }
+        journal::store(stock, stock_path)
+    }
+
+    /// Loads stock from the `stock_path` directory and attaches the wallet to
+    /// it. The stock is locked in the given `mode` until the runtime is
+    /// dropped; if it is locked by another process, waits for up to
+    /// `lock_timeout`.
+    ///
+    /// Runtime holding a shared lock can't save the stock: [`Runtime::store`]
+    /// fails with [`RuntimeError::LockUpgrade`].
     pub fn load_attach(
         stock_path: PathBuf,
         bprt: bpwallet::Runtime<D, K>,
+        mode: LockMode,
+        lock_timeout: Option<Duration>,
     ) -> Result<Self, RuntimeError> {
-        let stock = Self::load_walletless(&stock_path)?;
-        Ok(Self {
+        let (stock, lock) = match mode {
+            LockMode::Shared => Self::load_walletless_shared(&stock_path, lock_timeout)?,
+            LockMode::Exclusive => Self::load_walletless_exclusive(&stock_path, lock_timeout)?,
+        };
+        let mut runtime = Self {
             stock_path,
             stock,
             bprt,
-        })
+            clock: Box::new(SystemClock),
+            layer1: Layer1::Bitcoin,
+            _lock: Some(lock),
+        };
+        runtime.restore_tapret_tweaks()?;
+        Ok(runtime)
     }
 
-    pub fn store(&mut self) {
-        self.stock
-            .store(&self.stock_path)
-            .expect("unable to save stock");
-        self.bprt.try_store().expect("unable to save wallet data");
-    }
+    /// Saves both stock and wallet data.
+    ///
+    /// The stock is committed before the wallet is saved, since the wallet
+    /// data derived from RGB operations can be restored from the stock, while
+    /// the reverse is not possible. If the process gets interrupted after the
+    /// commit, saving of the stock is completed on the next load, and tapret
+    /// tweaks missing from the wallet are restored with
+    /// [`Runtime::restore_tapret_tweaks`] when the wallet is attached.
+    ///
+    /// Only the stock is saved atomically: wallet files are written in place,
+    /// thus an interruption while they are written may leave the wallet
+    /// partially updated.
+    pub fn store(&mut self) -> Result<(), RuntimeError> {
+        let _lock = StockLock::acquire(&self.stock_path, LockMode::Exclusive, None)?;
+        #[cfg(feature = "sqlite")]
+        if crate::SqliteStore::exists(&self.stock_path) {
+            self.stock
+                .store_sqlite(&mut crate::SqliteStore::open(&self.stock_path)?)?;
+            self.bprt.try_store()?;
+            return Ok(());
+        }
 
-    pub fn into_stock(self) -> Stock { self.stock }
+        journal::prepare(&self.stock, &self.stock_path)?;
+        journal::commit(&self.stock_path)?;
+        let res = self.bprt.try_store();
+        journal::apply(&self.stock_path)?;
+        res?;
+        Ok(())
+    }
 }
 
-impl<D: DescriptorRgb<K>, K> Runtime<D, K> {
+impl<D: DescriptorRgb<K>, K, S: StashProvider, H: StateProvider, P: IndexProvider>
+    Runtime<D, K, S, H, P>
+{
+    /// Constructs runtime from the stock using custom providers, which are
+    /// responsible for their own persistence. The `stock_path` is used only
+    /// by the data which are stored alongside the stock, like witness cache.
+    pub fn with_stock(
+        stock_path: PathBuf,
+        stock: Stock<S, H, P>,
+        bprt: bpwallet::Runtime<D, K>,
+    ) -> Self {
+        Self {
+            stock_path,
+            stock,
+            bprt,
+            clock: Box::new(SystemClock),
+            layer1: Layer1::Bitcoin,
+            _lock: None,
+        }
+    }
+
+    pub fn into_stock(self) -> Stock<S, H, P> { self.stock }
+
     pub fn wallet(&self) -> &Wallet<K, D> { self.bprt.wallet() }
 
     pub fn wallet_mut(&mut self) -> &mut Wallet<K, D> { self.bprt.wallet_mut() }
@@ -225,7 +512,28 @@ impl<D: DescriptorRgb<K>, K> Runtime<D, K> {
 
     pub fn network(&self) -> Network { self.bprt.network() }
 
-    // TODO: Integrate into BP Wallet `TxRow` as L2 and provide transactional info
+    /// Returns clock used to check invoice expiration.
+    pub fn clock(&self) -> &(dyn Clock + Send + Sync) { self.clock.as_ref() }
+
+    /// Replaces clock used to check invoice expiration, which by default uses
+    /// the system time.
+    pub fn set_clock(&mut self, clock: impl Clock + Send + Sync + 'static) {
+        self.clock = Box::new(clock)
+    }
+
+    /// Sets layer 1 on which the wallet operates, which is bitcoin by
+    /// default. The wallet descriptor and resolvers must be for the same
+    /// layer 1.
+    ///
+    /// Payments composed by the wallet pay invoices issued for the same layer
+    /// 1, spend only the seals defined on it and anchor the state transitions
+    /// to a witness transaction on it.
+    pub fn set_layer1(&mut self, layer1: Layer1) { self.layer1 = layer1 }
+}
+
+impl<D: DescriptorRgb<K>, K, S: StashProvider, H: StateProvider, P: IndexProvider>
+    Runtime<D, K, S, H, P>
+{
     pub fn fungible_history(
         &self,
         contract_id: ContractId,
@@ -0,0 +1,756 @@
+// RGB wallet library for smart contracts on Bitcoin & Lightning network
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+// Written in 2019-2024 by
+//     Dr Maxim Orlovsky <orlovsky@lnp-bp.org>
+//
+// Copyright (C) 2019-2024 LNP/BP Standards Association. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! SQLite persistence backend for the stock.
+//!
+//! Large collections of the stock providers (bundles, witnesses and
+//! extensions of the stash, state of each contract and the index entries) are
+//! kept as separate rows, keyed by the strict encoding of the item ids. The
+//! rest of each provider is kept as a single record, which doesn't change
+//! when the collections get new items. On each save only the records and rows
+//! which have changed since the last save are written, and the rows of the
+//! removed items are deleted, within a single database transaction.
+//!
+//! Each provider lists its fields in the order of their strict encoding (see
+//! [`SqliteProvider::fields`]), and is restored by inserting the rows, ordered
+//! by their keys, back into the provider record at the positions of its
+//! tables. If the listed fields don't reproduce the original encoding of the
+//! provider, i.e. its layout has changed, the whole provider is kept as the
+//! record and a warning is logged.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::io;
+use std::path::{Path, PathBuf};
+
+use amplify::confinement::{Confined, U16, U24, U32, U8};
+use rgbstd::persistence::fs::LoadFs;
+use rgbstd::persistence::{
+    IndexProvider, MemIndex, MemStash, MemState, StashProvider, StateProvider, Stock,
+};
+use rusqlite::{params, Connection, OptionalExtension};
+use sha2::{Digest, Sha256};
+use strict_types::encoding::{
+    DeserializeError, SerializeError, StrictEncode, StrictType, TypedWrite,
+};
+use strict_types::{StrictDeserialize, StrictSerialize};
+
+/// Name of the database file created inside the stock directory.
+pub const SQLITE_FILE: &str = "stock.sqlite";
+
+const COMPONENT_STASH: &str = "stash";
+const COMPONENT_STATE: &str = "state";
+const COMPONENT_INDEX: &str = "index";
+
+#[derive(Debug, Display, Error, From)]
+#[display(doc_comments)]
+pub enum SqliteError {
+    /// SQLite database error: {0}
+    #[from]
+    Sqlite(rusqlite::Error),
+
+    #[from]
+    #[display(inner)]
+    Serialize(SerializeError),
+
+    #[from]
+    #[display(inner)]
+    Deserialize(DeserializeError),
+
+    /// stock component '{0}' in the SQLite database is corrupted.
+    Corrupted(String),
+
+    /// SQLite database contains only some of the stock components.
+    Incomplete,
+}
+
+/// Collection of a stock provider, which is kept in the database as separate
+/// rows keyed by the item ids.
+#[derive(Clone, Eq, PartialEq, Debug)]
+pub struct SqliteTable {
+    name: &'static str,
+    /// Number of bytes used to encode the number of the items.
+    width: usize,
+    rows: BTreeMap<Vec<u8>, Vec<u8>>,
+}
+
+impl SqliteTable {
+    /// Constructs table named `name` from the items of a provider map.
+    pub fn with_map<K: Ord + StrictEncode, V: StrictEncode, const MIN: usize, const MAX: usize>(
+        name: &'static str,
+        map: &Confined<BTreeMap<K, V>, MIN, MAX>,
+    ) -> Result<Self, SerializeError> {
+        let width = if MAX <= U8 {
+            1
+        } else if MAX <= U16 {
+            2
+        } else if MAX <= U24 {
+            3
+        } else {
+            4
+        };
+        let mut rows = BTreeMap::new();
+        for (key, value) in map.iter() {
+            rows.insert(encode(key)?, encode(value)?);
+        }
+        Ok(Self { name, width, rows })
+    }
+
+    /// Name of the table.
+    pub fn name(&self) -> &'static str { self.name }
+
+    /// Strict encoding of the map: number of the items followed by the items
+    /// ordered by their keys.
+    fn encode(&self) -> Vec<u8> {
+        let len = self.rows.len() as u64;
+        let mut data = len.to_le_bytes()[..self.width].to_vec();
+        for (key, value) in &self.rows {
+            data.extend_from_slice(key);
+            data.extend_from_slice(value);
+        }
+        data
+    }
+}
+
+/// Field of a stock provider, as it is kept in the database.
+#[derive(Clone, Eq, PartialEq, Debug)]
+pub enum SqliteField {
+    /// Field kept as a part of the provider record, given by its strict
+    /// encoding.
+    Record(Vec<u8>),
+    /// Map kept as separate rows.
+    Table(SqliteTable),
+}
+
+impl SqliteField {
+    /// Constructs field kept as a part of the provider record.
+    pub fn record(value: &impl StrictEncode) -> Result<Self, SerializeError> {
+        encode(value).map(Self::Record)
+    }
+
+    /// Constructs field kept as table named `name`.
+    pub fn table<K: Ord + StrictEncode, V: StrictEncode, const MIN: usize, const MAX: usize>(
+        name: &'static str,
+        map: &Confined<BTreeMap<K, V>, MIN, MAX>,
+    ) -> Result<Self, SerializeError> {
+        SqliteTable::with_map(name, map).map(Self::Table)
+    }
+}
+
+/// Stock provider which can be kept in the SQLite database.
+pub trait SqliteProvider: StrictSerialize + StrictDeserialize {
+    /// Leading fields of the provider, in the order of their strict encoding,
+    /// up to its last field kept as a table. The rest of the provider encoding
+    /// is kept as a part of the provider record.
+    fn fields(&self) -> Result<Vec<SqliteField>, SerializeError>;
+}
+
+impl SqliteProvider for MemStash {
+    fn fields(&self) -> Result<Vec<SqliteField>, SerializeError> {
+        Ok(vec![
+            SqliteField::record(self.debug_schemata())?,
+            SqliteField::record(self.debug_ifaces())?,
+            SqliteField::table("geneses", self.debug_geneses())?,
+            SqliteField::record(self.debug_suppl())?,
+            SqliteField::table("bundles", self.debug_bundles())?,
+            SqliteField::table("extensions", self.debug_extensions())?,
+            SqliteField::table("witnesses", self.debug_witnesses())?,
+        ])
+    }
+}
+
+impl SqliteProvider for MemState {
+    fn fields(&self) -> Result<Vec<SqliteField>, SerializeError> {
+        Ok(vec![SqliteField::table("contract_state", self.debug_history())?])
+    }
+}
+
+impl SqliteProvider for MemIndex {
+    fn fields(&self) -> Result<Vec<SqliteField>, SerializeError> {
+        Ok(vec![
+            SqliteField::table("op_bundle_index", self.debug_op_bundle_index())?,
+            SqliteField::table("bundle_contract_index", self.debug_bundle_contract_index())?,
+            SqliteField::table("bundle_witness_index", self.debug_bundle_witness_index())?,
+            SqliteField::table("contract_index", self.debug_contract_index())?,
+            SqliteField::table("terminal_index", self.debug_terminal_index())?,
+        ])
+    }
+}
+
+/// Stock which can be kept in the SQLite database.
+pub trait SqliteStock: Sized {
+    /// Loads stock from the database. Returns `None` if the database doesn't
+    /// contain any stock data yet.
+    fn load_sqlite(store: &SqliteStore) -> Result<Option<Self>, SqliteError>;
+
+    /// Saves stock into the database. Returns number of written records.
+    fn store_sqlite(&self, store: &mut SqliteStore) -> Result<usize, SqliteError>;
+}
+
+impl<S, H, P> SqliteStock for Stock<S, H, P>
+where
+    S: StashProvider + SqliteProvider,
+    H: StateProvider + SqliteProvider,
+    P: IndexProvider + SqliteProvider,
+{
+    fn load_sqlite(store: &SqliteStore) -> Result<Option<Self>, SqliteError> { store.load() }
+
+    fn store_sqlite(&self, store: &mut SqliteStore) -> Result<usize, SqliteError> {
+        store.store(self)
+    }
+}
+
+/// Position of the rows of a table inside the strict encoding of a provider.
+#[derive(Clone, Eq, PartialEq, Debug)]
+struct Region {
+    table: String,
+    /// Offset in the provider record, at which the rows are inserted.
+    offset: usize,
+    /// Number of bytes used to encode the number of the rows.
+    width: usize,
+}
+
+/// Stock provider split into the rows of its tables and the provider record.
+struct Component {
+    record: Vec<u8>,
+    regions: Vec<Region>,
+    tables: Vec<SqliteTable>,
+}
+
+impl Component {
+    fn with<T: SqliteProvider>(provider: &T) -> Result<Self, SqliteError> {
+        let data = provider.to_strict_serialized::<U32>()?.into_inner();
+        // encoding of the listed fields, which must start the provider one
+        let mut prefix = vec![];
+        let mut record = vec![];
+        let mut regions = vec![];
+        let mut tables = vec![];
+        for field in provider.fields()? {
+            match field {
+                SqliteField::Record(field) => {
+                    prefix.extend_from_slice(&field);
+                    record.extend(field);
+                }
+                SqliteField::Table(table) => {
+                    prefix.extend(table.encode());
+                    regions.push(Region {
+                        table: table.name.to_owned(),
+                        offset: record.len(),
+                        width: table.width,
+                    });
+                    tables.push(table);
+                }
+            }
+        }
+        if !data.starts_with(&prefix) {
+            #[cfg(feature = "log")]
+            log::warn!(
+                "fields of {} don't match its encoding, thus it is kept as a single record",
+                std::any::type_name::<T>()
+            );
+            return Ok(Self {
+                record: data,
+                regions: vec![],
+                tables: vec![],
+            });
+        }
+        record.extend_from_slice(&data[prefix.len()..]);
+        Ok(Self {
+            record,
+            regions,
+            tables,
+        })
+    }
+
+    fn layout(&self) -> String {
+        self.regions
+            .iter()
+            .map(|region| format!("{}:{}:{}", region.table, region.offset, region.width))
+            .collect::<Vec<_>>()
+            .join(";")
+    }
+
+    fn digest(&self) -> Vec<u8> {
+        let mut engine = Sha256::new();
+        engine.update(&self.record);
+        engine.update(self.layout().as_bytes());
+        engine.finalize().to_vec()
+    }
+}
+
+/// Stock persistence backend using SQLite database.
+pub struct SqliteStore {
+    path: PathBuf,
+    conn: Connection,
+}
+
+impl SqliteStore {
+    /// Returns path to the database file for the stock located in the
+    /// `stock_path` directory.
+    pub fn db_path(stock_path: impl AsRef<Path>) -> PathBuf {
+        stock_path.as_ref().join(SQLITE_FILE)
+    }
+
+    /// Checks whether the stock in `stock_path` directory was migrated to the
+    /// SQLite backend.
+    pub fn exists(stock_path: impl AsRef<Path>) -> bool { Self::db_path(stock_path).is_file() }
+
+    /// Opens (or creates) the database for the stock located in the
+    /// `stock_path` directory.
+    pub fn open(stock_path: impl AsRef<Path>) -> Result<Self, SqliteError> {
+        let path = Self::db_path(stock_path);
+        let conn = Connection::open(&path)?;
+        Self::with_connection(path, conn)
+    }
+
+    fn with_connection(path: PathBuf, conn: Connection) -> Result<Self, SqliteError> {
+        conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS components (
+                name TEXT PRIMARY KEY NOT NULL,
+                digest BLOB NOT NULL,
+                data BLOB NOT NULL,
+                layout TEXT NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS items (
+                collection TEXT NOT NULL,
+                id BLOB NOT NULL,
+                digest BLOB NOT NULL,
+                data BLOB NOT NULL,
+                PRIMARY KEY (collection, id)
+            );",
+        )?;
+        Ok(Self { path, conn })
+    }
+
+    /// Path to the database file.
+    pub fn path(&self) -> &Path { &self.path }
+
+    /// Loads stock from the database. Returns `None` if the database doesn't
+    /// contain any stock data yet.
+    pub fn load<S, H, P>(&self) -> Result<Option<Stock<S, H, P>>, SqliteError>
+    where
+        S: StashProvider + SqliteProvider,
+        H: StateProvider + SqliteProvider,
+        P: IndexProvider + SqliteProvider,
+    {
+        let stash = self.load_component::<S>(COMPONENT_STASH)?;
+        let state = self.load_component::<H>(COMPONENT_STATE)?;
+        let index = self.load_component::<P>(COMPONENT_INDEX)?;
+        match (stash, state, index) {
+            (Some(stash), Some(state), Some(index)) => Ok(Some(Stock::with(stash, state, index))),
+            (None, None, None) => Ok(None),
+            _ => Err(SqliteError::Incomplete),
+        }
+    }
+
+    /// Saves stock into the database, writing only the provider records and
+    /// the table rows which were changed since the last save, and deleting
+    /// rows of the removed items. Returns number of written and deleted
+    /// records and rows.
+    pub fn store<S, H, P>(&mut self, stock: &Stock<S, H, P>) -> Result<usize, SqliteError>
+    where
+        S: StashProvider + SqliteProvider,
+        H: StateProvider + SqliteProvider,
+        P: IndexProvider + SqliteProvider,
+    {
+        self.store_components(&[
+            (COMPONENT_STASH, Component::with(stock.as_stash_provider())?),
+            (COMPONENT_STATE, Component::with(stock.as_state_provider())?),
+            (COMPONENT_INDEX, Component::with(stock.as_index_provider())?),
+        ])
+    }
+
+    /// Imports stock stored in the file system layout from the `stock_path`
+    /// directory into the database.
+    pub fn migrate_fs(&mut self, stock_path: impl AsRef<Path>) -> Result<(), SqliteError> {
+        let stock: Stock = Stock::load(stock_path.as_ref())?;
+        self.store(&stock)?;
+        Ok(())
+    }
+
+    fn store_components(&mut self, components: &[(&str, Component)]) -> Result<usize, SqliteError> {
+        let tx = self.conn.transaction()?;
+        let known_records = {
+            let mut stmt = tx.prepare("SELECT name, digest FROM components")?;
+            let rows = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?;
+            rows.collect::<Result<BTreeMap<String, Vec<u8>>, _>>()?
+        };
+        let known_rows = {
+            let mut stmt = tx.prepare("SELECT collection, id, digest FROM items")?;
+            let rows = stmt.query_map([], |row| Ok(((row.get(0)?, row.get(1)?), row.get(2)?)))?;
+            rows.collect::<Result<BTreeMap<(String, Vec<u8>), Vec<u8>>, _>>()?
+        };
+
+        let mut written = 0usize;
+        let mut present = BTreeSet::new();
+        for (name, component) in components {
+            let digest = component.digest();
+            if known_records.get(*name) != Some(&digest) {
+                tx.execute(
+                    "INSERT OR REPLACE INTO components (name, digest, data, layout)
+                     VALUES (?1, ?2, ?3, ?4)",
+                    params![name, digest, component.record, component.layout()],
+                )?;
+                written += 1;
+            }
+            for table in &component.tables {
+                for (id, data) in &table.rows {
+                    let key = (table.name.to_owned(), id.clone());
+                    let digest = Sha256::digest(data).to_vec();
+                    if known_rows.get(&key) != Some(&digest) {
+                        tx.execute(
+                            "INSERT OR REPLACE INTO items (collection, id, digest, data)
+                             VALUES (?1, ?2, ?3, ?4)",
+                            params![table.name, id, digest, data],
+                        )?;
+                        written += 1;
+                    }
+                    present.insert(key);
+                }
+            }
+        }
+        for (collection, id) in known_rows.keys().filter(|key| !present.contains(*key)) {
+            tx.execute("DELETE FROM items WHERE collection = ?1 AND id = ?2", params![
+                collection, id
+            ])?;
+            written += 1;
+        }
+        tx.commit()?;
+        Ok(written)
+    }
+
+    fn load_component<T: SqliteProvider>(&self, name: &str) -> Result<Option<T>, SqliteError> {
+        let corrupted = || SqliteError::Corrupted(name.to_owned());
+        let Some((record, layout)) = self
+            .conn
+            .query_row("SELECT data, layout FROM components WHERE name = ?1", [name], |row| {
+                Ok((row.get::<_, Vec<u8>>(0)?, row.get::<_, String>(1)?))
+            })
+            .optional()?
+        else {
+            return Ok(None);
+        };
+        let regions = parse_layout(&layout).ok_or_else(corrupted)?;
+        let mut tables = BTreeMap::new();
+        for region in &regions {
+            let mut stmt = self
+                .conn
+                .prepare("SELECT id, data FROM items WHERE collection = ?1")?;
+            let rows = stmt.query_map([&region.table], |row| Ok((row.get(0)?, row.get(1)?)))?;
+            let rows = rows.collect::<Result<BTreeMap<Vec<u8>, Vec<u8>>, _>>()?;
+            tables.insert(region.table.clone(), rows);
+        }
+        let data = join(&record, &regions, &tables).ok_or_else(corrupted)?;
+        let data = Confined::try_from(data).map_err(|_| corrupted())?;
+        Ok(Some(T::from_strict_serialized::<U32>(data)?))
+    }
+}
+
+/// Reconstructs strict encoding of the provider by inserting table rows into
+/// the provider record. Returns `None` if the layout doesn't match the record
+/// or the rows.
+fn join(
+    record: &[u8],
+    regions: &[Region],
+    tables: &BTreeMap<String, BTreeMap<Vec<u8>, Vec<u8>>>,
+) -> Option<Vec<u8>> {
+    let mut data = Vec::with_capacity(record.len());
+    let mut pos = 0usize;
+    for region in regions {
+        data.extend_from_slice(record.get(pos..region.offset)?);
+        let rows = tables.get(&region.table)?;
+        let len = rows.len() as u64;
+        if len >= 1 << (8 * region.width) {
+            return None;
+        }
+        data.extend_from_slice(&len.to_le_bytes()[..region.width]);
+        for (id, value) in rows {
+            data.extend_from_slice(id);
+            data.extend_from_slice(value);
+        }
+        pos = region.offset;
+    }
+    data.extend_from_slice(record.get(pos..)?);
+    Some(data)
+}
+
+fn parse_layout(layout: &str) -> Option<Vec<Region>> {
+    layout
+        .split(';')
+        .filter(|region| !region.is_empty())
+        .map(|region| {
+            let mut parts = region.split(':');
+            let table = parts.next()?.to_owned();
+            let offset = parts.next()?.parse().ok()?;
+            let width = parts
+                .next()?
+                .parse()
+                .ok()
+                .filter(|width| (1..=4).contains(width))?;
+            Some(Region {
+                table,
+                offset,
+                width,
+            })
+        })
+        .collect()
+}
+
+/// Wrapper strict-serializing keys and values of the table items, which are
+/// strict types but not standalone serializable data.
+struct Item<'a, T>(&'a T);
+
+impl<T: StrictType> StrictType for Item<'_, T> {
+    const STRICT_LIB_NAME: &'static str = T::STRICT_LIB_NAME;
+}
+
+impl<T: StrictEncode> StrictEncode for Item<'_, T> {
+    fn strict_encode<W: TypedWrite>(&self, writer: W) -> io::Result<W> {
+        self.0.strict_encode(writer)
+    }
+}
+
+impl<T: StrictEncode> StrictSerialize for Item<'_, T> {}
+
+fn encode<T: StrictEncode>(value: &T) -> Result<Vec<u8>, SerializeError> {
+    Item(value)
+        .to_strict_serialized::<U32>()
+        .map(Confined::into_inner)
+}
+
+#[cfg(test)]
+mod test {
+    use amplify::confinement::MediumOrdMap;
+    use rgbstd::containers::UniversalFile;
+    use strict_types::encoding::{DecodeError, StrictDecode, TypedRead};
+
+    use super::*;
+    use crate::offline;
+    use crate::test_helpers::TempDir;
+
+    #[derive(Clone, Eq, PartialEq, Debug, Default)]
+    struct TestProvider {
+        version: u16,
+        items: MediumOrdMap<[u8; 32], u64>,
+        other: MediumOrdMap<[u8; 32], u64>,
+        footer: u64,
+        /// Lists fields as if the provider layout has changed.
+        stale_layout: bool,
+    }
+
+    impl StrictType for TestProvider {
+        const STRICT_LIB_NAME: &'static str = "Test";
+    }
+
+    impl StrictEncode for TestProvider {
+        fn strict_encode<W: TypedWrite>(&self, writer: W) -> io::Result<W> {
+            let writer = self.version.strict_encode(writer)?;
+            let writer = self.items.strict_encode(writer)?;
+            let writer = self.other.strict_encode(writer)?;
+            self.footer.strict_encode(writer)
+        }
+    }
+
+    impl StrictDecode for TestProvider {
+        fn strict_decode(reader: &mut impl TypedRead) -> Result<Self, DecodeError> {
+            Ok(Self {
+                version: StrictDecode::strict_decode(reader)?,
+                items: StrictDecode::strict_decode(reader)?,
+                other: StrictDecode::strict_decode(reader)?,
+                footer: StrictDecode::strict_decode(reader)?,
+                stale_layout: false,
+            })
+        }
+    }
+
+    impl StrictSerialize for TestProvider {}
+    impl StrictDeserialize for TestProvider {}
+
+    impl SqliteProvider for TestProvider {
+        fn fields(&self) -> Result<Vec<SqliteField>, SerializeError> {
+            let mut fields = vec![];
+            if !self.stale_layout {
+                fields.push(SqliteField::record(&self.version)?);
+            }
+            fields.push(SqliteField::table("items", &self.items)?);
+            fields.push(SqliteField::table("other", &self.other)?);
+            Ok(fields)
+        }
+    }
+
+    fn store() -> SqliteStore {
+        SqliteStore::with_connection(PathBuf::new(), Connection::open_in_memory().unwrap()).unwrap()
+    }
+
+    fn save(store: &mut SqliteStore, provider: &TestProvider) -> usize {
+        let component = Component::with(provider).unwrap();
+        store.store_components(&[("test", component)]).unwrap()
+    }
+
+    // Stock with the demo contract
+    fn demo_stock() -> Stock {
+        let dir = TempDir::new("sqlite-demo-stock");
+        let mut resolver = offline::Resolver::load(&dir).unwrap();
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/examples/rgb20-demo.rgb");
+        let UniversalFile::Contract(contract) = UniversalFile::load_file(path).unwrap() else {
+            panic!("contract consignment expected")
+        };
+        let testnet = contract.genesis.testnet;
+        let contract = contract
+            .validate(&mut resolver, testnet)
+            .map_err(|(status, _)| status)
+            .unwrap();
+        let mut stock = Stock::default();
+        stock.import_contract(contract, &mut resolver).unwrap();
+        stock
+    }
+
+    fn encoded(provider: &impl StrictSerialize) -> Vec<u8> {
+        provider.to_strict_serialized::<U32>().unwrap().into_inner()
+    }
+
+    fn provider() -> TestProvider {
+        let mut provider = TestProvider {
+            version: 1,
+            footer: 0xFFFF_FFFF,
+            ..default!()
+        };
+        for no in 1u8..=10 {
+            provider.items.insert([no; 32], no as u64).unwrap();
+        }
+        provider.other.insert([0xAA; 32], 0xAA).unwrap();
+        provider
+    }
+
+    #[test]
+    fn rows() {
+        let provider = provider();
+        let component = Component::with(&provider).unwrap();
+        assert_eq!(component.tables.len(), 2);
+        assert_eq!(component.regions.len(), 2);
+        assert_eq!(component.record, [1u8, 0, 0xFF, 0xFF, 0xFF, 0xFF, 0, 0, 0, 0]);
+        assert_eq!(component.layout(), "items:2:3;other:2:3");
+    }
+
+    #[test]
+    fn empty_table() {
+        let mut store = store();
+        let mut provider = provider();
+        provider.items = default!();
+        let component = Component::with(&provider).unwrap();
+        assert_eq!(component.regions.len(), 2);
+        assert_eq!(save(&mut store, &provider), 2);
+        assert_eq!(store.load_component::<TestProvider>("test").unwrap(), Some(provider));
+    }
+
+    #[test]
+    fn stale_layout() {
+        let mut store = store();
+        let mut provider = provider();
+        provider.stale_layout = true;
+        let component = Component::with(&provider).unwrap();
+        assert!(component.regions.is_empty());
+        assert!(component.tables.is_empty());
+        assert_eq!(component.record, provider.to_strict_serialized::<U32>().unwrap().into_inner());
+
+        assert_eq!(save(&mut store, &provider), 1);
+        provider.stale_layout = false;
+        assert_eq!(store.load_component::<TestProvider>("test").unwrap(), Some(provider));
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut store = store();
+        assert_eq!(store.load_component::<TestProvider>("test").unwrap(), None);
+
+        let mut provider = provider();
+        save(&mut store, &provider);
+        assert_eq!(store.load_component::<TestProvider>("test").unwrap(), Some(provider.clone()));
+
+        provider.other = default!();
+        save(&mut store, &provider);
+        assert_eq!(store.load_component::<TestProvider>("test").unwrap(), Some(provider));
+    }
+
+    #[test]
+    fn incremental() {
+        let mut store = store();
+        let mut provider = provider();
+        // Provider record and a row for each of the items
+        assert_eq!(save(&mut store, &provider), 12);
+        assert_eq!(save(&mut store, &provider), 0);
+
+        // New and changed items touch only their rows
+        provider.items.insert([0x20; 32], 0x20).unwrap();
+        assert_eq!(save(&mut store, &provider), 1);
+        provider.items.insert([5; 32], 500).unwrap();
+        provider.other.insert([0xAA; 32], 0).unwrap();
+        assert_eq!(save(&mut store, &provider), 2);
+
+        // Removed items delete their rows
+        provider.items.remove(&[1; 32]).unwrap();
+        assert_eq!(save(&mut store, &provider), 1);
+
+        // Changes outside of the tables touch only the provider record
+        provider.footer = 0;
+        assert_eq!(save(&mut store, &provider), 1);
+
+        assert_eq!(store.load_component::<TestProvider>("test").unwrap(), Some(provider));
+    }
+
+    #[test]
+    fn stock_providers() {
+        let stock = demo_stock();
+        let mut store = store();
+        store.store(&stock).unwrap();
+
+        // Each provider of the stock is split into its tables, having rows for
+        // the items of the demo contract
+        for name in [COMPONENT_STASH, COMPONENT_STATE, COMPONENT_INDEX] {
+            let layout: String = store
+                .conn
+                .query_row("SELECT layout FROM components WHERE name = ?1", [name], |row| {
+                    row.get(0)
+                })
+                .unwrap();
+            let regions = parse_layout(&layout).unwrap();
+            assert!(!regions.is_empty(), "{name} is kept as a single record");
+            let rows = regions
+                .iter()
+                .map(|region| {
+                    store
+                        .conn
+                        .query_row(
+                            "SELECT COUNT(*) FROM items WHERE collection = ?1",
+                            [&region.table],
+                            |row| row.get::<_, usize>(0),
+                        )
+                        .unwrap()
+                })
+                .sum::<usize>();
+            assert!(rows > 0, "{name} has no item rows");
+        }
+
+        let loaded: Stock = store.load().unwrap().unwrap();
+        assert_eq!(encoded(loaded.as_stash_provider()), encoded(stock.as_stash_provider()));
+        assert_eq!(encoded(loaded.as_state_provider()), encoded(stock.as_state_provider()));
+        assert_eq!(encoded(loaded.as_index_provider()), encoded(stock.as_index_provider()));
+    }
+}